@@ -1,12 +1,19 @@
-use clap::{Parser, Subcommand};
-use libbootforge::usb::detect_devices;
-use serde_json;
+use clap::{Parser, Subcommand, ValueEnum};
+use libbootforge::usb::{
+    clear_port_label, detect_devices, detect_topology, device_db, export_history, export_transitions,
+    load_history, parse_history_date, set_cache_path, set_port_label, ExportFormat, HistoryQuery,
+};
+use std::path::PathBuf;
 use std::process;
 
 #[derive(Parser)]
 #[command(name = "bootforgeusb")]
 #[command(about = "BootForge USB enumeration CLI", long_about = None)]
 struct Cli {
+    /// Device cache file (default: per-user data dir, or $BOOTFORGE_CACHE_PATH)
+    #[arg(long, global = true, value_name = "PATH")]
+    cache: Option<PathBuf>,
+
     #[command(subcommand)]
     command: Commands,
 }
@@ -31,6 +38,56 @@ enum Commands {
         #[arg(short, long)]
         serial: Option<String>,
     },
+    /// Show the USB hub tree with port labels
+    Topology {
+        /// Output as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Label a physical port, e.g. `label 1-2.3 "Bench 3, slot 2"`
+    Label {
+        /// Port location (bus-port.port)
+        location: String,
+        /// Label text; omit with --clear to remove the label
+        label: Option<String>,
+        #[arg(long)]
+        clear: bool,
+    },
+    /// Validate USB device database files
+    LintDb {
+        /// Files to check; defaults to the embedded database and installed overrides
+        files: Vec<PathBuf>,
+        /// Output as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Show devices recorded in the device cache
+    History {
+        /// Only devices seen on or after this date (YYYY-MM-DD or RFC 3339)
+        #[arg(long)]
+        since: Option<String>,
+        /// Only devices seen on or before this date (YYYY-MM-DD or RFC 3339)
+        #[arg(long)]
+        until: Option<String>,
+        /// Logical device ID or serial number
+        #[arg(long)]
+        device: Option<String>,
+        /// List mode transitions instead of devices
+        #[arg(long)]
+        transitions: bool,
+        #[arg(long, value_enum, default_value_t = HistoryFormat::Table)]
+        format: HistoryFormat,
+        /// Write to a file instead of stdout
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum HistoryFormat {
+    Table,
+    Csv,
+    Json,
 }
 
 fn main() {
@@ -40,6 +97,10 @@ fn main() {
 
     let cli = Cli::parse();
 
+    if let Some(path) = cli.cache {
+        set_cache_path(Some(path));
+    }
+
     match cli.command {
         Commands::Scan { json } => {
             match detect_devices() {
@@ -84,5 +145,155 @@ fn main() {
         Commands::Detect { serial } => {
             println!("Detecting device mode for {:?}", serial);
         }
+        Commands::Topology { json } => {
+            match detect_topology() {
+                Ok(topology) => {
+                    if json {
+                        match serde_json::to_string_pretty(&topology) {
+                            Ok(json_str) => {
+                                println!("{}", json_str);
+                            }
+                            Err(e) => {
+                                eprintln!("Failed to serialize topology: {}", e);
+                                process::exit(1);
+                            }
+                        }
+                    } else {
+                        print!("{}", topology.render());
+                    }
+                }
+                Err(e) => {
+                    eprintln!("Failed to scan devices: {}", e);
+                    process::exit(1);
+                }
+            }
+        }
+        Commands::Label { location, label, clear } => {
+            let result = match (label, clear) {
+                (_, true) => clear_port_label(&location).map(|removed| match removed {
+                    Some(old) => println!("Removed label \"{}\" from {}", old, location),
+                    None => println!("No label on {}", location),
+                }),
+                (Some(label), false) => set_port_label(&location, &label)
+                    .map(|_| println!("Labelled {} as \"{}\"", location, label)),
+                (None, false) => {
+                    eprintln!("Provide a label or --clear");
+                    process::exit(2);
+                }
+            };
+
+            if let Err(e) = result {
+                eprintln!("Failed to update port label: {}", e);
+                process::exit(1);
+            }
+        }
+        Commands::LintDb { files, json } => {
+            let reports = device_db::lint(&files);
+            let failed = reports.iter().any(|r| r.has_errors());
+
+            if json {
+                match serde_json::to_string_pretty(&reports) {
+                    Ok(json_str) => {
+                        println!("{}", json_str);
+                    }
+                    Err(e) => {
+                        eprintln!("Failed to serialize lint report: {}", e);
+                        process::exit(1);
+                    }
+                }
+            } else {
+                for report in &reports {
+                    let status = if report.has_errors() { "FAIL" } else { "ok" };
+                    println!(
+                        "{} {} ({} vendors, {} products)",
+                        status, report.source, report.vendors, report.products
+                    );
+                    for issue in &report.issues {
+                        println!("  {:?}: {}", issue.severity, issue.message);
+                    }
+                }
+            }
+
+            if failed {
+                process::exit(1);
+            }
+        }
+        Commands::History { since, until, device, transitions, format, output } => {
+            let parse = |value: Option<String>, end_of_day: bool| {
+                value.map(|v| parse_history_date(&v, end_of_day)).transpose().unwrap_or_else(|e| {
+                    eprintln!("{}", e);
+                    process::exit(2);
+                })
+            };
+            let query = HistoryQuery {
+                since: parse(since, false),
+                until: parse(until, true),
+                device,
+            };
+
+            let records = match load_history(&query) {
+                Ok(records) => records,
+                Err(e) => {
+                    eprintln!("Failed to read device history: {}", e);
+                    process::exit(1);
+                }
+            };
+
+            let rendered = match (format, transitions) {
+                (HistoryFormat::Csv, false) => export_history(&records, ExportFormat::Csv),
+                (HistoryFormat::Json, false) => export_history(&records, ExportFormat::Json),
+                (HistoryFormat::Csv, true) => export_transitions(&records, ExportFormat::Csv),
+                (HistoryFormat::Json, true) => export_transitions(&records, ExportFormat::Json),
+                (HistoryFormat::Table, _) => Ok(render_history(&records, transitions)),
+            };
+
+            let text = match rendered {
+                Ok(text) => text,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    process::exit(1);
+                }
+            };
+
+            match output {
+                Some(path) => {
+                    if let Err(e) = std::fs::write(&path, text) {
+                        eprintln!("Failed to write {}: {}", path.display(), e);
+                        process::exit(1);
+                    }
+                }
+                None => print!("{}", text),
+            }
+        }
+    }
+}
+
+fn render_history(records: &[libbootforge::usb::DeviceHistoryRecord], transitions: bool) -> String {
+    use std::fmt::Write;
+
+    let mut out = String::new();
+    if records.is_empty() {
+        out.push_str("No devices recorded.\n");
+        return out;
+    }
+
+    for r in records {
+        let _ = writeln!(
+            out,
+            "{}  {:04x}  {}  first {}  last {}  {} connection(s), {} scan(s)",
+            r.logical_id,
+            r.vendor_id.unwrap_or_default(),
+            r.serial.as_deref().unwrap_or("(no serial)"),
+            r.first_seen.format("%Y-%m-%d %H:%M"),
+            r.last_seen.format("%Y-%m-%d %H:%M"),
+            r.connection_count,
+            r.sightings,
+        );
+        if transitions {
+            for t in &r.transitions {
+                let _ = writeln!(out, "    {}  {:?} -> {:?}", t.at.format("%Y-%m-%d %H:%M:%S"), t.from_mode, t.to_mode);
+            }
+        }
     }
+    out
 }
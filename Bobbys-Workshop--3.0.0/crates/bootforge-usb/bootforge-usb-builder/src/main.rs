@@ -1,21 +1,113 @@
-use clap::Parser;
+use clap::{Parser, Subcommand};
+use libbootforge::builder::private::{self, KeySource};
+use libbootforge::builder::{self, BuildConfig, UsbBuilder};
+use libbootforge::imaging::{DiskLayout, ImagingEngine, PartitionSlice};
 use log::info;
+use std::io::Write;
+use std::path::{Path, PathBuf};
 
 #[derive(Parser)]
 #[command(name = "bootforge-usb-builder")]
 #[command(about = "Create bootable BootForge USB with partitions", long_about = None)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Args {
-    /// Target device (e.g., /dev/sdb)
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Target device (e.g., /dev/sdb) or image file
+    #[arg(short, long, required = true)]
+    device: Option<String>,
+
+    /// Size of the image file to create (e.g., 8G); not used for devices
+    #[arg(short, long, value_parser = parse_size)]
+    size: Option<u64>,
+
+    /// EFI system partition size
+    #[arg(long, default_value = "256M", value_parser = parse_size)]
+    esp_size: u64,
+
+    /// Bootloader: an EFI binary, or a directory copied to the ESP root
+    #[arg(short, long)]
+    bootloader: Option<PathBuf>,
+
+    /// File or directory copied to the tools partition
     #[arg(short, long)]
-    device: String,
+    tools: Option<PathBuf>,
 
     /// Create private/encrypted partition
     #[arg(short, long)]
     private: bool,
 
-    /// Encryption password
+    /// Private partition size
+    #[arg(long, default_value = "1G", value_parser = parse_size)]
+    private_size: u64,
+
+    /// File or directory copied to the private partition
+    #[arg(long, requires = "private")]
+    private_files: Option<PathBuf>,
+
+    #[command(flatten)]
+    key: KeyArgs,
+
+    /// Print the planned layout without writing anything
+    #[arg(short = 'n', long)]
+    dry_run: bool,
+
+    /// Don't ask before erasing a block device or existing image file
+    #[arg(short, long)]
+    yes: bool,
+
+    /// List the device's partitions and check its partition table, then exit
     #[arg(short, long)]
-    password: Option<String>,
+    list: bool,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Decrypt the private partition of a BootForge USB
+    Unlock {
+        /// BootForge USB device or image file
+        #[arg(short, long)]
+        device: PathBuf,
+
+        /// Write the decrypted volume to this file (a loop-mountable FAT image)
+        #[arg(short, long, required_unless_present = "extract", conflicts_with = "extract")]
+        output: Option<PathBuf>,
+
+        /// Copy the private files into this directory
+        #[arg(short, long)]
+        extract: Option<PathBuf>,
+
+        #[command(flatten)]
+        key: KeyArgs,
+    },
+}
+
+/// The passphrase is prompted for unless one of these is given.
+#[derive(clap::Args)]
+struct KeyArgs {
+    /// Read the private partition passphrase from a file
+    #[arg(long, conflicts_with = "key_stdin")]
+    key_file: Option<PathBuf>,
+
+    /// Read the private partition passphrase from the first line of stdin
+    /// (needs --yes when building on a block device or existing image file)
+    #[arg(long)]
+    key_stdin: bool,
+}
+
+impl KeyArgs {
+    fn source(&self) -> KeySource {
+        match (&self.key_file, self.key_stdin) {
+            (Some(path), _) => KeySource::File(path.clone()),
+            (None, true) => KeySource::Stdin,
+            (None, false) => KeySource::Prompt,
+        }
+    }
+}
+
+fn parse_size(text: &str) -> Result<u64, String> {
+    builder::parse_size(text).map_err(|e| e.to_string())
 }
 
 #[tokio::main]
@@ -26,17 +118,128 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     let args = Args::parse();
 
-    info!("Building BootForge USB on {}", args.device);
+    if let Some(Command::Unlock { device, output, extract, key }) = &args.command {
+        return unlock(device, output.as_deref(), extract.as_deref(), key);
+    }
+    let device = args.device.clone().expect("clap requires --device without a subcommand");
+
+    if args.list {
+        return list_partitions(Path::new(&device));
+    }
+
+    info!("Building BootForge USB on {}", device);
     info!("Private partition: {}", args.private);
 
-    if args.private && args.password.is_none() {
-        eprintln!("Error: --password required when --private is set");
-        std::process::exit(1);
+    let mut config = BuildConfig {
+        target: PathBuf::from(&device),
+        image_size: args.size,
+        esp_size: args.esp_size,
+        bootloader: args.bootloader.clone(),
+        tools: args.tools.clone(),
+        private: args.private,
+        private_size: args.private_size,
+        private_files: args.private_files.clone(),
+        passphrase: None,
+    };
+    let plan = UsbBuilder::new(config.clone()).plan()?;
+    println!("{}", plan.describe());
+
+    if args.dry_run {
+        println!("\nDry run: nothing was written");
+        return Ok(());
+    }
+    if plan.target_kind.overwrites() && !args.yes {
+        // The confirmation would consume the passphrase line
+        if args.private && args.key.key_stdin {
+            return Err("--key-stdin needs --yes when the target already holds data".into());
+        }
+        if !confirm(&device)? {
+            println!("Aborted");
+            return Ok(());
+        }
+    }
+    if args.private {
+        config.passphrase = Some(args.key.source().read(true)?);
     }
 
-    println!("BootForge USB builder initialized (stub)");
-    println!("  Device: {}", args.device);
-    println!("  Private: {}", args.private);
+    let report = UsbBuilder::new(config).execute(plan).await?;
+    println!(
+        "\nBootForge USB ready on {}: {} partitions, {} files ({})",
+        device,
+        report.layout.partitions.len(),
+        report.files_copied,
+        builder::format_size(report.bytes_copied)
+    );
+
+    Ok(())
+}
 
+fn confirm(device: &str) -> Result<bool, Box<dyn std::error::Error>> {
+    print!("\nAll data on {} will be erased. Continue? [y/N] ", device);
+    std::io::stdout().flush()?;
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+fn unlock(
+    device: &Path,
+    output: Option<&Path>,
+    extract: Option<&Path>,
+    key: &KeyArgs,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let layout = DiskLayout::open(device)?;
+    let partition = private::find_partition(&layout)
+        .ok_or_else(|| format!("{} has no BootForge private partition", device.display()))?;
+    let mut disk = std::fs::File::open(device)?;
+    let mut slice = PartitionSlice::new(&mut disk, partition, layout.sector_size);
+    if !private::is_private_volume(&mut slice) {
+        return Err(format!("The private partition on {} was never sealed", device.display()).into());
+    }
+    let passphrase = key.source().read(false)?;
+
+    if let Some(dir) = extract {
+        let count = private::extract(&mut slice, &passphrase, dir)?;
+        println!("Extracted {} files to {}", count, dir.display());
+    } else if let Some(path) = output {
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let mut out = options.open(path)?;
+        let size = private::unlock(&mut slice, &passphrase, &mut out)?;
+        out.sync_all()?;
+        println!("Wrote decrypted volume ({}) to {}", builder::format_size(size), path.display());
+        println!("Mount it with: sudo mount -o loop {} /mnt", path.display());
+    }
+    Ok(())
+}
+
+fn list_partitions(device: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    let layout = ImagingEngine::partition_layout(device)?;
+    println!(
+        "{}: {:?}, {} sectors of {} bytes",
+        device.display(),
+        layout.table,
+        layout.disk_sectors,
+        layout.sector_size
+    );
+    println!("{:>3} {:>12} {:>12} {:>12}  {:<24} Label", "#", "Start", "End", "Size", "Type");
+    for p in &layout.partitions {
+        println!(
+            "{:>3} {:>12} {:>12} {:>12}  {:<24} {}",
+            p.number,
+            p.first_lba,
+            p.last_lba,
+            p.size_bytes(layout.sector_size),
+            p.type_name(),
+            p.label
+        );
+    }
+
+    let report = ImagingEngine::validate_partitions(device)?;
+    for problem in &report.problems {
+        println!("warning: {}", problem);
+    }
     Ok(())
 }
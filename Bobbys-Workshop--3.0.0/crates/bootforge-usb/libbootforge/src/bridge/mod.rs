@@ -1,5 +1,5 @@
 use crate::{Result, BootforgeError};
-use crate::usb::{DeviceEvent, DeviceWatcher, WatcherConfig, UsbDeviceInfo};
+use crate::usb::{DeviceEvent, DeviceWatcher, WatcherConfig, UsbDeviceInfo, UsbBackend, NusbBackend};
 use serde::{Deserialize, Serialize};
 use std::sync::Arc;
 use tokio::sync::RwLock;
@@ -18,21 +18,23 @@ pub enum BridgeMessage {
 
 pub struct BootforgeBridge {
     watcher: Arc<DeviceWatcher>,
+    backend: Arc<dyn UsbBackend>,
     running: Arc<RwLock<bool>>,
 }
 
 impl BootforgeBridge {
     pub fn new() -> Self {
-        let config = WatcherConfig::default();
-        BootforgeBridge {
-            watcher: Arc::new(DeviceWatcher::new(config)),
-            running: Arc::new(RwLock::new(false)),
-        }
+        Self::with_config(WatcherConfig::default())
     }
     
     pub fn with_config(config: WatcherConfig) -> Self {
+        Self::with_backend(config, Arc::new(NusbBackend))
+    }
+    
+    pub fn with_backend(config: WatcherConfig, backend: Arc<dyn UsbBackend>) -> Self {
         BootforgeBridge {
-            watcher: Arc::new(DeviceWatcher::new(config)),
+            watcher: Arc::new(DeviceWatcher::with_backend(config, backend.clone())),
+            backend,
             running: Arc::new(RwLock::new(false)),
         }
     }
@@ -70,7 +72,7 @@ impl BootforgeBridge {
     
     pub async fn scan_devices(&self) -> Result<BridgeMessage> {
         let start = std::time::Instant::now();
-        let devices = crate::usb::detect_devices()?;
+        let devices = crate::usb::detect_devices_with(self.backend.as_ref())?;
         let elapsed = start.elapsed().as_millis() as u64;
         
         Ok(BridgeMessage::ScanResponse { 
@@ -111,3 +113,26 @@ impl Default for BootforgeBridge {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::usb::backend::tests::fixture_device;
+    use crate::usb::{DeviceMode, FixtureBackend};
+
+    #[tokio::test]
+    async fn test_scan_request_uses_backend() {
+        let backend = Arc::new(FixtureBackend::with_devices(vec![
+            fixture_device(0x05ac, 0x1227, "IPHONE", DeviceMode::DFU),
+        ]));
+        let bridge = BootforgeBridge::with_backend(WatcherConfig::default(), backend);
+
+        match bridge.handle_message(BridgeMessage::ScanRequest).await.unwrap() {
+            BridgeMessage::ScanResponse { devices, .. } => {
+                assert_eq!(devices.len(), 1);
+                assert_eq!(devices[0].serial.as_deref(), Some("IPHONE"));
+            }
+            other => panic!("unexpected response: {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,689 @@
+//! BootForge USB Builder Module
+//!
+//! Lays out a bootable BootForge USB: an EFI system partition carrying the
+//! bootloader payload, a FAT tools partition and an optional private
+//! partition. The target can be a block device or a plain image file.
+
+pub mod private;
+
+use crate::imaging::partition::{DiskLayout, NewPartition, Partition, PartitionSlice, PartitionType};
+use crate::{BootforgeError, Result};
+use age::secrecy::SecretString;
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::{Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+const MIB: u64 = 1024 * 1024;
+pub const DEFAULT_ESP_SIZE: u64 = 256 * MIB;
+pub const DEFAULT_PRIVATE_SIZE: u64 = 1024 * MIB;
+const SECTOR_SIZE: u64 = 512;
+/// Smallest tools partition worth creating.
+const MIN_TOOLS_SIZE: u64 = 32 * MIB;
+/// Old tables and filesystem signatures are cleared from this much of the
+/// start and end of the target, and of unformatted partitions.
+const WIPE_BYTES: u64 = MIB;
+const ESP_VOLUME_LABEL: &str = "BOOTFORGE";
+const TOOLS_VOLUME_LABEL: &str = "BFTOOLS";
+/// Name UEFI firmware looks for on removable media when given a bare binary.
+const DEFAULT_BOOTLOADER_NAME: &str = "BOOTX64.EFI";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PartitionRole {
+    Esp,
+    Tools,
+    Private,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Filesystem {
+    Fat,
+    /// A FAT volume inside an age container; see [`private`].
+    Encrypted,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TargetKind {
+    BlockDevice,
+    ImageFile { exists: bool },
+}
+
+impl TargetKind {
+    /// Whether building erases data already on the target: any block
+    /// device, or an image file that exists.
+    pub fn overwrites(&self) -> bool {
+        matches!(self, TargetKind::BlockDevice | TargetKind::ImageFile { exists: true })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct BuildConfig {
+    pub target: PathBuf,
+    /// Size of the image file to create or resize. Block devices always
+    /// use their own size.
+    pub image_size: Option<u64>,
+    pub esp_size: u64,
+    /// An EFI binary, installed as `EFI/BOOT/BOOTX64.EFI` unless already
+    /// named `BOOT*.EFI`, or a directory copied to the root of the ESP.
+    pub bootloader: Option<PathBuf>,
+    /// File or directory copied to the root of the tools partition.
+    pub tools: Option<PathBuf>,
+    pub private: bool,
+    pub private_size: u64,
+    /// File or directory copied into the private partition.
+    pub private_files: Option<PathBuf>,
+    /// Required to build a private partition, not to plan one.
+    pub passphrase: Option<SecretString>,
+}
+
+impl BuildConfig {
+    pub fn new(target: impl Into<PathBuf>) -> Self {
+        BuildConfig {
+            target: target.into(),
+            image_size: None,
+            esp_size: DEFAULT_ESP_SIZE,
+            bootloader: None,
+            tools: None,
+            private: false,
+            private_size: DEFAULT_PRIVATE_SIZE,
+            private_files: None,
+            passphrase: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PayloadFile {
+    pub source: PathBuf,
+    /// '/'-separated path inside the partition.
+    pub destination: String,
+    pub size: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PlannedPartition {
+    pub role: PartitionRole,
+    pub partition: Partition,
+    pub filesystem: Filesystem,
+    pub volume_label: Option<String>,
+    pub payload: Vec<PayloadFile>,
+}
+
+impl PlannedPartition {
+    pub fn payload_bytes(&self) -> u64 {
+        self.payload.iter().map(|f| f.size).sum()
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BuildPlan {
+    pub target: PathBuf,
+    pub target_kind: TargetKind,
+    pub disk_bytes: u64,
+    pub layout: DiskLayout,
+    pub partitions: Vec<PlannedPartition>,
+    pub warnings: Vec<String>,
+}
+
+impl BuildPlan {
+    pub fn partition(&self, role: PartitionRole) -> Option<&PlannedPartition> {
+        self.partitions.iter().find(|p| p.role == role)
+    }
+
+    /// Human-readable summary of the plan, as printed by `--dry-run`.
+    pub fn describe(&self) -> String {
+        let kind = match self.target_kind {
+            TargetKind::BlockDevice => "block device",
+            TargetKind::ImageFile { exists: true } => "image file, will be overwritten",
+            TargetKind::ImageFile { exists: false } => "image file, will be created",
+        };
+        let mut out = format!(
+            "Target: {} ({}), {}\nPartition table: {:?}, {}-byte sectors\n\n",
+            self.target.display(),
+            kind,
+            format_size(self.disk_bytes),
+            self.layout.table,
+            self.layout.sector_size
+        );
+        out.push_str(&format!(
+            "{:>3} {:>10} {:>10} {:>10}  {:<20} {:<16} {}\n",
+            "#", "Start", "End", "Size", "Type", "Filesystem", "Label"
+        ));
+        for planned in &self.partitions {
+            let p = &planned.partition;
+            let filesystem = match (planned.filesystem, &planned.volume_label) {
+                (Filesystem::Fat, Some(label)) => format!("FAT ({})", label),
+                (Filesystem::Fat, None) => "FAT".to_string(),
+                (Filesystem::Encrypted, Some(label)) => format!("age ({})", label),
+                (Filesystem::Encrypted, None) => "age".to_string(),
+            };
+            out.push_str(&format!(
+                "{:>3} {:>10} {:>10} {:>10}  {:<20} {:<16} {}\n",
+                p.number,
+                p.first_lba,
+                p.last_lba,
+                format_size(p.size_bytes(self.layout.sector_size)),
+                p.type_name(),
+                filesystem,
+                p.label
+            ));
+        }
+
+        for planned in self.partitions.iter().filter(|p| !p.payload.is_empty()) {
+            out.push_str(&format!(
+                "\n{:?} payload: {} files, {}\n",
+                planned.role,
+                planned.payload.len(),
+                format_size(planned.payload_bytes())
+            ));
+            for file in &planned.payload {
+                out.push_str(&format!("  {} <- {}\n", file.destination, file.source.display()));
+            }
+        }
+        for warning in &self.warnings {
+            out.push_str(&format!("\nwarning: {}", warning));
+        }
+        out
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BuildReport {
+    pub layout: DiskLayout,
+    pub files_copied: usize,
+    pub bytes_copied: u64,
+}
+
+pub struct UsbBuilder {
+    config: BuildConfig,
+}
+
+impl UsbBuilder {
+    pub fn new(config: BuildConfig) -> Self {
+        UsbBuilder { config }
+    }
+
+    /// Work out the layout and payload without touching the target.
+    pub fn plan(&self) -> Result<BuildPlan> {
+        let config = &self.config;
+        let (target_kind, disk_bytes) = inspect_target(config)?;
+        let mut warnings = Vec::new();
+
+        let mut layout = DiskLayout::new_gpt(disk_bytes, SECTOR_SIZE)?;
+        let esp = layout.add_partition(&NewPartition::new("BootForge ESP", PartitionType::EFI_SYSTEM, Some(config.esp_size)))?;
+        let tools = layout.add_partition(&NewPartition::new("BootForge Tools", PartitionType::BASIC_DATA, None))?;
+        let private = if config.private {
+            let available = layout.partition(tools).map_or(0, |p| p.size_bytes(SECTOR_SIZE));
+            let tools_size = available.saturating_sub(config.private_size + MIB) / MIB * MIB;
+            if tools_size < MIN_TOOLS_SIZE {
+                return Err(BootforgeError::Imaging(format!(
+                    "{} is too small for a {} private partition",
+                    format_size(disk_bytes),
+                    format_size(config.private_size)
+                )));
+            }
+            layout.resize_partition(tools, Some(tools_size))?;
+            Some(layout.add_partition(&NewPartition::new("BootForge Private", PartitionType::BOOTFORGE_PRIVATE, Some(config.private_size)))?)
+        } else {
+            None
+        };
+        if layout.partition(tools).is_some_and(|p| p.size_bytes(SECTOR_SIZE) < MIN_TOOLS_SIZE) {
+            return Err(BootforgeError::Imaging(format!("{} leaves no room for the tools partition", format_size(disk_bytes))));
+        }
+
+        let bootloader = match &config.bootloader {
+            Some(path) => bootloader_payload(path)?,
+            None => {
+                warnings.push("No bootloader given; the ESP will be empty and the USB will not boot".to_string());
+                Vec::new()
+            }
+        };
+        let tools_payload = match &config.tools {
+            Some(path) => collect_payload(path, "")?,
+            None => Vec::new(),
+        };
+        let private_payload = match &config.private_files {
+            Some(path) if config.private => collect_payload(path, "")?,
+            Some(_) => return Err(BootforgeError::Imaging("Private files need a private partition".to_string())),
+            None => Vec::new(),
+        };
+
+        let planned = |number: u32, role, filesystem, label: Option<&str>, payload| PlannedPartition {
+            role,
+            partition: layout.partition(number).cloned().expect("partition was just added"),
+            filesystem,
+            volume_label: label.map(str::to_string),
+            payload,
+        };
+        let mut partitions = vec![
+            planned(esp, PartitionRole::Esp, Filesystem::Fat, Some(ESP_VOLUME_LABEL), bootloader),
+            planned(tools, PartitionRole::Tools, Filesystem::Fat, Some(TOOLS_VOLUME_LABEL), tools_payload),
+        ];
+        if let Some(number) = private {
+            partitions.push(planned(number, PartitionRole::Private, Filesystem::Encrypted, Some(private::PRIVATE_VOLUME_LABEL), private_payload));
+        }
+
+        for p in &partitions {
+            let volume = match p.filesystem {
+                Filesystem::Fat => p.partition.size_bytes(SECTOR_SIZE),
+                Filesystem::Encrypted => private::volume_size(p.partition.size_bytes(SECTOR_SIZE)),
+            };
+            // Leave room for the FATs, directories and cluster slack
+            let capacity = volume / 10 * 9;
+            if p.payload_bytes() > capacity {
+                return Err(BootforgeError::Imaging(format!(
+                    "{:?} payload ({}) does not fit in a {} partition",
+                    p.role,
+                    format_size(p.payload_bytes()),
+                    format_size(p.partition.size_bytes(SECTOR_SIZE))
+                )));
+            }
+        }
+
+        Ok(BuildPlan { target: config.target.clone(), target_kind, disk_bytes, layout, partitions, warnings })
+    }
+
+    /// Plan, then erase the target and write the layout and payload.
+    pub async fn build(&self) -> Result<BuildReport> {
+        let plan = self.plan()?;
+        self.execute(plan).await
+    }
+
+    /// Carry out a plan from `plan`. Everything on the target is lost.
+    pub async fn execute(&self, plan: BuildPlan) -> Result<BuildReport> {
+        let passphrase = self.config.passphrase.clone();
+        if plan.partition(PartitionRole::Private).is_some() && passphrase.is_none() {
+            return Err(BootforgeError::Imaging("A passphrase is required for the private partition".to_string()));
+        }
+        tokio::task::spawn_blocking(move || execute_plan(&plan, passphrase.as_ref()))
+            .await
+            .map_err(|e| BootforgeError::Imaging(format!("USB build task failed: {}", e)))?
+    }
+}
+
+fn inspect_target(config: &BuildConfig) -> Result<(TargetKind, u64)> {
+    let target = &config.target;
+    match std::fs::metadata(target) {
+        Ok(meta) if is_device(&meta) => {
+            if config.image_size.is_some() {
+                return Err(BootforgeError::Imaging(format!(
+                    "{} is a block device; a size only applies to image files",
+                    target.display()
+                )));
+            }
+            let size = File::open(target)
+                .and_then(|mut f| f.seek(SeekFrom::End(0)))
+                .map_err(|e| BootforgeError::Imaging(format!("Failed to open {}: {}", target.display(), e)))?;
+            Ok((TargetKind::BlockDevice, size))
+        }
+        Ok(meta) if meta.is_file() => {
+            let size = config.image_size.unwrap_or(meta.len());
+            if size == 0 {
+                return Err(BootforgeError::Imaging(format!("{} is empty; give an image size", target.display())));
+            }
+            Ok((TargetKind::ImageFile { exists: true }, size))
+        }
+        Ok(_) => Err(BootforgeError::Imaging(format!("{} is not a block device or image file", target.display()))),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => match config.image_size {
+            Some(size) => Ok((TargetKind::ImageFile { exists: false }, size)),
+            None => Err(BootforgeError::Imaging(format!(
+                "{} does not exist; give an image size to create it",
+                target.display()
+            ))),
+        },
+        Err(e) => Err(BootforgeError::Imaging(format!("Failed to inspect {}: {}", target.display(), e))),
+    }
+}
+
+#[cfg(unix)]
+fn is_device(meta: &std::fs::Metadata) -> bool {
+    use std::os::unix::fs::FileTypeExt;
+    // macOS raw disks (/dev/rdiskN) are character devices
+    meta.file_type().is_block_device() || meta.file_type().is_char_device()
+}
+
+#[cfg(not(unix))]
+fn is_device(_meta: &std::fs::Metadata) -> bool {
+    false
+}
+
+fn bootloader_payload(path: &Path) -> Result<Vec<PayloadFile>> {
+    if path.is_dir() {
+        return collect_payload(path, "");
+    }
+
+    let name = path.file_name().and_then(|n| n.to_str()).unwrap_or_default();
+    let upper = name.to_ascii_uppercase();
+    let name = if upper.starts_with("BOOT") && upper.ends_with(".EFI") { upper } else { DEFAULT_BOOTLOADER_NAME.to_string() };
+    collect_payload(path, &format!("EFI/BOOT/{}", name))
+}
+
+/// Files under `source` with their destinations below `destination`. A
+/// single file is copied to `destination` itself, or under its own name
+/// when `destination` is empty.
+fn collect_payload(source: &Path, destination: &str) -> Result<Vec<PayloadFile>> {
+    let meta = std::fs::metadata(source)
+        .map_err(|e| BootforgeError::Imaging(format!("Failed to read payload {}: {}", source.display(), e)))?;
+    if meta.is_file() {
+        let destination = match destination {
+            "" => source.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default(),
+            d => d.to_string(),
+        };
+        return Ok(vec![PayloadFile { source: source.to_path_buf(), destination, size: meta.len() }]);
+    }
+
+    let mut entries: Vec<_> = std::fs::read_dir(source)?.collect::<std::io::Result<_>>()?;
+    entries.sort_by_key(|e| e.file_name());
+    let mut files = Vec::new();
+    for entry in entries {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let nested = if destination.is_empty() { name } else { format!("{}/{}", destination, name) };
+        files.extend(collect_payload(&entry.path(), &nested)?);
+    }
+    Ok(files)
+}
+
+fn execute_plan(plan: &BuildPlan, passphrase: Option<&SecretString>) -> Result<BuildReport> {
+    if plan.target_kind == TargetKind::BlockDevice {
+        ensure_not_mounted(&plan.target)?;
+    }
+
+    let mut disk = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(matches!(plan.target_kind, TargetKind::ImageFile { .. }))
+        .truncate(false)
+        .open(&plan.target)
+        .map_err(|e| BootforgeError::Imaging(format!("Failed to open {}: {}", plan.target.display(), e)))?;
+    if matches!(plan.target_kind, TargetKind::ImageFile { .. }) {
+        disk.set_len(plan.disk_bytes)?;
+    }
+
+    log::info!("Clearing old partition tables on {}", plan.target.display());
+    zero_range(&mut disk, 0, WIPE_BYTES.min(plan.disk_bytes))?;
+    zero_range(&mut disk, plan.disk_bytes.saturating_sub(WIPE_BYTES), WIPE_BYTES.min(plan.disk_bytes))?;
+    plan.layout.write(&mut disk)?;
+
+    let mut report = BuildReport { layout: plan.layout.clone(), files_copied: 0, bytes_copied: 0 };
+    for planned in &plan.partitions {
+        let mut slice = PartitionSlice::new(&mut disk, &planned.partition, plan.layout.sector_size);
+        match planned.filesystem {
+            Filesystem::Fat => {
+                log::info!("Formatting {} as FAT", planned.partition.label);
+                format_fat(&mut slice, planned.volume_label.as_deref().unwrap_or_default())?;
+                copy_files(&mut slice, &planned.partition.label, &planned.payload, &mut report)?;
+            }
+            Filesystem::Encrypted => {
+                let passphrase = passphrase
+                    .ok_or_else(|| BootforgeError::Imaging("A passphrase is required for the private partition".to_string()))?;
+                let len = slice.len();
+                private::seal(&mut slice, len, passphrase, &planned.payload, &mut report)?;
+            }
+        }
+    }
+    disk.sync_all()?;
+
+    let validation = DiskLayout::validate(&mut disk)?;
+    if !validation.is_ok() {
+        return Err(BootforgeError::Imaging(format!(
+            "Partition table on {} failed validation: {}",
+            plan.target.display(),
+            validation.problems.join("; ")
+        )));
+    }
+    report.layout = DiskLayout::read(&mut disk)?;
+    log::info!(
+        "Built BootForge USB on {}: {} files, {} copied",
+        plan.target.display(),
+        report.files_copied,
+        format_size(report.bytes_copied)
+    );
+    Ok(report)
+}
+
+fn format_fat<D: std::io::Read + Write + Seek>(volume: &mut D, label: &str) -> Result<()> {
+    let options = fatfs::FormatVolumeOptions::new().volume_label(volume_label(label));
+    fatfs::format_volume(volume, options)
+        .map_err(|e| BootforgeError::Imaging(format!("Failed to format {}: {}", label, e)))
+}
+
+fn copy_files<D: std::io::Read + Write + Seek>(
+    volume: &mut D,
+    name: &str,
+    files: &[PayloadFile],
+    report: &mut BuildReport,
+) -> Result<()> {
+    let fs_err = |e: std::io::Error| BootforgeError::Imaging(format!("{}: {}", name, e));
+    let fs = fatfs::FileSystem::new(volume, fatfs::FsOptions::new()).map_err(fs_err)?;
+    {
+        let root = fs.root_dir();
+        for file in files {
+            let mut parent = String::new();
+            let parts: Vec<&str> = file.destination.split('/').collect();
+            for dir in &parts[..parts.len() - 1] {
+                parent = if parent.is_empty() { dir.to_string() } else { format!("{}/{}", parent, dir) };
+                root.create_dir(&parent).map_err(fs_err)?;
+            }
+
+            let mut source = File::open(&file.source)
+                .map_err(|e| BootforgeError::Imaging(format!("Failed to open {}: {}", file.source.display(), e)))?;
+            let mut out = root.create_file(&file.destination).map_err(fs_err)?;
+            out.truncate().map_err(fs_err)?;
+            report.bytes_copied += std::io::copy(&mut source, &mut out).map_err(fs_err)?;
+            out.flush().map_err(fs_err)?;
+            report.files_copied += 1;
+        }
+    }
+    fs.unmount().map_err(fs_err)
+}
+
+/// FAT volume labels are 11 upper-case characters padded with spaces.
+fn volume_label(label: &str) -> [u8; 11] {
+    let mut out = [b' '; 11];
+    for (slot, byte) in out.iter_mut().zip(label.to_ascii_uppercase().bytes()) {
+        *slot = byte;
+    }
+    out
+}
+
+fn zero_range<D: Write + Seek>(disk: &mut D, offset: u64, len: u64) -> Result<()> {
+    let zeros = vec![0u8; MIB as usize];
+    disk.seek(SeekFrom::Start(offset))?;
+    let mut left = len;
+    while left > 0 {
+        let n = left.min(zeros.len() as u64) as usize;
+        disk.write_all(&zeros[..n])?;
+        left -= n as u64;
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn ensure_not_mounted(device: &Path) -> Result<()> {
+    let mounts = std::fs::read_to_string("/proc/mounts").unwrap_or_default();
+    // Resolve /dev/disk/by-id/... and other links to the kernel's name
+    let device = std::fs::canonicalize(device)
+        .map_err(|e| BootforgeError::Imaging(format!("Failed to resolve {}: {}", device.display(), e)))?;
+    let device = device.to_string_lossy();
+    for source in mounts.lines().filter_map(|l| l.split_whitespace().next()) {
+        let resolved = std::fs::canonicalize(source).ok();
+        let source = resolved.as_deref().and_then(Path::to_str).unwrap_or(source);
+        // /dev/sdb1 and /dev/mmcblk0p1 belong to /dev/sdb and /dev/mmcblk0
+        let is_part = source
+            .strip_prefix(device.as_ref())
+            .is_some_and(|rest| rest.is_empty() || rest.trim_start_matches('p').chars().all(|c| c.is_ascii_digit()));
+        if is_part {
+            return Err(BootforgeError::Imaging(format!("{} is mounted; unmount it first", source)));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn ensure_not_mounted(_device: &Path) -> Result<()> {
+    Ok(())
+}
+
+/// Parse sizes like `512M`, `2G`, `1.5GiB` or a plain byte count.
+pub fn parse_size(text: &str) -> Result<u64> {
+    let text = text.trim();
+    let split = text.find(|c: char| c.is_ascii_alphabetic()).unwrap_or(text.len());
+    let (number, unit) = text.split_at(split);
+    let multiplier: u64 = match unit.to_ascii_uppercase().trim_end_matches("IB").trim_end_matches('B') {
+        "" => 1,
+        "K" => 1024,
+        "M" => MIB,
+        "G" => 1024 * MIB,
+        "T" => 1024 * 1024 * MIB,
+        _ => return Err(BootforgeError::Other(format!("Unknown size unit in '{}'", text))),
+    };
+    let value: f64 = number
+        .trim()
+        .parse()
+        .map_err(|_| BootforgeError::Other(format!("Invalid size '{}'", text)))?;
+    if value < 0.0 {
+        return Err(BootforgeError::Other(format!("Invalid size '{}'", text)));
+    }
+    Ok((value * multiplier as f64) as u64)
+}
+
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    fn config(dir: &tempfile::TempDir) -> BuildConfig {
+        let mut config = BuildConfig::new(dir.path().join("usb.img"));
+        config.image_size = Some(128 * MIB);
+        config.esp_size = 32 * MIB;
+        config.private_size = 32 * MIB;
+        config
+    }
+
+    #[test]
+    fn test_plan_does_not_touch_target() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = config(&dir);
+        config.private = true;
+
+        let plan = UsbBuilder::new(config.clone()).plan().unwrap();
+        assert!(!config.target.exists());
+        assert_eq!(plan.target_kind, TargetKind::ImageFile { exists: false });
+        assert!(!plan.target_kind.overwrites());
+
+        let roles: Vec<_> = plan.partitions.iter().map(|p| p.role).collect();
+        assert_eq!(roles, [PartitionRole::Esp, PartitionRole::Tools, PartitionRole::Private]);
+        let esp = &plan.partition(PartitionRole::Esp).unwrap().partition;
+        assert_eq!(esp.size_bytes(SECTOR_SIZE), 32 * MIB);
+        assert_eq!(esp.type_name(), "EFI System");
+        let private = &plan.partition(PartitionRole::Private).unwrap().partition;
+        assert_eq!(private.size_bytes(SECTOR_SIZE), 32 * MIB);
+        assert!(private.last_lba <= plan.layout.last_usable_lba);
+        assert!(plan.describe().contains("BootForge Tools"));
+
+        std::fs::write(&config.target, b"").unwrap();
+        let plan = UsbBuilder::new(config.clone()).plan().unwrap();
+        assert_eq!(plan.target_kind, TargetKind::ImageFile { exists: true });
+        assert!(plan.target_kind.overwrites());
+        assert!(plan.describe().contains("will be overwritten"));
+    }
+
+    #[test]
+    fn test_build_image_end_to_end() {
+        let dir = tempfile::tempdir().unwrap();
+        let loader = dir.path().join("grubx64.efi");
+        std::fs::write(&loader, b"MZ fake loader").unwrap();
+        let tools = dir.path().join("tools");
+        std::fs::create_dir_all(tools.join("android")).unwrap();
+        std::fs::write(tools.join("README.txt"), b"BootForge tools").unwrap();
+        std::fs::write(tools.join("android/adb"), vec![7u8; 100_000]).unwrap();
+
+        let secret = dir.path().join("secret.key");
+        std::fs::write(&secret, b"unlock me").unwrap();
+
+        let mut config = config(&dir);
+        config.bootloader = Some(loader);
+        config.tools = Some(tools);
+        config.private = true;
+        config.private_files = Some(secret);
+        let builder = UsbBuilder::new(config.clone());
+        let plan = builder.plan().unwrap();
+        let esp = plan.partition(PartitionRole::Esp).unwrap().partition.clone();
+        let tools_part = plan.partition(PartitionRole::Tools).unwrap().partition.clone();
+        let private_part = plan.partition(PartitionRole::Private).unwrap().partition.clone();
+
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        // Planning works without a passphrase, building does not
+        assert!(rt.block_on(builder.execute(plan.clone())).is_err());
+        let passphrase = SecretString::from("hunter2".to_string());
+        config.passphrase = Some(passphrase.clone());
+        let report = rt.block_on(UsbBuilder::new(config.clone()).execute(plan)).unwrap();
+        assert_eq!(report.files_copied, 4);
+        assert_eq!(report.layout.partitions.len(), 3);
+        assert_eq!(std::fs::metadata(&config.target).unwrap().len(), 128 * MIB);
+
+        let mut disk = OpenOptions::new().read(true).write(true).open(&config.target).unwrap();
+        assert!(DiskLayout::validate(&mut disk).unwrap().is_ok());
+
+        let fs = fatfs::FileSystem::new(PartitionSlice::new(&mut disk, &esp, SECTOR_SIZE), fatfs::FsOptions::new()).unwrap();
+        assert_eq!(fs.volume_label(), "BOOTFORGE");
+        let mut loader = String::new();
+        fs.root_dir().open_file("EFI/BOOT/BOOTX64.EFI").unwrap().read_to_string(&mut loader).unwrap();
+        assert_eq!(loader, "MZ fake loader");
+        drop(fs);
+
+        let fs = fatfs::FileSystem::new(PartitionSlice::new(&mut disk, &tools_part, SECTOR_SIZE), fatfs::FsOptions::new()).unwrap();
+        let mut adb = Vec::new();
+        fs.root_dir().open_file("android/adb").unwrap().read_to_end(&mut adb).unwrap();
+        assert_eq!(adb, vec![7u8; 100_000]);
+        drop(fs);
+
+        let out = dir.path().join("unlocked");
+        let mut slice = PartitionSlice::new(&mut disk, &private_part, SECTOR_SIZE);
+        assert_eq!(private::extract(&mut slice, &passphrase, &out).unwrap(), 1);
+        assert_eq!(std::fs::read(out.join("secret.key")).unwrap(), b"unlock me");
+    }
+
+    #[test]
+    fn test_plan_rejects_oversized_payload_and_small_disk() {
+        let dir = tempfile::tempdir().unwrap();
+        let loader = dir.path().join("BOOTAA64.EFI");
+        File::create(&loader).unwrap().set_len(40 * MIB).unwrap();
+
+        let mut config = config(&dir);
+        config.bootloader = Some(loader);
+        let err = UsbBuilder::new(config.clone()).plan().unwrap_err();
+        assert!(err.to_string().contains("does not fit"));
+
+        config.bootloader = None;
+        config.private = true;
+        config.private_size = 100 * MIB;
+        assert!(UsbBuilder::new(config).plan().is_err());
+    }
+
+    #[test]
+    fn test_parse_and_format_size() {
+        assert_eq!(parse_size("512M").unwrap(), 512 * MIB);
+        assert_eq!(parse_size("2G").unwrap(), 2048 * MIB);
+        assert_eq!(parse_size("1.5GiB").unwrap(), 1536 * MIB);
+        assert_eq!(parse_size("4096").unwrap(), 4096);
+        assert!(parse_size("12Q").is_err());
+        assert_eq!(format_size(256 * MIB), "256.0 MiB");
+        assert_eq!(format_size(100), "100 B");
+    }
+}
@@ -0,0 +1,346 @@
+//! Encrypted private partition.
+//!
+//! The partition holds a FAT volume encrypted as a standard age file with a
+//! passphrase (scrypt) recipient, behind a small plaintext header:
+//!
+//! ```text
+//! 0    8  magic "BFPRIV01"
+//! 8    8  length of the age file (LE)
+//! 16   8  size of the decrypted volume (LE)
+//! 512  -  age file
+//! ```
+//!
+//! The volume is assembled in memory so no plaintext reaches the host's
+//! disk. `unlock` reverses it; `age -d` also works on the extracted file.
+
+use super::{copy_files, format_fat, BuildReport, PayloadFile};
+use crate::imaging::partition::{DiskLayout, Partition, PartitionType};
+use crate::{BootforgeError, Result};
+use age::secrecy::SecretString;
+use std::collections::BTreeMap;
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+const PRIVATE_MAGIC: &[u8; 8] = b"BFPRIV01";
+const HEADER_LEN: u64 = 512;
+pub const PRIVATE_VOLUME_LABEL: &str = "BFPRIVATE";
+/// Granularity of the in-memory volume.
+const BLOCK: u64 = 64 * 1024;
+/// scrypt work factor for tests; otherwise age picks one taking about a
+/// second on this machine.
+const TEST_WORK_FACTOR: Option<u8> = if cfg!(test) { Some(10) } else { None };
+
+/// Where the passphrase comes from. Never the command line, which ends up in
+/// shell history and `ps`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KeySource {
+    /// Ask on the terminal without echo.
+    Prompt,
+    /// The file's contents, minus a trailing newline.
+    File(PathBuf),
+    /// The first line of standard input.
+    Stdin,
+}
+
+impl KeySource {
+    /// Read the passphrase. With `confirm`, a prompt asks twice.
+    pub fn read(&self, confirm: bool) -> Result<SecretString> {
+        let passphrase = match self {
+            KeySource::File(path) => std::fs::read_to_string(path)
+                .map_err(|e| BootforgeError::Imaging(format!("Failed to read key file {}: {}", path.display(), e)))?
+                .trim_end_matches(['\r', '\n'])
+                .to_string(),
+            KeySource::Stdin => {
+                let mut line = String::new();
+                std::io::stdin().lock().read_line(&mut line)?;
+                line.trim_end_matches(['\r', '\n']).to_string()
+            }
+            KeySource::Prompt => {
+                let first = rpassword::prompt_password("Private partition passphrase: ")?;
+                if confirm && rpassword::prompt_password("Repeat passphrase: ")? != first {
+                    return Err(BootforgeError::Imaging("Passphrases do not match".to_string()));
+                }
+                first
+            }
+        };
+
+        if passphrase.is_empty() {
+            return Err(BootforgeError::Imaging("Empty passphrase".to_string()));
+        }
+        Ok(SecretString::from(passphrase))
+    }
+}
+
+/// Decrypted volume size for a private partition of `partition_bytes`,
+/// leaving room for the header and age's 16 bytes per 64 KiB chunk.
+pub fn volume_size(partition_bytes: u64) -> u64 {
+    let available = partition_bytes.saturating_sub(HEADER_LEN + BLOCK);
+    (available - available / 1024) / BLOCK * BLOCK
+}
+
+/// Format a FAT volume holding `payload`, encrypt it with `passphrase` and
+/// write it to the start of `partition`.
+pub fn seal<D: Write + Seek>(
+    partition: &mut D,
+    partition_bytes: u64,
+    passphrase: &SecretString,
+    payload: &[PayloadFile],
+    report: &mut BuildReport,
+) -> Result<()> {
+    let size = volume_size(partition_bytes);
+    let mut volume = MemoryVolume::new(size);
+    format_fat(&mut volume, PRIVATE_VOLUME_LABEL)?;
+    copy_files(&mut volume, "private partition", payload, report)?;
+    volume.seek(SeekFrom::Start(0))?;
+
+    log::info!("Encrypting private volume");
+    partition.seek(SeekFrom::Start(HEADER_LEN))?;
+    let mut recipient = age::scrypt::Recipient::new(passphrase.clone());
+    if let Some(log_n) = TEST_WORK_FACTOR {
+        recipient.set_work_factor(log_n);
+    }
+    let encryptor = age::Encryptor::with_recipients(std::iter::once(&recipient as &dyn age::Recipient))
+        .map_err(|e| BootforgeError::Imaging(format!("Failed to encrypt private volume: {}", e)))?;
+    let mut counted = CountingWriter { inner: &mut *partition, written: 0 };
+    let mut writer = encryptor.wrap_output(&mut counted)?;
+    std::io::copy(&mut volume, &mut writer)?;
+    writer.finish()?;
+    let container_len = counted.written;
+    if HEADER_LEN + container_len > partition_bytes {
+        return Err(BootforgeError::Imaging(format!(
+            "Encrypted volume ({} bytes) does not fit the private partition",
+            container_len
+        )));
+    }
+
+    let mut header = vec![0u8; HEADER_LEN as usize];
+    header[0..8].copy_from_slice(PRIVATE_MAGIC);
+    header[8..16].copy_from_slice(&container_len.to_le_bytes());
+    header[16..24].copy_from_slice(&size.to_le_bytes());
+    partition.seek(SeekFrom::Start(0))?;
+    partition.write_all(&header)?;
+    partition.flush()?;
+    Ok(())
+}
+
+/// The private partition of a BootForge USB layout.
+pub fn find_partition(layout: &DiskLayout) -> Option<&Partition> {
+    layout
+        .partitions
+        .iter()
+        .find(|p| p.type_guid == Some(PartitionType::BOOTFORGE_PRIVATE.guid))
+}
+
+/// Whether `partition` starts with a private volume header.
+pub fn is_private_volume<D: Read + Seek>(partition: &mut D) -> bool {
+    read_header(partition).is_ok()
+}
+
+/// Decrypt the volume in `partition` into `out`. Returns its size.
+pub fn unlock<D: Read + Seek, W: Write>(partition: &mut D, passphrase: &SecretString, out: &mut W) -> Result<u64> {
+    let (container_len, size) = read_header(partition)?;
+    partition.seek(SeekFrom::Start(HEADER_LEN))?;
+    let container = BufReader::new(partition.take(container_len));
+
+    let decryptor = age::Decryptor::new_buffered(container)
+        .map_err(|e| BootforgeError::Imaging(format!("Private partition is damaged: {}", e)))?;
+    let identity = age::scrypt::Identity::new(passphrase.clone());
+    let mut reader = decryptor
+        .decrypt(std::iter::once(&identity as &dyn age::Identity))
+        .map_err(|e| match e {
+            age::DecryptError::DecryptionFailed | age::DecryptError::NoMatchingKeys => {
+                BootforgeError::Imaging("Wrong passphrase for the private partition".to_string())
+            }
+            e => BootforgeError::Imaging(format!("Failed to unlock private partition: {}", e)),
+        })?;
+
+    let copied = std::io::copy(&mut reader, out)
+        .map_err(|e| BootforgeError::Imaging(format!("Private partition is damaged: {}", e)))?;
+    if copied != size {
+        return Err(BootforgeError::Imaging(format!(
+            "Private volume is {} bytes, expected {}",
+            copied, size
+        )));
+    }
+    Ok(copied)
+}
+
+/// Decrypt the volume in `partition` and copy its files into `dir`. Returns
+/// the number of files copied.
+pub fn extract<D: Read + Seek>(partition: &mut D, passphrase: &SecretString, dir: &Path) -> Result<usize> {
+    let (_, size) = read_header(partition)?;
+    let mut volume = MemoryVolume::new(size);
+    unlock(partition, passphrase, &mut volume)?;
+    volume.seek(SeekFrom::Start(0))?;
+
+    let fs = fatfs::FileSystem::new(&mut volume, fatfs::FsOptions::new())
+        .map_err(|e| BootforgeError::Imaging(format!("Private volume is not readable: {}", e)))?;
+    std::fs::create_dir_all(dir)?;
+    let count = extract_dir(&fs.root_dir(), dir)?;
+    Ok(count)
+}
+
+fn extract_dir<T: fatfs::ReadWriteSeek>(from: &fatfs::Dir<'_, T>, to: &Path) -> Result<usize> {
+    let mut count = 0;
+    for entry in from.iter() {
+        let entry = entry?;
+        let name = entry.file_name();
+        if name == "." || name == ".." {
+            continue;
+        }
+        let path = to.join(&name);
+        if entry.is_dir() {
+            std::fs::create_dir_all(&path)?;
+            count += extract_dir(&entry.to_dir(), &path)?;
+        } else {
+            std::io::copy(&mut entry.to_file(), &mut std::fs::File::create(&path)?)?;
+            count += 1;
+        }
+    }
+    Ok(count)
+}
+
+fn read_header<D: Read + Seek>(partition: &mut D) -> Result<(u64, u64)> {
+    let mut header = [0u8; 24];
+    partition.seek(SeekFrom::Start(0))?;
+    partition.read_exact(&mut header)?;
+    if &header[0..8] != PRIVATE_MAGIC {
+        return Err(BootforgeError::Imaging("Not a BootForge private partition".to_string()));
+    }
+    let field = |at: usize| u64::from_le_bytes(header[at..at + 8].try_into().expect("8-byte field"));
+    Ok((field(8), field(16)))
+}
+
+/// A sparse volume held in memory: only blocks that were written take up
+/// space, the rest read as zeros.
+struct MemoryVolume {
+    blocks: BTreeMap<u64, Vec<u8>>,
+    len: u64,
+    pos: u64,
+}
+
+impl MemoryVolume {
+    fn new(len: u64) -> Self {
+        MemoryVolume { blocks: BTreeMap::new(), len, pos: 0 }
+    }
+
+    /// Block index, offset into it, and how many bytes of `want` fit before
+    /// the block or volume ends.
+    fn span(&self, want: usize) -> (u64, usize, usize) {
+        let index = self.pos / BLOCK;
+        let offset = (self.pos % BLOCK) as usize;
+        let n = (BLOCK as usize - offset).min(want).min(self.len.saturating_sub(self.pos) as usize);
+        (index, offset, n)
+    }
+}
+
+impl Read for MemoryVolume {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let (index, offset, n) = self.span(buf.len());
+        match self.blocks.get(&index) {
+            Some(block) => buf[..n].copy_from_slice(&block[offset..offset + n]),
+            None => buf[..n].fill(0),
+        }
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Write for MemoryVolume {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let (index, offset, n) = self.span(buf.len());
+        if n == 0 && !buf.is_empty() {
+            return Err(std::io::Error::new(std::io::ErrorKind::WriteZero, "write past the end of the volume"));
+        }
+        let data = &buf[..n];
+        // Zeros need no storage unless they overwrite data
+        if data.iter().any(|&b| b != 0) || self.blocks.contains_key(&index) {
+            let block = self.blocks.entry(index).or_insert_with(|| vec![0u8; BLOCK as usize]);
+            block[offset..offset + n].copy_from_slice(data);
+        }
+        self.pos += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for MemoryVolume {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let target = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(delta) => self.len.checked_add_signed(delta),
+            SeekFrom::Current(delta) => self.pos.checked_add_signed(delta),
+        };
+        self.pos = target.ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, "invalid seek"))?;
+        Ok(self.pos)
+    }
+}
+
+struct CountingWriter<W> {
+    inner: W,
+    written: u64,
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.written += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use age::secrecy::ExposeSecret;
+    use std::io::Cursor;
+
+    const MIB: u64 = 1024 * 1024;
+
+    #[test]
+    fn test_seal_and_unlock_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let secret = dir.path().join("notes.txt");
+        std::fs::write(&secret, b"private notes").unwrap();
+        let payload = [PayloadFile { source: secret, destination: "docs/notes.txt".to_string(), size: 13 }];
+
+        let mut report = BuildReport { layout: crate::imaging::DiskLayout::new_gpt(64 * MIB, 512).unwrap(), files_copied: 0, bytes_copied: 0 };
+        let mut partition = Cursor::new(vec![0u8; (8 * MIB) as usize]);
+        let passphrase = SecretString::from("correct horse".to_string());
+        seal(&mut partition, 8 * MIB, &passphrase, &payload, &mut report).unwrap();
+        assert_eq!(report.files_copied, 1);
+        assert!(is_private_volume(&mut partition));
+        // Nothing of the payload is visible in the partition
+        assert!(!partition.get_ref().windows(13).any(|w| w == b"private notes"));
+
+        let out = dir.path().join("out");
+        assert_eq!(extract(&mut partition, &passphrase, &out).unwrap(), 1);
+        assert_eq!(std::fs::read(out.join("docs/notes.txt")).unwrap(), b"private notes");
+
+        let wrong = SecretString::from("wrong".to_string());
+        let err = unlock(&mut partition, &wrong, &mut std::io::sink()).unwrap_err();
+        assert!(err.to_string().contains("Wrong passphrase"));
+    }
+
+    #[test]
+    fn test_key_file_and_volume_size() {
+        let dir = tempfile::tempdir().unwrap();
+        let key = dir.path().join("key");
+        std::fs::write(&key, "s3cret\n").unwrap();
+        assert_eq!(KeySource::File(key.clone()).read(false).unwrap().expose_secret(), "s3cret");
+        std::fs::write(&key, "\n").unwrap();
+        assert!(KeySource::File(key).read(false).is_err());
+
+        let size = volume_size(8 * MIB);
+        assert!(size.is_multiple_of(BLOCK));
+        assert!(HEADER_LEN + size + size / BLOCK * 16 + 1024 < 8 * MIB);
+        assert!(!is_private_volume(&mut Cursor::new(vec![0u8; 1024])));
+    }
+}
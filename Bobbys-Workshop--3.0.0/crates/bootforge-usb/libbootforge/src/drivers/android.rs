@@ -1,21 +0,0 @@
-use crate::Result;
-use crate::usb::UsbDeviceInfo;
-
-pub struct AndroidDriver;
-
-impl AndroidDriver {
-    pub async fn adb_shell(_device: &UsbDeviceInfo, _cmd: &str) -> Result<String> {
-        log::info!("Executing ADB shell command");
-        Ok("Output pending".to_string())
-    }
-
-    pub async fn enter_fastboot(_device: &UsbDeviceInfo) -> Result<()> {
-        log::info!("Rebooting to fastboot");
-        Ok(())
-    }
-
-    pub async fn get_device_info(_device: &UsbDeviceInfo) -> Result<String> {
-        log::info!("Fetching Android device info");
-        Ok("Device info pending".to_string())
-    }
-}
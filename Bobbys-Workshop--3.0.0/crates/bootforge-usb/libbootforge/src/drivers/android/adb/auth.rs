@@ -0,0 +1,216 @@
+//! ADB host key.
+//!
+//! An RSA-2048 key pair kept in the same files as the platform-tools `adb`
+//! (`~/.android/adbkey` and `adbkey.pub`), so a device that already trusts
+//! this computer does not prompt again.
+
+use crate::{BootforgeError, Result};
+use base64::Engine;
+use rsa::pkcs1::DecodeRsaPrivateKey;
+use rsa::pkcs8::{DecodePrivateKey, EncodePrivateKey, LineEnding};
+use rsa::traits::PublicKeyParts;
+use rsa::{BigUint, Pkcs1v15Sign, RsaPrivateKey};
+use sha1::Sha1;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+pub const KEY_BITS: usize = 2048;
+const KEY_WORDS: usize = KEY_BITS / 32;
+/// Length of a public key in Android's `RSAPublicKey` layout.
+pub const ANDROID_PUBKEY_LEN: usize = 4 + 4 + KEY_BITS / 8 * 2 + 4;
+
+#[derive(Clone)]
+pub struct AdbKey {
+    private: RsaPrivateKey,
+}
+
+impl std::fmt::Debug for AdbKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AdbKey").field("public", &self.public_key_line()).finish()
+    }
+}
+
+impl AdbKey {
+    pub fn generate() -> Result<Self> {
+        let private = RsaPrivateKey::new(&mut rsa::rand_core::OsRng, KEY_BITS)
+            .map_err(|e| BootforgeError::Driver(format!("Failed to generate ADB key: {}", e)))?;
+        Ok(AdbKey { private })
+    }
+
+    /// `$ANDROID_USER_HOME/adbkey`, or `adbkey` in `.android` under the
+    /// home directory.
+    pub fn default_path() -> Result<PathBuf> {
+        if let Some(dir) = std::env::var_os("ANDROID_USER_HOME") {
+            return Ok(PathBuf::from(dir).join("adbkey"));
+        }
+        let home = std::env::var_os(if cfg!(windows) { "USERPROFILE" } else { "HOME" })
+            .ok_or_else(|| BootforgeError::Driver("Could not determine home directory".to_string()))?;
+        Ok(PathBuf::from(home).join(".android").join("adbkey"))
+    }
+
+    /// Load the key at `path`, creating it and its `.pub` file if missing.
+    pub fn load_or_create(path: &Path) -> Result<Self> {
+        if path.exists() {
+            return Self::load(path);
+        }
+
+        log::info!("Generating ADB key {}", path.display());
+        let key = Self::generate()?;
+        key.save(path)?;
+        Ok(key)
+    }
+
+    /// Read a PKCS#8 or PKCS#1 PEM private key.
+    pub fn load(path: &Path) -> Result<Self> {
+        let pem = fs::read_to_string(path)
+            .map_err(|e| BootforgeError::Driver(format!("Failed to read ADB key {}: {}", path.display(), e)))?;
+        let private = RsaPrivateKey::from_pkcs8_pem(&pem)
+            .or_else(|_| RsaPrivateKey::from_pkcs1_pem(&pem))
+            .map_err(|e| BootforgeError::Driver(format!("Invalid ADB key {}: {}", path.display(), e)))?;
+        Ok(AdbKey { private })
+    }
+
+    /// Write the private key (owner-only) and `<path>.pub`. An existing key
+    /// at `path` is never overwritten.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            fs::create_dir_all(parent)?;
+        }
+        let pem = self
+            .private
+            .to_pkcs8_pem(LineEnding::LF)
+            .map_err(|e| BootforgeError::Driver(format!("Failed to encode ADB key: {}", e)))?;
+
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let mut file = options
+            .open(path)
+            .map_err(|e| BootforgeError::Driver(format!("Failed to create ADB key {}: {}", path.display(), e)))?;
+        std::io::Write::write_all(&mut file, pem.as_bytes())?;
+        file.sync_all()?;
+
+        let mut public = path.as_os_str().to_owned();
+        public.push(".pub");
+        fs::write(PathBuf::from(public), format!("{}\n", self.public_key_line()))?;
+        Ok(())
+    }
+
+    /// Sign an `AUTH` token. The device treats the token as a SHA-1 digest.
+    pub fn sign_token(&self, token: &[u8]) -> Result<Vec<u8>> {
+        self.private
+            .sign(Pkcs1v15Sign::new::<Sha1>(), token)
+            .map_err(|e| BootforgeError::Driver(format!("Failed to sign ADB auth token: {}", e)))
+    }
+
+    /// The public key as the device stores it: base64 of
+    /// `android_public_key` followed by `user@host`.
+    pub fn public_key_line(&self) -> String {
+        let encoded = base64::engine::general_purpose::STANDARD.encode(self.android_public_key());
+        format!("{} {}", encoded, user_at_host())
+    }
+
+    /// Android's `RSAPublicKey` layout: word count, -1/n mod 2^32, the
+    /// modulus, R^2 mod n (all little-endian words) and the exponent.
+    pub fn android_public_key(&self) -> Vec<u8> {
+        let n = self.private.n();
+        let n0 = u32::from_le_bytes(le_bytes(n, 4).try_into().expect("4 bytes"));
+        // Newton's iteration doubles the correct low bits each round
+        let mut inverse = 1u32;
+        for _ in 0..5 {
+            inverse = inverse.wrapping_mul(2u32.wrapping_sub(n0.wrapping_mul(inverse)));
+        }
+        let rr = (BigUint::from(1u8) << (KEY_BITS * 2)) % n;
+        let exponent = u32::from_le_bytes(le_bytes(self.private.e(), 4)[..4].try_into().expect("4 bytes"));
+
+        let mut out = Vec::with_capacity(ANDROID_PUBKEY_LEN);
+        out.extend_from_slice(&(KEY_WORDS as u32).to_le_bytes());
+        out.extend_from_slice(&inverse.wrapping_neg().to_le_bytes());
+        out.extend_from_slice(&le_bytes(n, KEY_BITS / 8));
+        out.extend_from_slice(&le_bytes(&rr, KEY_BITS / 8));
+        out.extend_from_slice(&exponent.to_le_bytes());
+        out
+    }
+}
+
+fn le_bytes(value: &BigUint, len: usize) -> Vec<u8> {
+    let mut bytes = value.to_bytes_le();
+    bytes.resize(len, 0);
+    bytes
+}
+
+fn user_at_host() -> String {
+    let user = std::env::var("USER").or_else(|_| std::env::var("USERNAME")).unwrap_or_else(|_| "unknown".to_string());
+    let host = std::env::var("HOSTNAME")
+        .or_else(|_| std::env::var("COMPUTERNAME"))
+        .ok()
+        .or_else(|| fs::read_to_string("/etc/hostname").ok().map(|h| h.trim().to_string()))
+        .filter(|h| !h.is_empty())
+        .unwrap_or_else(|| "unknown".to_string());
+    format!("{}@{}", user, host)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use rsa::RsaPublicKey;
+    use std::sync::OnceLock;
+
+    /// Key generation is slow; tests share one key.
+    pub(crate) fn test_key() -> AdbKey {
+        static KEY: OnceLock<AdbKey> = OnceLock::new();
+        KEY.get_or_init(|| AdbKey::generate().unwrap()).clone()
+    }
+
+    /// What the device does with the `RSAPUBLICKEY` payload.
+    pub(crate) fn decode_android_public_key(bytes: &[u8]) -> RsaPublicKey {
+        assert_eq!(bytes.len(), ANDROID_PUBKEY_LEN);
+        let words = u32::from_le_bytes(bytes[..4].try_into().unwrap()) as usize;
+        let n = BigUint::from_bytes_le(&bytes[8..8 + words * 4]);
+        let e = u32::from_le_bytes(bytes[bytes.len() - 4..].try_into().unwrap());
+        RsaPublicKey::new(n, BigUint::from(e)).unwrap()
+    }
+
+    #[test]
+    fn test_public_key_and_signature() {
+        let key = test_key();
+        let encoded = key.android_public_key();
+        let public = decode_android_public_key(&encoded);
+        assert_eq!(&public, key.private.as_ref());
+
+        // n * n0inv == -1 mod 2^32
+        let n0 = u32::from_le_bytes(encoded[8..12].try_into().unwrap());
+        let n0inv = u32::from_le_bytes(encoded[4..8].try_into().unwrap());
+        assert_eq!(n0.wrapping_mul(n0inv), u32::MAX);
+
+        let token = [7u8; 20];
+        let signature = key.sign_token(&token).unwrap();
+        public.verify(Pkcs1v15Sign::new::<Sha1>(), &token, &signature).unwrap();
+
+        let line = key.public_key_line();
+        let (b64, user) = line.split_once(' ').unwrap();
+        assert_eq!(base64::engine::general_purpose::STANDARD.decode(b64).unwrap(), encoded);
+        assert!(user.contains('@'));
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("android").join("adbkey");
+        let key = test_key();
+        key.save(&path).unwrap();
+
+        let loaded = AdbKey::load_or_create(&path).unwrap();
+        assert_eq!(loaded.android_public_key(), key.android_public_key());
+        let public = fs::read_to_string(dir.path().join("android").join("adbkey.pub")).unwrap();
+        assert_eq!(public.trim_end(), key.public_key_line());
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        }
+        assert!(key.save(&path).is_err());
+    }
+}
@@ -0,0 +1,524 @@
+//! Native ADB client.
+//!
+//! Talks to a device either through a running adb server (the smart-socket
+//! protocol on tcp:5037) or directly over its USB ADB interface. The server
+//! is preferred when it already has the device, since it holds the
+//! interface and a second claim would fail.
+
+pub mod auth;
+pub mod props;
+pub mod server;
+pub mod sync;
+pub mod usb;
+
+pub use auth::AdbKey;
+pub use props::{hardware_info, parse_getprop, platform_info};
+pub use server::{AdbDeviceEntry, DEFAULT_SERVER_ADDR};
+pub use sync::FileStat;
+pub use usb::UsbSession;
+
+use crate::usb::{ProtocolType, UsbDeviceInfo, UsbTransport};
+use crate::{BootforgeError, Result};
+use device_analysis::device_state::{AndroidStateBuilder, UnifiedDeviceState};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::path::Path;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use usb::{AdbMessage, A_CLSE, A_OKAY, A_WRTE};
+
+/// Bytes read at a time from an adb server connection.
+const SERVER_READ_CHUNK: usize = 64 * 1024;
+
+#[derive(Debug)]
+enum Link {
+    Server { addr: String, serial: Option<String> },
+    Usb(Box<UsbSession>),
+}
+
+#[derive(Debug)]
+pub struct AdbClient {
+    link: Link,
+}
+
+impl AdbClient {
+    /// Use the adb server at `addr` for the device `serial`, or for the only
+    /// device it has if `None`.
+    pub fn server(addr: &str, serial: Option<String>) -> Self {
+        AdbClient { link: Link::Server { addr: addr.to_string(), serial } }
+    }
+
+    /// Connect over an ADB USB interface, authenticating with `key`.
+    pub async fn usb(transport: UsbTransport, key: &AdbKey) -> Result<Self> {
+        Ok(AdbClient { link: Link::Usb(Box::new(UsbSession::connect(transport, key).await?)) })
+    }
+
+    /// Reach `device` through the local adb server if it lists the device as
+    /// online, otherwise over USB with the key in `AdbKey::default_path`.
+    pub async fn connect(device: &UsbDeviceInfo) -> Result<Self> {
+        if let Some(serial) = &device.serial {
+            match server::list_devices(DEFAULT_SERVER_ADDR).await {
+                Ok(devices) if devices.iter().any(|d| &d.serial == serial && d.state == "device") => {
+                    log::info!("[ADB] Using adb server for {}", serial);
+                    return Ok(Self::server(DEFAULT_SERVER_ADDR, Some(serial.clone())));
+                }
+                Ok(_) => log::debug!("[ADB] adb server doesn't have {}", serial),
+                Err(e) => log::debug!("[ADB] {}", e),
+            }
+        }
+
+        let interface = device
+            .interfaces
+            .iter()
+            .find(|i| i.protocol_type() == Some(ProtocolType::ADB))
+            .map_or(0, |i| i.number);
+        let transport = UsbTransport::open(device.clone(), interface)?;
+        let key = AdbKey::load_or_create(&AdbKey::default_path()?)?;
+        Self::usb(transport, &key).await
+    }
+
+    /// Devices the adb server at `addr` knows about.
+    pub async fn list_devices(addr: &str) -> Result<Vec<AdbDeviceEntry>> {
+        server::list_devices(addr).await
+    }
+
+    /// Open a raw stream to `service` (`shell:ls`, `sync:`, ...).
+    pub async fn open(&mut self, service: &str) -> Result<AdbStream<'_>> {
+        let inner = match &mut self.link {
+            Link::Server { addr, serial } => {
+                StreamInner::Server(server::open_service(addr, serial.as_deref(), service).await?)
+            }
+            Link::Usb(session) => {
+                let (local, remote) = session.open(service).await?;
+                StreamInner::Usb { session, local, remote, closed: false }
+            }
+        };
+        Ok(AdbStream { inner, pending: Vec::new() })
+    }
+
+    /// Run `command` in a shell and return its output.
+    pub async fn shell(&mut self, command: &str) -> Result<String> {
+        let mut stream = self.open(&format!("shell:{}", command)).await?;
+        let output = stream.read_to_end().await?;
+        stream.close().await?;
+        Ok(String::from_utf8_lossy(&output).into_owned())
+    }
+
+    /// All system properties.
+    pub async fn getprop(&mut self) -> Result<BTreeMap<String, String>> {
+        Ok(parse_getprop(&self.shell("getprop").await?))
+    }
+
+    /// Everything `getprop` and a few `dumpsys` sections say about the
+    /// device, with its recorded connection history. Sections that fail to
+    /// run are left out.
+    pub async fn device_state(&mut self) -> Result<UnifiedDeviceState> {
+        let mut builder = AndroidStateBuilder::new(&self.shell("getprop").await?);
+        if let Some(kernel) = self.optional_shell("uname -r").await {
+            builder = builder.kernel(&kernel);
+        }
+        if let Some(meminfo) = self.optional_shell("cat /proc/meminfo").await {
+            builder = builder.meminfo(&meminfo);
+        }
+        if let Some(diskstats) = self.optional_shell("dumpsys diskstats").await {
+            builder = builder.diskstats(&diskstats);
+        }
+        if let Some(policy) = self.optional_shell("dumpsys device_policy").await {
+            builder = builder.device_policy(&policy);
+        }
+        if let Some(history) = super::recorded_history(builder.identity().serial.as_deref()).await {
+            builder = builder.history(history);
+        }
+        Ok(builder.build())
+    }
+
+    async fn optional_shell(&mut self, command: &str) -> Option<String> {
+        match self.shell(command).await {
+            Ok(output) if !output.trim().is_empty() => Some(output),
+            Ok(_) => None,
+            Err(e) => {
+                log::debug!("[ADB] {} failed: {}", command, e);
+                None
+            }
+        }
+    }
+
+    /// `None` if `path` doesn't exist on the device.
+    pub async fn stat(&mut self, path: &str) -> Result<Option<FileStat>> {
+        let mut stream = self.open("sync:").await?;
+        let stat = sync::stat(&mut stream, path).await?;
+        sync::quit(&mut stream).await?;
+        stream.close().await?;
+        Ok(stat)
+    }
+
+    /// Copy the device's `remote` file to `local`, returning its size.
+    /// The remote path is checked before `local` is created, so a missing
+    /// file or a directory leaves nothing behind.
+    pub async fn pull(&mut self, remote: &str, local: &Path) -> Result<u64> {
+        let mut stream = self.open("sync:").await?;
+        match sync::stat(&mut stream, remote).await? {
+            None => return Err(adb_error(format!("Failed to pull {}: No such file or directory", remote))),
+            Some(stat) if stat.is_dir() => {
+                return Err(adb_error(format!("Failed to pull {}: Is a directory", remote)));
+            }
+            Some(_) => {}
+        }
+        let mut file = File::create(local)
+            .map_err(|e| adb_error(format!("Failed to create {}: {}", local.display(), e)))?;
+        let size = sync::pull(&mut stream, remote, &mut file).await?;
+        sync::quit(&mut stream).await?;
+        stream.close().await?;
+        file.sync_all()?;
+        Ok(size)
+    }
+
+    /// Copy `local` to `remote` on the device with `mode` permissions
+    /// (e.g. `0o644`), returning the bytes sent.
+    pub async fn push(&mut self, local: &Path, remote: &str, mode: u32) -> Result<u64> {
+        let mut file = File::open(local)
+            .map_err(|e| adb_error(format!("Failed to open {}: {}", local.display(), e)))?;
+        let mtime = file
+            .metadata()?
+            .modified()
+            .ok()
+            .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+            .map_or(0, |d| d.as_secs() as u32);
+
+        let mut stream = self.open("sync:").await?;
+        let sent = sync::push(&mut stream, &mut file, remote, mode & 0o7777 | 0o100000, mtime).await?;
+        sync::quit(&mut stream).await?;
+        stream.close().await?;
+        Ok(sent)
+    }
+
+    /// Reboot into `target`: `""` for a normal boot, `bootloader`,
+    /// `recovery`, `sideload`, ...
+    pub async fn reboot(&mut self, target: &str) -> Result<()> {
+        let mut stream = self.open(&format!("reboot:{}", target)).await?;
+        // The device drops the connection as it goes down
+        if let Err(e) = stream.read_to_end().await {
+            log::debug!("[ADB] Connection ended during reboot: {}", e);
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+enum StreamInner<'a> {
+    Server(TcpStream),
+    Usb { session: &'a mut UsbSession, local: u32, remote: u32, closed: bool },
+}
+
+/// An open service. Reading returns what the device writes until it
+/// closes the stream.
+#[derive(Debug)]
+pub struct AdbStream<'a> {
+    inner: StreamInner<'a>,
+    pending: Vec<u8>,
+}
+
+impl AdbStream<'_> {
+    pub async fn write_all(&mut self, data: &[u8]) -> Result<()> {
+        match &mut self.inner {
+            StreamInner::Server(stream) => Ok(stream.write_all(data).await?),
+            StreamInner::Usb { session, local, remote, closed } => {
+                for chunk in data.chunks(session.max_payload()) {
+                    if *closed {
+                        return Err(adb_error("Device closed the stream".to_string()));
+                    }
+                    session.send(AdbMessage::new(A_WRTE, *local, *remote, chunk.to_vec())).await?;
+
+                    // Data the device writes meanwhile is kept for the next read
+                    loop {
+                        let message = session.read().await?;
+                        if message.arg1 != *local {
+                            continue;
+                        }
+                        match message.command {
+                            A_OKAY => break,
+                            A_WRTE => {
+                                session.send(AdbMessage::new(A_OKAY, *local, *remote, Vec::new())).await?;
+                                self.pending.extend_from_slice(&message.payload);
+                            }
+                            A_CLSE => {
+                                *closed = true;
+                                break;
+                            }
+                            _ => {}
+                        }
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// The next piece of data, or `None` once the device has closed the
+    /// stream.
+    pub async fn read_chunk(&mut self) -> Result<Option<Vec<u8>>> {
+        if !self.pending.is_empty() {
+            return Ok(Some(std::mem::take(&mut self.pending)));
+        }
+
+        match &mut self.inner {
+            StreamInner::Server(stream) => {
+                let mut buffer = vec![0u8; SERVER_READ_CHUNK];
+                let n = stream.read(&mut buffer).await?;
+                buffer.truncate(n);
+                Ok((n > 0).then_some(buffer))
+            }
+            StreamInner::Usb { session, local, remote, closed } => {
+                while !*closed {
+                    let message = session.read().await?;
+                    if message.arg1 != *local {
+                        continue;
+                    }
+                    match message.command {
+                        A_WRTE => {
+                            session.send(AdbMessage::new(A_OKAY, *local, *remote, Vec::new())).await?;
+                            return Ok(Some(message.payload));
+                        }
+                        A_CLSE => *closed = true,
+                        _ => {}
+                    }
+                }
+                Ok(None)
+            }
+        }
+    }
+
+    pub async fn read_exact(&mut self, buf: &mut [u8]) -> Result<()> {
+        let mut filled = 0;
+        while filled < buf.len() {
+            let chunk = self
+                .read_chunk()
+                .await?
+                .ok_or_else(|| adb_error(format!("Stream ended after {} of {} bytes", filled, buf.len())))?;
+            let n = chunk.len().min(buf.len() - filled);
+            buf[filled..filled + n].copy_from_slice(&chunk[..n]);
+            self.pending = chunk[n..].to_vec();
+            filled += n;
+        }
+        Ok(())
+    }
+
+    pub async fn read_to_end(&mut self) -> Result<Vec<u8>> {
+        let mut out = Vec::new();
+        while let Some(chunk) = self.read_chunk().await? {
+            out.extend_from_slice(&chunk);
+        }
+        Ok(out)
+    }
+
+    pub async fn close(self) -> Result<()> {
+        match self.inner {
+            StreamInner::Server(mut stream) => {
+                let _ = stream.shutdown().await;
+                Ok(())
+            }
+            StreamInner::Usb { session, local, remote, closed } => {
+                if !closed {
+                    session.send(AdbMessage::new(A_CLSE, local, remote, Vec::new())).await?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+fn adb_error(message: String) -> BootforgeError {
+    BootforgeError::Driver(format!("ADB: {}", message))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use tokio::net::TcpListener;
+
+    type Files = Arc<Mutex<BTreeMap<String, (u32, Vec<u8>)>>>;
+
+    /// adb server with one device, `ADB123`, whose filesystem is `files`.
+    async fn fake_server(files: Files) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            loop {
+                let (socket, _) = listener.accept().await.unwrap();
+                tokio::spawn(serve(socket, files.clone()));
+            }
+        });
+        addr
+    }
+
+    async fn serve(mut socket: TcpStream, files: Files) {
+        loop {
+            let mut len = [0u8; 4];
+            if socket.read_exact(&mut len).await.is_err() {
+                return;
+            }
+            let mut request = vec![0u8; usize::from_str_radix(std::str::from_utf8(&len).unwrap(), 16).unwrap()];
+            socket.read_exact(&mut request).await.unwrap();
+            let request = String::from_utf8(request).unwrap();
+
+            match request.as_str() {
+                "host:devices" => {
+                    let listing = "ADB123\tdevice\nOTHER\tunauthorized\n";
+                    socket.write_all(format!("OKAY{:04x}{}", listing.len(), listing).as_bytes()).await.unwrap();
+                    return;
+                }
+                "host:transport:ADB123" | "host:transport-any" => socket.write_all(b"OKAY").await.unwrap(),
+                "sync:" => {
+                    socket.write_all(b"OKAY").await.unwrap();
+                    return serve_sync(socket, files).await;
+                }
+                "shell:getprop" => {
+                    socket.write_all(b"OKAY").await.unwrap();
+                    socket.write_all(props::tests::GETPROP.as_bytes()).await.unwrap();
+                    return;
+                }
+                "shell:uname -r" => {
+                    socket.write_all(b"OKAY4.9.270-g8d1d5b6\n").await.unwrap();
+                    return;
+                }
+                shell if shell.starts_with("shell:echo ") => {
+                    socket.write_all(b"OKAY").await.unwrap();
+                    socket.write_all(format!("{}\n", &shell[11..]).as_bytes()).await.unwrap();
+                    return;
+                }
+                other => {
+                    let reason = format!("unknown service {}", other);
+                    socket.write_all(format!("FAIL{:04x}{}", reason.len(), reason).as_bytes()).await.unwrap();
+                    return;
+                }
+            }
+        }
+    }
+
+    async fn serve_sync(mut socket: TcpStream, files: Files) {
+        loop {
+            let mut header = [0u8; 8];
+            socket.read_exact(&mut header).await.unwrap();
+            let mut path = vec![0u8; u32::from_le_bytes(header[4..].try_into().unwrap()) as usize];
+            socket.read_exact(&mut path).await.unwrap();
+            let path = String::from_utf8(path).unwrap();
+
+            match &header[..4] {
+                b"STAT" => {
+                    let (mode, size) = files.lock().unwrap().get(&path).map_or((0, 0), |(m, d)| (*m, d.len() as u32));
+                    let mut reply = b"STAT".to_vec();
+                    for word in [mode, size, 1_700_000_000] {
+                        reply.extend_from_slice(&word.to_le_bytes());
+                    }
+                    socket.write_all(&reply).await.unwrap();
+                }
+                b"RECV" => {
+                    let data = files.lock().unwrap().get(&path).map(|(_, d)| d.clone());
+                    let mut reply = Vec::new();
+                    match data {
+                        Some(data) => {
+                            for chunk in data.chunks(sync::SYNC_DATA_MAX) {
+                                reply.extend_from_slice(b"DATA");
+                                reply.extend_from_slice(&(chunk.len() as u32).to_le_bytes());
+                                reply.extend_from_slice(chunk);
+                            }
+                            reply.extend_from_slice(b"DONE\0\0\0\0");
+                        }
+                        None => {
+                            let reason = b"No such file or directory";
+                            reply.extend_from_slice(b"FAIL");
+                            reply.extend_from_slice(&(reason.len() as u32).to_le_bytes());
+                            reply.extend_from_slice(reason);
+                        }
+                    }
+                    socket.write_all(&reply).await.unwrap();
+                }
+                b"SEND" => {
+                    let (name, mode) = path.rsplit_once(',').unwrap();
+                    let mut data = Vec::new();
+                    loop {
+                        socket.read_exact(&mut header).await.unwrap();
+                        let len = u32::from_le_bytes(header[4..].try_into().unwrap()) as usize;
+                        if &header[..4] == b"DONE" {
+                            break;
+                        }
+                        assert!(len <= sync::SYNC_DATA_MAX);
+                        let start = data.len();
+                        data.resize(start + len, 0);
+                        socket.read_exact(&mut data[start..]).await.unwrap();
+                    }
+                    files.lock().unwrap().insert(name.to_string(), (mode.parse().unwrap(), data));
+                    socket.write_all(b"OKAY\0\0\0\0").await.unwrap();
+                }
+                b"QUIT" => return,
+                other => panic!("unexpected sync request {:?}", other),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_server_shell_and_getprop() {
+        let addr = fake_server(Files::default()).await;
+
+        let devices = AdbClient::list_devices(&addr).await.unwrap();
+        assert_eq!(devices.len(), 2);
+        assert_eq!((devices[0].serial.as_str(), devices[0].state.as_str()), ("ADB123", "device"));
+
+        let mut client = AdbClient::server(&addr, Some("ADB123".to_string()));
+        assert_eq!(client.shell("echo hello").await.unwrap(), "hello\n");
+        let props = client.getprop().await.unwrap();
+        assert_eq!(platform_info(&props).api_level, Some(33));
+        assert_eq!(hardware_info(&props).cpu_abi.as_deref(), Some("arm64-v8a"));
+
+        // The fake server has no dumpsys; those sections are just missing
+        let state = client.device_state().await.unwrap();
+        assert_eq!(state.device_id, "ADB123");
+        let platform = state.platform.unwrap();
+        assert_eq!(platform.kernel_version.as_deref(), Some("4.9.270-g8d1d5b6"));
+        assert_eq!(platform.api_level, Some(33));
+        assert!(state.hardware.unwrap().ram_bytes.is_none());
+
+        let err = AdbClient::server(&addr, Some("MISSING".to_string())).shell("echo").await.unwrap_err();
+        assert!(err.to_string().contains("host:transport:MISSING failed"));
+    }
+
+    #[tokio::test]
+    async fn test_server_sync_push_stat_pull() {
+        let files = Files::default();
+        let addr = fake_server(files.clone()).await;
+        let dir = tempfile::tempdir().unwrap();
+        let local = dir.path().join("payload.bin");
+        let data: Vec<u8> = (0..150_000).map(|i| (i % 253) as u8).collect();
+        std::fs::write(&local, &data).unwrap();
+
+        let mut client = AdbClient::server(&addr, None);
+        assert_eq!(client.push(&local, "/sdcard/payload.bin", 0o644).await.unwrap(), data.len() as u64);
+        assert_eq!(files.lock().unwrap()["/sdcard/payload.bin"].1, data);
+
+        let stat = client.stat("/sdcard/payload.bin").await.unwrap().unwrap();
+        assert_eq!(stat.mode, 0o100644);
+        assert_eq!(stat.size as usize, data.len());
+        assert!(!stat.is_dir());
+        assert!(client.stat("/sdcard/missing").await.unwrap().is_none());
+
+        let copy = dir.path().join("copy.bin");
+        assert_eq!(client.pull("/sdcard/payload.bin", &copy).await.unwrap(), data.len() as u64);
+        assert_eq!(std::fs::read(&copy).unwrap(), data);
+
+        let missing = dir.path().join("missing.bin");
+        let err = client.pull("/sdcard/missing", &missing).await.unwrap_err();
+        assert!(err.to_string().contains("No such file or directory"));
+        assert!(!missing.exists());
+    }
+
+    #[tokio::test]
+    async fn test_usb_shell() {
+        let (transport, state) = usb::tests::simulated(usb::tests::Adbd::default());
+        let mut client = AdbClient::usb(transport, &auth::tests::test_key()).await.unwrap();
+
+        assert_eq!(client.shell("getprop ro.serialno").await.unwrap(), "getprop ro.serialno\n");
+        assert!(client.open("sync:").await.unwrap_err().to_string().contains("refused"));
+        assert_eq!(state.lock().unwrap().services, ["shell:getprop ro.serialno", "sync:"]);
+    }
+}
@@ -0,0 +1,59 @@
+//! `getprop` output and what it says about the device.
+//!
+//! The mapping itself lives in `device_analysis::device_state::android`.
+
+pub use device_analysis::device_state::android::parse_getprop;
+use device_analysis::device_state::{AndroidStateBuilder, HardwareInfo, PlatformInfo};
+use std::collections::BTreeMap;
+
+/// Android version, API level, build and patch level.
+pub fn platform_info(props: &BTreeMap<String, String>) -> PlatformInfo {
+    AndroidStateBuilder::from_props(props.clone()).platform_info()
+}
+
+/// CPU ABIs and SoC. RAM and storage aren't properties.
+pub fn hardware_info(props: &BTreeMap<String, String>) -> HardwareInfo {
+    AndroidStateBuilder::from_props(props.clone()).hardware_info()
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use device_analysis::device_state::PlatformType;
+
+    pub(crate) const GETPROP: &str = "\
+[ro.build.id]: [TQ3A.230805.001]
+[ro.build.version.release]: [13]
+[ro.build.version.sdk]: [33]
+[ro.build.version.security_patch]: [2023-08-05]
+[ro.product.cpu.abi]: [arm64-v8a]
+[ro.product.cpu.abilist]: [arm64-v8a,armeabi-v7a,armeabi]
+[ro.board.platform]: [sdm670]
+[ro.hardware]: [sargo]
+[ro.soc.model]: []
+[persist.sys.motd]: [first line
+second line]
+[ro.serialno]: [ADB123]
+";
+
+    #[test]
+    fn test_platform_and_hardware_info() {
+        let props = parse_getprop(GETPROP);
+
+        let platform = platform_info(&props);
+        assert_eq!(platform.platform_type, Some(PlatformType::Android));
+        assert_eq!(platform.version.as_deref(), Some("13"));
+        assert_eq!(platform.api_level, Some(33));
+        assert_eq!(platform.build_id.as_deref(), Some("TQ3A.230805.001"));
+        assert_eq!(platform.security_patch.as_deref(), Some("2023-08-05"));
+
+        let hardware = hardware_info(&props);
+        assert_eq!(hardware.cpu_abi.as_deref(), Some("arm64-v8a"));
+        assert_eq!(hardware.cpu_abi_list.unwrap(), ["arm64-v8a", "armeabi-v7a", "armeabi"]);
+        // An empty ro.soc.model falls through to the board platform
+        assert_eq!(hardware.soc.as_deref(), Some("sdm670"));
+
+        let empty = hardware_info(&BTreeMap::new());
+        assert!(empty.cpu_abi.is_none() && empty.soc.is_none());
+    }
+}
@@ -0,0 +1,104 @@
+//! Smart-socket protocol of a local adb server.
+//!
+//! Each request is four hex digits of length followed by the service name.
+//! The server answers `OKAY`, or `FAIL` with a hex-length-prefixed reason.
+//! Device services are reached by first selecting a device with
+//! `host:transport:<serial>` on the same connection.
+
+use super::adb_error;
+use crate::Result;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// Where `adb start-server` listens.
+pub const DEFAULT_SERVER_ADDR: &str = "127.0.0.1:5037";
+
+/// A device as listed by `host:devices`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AdbDeviceEntry {
+    pub serial: String,
+    /// `device`, `unauthorized`, `offline`, `recovery`, ...
+    pub state: String,
+}
+
+/// Devices the server at `addr` knows about.
+pub async fn list_devices(addr: &str) -> Result<Vec<AdbDeviceEntry>> {
+    let mut stream = connect(addr).await?;
+    request(&mut stream, "host:devices").await?;
+    let listing = read_length_prefixed(&mut stream).await?;
+    Ok(parse_devices(&listing))
+}
+
+/// Parse `host:devices` output: one `serial<TAB>state` line per device.
+pub fn parse_devices(listing: &str) -> Vec<AdbDeviceEntry> {
+    listing
+        .lines()
+        .filter_map(|line| line.split_once('\t'))
+        .map(|(serial, state)| AdbDeviceEntry { serial: serial.to_string(), state: state.trim().to_string() })
+        .collect()
+}
+
+/// Open `service` on the device `serial` (any single device if `None`),
+/// returning the connection the service talks over.
+pub async fn open_service(addr: &str, serial: Option<&str>, service: &str) -> Result<TcpStream> {
+    let mut stream = connect(addr).await?;
+    let transport = match serial {
+        Some(serial) => format!("host:transport:{}", serial),
+        None => "host:transport-any".to_string(),
+    };
+    request(&mut stream, &transport).await?;
+    request(&mut stream, service).await?;
+    Ok(stream)
+}
+
+async fn connect(addr: &str) -> Result<TcpStream> {
+    TcpStream::connect(addr)
+        .await
+        .map_err(|e| adb_error(format!("No adb server at {}: {}", addr, e)))
+}
+
+/// Send one request and wait for its status.
+async fn request(stream: &mut TcpStream, service: &str) -> Result<()> {
+    if service.len() > 0xffff {
+        return Err(adb_error(format!("Request of {} bytes is too long", service.len())));
+    }
+    log::debug!("[ADB] > {}", service);
+    stream.write_all(format!("{:04x}{}", service.len(), service).as_bytes()).await?;
+
+    let mut status = [0u8; 4];
+    stream.read_exact(&mut status).await?;
+    match &status {
+        b"OKAY" => Ok(()),
+        b"FAIL" => {
+            let reason = read_length_prefixed(stream).await?;
+            Err(adb_error(format!("{} failed: {}", service, reason)))
+        }
+        other => Err(adb_error(format!("Unexpected status {:?} for {}", String::from_utf8_lossy(other), service))),
+    }
+}
+
+async fn read_length_prefixed(stream: &mut TcpStream) -> Result<String> {
+    let mut len = [0u8; 4];
+    stream.read_exact(&mut len).await?;
+    let len = std::str::from_utf8(&len)
+        .ok()
+        .and_then(|hex| usize::from_str_radix(hex, 16).ok())
+        .ok_or_else(|| adb_error(format!("Invalid length {:?}", String::from_utf8_lossy(&len))))?;
+    let mut text = vec![0u8; len];
+    stream.read_exact(&mut text).await?;
+    Ok(String::from_utf8_lossy(&text).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_devices() {
+        let devices = parse_devices("ADB123\tdevice\nemulator-5554\tunauthorized\n\n");
+        assert_eq!(devices.len(), 2);
+        assert_eq!(devices[0], AdbDeviceEntry { serial: "ADB123".to_string(), state: "device".to_string() });
+        assert_eq!(devices[1].state, "unauthorized");
+        assert!(parse_devices("").is_empty());
+    }
+}
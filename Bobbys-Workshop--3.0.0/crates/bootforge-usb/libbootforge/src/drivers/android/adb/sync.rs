@@ -0,0 +1,133 @@
+//! The `sync:` file transfer service.
+//!
+//! Requests are a four-letter id, a little-endian length and a path.
+//! `STAT` answers with mode, size and mtime; `RECV` with `DATA` chunks and
+//! `DONE`; `SEND` takes `DATA` chunks and a `DONE` carrying the mtime, then
+//! answers `OKAY`. Failures come back as `FAIL` with a message.
+
+use super::{adb_error, AdbStream};
+use crate::imaging::stream::read_full;
+use crate::Result;
+use std::io::{Read, Write};
+
+/// Largest `DATA` chunk the protocol allows.
+pub const SYNC_DATA_MAX: usize = 64 * 1024;
+
+/// What `STAT` reports about a remote path.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileStat {
+    /// Unix mode bits, including the file type.
+    pub mode: u32,
+    pub size: u32,
+    /// Seconds since the Unix epoch.
+    pub mtime: u32,
+}
+
+impl FileStat {
+    pub fn is_dir(&self) -> bool {
+        self.mode & 0o170000 == 0o040000
+    }
+}
+
+/// `None` if `path` doesn't exist on the device.
+pub async fn stat(stream: &mut AdbStream<'_>, path: &str) -> Result<Option<FileStat>> {
+    send_request(stream, b"STAT", path.as_bytes()).await?;
+    let (id, mode) = read_header(stream).await?;
+    if &id != b"STAT" {
+        return Err(unexpected(&id, "STAT"));
+    }
+    let size = read_u32(stream).await?;
+    let mtime = read_u32(stream).await?;
+    Ok((mode != 0).then_some(FileStat { mode, size, mtime }))
+}
+
+/// Copy the remote file `path` into `out`, returning its size.
+pub async fn pull<W: Write>(stream: &mut AdbStream<'_>, path: &str, out: &mut W) -> Result<u64> {
+    send_request(stream, b"RECV", path.as_bytes()).await?;
+    let mut total = 0u64;
+    let mut buffer = vec![0u8; SYNC_DATA_MAX];
+    loop {
+        let (id, len) = read_header(stream).await?;
+        match &id {
+            b"DATA" => {
+                let len = len as usize;
+                if len > SYNC_DATA_MAX {
+                    return Err(adb_error(format!("Sync chunk of {} bytes is too large", len)));
+                }
+                stream.read_exact(&mut buffer[..len]).await?;
+                out.write_all(&buffer[..len])?;
+                total += len as u64;
+            }
+            b"DONE" => return Ok(total),
+            b"FAIL" => return Err(failure(stream, len, path).await),
+            other => return Err(unexpected(other, "RECV")),
+        }
+    }
+}
+
+/// Write everything from `source` to the remote `path` with `mode`
+/// permissions, returning the bytes sent.
+pub async fn push<R: Read>(stream: &mut AdbStream<'_>, source: &mut R, path: &str, mode: u32, mtime: u32) -> Result<u64> {
+    let target = format!("{},{}", path, mode);
+    send_request(stream, b"SEND", target.as_bytes()).await?;
+
+    let mut buffer = vec![0u8; SYNC_DATA_MAX];
+    let mut total = 0u64;
+    loop {
+        let n = read_full(source, &mut buffer)?;
+        if n == 0 {
+            break;
+        }
+        send_request(stream, b"DATA", &buffer[..n]).await?;
+        total += n as u64;
+    }
+
+    let mut done = b"DONE".to_vec();
+    done.extend_from_slice(&mtime.to_le_bytes());
+    stream.write_all(&done).await?;
+
+    let (id, len) = read_header(stream).await?;
+    match &id {
+        b"OKAY" => Ok(total),
+        b"FAIL" => Err(failure(stream, len, path).await),
+        other => Err(unexpected(other, "SEND")),
+    }
+}
+
+/// End the sync session.
+pub async fn quit(stream: &mut AdbStream<'_>) -> Result<()> {
+    send_request(stream, b"QUIT", &[]).await
+}
+
+async fn send_request(stream: &mut AdbStream<'_>, id: &[u8; 4], data: &[u8]) -> Result<()> {
+    let mut request = Vec::with_capacity(8 + data.len());
+    request.extend_from_slice(id);
+    request.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    request.extend_from_slice(data);
+    stream.write_all(&request).await
+}
+
+async fn read_header(stream: &mut AdbStream<'_>) -> Result<([u8; 4], u32)> {
+    let mut header = [0u8; 8];
+    stream.read_exact(&mut header).await?;
+    let id = header[..4].try_into().expect("4 bytes");
+    Ok((id, u32::from_le_bytes(header[4..].try_into().expect("4 bytes"))))
+}
+
+async fn read_u32(stream: &mut AdbStream<'_>) -> Result<u32> {
+    let mut word = [0u8; 4];
+    stream.read_exact(&mut word).await?;
+    Ok(u32::from_le_bytes(word))
+}
+
+async fn failure(stream: &mut AdbStream<'_>, len: u32, path: &str) -> crate::BootforgeError {
+    let mut reason = vec![0u8; (len as usize).min(SYNC_DATA_MAX)];
+    match stream.read_exact(&mut reason).await {
+        Ok(()) => adb_error(format!("{}: {}", path, String::from_utf8_lossy(&reason))),
+        Err(e) => e,
+    }
+}
+
+fn unexpected(id: &[u8], request: &str) -> crate::BootforgeError {
+    adb_error(format!("Unexpected sync reply {:?} to {}", String::from_utf8_lossy(id), request))
+}
@@ -0,0 +1,341 @@
+//! ADB transport protocol over USB.
+//!
+//! Every message is a 24-byte header (command, two arguments, payload length,
+//! payload checksum and the command's complement) followed by the payload.
+//! After `CNXN`/`AUTH`, services are opened as streams identified by a local
+//! and a remote id; each `WRTE` is acknowledged with `OKAY` before the next.
+
+use super::auth::AdbKey;
+use super::adb_error;
+use crate::usb::UsbTransport;
+use crate::Result;
+use std::time::Duration;
+
+pub const A_CNXN: u32 = 0x4e58_4e43;
+pub const A_AUTH: u32 = 0x4854_5541;
+pub const A_OPEN: u32 = 0x4e45_504f;
+pub const A_OKAY: u32 = 0x5941_4b4f;
+pub const A_CLSE: u32 = 0x4553_4c43;
+pub const A_WRTE: u32 = 0x4554_5257;
+
+pub const A_VERSION: u32 = 0x0100_0001;
+/// Largest payload this host accepts.
+pub const MAX_PAYLOAD: u32 = 1024 * 1024;
+pub const HEADER_LEN: usize = 24;
+
+pub const AUTH_TOKEN: u32 = 1;
+pub const AUTH_SIGNATURE: u32 = 2;
+pub const AUTH_RSAPUBLICKEY: u32 = 3;
+
+/// How long the user has to accept the "Allow USB debugging?" prompt.
+pub const AUTH_PROMPT_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AdbMessage {
+    pub command: u32,
+    pub arg0: u32,
+    pub arg1: u32,
+    pub payload: Vec<u8>,
+}
+
+impl AdbMessage {
+    pub fn new(command: u32, arg0: u32, arg1: u32, payload: Vec<u8>) -> Self {
+        AdbMessage { command, arg0, arg1, payload }
+    }
+
+    pub fn header(&self) -> [u8; HEADER_LEN] {
+        let checksum = self.payload.iter().fold(0u32, |sum, &b| sum.wrapping_add(b as u32));
+        let mut header = [0u8; HEADER_LEN];
+        for (i, word) in [self.command, self.arg0, self.arg1, self.payload.len() as u32, checksum, !self.command]
+            .iter()
+            .enumerate()
+        {
+            header[i * 4..i * 4 + 4].copy_from_slice(&word.to_le_bytes());
+        }
+        header
+    }
+
+    /// Decode a header, returning the message without its payload and the
+    /// payload length to read next.
+    pub fn parse_header(header: &[u8]) -> Result<(Self, usize)> {
+        if header.len() != HEADER_LEN {
+            return Err(adb_error(format!("Expected a {}-byte header, got {} bytes", HEADER_LEN, header.len())));
+        }
+        let word = |i: usize| u32::from_le_bytes(header[i * 4..i * 4 + 4].try_into().expect("4 bytes"));
+        if word(5) != !word(0) {
+            return Err(adb_error(format!("Bad message magic for command {:#010x}", word(0))));
+        }
+        let len = word(3);
+        if len > MAX_PAYLOAD {
+            return Err(adb_error(format!("Payload of {} bytes exceeds {}", len, MAX_PAYLOAD)));
+        }
+        Ok((AdbMessage::new(word(0), word(1), word(2), Vec::new()), len as usize))
+    }
+}
+
+/// An authenticated ADB connection to a device's ADB interface.
+#[derive(Debug)]
+pub struct UsbSession {
+    transport: UsbTransport,
+    max_payload: usize,
+    next_id: u32,
+    banner: String,
+}
+
+impl UsbSession {
+    /// Send `CNXN` and answer the device's `AUTH` challenges with `key`.
+    ///
+    /// A device that doesn't know the key yet is sent the public key, which
+    /// shows the authorisation prompt on its screen; this waits up to
+    /// `AUTH_PROMPT_TIMEOUT` for the user to accept it.
+    pub async fn connect(transport: UsbTransport, key: &AdbKey) -> Result<Self> {
+        let mut session = UsbSession { transport, max_payload: MAX_PAYLOAD as usize, next_id: 1, banner: String::new() };
+        session.send(AdbMessage::new(A_CNXN, A_VERSION, MAX_PAYLOAD, b"host::\0".to_vec())).await?;
+
+        let mut signed = false;
+        loop {
+            let message = if signed { session.read_with_timeout(AUTH_PROMPT_TIMEOUT).await? } else { session.read().await? };
+            match message.command {
+                A_CNXN => {
+                    session.max_payload = (message.arg1 as usize).clamp(4096, MAX_PAYLOAD as usize);
+                    session.banner = String::from_utf8_lossy(&message.payload).trim_end_matches('\0').to_string();
+                    log::info!("[ADB] Connected to {}", session.banner);
+                    return Ok(session);
+                }
+                A_AUTH if message.arg0 == AUTH_TOKEN && !signed => {
+                    let signature = key.sign_token(&message.payload)?;
+                    session.send(AdbMessage::new(A_AUTH, AUTH_SIGNATURE, 0, signature)).await?;
+                    signed = true;
+                }
+                A_AUTH if message.arg0 == AUTH_TOKEN => {
+                    log::info!("[ADB] Device doesn't know this computer; accept the prompt on its screen");
+                    let mut public = key.public_key_line().into_bytes();
+                    public.push(0);
+                    session.send(AdbMessage::new(A_AUTH, AUTH_RSAPUBLICKEY, 0, public)).await?;
+                }
+                other => log::debug!("[ADB] Ignoring {:#010x} while connecting", other),
+            }
+        }
+    }
+
+    /// The device's `CNXN` banner, e.g. `device::ro.product.name=...;`.
+    pub fn banner(&self) -> &str {
+        &self.banner
+    }
+
+    pub fn max_payload(&self) -> usize {
+        self.max_payload
+    }
+
+    pub fn into_transport(self) -> UsbTransport {
+        self.transport
+    }
+
+    /// Open `service`, returning the local and remote stream ids.
+    pub async fn open(&mut self, service: &str) -> Result<(u32, u32)> {
+        let local = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1).max(1);
+
+        let mut payload = service.as_bytes().to_vec();
+        payload.push(0);
+        self.send(AdbMessage::new(A_OPEN, local, 0, payload)).await?;
+
+        loop {
+            let message = self.read().await?;
+            match message.command {
+                A_OKAY if message.arg1 == local => return Ok((local, message.arg0)),
+                A_CLSE if message.arg1 == local => {
+                    return Err(adb_error(format!("Device refused service '{}'", service)))
+                }
+                _ => log::debug!("[ADB] Ignoring {:#010x} for stream {}", message.command, message.arg1),
+            }
+        }
+    }
+
+    pub async fn send(&self, message: AdbMessage) -> Result<()> {
+        self.transport.send(&message.header()).await?;
+        if !message.payload.is_empty() {
+            self.transport.send(&message.payload).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn read(&mut self) -> Result<AdbMessage> {
+        let header = self.transport.receive(HEADER_LEN).await?;
+        let (mut message, len) = AdbMessage::parse_header(&header)?;
+        while message.payload.len() < len {
+            let chunk = self.transport.receive(len - message.payload.len()).await?;
+            if chunk.is_empty() {
+                return Err(adb_error("Device sent an empty transfer mid-payload".to_string()));
+            }
+            message.payload.extend_from_slice(&chunk);
+        }
+        message.payload.truncate(len);
+        Ok(message)
+    }
+
+    async fn read_with_timeout(&mut self, timeout: Duration) -> Result<AdbMessage> {
+        let previous = self.transport.timeout();
+        self.transport.set_timeout(timeout);
+        let message = self.read().await;
+        self.transport.set_timeout(previous);
+        message
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::drivers::android::adb::auth::tests::{decode_android_public_key, test_key};
+    use crate::usb::transport::tests::{simulated_transport, test_device, SimulatedDevice};
+    use crate::usb::{DeviceMode, ProtocolType};
+    use crate::BootforgeError;
+    use rsa::{Pkcs1v15Sign, RsaPublicKey};
+    use sha1::Sha1;
+    use std::collections::VecDeque;
+    use std::sync::{Arc, Mutex};
+
+    /// adbd with a shell that echoes its command, and a single key it
+    /// trusts once the (simulated) user accepts the prompt.
+    #[derive(Debug, Default)]
+    pub(crate) struct Adbd {
+        pub(crate) trusted: Option<RsaPublicKey>,
+        pub(crate) prompted: bool,
+        pub(crate) connected: bool,
+        token: Vec<u8>,
+        partial: Option<(AdbMessage, usize)>,
+        replies: VecDeque<Vec<u8>>,
+        pub(crate) services: Vec<String>,
+    }
+
+    impl Adbd {
+        fn reply(&mut self, message: AdbMessage) {
+            self.replies.push_back(message.header().to_vec());
+            if !message.payload.is_empty() {
+                self.replies.push_back(message.payload);
+            }
+        }
+
+        fn challenge(&mut self) {
+            self.token = (0..20).map(|i| i * 7).collect();
+            self.reply(AdbMessage::new(A_AUTH, AUTH_TOKEN, 0, self.token.clone()));
+        }
+
+        fn accept(&mut self) {
+            self.connected = true;
+            self.reply(AdbMessage::new(A_CNXN, A_VERSION, 4096, b"device::ro.product.name=sargo;\0".to_vec()));
+        }
+
+        fn handle(&mut self, data: &[u8]) {
+            let message = match self.partial.take() {
+                Some((mut message, len)) => {
+                    message.payload.extend_from_slice(data);
+                    if message.payload.len() < len {
+                        self.partial = Some((message, len));
+                        return;
+                    }
+                    message
+                }
+                None => {
+                    let (message, len) = AdbMessage::parse_header(data).unwrap();
+                    if len > 0 {
+                        self.partial = Some((message, len));
+                        return;
+                    }
+                    message
+                }
+            };
+
+            match (message.command, message.arg0) {
+                (A_CNXN, _) => self.challenge(),
+                (A_AUTH, AUTH_SIGNATURE) => {
+                    let verified = self.trusted.as_ref().is_some_and(|key| {
+                        key.verify(Pkcs1v15Sign::new::<Sha1>(), &self.token, &message.payload).is_ok()
+                    });
+                    if verified {
+                        self.accept();
+                    } else {
+                        self.challenge();
+                    }
+                }
+                (A_AUTH, AUTH_RSAPUBLICKEY) => {
+                    let line = String::from_utf8(message.payload).unwrap();
+                    let encoded = line.trim_end_matches('\0').split(' ').next().unwrap();
+                    let bytes = base64::Engine::decode(&base64::engine::general_purpose::STANDARD, encoded).unwrap();
+                    self.trusted = Some(decode_android_public_key(&bytes));
+                    self.prompted = true;
+                    self.accept();
+                }
+                (A_OPEN, local) => {
+                    assert!(self.connected);
+                    let service = String::from_utf8(message.payload).unwrap().trim_end_matches('\0').to_string();
+                    self.services.push(service.clone());
+                    match service.strip_prefix("shell:") {
+                        Some(command) => {
+                            self.reply(AdbMessage::new(A_OKAY, 100, local, Vec::new()));
+                            self.reply(AdbMessage::new(A_WRTE, 100, local, format!("{}\n", command).into_bytes()));
+                            self.reply(AdbMessage::new(A_CLSE, 100, local, Vec::new()));
+                        }
+                        None => self.reply(AdbMessage::new(A_CLSE, 0, local, Vec::new())),
+                    }
+                }
+                // Acks and closes from the host need no answer here
+                (A_OKAY, _) | (A_CLSE, _) => {}
+                (command, _) => panic!("unexpected command {:#010x}", command),
+            }
+        }
+    }
+
+    impl SimulatedDevice for Adbd {
+        fn bulk_out(&mut self, data: &[u8]) -> Result<()> {
+            self.handle(data);
+            Ok(())
+        }
+
+        fn bulk_in(&mut self, max_len: usize) -> Result<Vec<u8>> {
+            let mut packet =
+                self.replies.pop_front().ok_or_else(|| BootforgeError::Usb("No response queued".to_string()))?;
+            if packet.len() > max_len {
+                let rest = packet.split_off(max_len);
+                self.replies.push_front(rest);
+            }
+            Ok(packet)
+        }
+    }
+
+    pub(crate) fn simulated(adbd: Adbd) -> (UsbTransport, Arc<Mutex<Adbd>>) {
+        simulated_transport(test_device(0x18d1, 0x4ee7, "ADB123", DeviceMode::Normal, ProtocolType::ADB), adbd)
+    }
+
+    #[test]
+    fn test_message_header() {
+        let message = AdbMessage::new(A_OPEN, 1, 0, b"shell:ls\0".to_vec());
+        let header = message.header();
+        assert_eq!(&header[..4], b"OPEN");
+        assert_eq!(u32::from_le_bytes(header[12..16].try_into().unwrap()), 9);
+        let (parsed, len) = AdbMessage::parse_header(&header).unwrap();
+        assert_eq!((parsed.command, parsed.arg0, parsed.arg1, len), (A_OPEN, 1, 0, 9));
+
+        let mut bad = header;
+        bad[20] ^= 1;
+        assert!(AdbMessage::parse_header(&bad).is_err());
+        assert!(AdbMessage::parse_header(&header[..20]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_auth_with_known_and_new_key() {
+        let key = test_key();
+
+        let (transport, state) = simulated(Adbd::default());
+        let session = UsbSession::connect(transport, &key).await.unwrap();
+        assert!(state.lock().unwrap().prompted);
+        assert_eq!(session.banner(), "device::ro.product.name=sargo;");
+        assert_eq!(session.max_payload(), 4096);
+
+        // Once trusted, the signature alone is enough
+        let trusted = state.lock().unwrap().trusted.clone();
+        let (transport, state) = simulated(Adbd { trusted, ..Default::default() });
+        UsbSession::connect(transport, &key).await.unwrap();
+        assert!(!state.lock().unwrap().prompted);
+    }
+}
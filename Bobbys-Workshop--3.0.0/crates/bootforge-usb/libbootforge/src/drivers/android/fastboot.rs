@@ -0,0 +1,649 @@
+//! Fastboot protocol client over `UsbTransport`.
+//!
+//! Commands are ASCII strings sent on the bulk OUT endpoint. The bootloader
+//! answers with packets tagged `INFO` or `TEXT` (progress, keep reading),
+//! `OKAY` or `FAIL` (done), or `DATA` plus eight hex digits (send that many
+//! bytes now, or for `fetch`, receive them).
+
+use crate::imaging::sparse::{SparseImage, DEFAULT_SPARSE_BLOCK_SIZE, SPARSE_HEADER_MAGIC};
+use crate::imaging::stream::read_full;
+use crate::usb::{ProtocolType, UsbDeviceInfo, UsbTransport};
+use crate::{BootforgeError, Result};
+use device_analysis::device_state::fastboot as fastboot_state;
+use device_analysis::device_state::{FastbootStateBuilder, UnifiedDeviceState};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::time::Duration;
+
+/// Decimal or `0x`-prefixed hex, as bootloaders report sizes either way.
+pub use fastboot_state::parse_number;
+
+/// Longest command the protocol allows.
+pub const MAX_COMMAND_LEN: usize = 4096;
+/// Longest response packet; older bootloaders send at most 64 bytes.
+pub const MAX_RESPONSE_LEN: usize = 256;
+/// Download buffer assumed when the bootloader doesn't report `max-download-size`.
+pub const DEFAULT_MAX_DOWNLOAD_SIZE: u64 = 256 * 1024 * 1024;
+/// Flashing or erasing a large partition can take minutes without any INFO.
+pub const FLASH_TIMEOUT: Duration = Duration::from_secs(300);
+/// Bytes per bulk transfer during a download or fetch.
+const DOWNLOAD_CHUNK: usize = 1024 * 1024;
+
+/// One response packet.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FastbootResponse {
+    Okay(String),
+    Fail(String),
+    Info(String),
+    Text(String),
+    /// The bootloader is ready to receive this many bytes.
+    Data(u32),
+}
+
+impl FastbootResponse {
+    pub fn parse(packet: &[u8]) -> Result<Self> {
+        if packet.len() < 4 {
+            return Err(fastboot_error(format!("Short response {:?}", String::from_utf8_lossy(packet))));
+        }
+
+        let (tag, payload) = packet.split_at(4);
+        let text = String::from_utf8_lossy(payload).trim_end_matches(['\0', '\r', '\n']).to_string();
+        match tag {
+            b"OKAY" => Ok(FastbootResponse::Okay(text)),
+            b"FAIL" => Ok(FastbootResponse::Fail(text)),
+            b"INFO" => Ok(FastbootResponse::Info(text)),
+            b"TEXT" => Ok(FastbootResponse::Text(text)),
+            b"DATA" => u32::from_str_radix(text.trim(), 16)
+                .map(FastbootResponse::Data)
+                .map_err(|_| fastboot_error(format!("Invalid DATA length {:?}", text))),
+            _ => Err(fastboot_error(format!("Unknown response {:?}", String::from_utf8_lossy(packet)))),
+        }
+    }
+}
+
+/// What a command returned: the `OKAY` payload and any `INFO`/`TEXT` lines
+/// sent before it.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FastbootReply {
+    pub value: String,
+    pub info: Vec<String>,
+}
+
+#[derive(Debug)]
+pub struct FastbootClient {
+    transport: UsbTransport,
+    max_download_size: Option<u64>,
+}
+
+impl FastbootClient {
+    /// Wrap a transport whose interface is the fastboot interface.
+    pub fn new(transport: UsbTransport) -> Self {
+        FastbootClient { transport, max_download_size: None }
+    }
+
+    /// Open the fastboot interface of `device`.
+    pub fn open(device: &UsbDeviceInfo) -> Result<Self> {
+        let interface = device
+            .interfaces
+            .iter()
+            .find(|i| i.protocol_type() == Some(ProtocolType::Fastboot))
+            .map_or(0, |i| i.number);
+        Ok(Self::new(UsbTransport::open(device.clone(), interface)?))
+    }
+
+    pub fn transport(&self) -> &UsbTransport {
+        &self.transport
+    }
+
+    pub fn into_transport(self) -> UsbTransport {
+        self.transport
+    }
+
+    /// Send `command` and wait for `OKAY`. `FAIL` becomes an error carrying
+    /// the bootloader's reason.
+    pub async fn command(&mut self, command: &str) -> Result<FastbootReply> {
+        self.command_with_timeout(command, self.transport.timeout()).await
+    }
+
+    pub async fn getvar(&mut self, name: &str) -> Result<String> {
+        Ok(self.command(&format!("getvar:{}", name)).await?.value)
+    }
+
+    /// Every variable the bootloader reports, keyed by name. Per-partition
+    /// variables keep their partition in the key (`partition-size:boot_a`).
+    pub async fn getvar_all(&mut self) -> Result<BTreeMap<String, String>> {
+        let reply = self.command("getvar:all").await?;
+        Ok(parse_getvar_all(&reply.info))
+    }
+
+    /// Identity, lock state and partitions from `getvar:all`, with the
+    /// device's recorded connection history.
+    pub async fn device_state(&mut self) -> Result<UnifiedDeviceState> {
+        let mut builder = FastbootStateBuilder::new(self.getvar_all().await?);
+        if let Some(history) = super::recorded_history(builder.identity().serial.as_deref()).await {
+            builder = builder.history(history);
+        }
+        Ok(builder.build())
+    }
+
+    /// Size of the bootloader's download buffer, asked once per session.
+    pub async fn max_download_size(&mut self) -> Result<u64> {
+        if let Some(size) = self.max_download_size {
+            return Ok(size);
+        }
+
+        let size = match self.getvar("max-download-size").await {
+            Ok(value) => parse_number(&value)
+                .filter(|&size| size > 0)
+                .ok_or_else(|| fastboot_error(format!("Invalid max-download-size {:?}", value)))?,
+            Err(BootforgeError::Driver(reason)) => {
+                log::info!("[Fastboot] No max-download-size ({}), assuming {} bytes", reason, DEFAULT_MAX_DOWNLOAD_SIZE);
+                DEFAULT_MAX_DOWNLOAD_SIZE
+            }
+            Err(e) => return Err(e),
+        };
+        self.max_download_size = Some(size);
+        Ok(size)
+    }
+
+    /// Load `data` into the bootloader's download buffer.
+    pub async fn download(&mut self, data: &[u8]) -> Result<()> {
+        self.download_from(&mut &data[..], data.len() as u64, &mut |_| {}).await
+    }
+
+    /// Flash `image` to `partition`.
+    pub async fn flash(&mut self, partition: &str, image: &Path) -> Result<()> {
+        self.flash_with_progress(partition, image, |_, _| {}).await
+    }
+
+    /// `flash`, calling `progress` with the bytes downloaded so far and in
+    /// total.
+    ///
+    /// Images that fit the download buffer go down in one piece. Sparse
+    /// images, and raw images that don't fit, are sent as sparse pieces of
+    /// at most `max-download-size` bytes (and never over the protocol's
+    /// 4 GiB limit), each encoded as it is sent and flashed in turn.
+    pub async fn flash_with_progress(
+        &mut self,
+        partition: &str,
+        image: &Path,
+        mut progress: impl FnMut(u64, u64),
+    ) -> Result<()> {
+        let max = self.max_download_size().await?.min(u32::MAX as u64);
+        let mut file = File::open(image)
+            .map_err(|e| BootforgeError::Driver(format!("Failed to open image {}: {}", image.display(), e)))?;
+        let len = file.metadata()?.len();
+        let sparse = is_sparse(&mut file)?;
+
+        if !sparse && len <= max {
+            self.download_from(&mut file, len, &mut |sent| progress(sent, len)).await?;
+            return self.flash_downloaded(partition).await;
+        }
+
+        let layout = if sparse {
+            SparseImage::parse(&mut file)?
+        } else {
+            SparseImage::from_raw(&mut file, DEFAULT_SPARSE_BLOCK_SIZE)?
+        };
+        let pieces = layout.split(max)?;
+        let total: u64 = pieces.iter().map(SparseImage::encoded_len).sum();
+        let mut done = 0u64;
+
+        for (index, piece) in pieces.iter().enumerate() {
+            let piece_len = piece.encoded_len();
+            log::info!(
+                "[Fastboot] Sending sparse '{}' {}/{} ({} bytes)",
+                partition, index + 1, pieces.len(), piece_len
+            );
+            self.download_from(&mut piece.encoder(&mut file), piece_len, &mut |sent| progress(done + sent, total))
+                .await?;
+            self.flash_downloaded(partition).await?;
+            done += piece_len;
+        }
+        Ok(())
+    }
+
+    /// Read `size` bytes of `partition` from `offset` into `dest` with the
+    /// `fetch` command, in pieces of at most `max-fetch-size` bytes.
+    ///
+    /// Only fastbootd and recent bootloaders implement `fetch`; others fail
+    /// the first command.
+    pub async fn fetch<W: Write>(&mut self, partition: &str, offset: u64, size: u64, dest: &mut W) -> Result<()> {
+        let max = match self.getvar("max-fetch-size").await {
+            Ok(value) => parse_number(&value)
+                .filter(|&max| max > 0)
+                .ok_or_else(|| fastboot_error(format!("Invalid max-fetch-size {:?}", value)))?,
+            Err(BootforgeError::Driver(_)) => self.max_download_size().await?,
+            Err(e) => return Err(e),
+        };
+
+        let mut done = 0u64;
+        while done < size {
+            let len = (size - done).min(max).min(u32::MAX as u64);
+            let command = format!("fetch:{}:0x{:08x}:0x{:08x}", partition, offset + done, len);
+            self.fetch_piece(&command, len, dest).await?;
+            done += len;
+        }
+        Ok(())
+    }
+
+    pub async fn erase(&mut self, partition: &str) -> Result<()> {
+        self.command_with_timeout(&format!("erase:{}", partition), FLASH_TIMEOUT).await?;
+        Ok(())
+    }
+
+    pub async fn reboot(&mut self) -> Result<()> {
+        self.command("reboot").await?;
+        Ok(())
+    }
+
+    pub async fn reboot_bootloader(&mut self) -> Result<()> {
+        self.command("reboot-bootloader").await?;
+        Ok(())
+    }
+
+    async fn flash_downloaded(&mut self, partition: &str) -> Result<()> {
+        self.command_with_timeout(&format!("flash:{}", partition), FLASH_TIMEOUT).await?;
+        Ok(())
+    }
+
+    async fn command_with_timeout(&mut self, command: &str, timeout: Duration) -> Result<FastbootReply> {
+        self.send_command(command).await?;
+
+        let mut info = Vec::new();
+        loop {
+            match self.read_response(timeout).await? {
+                FastbootResponse::Info(line) | FastbootResponse::Text(line) => {
+                    log::info!("[Fastboot] {}", line);
+                    info.push(line);
+                }
+                FastbootResponse::Okay(value) => return Ok(FastbootReply { value, info }),
+                FastbootResponse::Fail(reason) => return Err(command_failed(command, &reason)),
+                FastbootResponse::Data(len) => {
+                    return Err(fastboot_error(format!("Unexpected DATA ({} bytes) in reply to {}", len, command)))
+                }
+            }
+        }
+    }
+
+    /// Send `len` bytes from `source` with the `download` command.
+    async fn download_from<R: Read>(&mut self, source: &mut R, len: u64, progress: &mut impl FnMut(u64)) -> Result<()> {
+        let size = u32::try_from(len)
+            .map_err(|_| fastboot_error(format!("{} bytes is too large for a single download", len)))?;
+        let command = format!("download:{:08x}", size);
+        self.send_command(&command).await?;
+
+        loop {
+            match self.read_response(self.transport.timeout()).await? {
+                FastbootResponse::Info(line) | FastbootResponse::Text(line) => log::info!("[Fastboot] {}", line),
+                FastbootResponse::Data(accepted) if accepted == size => break,
+                FastbootResponse::Data(accepted) => {
+                    return Err(fastboot_error(format!("Bootloader accepts {} of {} bytes", accepted, size)))
+                }
+                FastbootResponse::Fail(reason) => return Err(command_failed(&command, &reason)),
+                FastbootResponse::Okay(_) => return Err(fastboot_error(format!("Expected DATA in reply to {}", command))),
+            }
+        }
+
+        let mut buffer = vec![0u8; DOWNLOAD_CHUNK.min(len as usize).max(1)];
+        let mut sent = 0u64;
+        while sent < len {
+            let want = (len - sent).min(buffer.len() as u64) as usize;
+            let n = read_full(source, &mut buffer[..want])?;
+            if n == 0 {
+                return Err(fastboot_error(format!("Image ended after {} of {} bytes", sent, len)));
+            }
+            self.transport.send(&buffer[..n]).await?;
+            sent += n as u64;
+            progress(sent);
+        }
+
+        self.command_reply(&command).await
+    }
+
+    /// Send a `fetch` command and copy the `len` bytes it returns to `dest`.
+    async fn fetch_piece<W: Write>(&mut self, command: &str, len: u64, dest: &mut W) -> Result<()> {
+        self.send_command(command).await?;
+
+        loop {
+            match self.read_response(self.transport.timeout()).await? {
+                FastbootResponse::Info(line) | FastbootResponse::Text(line) => log::info!("[Fastboot] {}", line),
+                FastbootResponse::Data(offered) if offered as u64 == len => break,
+                FastbootResponse::Data(offered) => {
+                    return Err(fastboot_error(format!("Bootloader offers {} of {} bytes", offered, len)))
+                }
+                FastbootResponse::Fail(reason) => return Err(command_failed(command, &reason)),
+                FastbootResponse::Okay(_) => return Err(fastboot_error(format!("Expected DATA in reply to {}", command))),
+            }
+        }
+
+        let mut received = 0u64;
+        while received < len {
+            let want = (len - received).min(DOWNLOAD_CHUNK as u64) as usize;
+            let data = self.transport.receive(want).await?;
+            if data.is_empty() {
+                return Err(fastboot_error(format!("Fetch ended after {} of {} bytes", received, len)));
+            }
+            dest.write_all(&data)?;
+            received += data.len() as u64;
+        }
+
+        self.command_reply(command).await
+    }
+
+    /// Wait for the `OKAY` that ends a download or fetch.
+    async fn command_reply(&mut self, command: &str) -> Result<()> {
+        loop {
+            match self.read_response(self.transport.timeout()).await? {
+                FastbootResponse::Info(line) | FastbootResponse::Text(line) => log::info!("[Fastboot] {}", line),
+                FastbootResponse::Okay(_) => return Ok(()),
+                FastbootResponse::Fail(reason) => return Err(command_failed(command, &reason)),
+                FastbootResponse::Data(_) => return Err(fastboot_error(format!("Unexpected DATA after {}", command))),
+            }
+        }
+    }
+
+    async fn send_command(&self, command: &str) -> Result<()> {
+        if command.len() > MAX_COMMAND_LEN {
+            return Err(fastboot_error(format!("Command is longer than {} bytes", MAX_COMMAND_LEN)));
+        }
+        log::debug!("[Fastboot] > {}", command);
+        self.transport.send(command.as_bytes()).await?;
+        Ok(())
+    }
+
+    async fn read_response(&mut self, timeout: Duration) -> Result<FastbootResponse> {
+        let previous = self.transport.timeout();
+        self.transport.set_timeout(timeout);
+        let packet = self.transport.receive(MAX_RESPONSE_LEN).await;
+        self.transport.set_timeout(previous);
+        FastbootResponse::parse(&packet?)
+    }
+}
+
+/// Parse the `INFO` lines of `getvar:all` (`key: value`). Later lines win
+/// when a key repeats.
+pub fn parse_getvar_all(lines: &[String]) -> BTreeMap<String, String> {
+    fastboot_state::parse_getvar_all(lines)
+}
+
+fn is_sparse(file: &mut File) -> Result<bool> {
+    let mut magic = [0u8; 4];
+    let n = read_full(file, &mut magic)?;
+    file.seek(SeekFrom::Start(0))?;
+    Ok(n == 4 && u32::from_le_bytes(magic) == SPARSE_HEADER_MAGIC)
+}
+
+fn fastboot_error(message: String) -> BootforgeError {
+    BootforgeError::Driver(format!("Fastboot: {}", message))
+}
+
+fn command_failed(command: &str, reason: &str) -> BootforgeError {
+    BootforgeError::Driver(format!("fastboot {} failed: {}", command, reason))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::pattern;
+    use crate::imaging::sparse::ChunkKind;
+    use crate::usb::transport::tests::{simulated_transport, test_device, SimulatedDevice};
+    use crate::usb::DeviceMode;
+    use std::collections::VecDeque;
+    use std::io::Cursor;
+    use std::sync::{Arc, Mutex};
+
+    /// Bootloader that keeps its partitions in memory and applies sparse
+    /// images the way real ones do, skipping don't-care blocks.
+    #[derive(Debug, Default)]
+    struct Bootloader {
+        vars: BTreeMap<String, String>,
+        max_download: u64,
+        receiving: Option<usize>,
+        buffer: Vec<u8>,
+        commands: Vec<String>,
+        partitions: BTreeMap<String, Vec<u8>>,
+        replies: VecDeque<Vec<u8>>,
+    }
+
+    impl Bootloader {
+        fn reply(&mut self, packet: String) {
+            self.replies.push_back(packet.into_bytes());
+        }
+
+        fn handle(&mut self, data: &[u8]) {
+            if let Some(remaining) = self.receiving {
+                self.buffer.extend_from_slice(data);
+                self.receiving = remaining.checked_sub(data.len()).filter(|&left| left > 0);
+                if self.receiving.is_none() {
+                    self.reply("OKAY".to_string());
+                }
+                return;
+            }
+
+            let command = String::from_utf8_lossy(data).to_string();
+            self.commands.push(command.clone());
+            match command.split_once(':') {
+                Some(("getvar", "all")) => {
+                    for (key, value) in self.vars.clone() {
+                        self.reply(format!("INFO{}: {}", key, value));
+                    }
+                    self.reply("OKAY".to_string());
+                }
+                Some(("getvar", name)) => match self.vars.get(name).cloned() {
+                    Some(value) => self.reply(format!("OKAY{}", value)),
+                    None => self.reply("FAILGetVar Variable Not found".to_string()),
+                },
+                Some(("download", size)) => {
+                    let size = usize::from_str_radix(size, 16).unwrap();
+                    if size as u64 > self.max_download {
+                        self.reply("FAILdata too large".to_string());
+                    } else {
+                        self.buffer.clear();
+                        self.receiving = Some(size);
+                        self.reply(format!("DATA{:08x}", size));
+                    }
+                }
+                Some(("flash", partition)) => {
+                    let image = std::mem::take(&mut self.buffer);
+                    let target = self.partitions.entry(partition.to_string()).or_default();
+                    apply_image(target, &image);
+                    self.reply(format!("INFOWriting '{}'", partition));
+                    self.reply("OKAY".to_string());
+                }
+                Some(("erase", partition)) => {
+                    self.partitions.remove(partition);
+                    self.reply("OKAY".to_string());
+                }
+                Some(("fetch", args)) => {
+                    let mut args = args.split(':');
+                    let (partition, offset, size) = (args.next().unwrap(), args.next().unwrap(), args.next().unwrap());
+                    let offset = parse_number(offset).unwrap() as usize;
+                    let size = parse_number(size).unwrap() as usize;
+                    match self.partitions.get(partition).and_then(|data| data.get(offset..offset + size)) {
+                        Some(data) => {
+                            let data = data.to_vec();
+                            self.reply(format!("DATA{:08x}", size));
+                            for packet in data.chunks(512) {
+                                self.replies.push_back(packet.to_vec());
+                            }
+                            self.reply("OKAY".to_string());
+                        }
+                        None => self.reply("FAILinvalid fetch range".to_string()),
+                    }
+                }
+                None if command == "reboot" || command == "reboot-bootloader" => self.reply("OKAY".to_string()),
+                _ => self.reply("FAILunknown command".to_string()),
+            }
+        }
+    }
+
+    fn apply_image(target: &mut Vec<u8>, image: &[u8]) {
+        if image.len() < 4 || u32::from_le_bytes(image[..4].try_into().unwrap()) != SPARSE_HEADER_MAGIC {
+            *target = image.to_vec();
+            return;
+        }
+
+        let sparse = SparseImage::parse(&mut Cursor::new(image)).unwrap();
+        let block_size = sparse.header.block_size as usize;
+        target.resize(sparse.expanded_size() as usize, 0);
+        let mut offset = 0usize;
+        for chunk in &sparse.chunks {
+            let len = chunk.blocks as usize * block_size;
+            match chunk.kind {
+                ChunkKind::Raw => {
+                    let start = chunk.data_offset as usize;
+                    target[offset..offset + len].copy_from_slice(&image[start..start + len]);
+                }
+                ChunkKind::Fill(value) => {
+                    for word in target[offset..offset + len].chunks_mut(4) {
+                        word.copy_from_slice(&value.to_le_bytes());
+                    }
+                }
+                ChunkKind::DontCare | ChunkKind::Crc32(_) => {}
+            }
+            offset += len;
+        }
+    }
+
+    impl SimulatedDevice for Bootloader {
+        fn bulk_out(&mut self, data: &[u8]) -> Result<()> {
+            self.handle(data);
+            Ok(())
+        }
+
+        fn bulk_in(&mut self, max_len: usize) -> Result<Vec<u8>> {
+            let mut packet = self
+                .replies
+                .pop_front()
+                .ok_or_else(|| BootforgeError::Usb("No response queued".to_string()))?;
+            packet.truncate(max_len);
+            Ok(packet)
+        }
+    }
+
+    fn simulated(max_download: u64) -> (FastbootClient, Arc<Mutex<Bootloader>>) {
+        let mut bootloader = Bootloader { max_download, ..Default::default() };
+        bootloader.vars.insert("max-download-size".to_string(), format!("{:#x}", max_download));
+        bootloader.vars.insert("product".to_string(), "sargo".to_string());
+        bootloader.vars.insert("partition-size:boot_a".to_string(), "0x4000000".to_string());
+
+        let info = test_device(0x18d1, 0x4ee0, "TEST123", DeviceMode::Fastboot, ProtocolType::Fastboot);
+        let (transport, state) = simulated_transport(info, bootloader);
+        (FastbootClient::new(transport), state)
+    }
+
+    #[test]
+    fn test_parse_responses() {
+        assert_eq!(FastbootResponse::parse(b"OKAY0.4").unwrap(), FastbootResponse::Okay("0.4".to_string()));
+        assert_eq!(FastbootResponse::parse(b"INFOerasing\n").unwrap(), FastbootResponse::Info("erasing".to_string()));
+        assert_eq!(FastbootResponse::parse(b"DATA00100000").unwrap(), FastbootResponse::Data(0x100000));
+        assert_eq!(FastbootResponse::parse(b"FAIL").unwrap(), FastbootResponse::Fail(String::new()));
+        assert!(FastbootResponse::parse(b"DATAzz").is_err());
+        assert!(FastbootResponse::parse(b"OK").is_err());
+        assert!(FastbootResponse::parse(b"WHAT").is_err());
+
+        assert_eq!(parse_number("0x20000000"), Some(0x2000_0000));
+        assert_eq!(parse_number("536870912"), Some(536_870_912));
+        assert_eq!(parse_number("lots"), None);
+    }
+
+    #[tokio::test]
+    async fn test_getvar() {
+        let (mut client, state) = simulated(1024 * 1024);
+
+        assert_eq!(client.getvar("product").await.unwrap(), "sargo");
+        let err = client.getvar("serialno").await.unwrap_err();
+        assert!(err.to_string().contains("Variable Not found"));
+
+        let vars = client.getvar_all().await.unwrap();
+        assert_eq!(vars["product"], "sargo");
+        assert_eq!(vars["partition-size:boot_a"], "0x4000000");
+        assert_eq!(client.max_download_size().await.unwrap(), 1024 * 1024);
+
+        state.lock().unwrap().vars.insert("unlocked".to_string(), "yes".to_string());
+        let device = client.device_state().await.unwrap();
+        assert_eq!(device.identity.unwrap().device_codename.as_deref(), Some("sargo"));
+        assert_eq!(device.state.locked, Some(false));
+        assert_eq!(device.metadata.unwrap()["fastboot"]["partitions"]["boot_a"]["size"], 0x400_0000);
+    }
+
+    #[tokio::test]
+    async fn test_flash_small_image_in_one_download() {
+        let dir = tempfile::tempdir().unwrap();
+        let image = dir.path().join("boot.img");
+        let data = pattern(100_000);
+        std::fs::write(&image, &data).unwrap();
+
+        let (mut client, state) = simulated(1024 * 1024);
+        let mut last = (0, 0);
+        client.flash_with_progress("boot_a", &image, |sent, total| last = (sent, total)).await.unwrap();
+
+        let state = state.lock().unwrap();
+        assert_eq!(state.partitions["boot_a"], data);
+        assert_eq!(state.commands, ["getvar:max-download-size", "download:000186a0", "flash:boot_a"]);
+        assert_eq!(last, (100_000, 100_000));
+    }
+
+    #[tokio::test]
+    async fn test_flash_splits_large_image() {
+        let dir = tempfile::tempdir().unwrap();
+        let image = dir.path().join("system.img");
+        let mut data = pattern(300 * 1024);
+        data[64 * 1024..128 * 1024].fill(0);
+        std::fs::write(&image, &data).unwrap();
+
+        let (mut client, state) = simulated(64 * 1024);
+        let mut last = (0, 0);
+        client.flash_with_progress("system", &image, |sent, total| last = (sent, total)).await.unwrap();
+
+        let state = state.lock().unwrap();
+        let flashes = state.commands.iter().filter(|c| *c == "flash:system").count();
+        assert!(flashes > 1);
+        assert_eq!(state.partitions["system"], data);
+        assert_eq!(last.0, last.1);
+    }
+
+    #[tokio::test]
+    async fn test_fetch() {
+        let (mut client, state) = simulated(64 * 1024);
+        let data = pattern(200_000);
+        {
+            let mut state = state.lock().unwrap();
+            state.partitions.insert("boot_a".to_string(), data.clone());
+            state.vars.insert("max-fetch-size".to_string(), "0x10000".to_string());
+        }
+
+        let mut fetched = Vec::new();
+        client.fetch("boot_a", 1000, 150_000, &mut fetched).await.unwrap();
+        assert_eq!(fetched, data[1000..151_000]);
+
+        let fetches: Vec<String> = state.lock().unwrap().commands.iter()
+            .filter(|c| c.starts_with("fetch:"))
+            .cloned()
+            .collect();
+        assert_eq!(fetches.len(), 3);
+        assert_eq!(fetches[0], "fetch:boot_a:0x000003e8:0x00010000");
+
+        let err = client.fetch("boot_a", 0, 300_000, &mut Vec::new()).await.unwrap_err();
+        assert!(err.to_string().contains("invalid fetch range"));
+    }
+
+    #[tokio::test]
+    async fn test_erase_reboot_and_failures() {
+        let (mut client, state) = simulated(1024);
+        state.lock().unwrap().partitions.insert("userdata".to_string(), vec![1; 16]);
+
+        client.erase("userdata").await.unwrap();
+        client.reboot_bootloader().await.unwrap();
+        client.reboot().await.unwrap();
+        assert!(!state.lock().unwrap().partitions.contains_key("userdata"));
+
+        let err = client.download(&[0; 2048]).await.unwrap_err();
+        assert!(err.to_string().contains("data too large"));
+        let err = client.command("oem unlock").await.unwrap_err();
+        assert!(err.to_string().contains("unknown command"));
+    }
+}
@@ -0,0 +1,71 @@
+pub mod adb;
+pub mod fastboot;
+
+use crate::{BootforgeError, Result};
+use crate::usb::{history_for_device, ProtocolType, UsbDeviceInfo};
+use device_analysis::device_state::DeviceHistory;
+
+pub use adb::{AdbClient, AdbKey};
+pub use fastboot::{FastbootClient, FastbootReply, FastbootResponse};
+
+pub struct AndroidDriver;
+
+impl AndroidDriver {
+    /// Open an ADB session with a booted device.
+    pub async fn adb(device: &UsbDeviceInfo) -> Result<AdbClient> {
+        AdbClient::connect(device).await
+    }
+
+    pub async fn adb_shell(device: &UsbDeviceInfo, cmd: &str) -> Result<String> {
+        log::info!("Executing ADB shell command");
+        Self::adb(device).await?.shell(cmd).await
+    }
+
+    /// Open a fastboot session with a device in bootloader mode.
+    pub fn fastboot(device: &UsbDeviceInfo) -> Result<FastbootClient> {
+        FastbootClient::open(device)
+    }
+
+    pub async fn enter_fastboot(device: &UsbDeviceInfo) -> Result<()> {
+        if device.protocol == ProtocolType::Fastboot {
+            log::info!("{} is already in fastboot", device.unique_key());
+            return Ok(());
+        }
+        if device.protocol != ProtocolType::ADB {
+            return Err(BootforgeError::Driver(format!(
+                "{} is not in fastboot and has no ADB to reboot it there",
+                device.unique_key()
+            )));
+        }
+        Self::adb(device).await?.reboot("bootloader").await
+    }
+
+    /// The device's `UnifiedDeviceState`, built from `getprop` and
+    /// `dumpsys`, as JSON.
+    pub async fn get_device_info(device: &UsbDeviceInfo) -> Result<String> {
+        log::info!("Fetching Android device info");
+        let state = Self::adb(device).await?.device_state().await?;
+        state
+            .to_json()
+            .map_err(|e| BootforgeError::Driver(format!("Failed to encode device state: {}", e)))
+    }
+}
+
+/// What the device cache has recorded about the device with `serial`.
+/// Cache errors only cost the history, not the device state. The cache is
+/// read on the blocking pool since it waits on the cache lock.
+pub(crate) async fn recorded_history(serial: Option<&str>) -> Option<DeviceHistory> {
+    let serial = serial?.to_string();
+    let lookup = serial.clone();
+    match tokio::task::spawn_blocking(move || history_for_device(&lookup)).await {
+        Ok(Ok(history)) => history,
+        Ok(Err(e)) => {
+            log::debug!("No device history for {}: {}", serial, e);
+            None
+        }
+        Err(e) => {
+            log::debug!("Device history lookup for {} failed: {}", serial, e);
+            None
+        }
+    }
+}
@@ -1,5 +1,6 @@
-use crate::Result;
-use crate::usb::UsbDeviceInfo;
+use crate::drivers::dfu::DfuClient;
+use crate::usb::{DeviceMode, UsbDeviceInfo};
+use crate::{BootforgeError, Result};
 
 pub struct AppleDriver;
 
@@ -10,9 +11,21 @@ impl AppleDriver {
         Ok("normal".to_string())
     }
 
-    pub async fn enter_dfu(_device: &UsbDeviceInfo) -> Result<()> {
+    /// Detach into DFU through a DFU runtime interface. iPhones and iPads
+    /// don't expose one; they only enter DFU through the button sequence.
+    pub async fn enter_dfu(device: &UsbDeviceInfo) -> Result<()> {
         log::info!("Attempting to enter DFU mode");
-        Ok(())
+        if device.mode == DeviceMode::DFU {
+            return Ok(());
+        }
+        if !device.interfaces.iter().any(|i| i.is_dfu_runtime()) {
+            return Err(BootforgeError::Driver(format!(
+                "{:04x}:{:04x} has no DFU runtime interface; enter DFU with the device's button sequence",
+                device.vendor_id, device.product_id
+            )));
+        }
+
+        DfuClient::open(device).await?.detach().await
     }
 
     pub async fn get_device_info(_device: &UsbDeviceInfo) -> Result<String> {
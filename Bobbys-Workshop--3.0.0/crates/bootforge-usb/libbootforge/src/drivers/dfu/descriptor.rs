@@ -0,0 +1,302 @@
+//! DFU descriptors: the functional descriptor that follows a DFU interface
+//! in the configuration descriptor, and the DfuSe memory layout encoded in
+//! alternate setting names.
+
+use super::dfu_error;
+use crate::Result;
+
+pub const DESCRIPTOR_INTERFACE: u8 = 0x04;
+pub const DESCRIPTOR_DFU_FUNCTIONAL: u8 = 0x21;
+
+const CLASS_APPLICATION: u8 = 0xfe;
+const SUBCLASS_DFU: u8 = 0x01;
+pub const PROTOCOL_RUNTIME: u8 = 0x01;
+pub const PROTOCOL_DFU_MODE: u8 = 0x02;
+
+/// `bcdDFUVersion` of DFU 1.1 and of ST's DfuSe extension.
+pub const DFU_VERSION_1_1: u16 = 0x0110;
+pub const DFU_VERSION_DFUSE: u16 = 0x011a;
+
+const ATTR_CAN_DNLOAD: u8 = 0x01;
+const ATTR_CAN_UPLOAD: u8 = 0x02;
+const ATTR_MANIFESTATION_TOLERANT: u8 = 0x04;
+const ATTR_WILL_DETACH: u8 = 0x08;
+
+/// The DFU functional descriptor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DfuFunctional {
+    pub attributes: u8,
+    /// Milliseconds the device waits for a reset after `DETACH`.
+    pub detach_timeout: u16,
+    /// Largest `DNLOAD`/`UPLOAD` block.
+    pub transfer_size: u16,
+    pub dfu_version: u16,
+}
+
+impl DfuFunctional {
+    /// Assumed for devices that don't provide a functional descriptor.
+    pub const FALLBACK: DfuFunctional = DfuFunctional {
+        attributes: ATTR_CAN_DNLOAD | ATTR_CAN_UPLOAD,
+        detach_timeout: 1000,
+        transfer_size: 1024,
+        dfu_version: DFU_VERSION_1_1,
+    };
+
+    pub fn parse(descriptor: &[u8]) -> Result<Self> {
+        // DFU 1.0 descriptors stop before bcdDFUVersion
+        if descriptor.len() < 7 || descriptor[1] != DESCRIPTOR_DFU_FUNCTIONAL {
+            return Err(dfu_error(format!("Invalid functional descriptor {:02x?}", descriptor)));
+        }
+        let word = |i: usize| u16::from_le_bytes([descriptor[i], descriptor[i + 1]]);
+        Ok(DfuFunctional {
+            attributes: descriptor[2],
+            detach_timeout: word(3),
+            transfer_size: word(5),
+            dfu_version: if descriptor.len() >= 9 { word(7) } else { 0x0100 },
+        })
+    }
+
+    pub fn can_download(&self) -> bool {
+        self.attributes & ATTR_CAN_DNLOAD != 0
+    }
+
+    pub fn can_upload(&self) -> bool {
+        self.attributes & ATTR_CAN_UPLOAD != 0
+    }
+
+    /// The device still answers after manifestation instead of resetting.
+    pub fn manifestation_tolerant(&self) -> bool {
+        self.attributes & ATTR_MANIFESTATION_TOLERANT != 0
+    }
+
+    /// The device detaches by itself after `DETACH`, without a bus reset.
+    pub fn will_detach(&self) -> bool {
+        self.attributes & ATTR_WILL_DETACH != 0
+    }
+
+    pub fn is_dfuse(&self) -> bool {
+        self.dfu_version == DFU_VERSION_DFUSE
+    }
+}
+
+/// A DFU interface alternate setting.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DfuAltSetting {
+    pub interface: u8,
+    pub alt_setting: u8,
+    /// `PROTOCOL_RUNTIME` or `PROTOCOL_DFU_MODE`.
+    pub protocol: u8,
+    /// `iInterface`; the string is the DfuSe memory layout.
+    pub name_index: u8,
+    pub name: Option<String>,
+}
+
+/// DFU interfaces of a configuration and the functional descriptor that
+/// follows them.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DfuDescriptors {
+    pub alt_settings: Vec<DfuAltSetting>,
+    pub functional: Option<DfuFunctional>,
+}
+
+impl DfuDescriptors {
+    /// Walk a full configuration descriptor.
+    pub fn parse(config: &[u8]) -> Result<Self> {
+        let mut found = DfuDescriptors::default();
+        let mut in_dfu = false;
+        let mut offset = 0;
+
+        while offset + 2 <= config.len() {
+            let len = config[offset] as usize;
+            if len < 2 || offset + len > config.len() {
+                return Err(dfu_error(format!("Truncated descriptor at offset {}", offset)));
+            }
+            let descriptor = &config[offset..offset + len];
+
+            match descriptor[1] {
+                DESCRIPTOR_INTERFACE if len >= 9 => {
+                    in_dfu = descriptor[5] == CLASS_APPLICATION && descriptor[6] == SUBCLASS_DFU;
+                    if in_dfu {
+                        found.alt_settings.push(DfuAltSetting {
+                            interface: descriptor[2],
+                            alt_setting: descriptor[3],
+                            protocol: descriptor[7],
+                            name_index: descriptor[8],
+                            name: None,
+                        });
+                    }
+                }
+                DESCRIPTOR_DFU_FUNCTIONAL if in_dfu && found.functional.is_none() => {
+                    found.functional = Some(DfuFunctional::parse(descriptor)?);
+                }
+                _ => {}
+            }
+            offset += len;
+        }
+        Ok(found)
+    }
+}
+
+/// Sector attributes in a DfuSe layout.
+pub const SECTOR_READABLE: u8 = 0x01;
+pub const SECTOR_ERASABLE: u8 = 0x02;
+pub const SECTOR_WRITABLE: u8 = 0x04;
+
+/// A run of equally sized sectors.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DfuseSegment {
+    pub start: u32,
+    pub sector_size: u32,
+    pub sector_count: u32,
+    pub attributes: u8,
+}
+
+impl DfuseSegment {
+    pub fn end(&self) -> u64 {
+        self.start as u64 + self.sector_size as u64 * self.sector_count as u64
+    }
+
+    pub fn contains(&self, address: u32) -> bool {
+        address >= self.start && (address as u64) < self.end()
+    }
+}
+
+/// A DfuSe memory region, parsed from an alternate setting name such as
+/// `@Internal Flash  /0x08000000/04*016Kg,01*064Kg,07*128Kg`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DfuseMemory {
+    pub name: String,
+    pub segments: Vec<DfuseSegment>,
+}
+
+impl DfuseMemory {
+    pub fn parse(layout: &str) -> Result<Self> {
+        let invalid = || dfu_error(format!("Invalid DfuSe memory layout {:?}", layout));
+        let mut parts = layout.strip_prefix('@').ok_or_else(invalid)?.split('/');
+        let name = parts.next().ok_or_else(invalid)?.trim().to_string();
+
+        let mut segments = Vec::new();
+        // Address and sector list pairs
+        while let Some(address) = parts.next() {
+            let address = address.trim();
+            if address.is_empty() {
+                continue;
+            }
+            let mut start = address
+                .strip_prefix("0x")
+                .or_else(|| address.strip_prefix("0X"))
+                .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                .ok_or_else(invalid)?;
+            let sectors = parts.next().ok_or_else(invalid)?;
+
+            for group in sectors.split(',').map(str::trim).filter(|g| !g.is_empty()) {
+                let (count, rest) = group.split_once('*').ok_or_else(invalid)?;
+                let count: u32 = count.trim().parse().map_err(|_| invalid())?;
+                let digits = rest.find(|c: char| !c.is_ascii_digit()).ok_or_else(invalid)?;
+                let size: u32 = rest[..digits].parse().map_err(|_| invalid())?;
+                let mut suffix = rest[digits..].chars();
+                let multiplier = match suffix.next() {
+                    Some('K') => 1024,
+                    Some('M') => 1024 * 1024,
+                    Some(' ') | Some('B') => 1,
+                    _ => return Err(invalid()),
+                };
+                let attributes = match suffix.next() {
+                    Some(c @ 'a'..='g') => c as u8 - b'a' + 1,
+                    _ => return Err(invalid()),
+                };
+
+                let sector_size = size.checked_mul(multiplier).ok_or_else(invalid)?;
+                let segment = DfuseSegment { start, sector_size, sector_count: count, attributes };
+                start = u32::try_from(segment.end()).map_err(|_| invalid())?;
+                segments.push(segment);
+            }
+        }
+        Ok(DfuseMemory { name, segments })
+    }
+
+    pub fn segment_at(&self, address: u32) -> Option<&DfuseSegment> {
+        self.segments.iter().find(|s| s.contains(address))
+    }
+
+    /// Start addresses of the sectors overlapping `address..address + len`.
+    pub fn sectors_in(&self, address: u32, len: u32) -> Result<Vec<u32>> {
+        let end = address as u64 + len as u64;
+        let mut sectors = Vec::new();
+        let mut at = address as u64;
+        while at < end {
+            let segment = self
+                .segment_at(at as u32)
+                .ok_or_else(|| dfu_error(format!("{:#010x} is outside {}", at, self.name)))?;
+            let sector = segment.start as u64 + (at - segment.start as u64) / segment.sector_size as u64 * segment.sector_size as u64;
+            sectors.push(sector as u32);
+            at = sector + segment.sector_size as u64;
+        }
+        Ok(sectors)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// Configuration of an STM32F4 in its ROM bootloader: one DFU interface
+    /// with four alternate settings.
+    pub(crate) fn stm32_config() -> Vec<u8> {
+        let mut config = vec![9, 0x02, 0, 0, 1, 1, 0, 0xc0, 50];
+        for alt in 0..4u8 {
+            config.extend_from_slice(&[9, DESCRIPTOR_INTERFACE, 0, alt, 0, CLASS_APPLICATION, SUBCLASS_DFU, PROTOCOL_DFU_MODE, 4 + alt]);
+        }
+        config.extend_from_slice(&[9, DESCRIPTOR_DFU_FUNCTIONAL, 0x0b, 0xff, 0x00, 0x00, 0x08, 0x1a, 0x01]);
+        let total = config.len() as u16;
+        config[2..4].copy_from_slice(&total.to_le_bytes());
+        config
+    }
+
+    #[test]
+    fn test_parse_configuration() {
+        let descriptors = DfuDescriptors::parse(&stm32_config()).unwrap();
+        assert_eq!(descriptors.alt_settings.len(), 4);
+        assert_eq!(descriptors.alt_settings[2].alt_setting, 2);
+        assert_eq!(descriptors.alt_settings[2].name_index, 6);
+
+        let functional = descriptors.functional.unwrap();
+        assert_eq!(functional.transfer_size, 2048);
+        assert_eq!(functional.detach_timeout, 255);
+        assert!(functional.is_dfuse());
+        assert!(functional.can_download() && functional.can_upload() && functional.will_detach());
+        assert!(!functional.manifestation_tolerant());
+
+        assert!(DfuDescriptors::parse(&[9, 0x02, 9]).is_err());
+        // DFU 1.0 functional descriptors are two bytes shorter
+        assert_eq!(DfuFunctional::parse(&[7, 0x21, 0x03, 0, 0, 0, 4]).unwrap().dfu_version, 0x0100);
+    }
+
+    #[test]
+    fn test_parse_dfuse_layout() {
+        let flash = DfuseMemory::parse("@Internal Flash  /0x08000000/04*016Kg,01*064Kg,07*128Kg").unwrap();
+        assert_eq!(flash.name, "Internal Flash");
+        assert_eq!(flash.segments.len(), 3);
+        assert_eq!(flash.segments[1], DfuseSegment { start: 0x0801_0000, sector_size: 0x1_0000, sector_count: 1, attributes: 7 });
+        assert_eq!(flash.segments[2].end(), 0x0810_0000);
+        assert_eq!(flash.segment_at(0x0800_4000).unwrap().sector_size, 16 * 1024);
+        assert!(flash.segment_at(0x0810_0000).is_none());
+
+        assert_eq!(flash.sectors_in(0x0800_3000, 0x2000).unwrap(), [0x0800_0000, 0x0800_4000]);
+        assert_eq!(flash.sectors_in(0x0801_0000, 0x1_0001).unwrap(), [0x0801_0000, 0x0802_0000]);
+        assert!(flash.sectors_in(0x080f_ffff, 2).is_err());
+
+        let option_bytes = DfuseMemory::parse("@Option Bytes  /0x1FFFC000/01*016 e").unwrap();
+        assert_eq!(option_bytes.segments[0].sector_size, 16);
+        assert_eq!(option_bytes.segments[0].attributes & SECTOR_WRITABLE, SECTOR_WRITABLE);
+        assert_eq!(option_bytes.segments[0].attributes & SECTOR_ERASABLE, 0);
+
+        let two_regions = DfuseMemory::parse("@SRAM /0x20000000/64*001Kg/0x20010000/16*001Kg").unwrap();
+        assert_eq!(two_regions.segments[1].start, 0x2001_0000);
+
+        assert!(DfuseMemory::parse("Internal Flash").is_err());
+        assert!(DfuseMemory::parse("@Flash/0x08000000/04*016Kz").is_err());
+        // 8192 MiB sectors overflow the 32-bit address space
+        assert!(DfuseMemory::parse("@Flash/0x08000000/01*8192Mg").is_err());
+    }
+}
@@ -0,0 +1,332 @@
+//! `.dfu` files: a firmware payload followed by the 16-byte DFU suffix, and
+//! ST's DfuSe container that places several images at flash addresses.
+
+use super::descriptor::DFU_VERSION_DFUSE;
+use super::dfu_error;
+use crate::Result;
+use std::path::Path;
+
+pub const SUFFIX_LEN: usize = 16;
+const SUFFIX_SIGNATURE: &[u8; 3] = b"UFD";
+/// Matches any vendor, product or device release.
+pub const ANY_ID: u16 = 0xffff;
+
+const DFUSE_SIGNATURE: &[u8; 5] = b"DfuSe";
+const DFUSE_PREFIX_LEN: usize = 11;
+const TARGET_SIGNATURE: &[u8; 6] = b"Target";
+const TARGET_NAME_LEN: usize = 255;
+const TARGET_PREFIX_LEN: usize = 274;
+const ELEMENT_HEADER_LEN: usize = 8;
+
+/// Which device a file is meant for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DfuSuffix {
+    /// `bcdDevice` of the target, or [`ANY_ID`].
+    pub device: u16,
+    pub product: u16,
+    pub vendor: u16,
+    /// `0x0100`, or `0x011a` for a DfuSe image.
+    pub dfu_version: u16,
+}
+
+impl DfuSuffix {
+    pub fn new(vendor: u16, product: u16) -> Self {
+        DfuSuffix { device: ANY_ID, product, vendor, dfu_version: 0x0100 }
+    }
+
+    /// Parse the suffix at the end of `file`, checking its CRC. `None` when
+    /// the file has no suffix at all.
+    pub fn parse(file: &[u8]) -> Result<Option<Self>> {
+        if file.len() < SUFFIX_LEN {
+            return Ok(None);
+        }
+        let suffix = &file[file.len() - SUFFIX_LEN..];
+        if &suffix[8..11] != SUFFIX_SIGNATURE || (suffix[11] as usize) < SUFFIX_LEN {
+            return Ok(None);
+        }
+
+        let stored = u32::from_le_bytes(suffix[12..16].try_into().unwrap());
+        let computed = suffix_crc(&file[..file.len() - 4]);
+        if stored != computed {
+            return Err(dfu_error(format!("Suffix CRC {:#010x} does not match contents ({:#010x})", stored, computed)));
+        }
+
+        let word = |i: usize| u16::from_le_bytes([suffix[i], suffix[i + 1]]);
+        Ok(Some(DfuSuffix { device: word(0), product: word(2), vendor: word(4), dfu_version: word(6) }))
+    }
+
+    /// Whether the file may be flashed to `vendor:product`.
+    pub fn matches(&self, vendor: u16, product: u16) -> bool {
+        (self.vendor == ANY_ID || self.vendor == vendor) && (self.product == ANY_ID || self.product == product)
+    }
+}
+
+/// The suffix CRC is CRC-32 without the final inversion.
+fn suffix_crc(data: &[u8]) -> u32 {
+    !crc32fast::hash(data)
+}
+
+/// A firmware file, with or without a DFU suffix.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DfuFile {
+    pub payload: Vec<u8>,
+    pub suffix: Option<DfuSuffix>,
+}
+
+impl DfuFile {
+    pub fn new(payload: Vec<u8>, suffix: Option<DfuSuffix>) -> Self {
+        DfuFile { payload, suffix }
+    }
+
+    pub fn parse(mut data: Vec<u8>) -> Result<Self> {
+        let suffix = DfuSuffix::parse(&data)?;
+        if let Some(suffix_len) = suffix.map(|_| data[data.len() - SUFFIX_LEN + 11] as usize) {
+            if suffix_len > data.len() {
+                return Err(dfu_error(format!("Suffix length {} exceeds file size {}", suffix_len, data.len())));
+            }
+            data.truncate(data.len() - suffix_len);
+        }
+        Ok(DfuFile { payload: data, suffix })
+    }
+
+    pub fn read(path: &Path) -> Result<Self> {
+        Self::parse(std::fs::read(path)?)
+    }
+
+    /// Payload followed by the suffix, with its CRC filled in.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.payload.clone();
+        if let Some(suffix) = &self.suffix {
+            for word in [suffix.device, suffix.product, suffix.vendor, suffix.dfu_version] {
+                bytes.extend_from_slice(&word.to_le_bytes());
+            }
+            bytes.extend_from_slice(SUFFIX_SIGNATURE);
+            bytes.push(SUFFIX_LEN as u8);
+            let crc = suffix_crc(&bytes);
+            bytes.extend_from_slice(&crc.to_le_bytes());
+        }
+        bytes
+    }
+
+    pub fn write(&self, path: &Path) -> Result<()> {
+        std::fs::write(path, self.to_bytes())?;
+        Ok(())
+    }
+
+    /// Files without a suffix match every device.
+    pub fn matches(&self, vendor: u16, product: u16) -> bool {
+        self.suffix.is_none_or(|suffix| suffix.matches(vendor, product))
+    }
+
+    pub fn is_dfuse(&self) -> bool {
+        self.payload.starts_with(DFUSE_SIGNATURE)
+    }
+
+    /// The DfuSe container in the payload, if it is one.
+    pub fn dfuse_image(&self) -> Result<Option<DfuseImage>> {
+        if !self.is_dfuse() {
+            return Ok(None);
+        }
+        if self.suffix.is_some_and(|suffix| suffix.dfu_version != DFU_VERSION_DFUSE) {
+            log::warn!("[DFU] DfuSe image with a DFU {:#06x} suffix", self.suffix.unwrap().dfu_version);
+        }
+        DfuseImage::parse(&self.payload).map(Some)
+    }
+}
+
+/// Data to write at `address`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DfuseElement {
+    pub address: u32,
+    pub data: Vec<u8>,
+}
+
+/// The elements for one alternate setting (one memory).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DfuseTarget {
+    pub alt_setting: u8,
+    pub name: Option<String>,
+    pub elements: Vec<DfuseElement>,
+}
+
+/// A DfuSe container, as ST's DfuSe tools and `dfuse-pack` produce them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DfuseImage {
+    pub targets: Vec<DfuseTarget>,
+}
+
+impl DfuseImage {
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        if data.len() < DFUSE_PREFIX_LEN || &data[..5] != DFUSE_SIGNATURE {
+            return Err(dfu_error("Missing DfuSe prefix".to_string()));
+        }
+        if data[5] != 0x01 {
+            return Err(dfu_error(format!("Unsupported DfuSe version {}", data[5])));
+        }
+        let image_size = read_u32(data, 6)? as usize;
+        if image_size > data.len() {
+            return Err(dfu_error(format!("DfuSe image size {} exceeds the {} bytes present", image_size, data.len())));
+        }
+        let target_count = data[10];
+
+        let mut targets = Vec::with_capacity(target_count as usize);
+        let mut offset = DFUSE_PREFIX_LEN;
+        for index in 0..target_count {
+            let prefix = data
+                .get(offset..offset + TARGET_PREFIX_LEN)
+                .filter(|prefix| prefix.starts_with(TARGET_SIGNATURE))
+                .ok_or_else(|| dfu_error(format!("Invalid DfuSe target {} at offset {}", index, offset)))?;
+            let named = read_u32(prefix, 7)? != 0;
+            let name_field = &prefix[11..11 + TARGET_NAME_LEN];
+            let name_len = name_field.iter().position(|&b| b == 0).unwrap_or(TARGET_NAME_LEN);
+            let target_size = read_u32(prefix, 266)? as usize;
+            let element_count = read_u32(prefix, 270)?;
+            offset += TARGET_PREFIX_LEN;
+
+            let target_end = offset + target_size;
+            let mut elements = Vec::new();
+            for _ in 0..element_count {
+                let address = read_u32(data, offset)?;
+                let size = read_u32(data, offset + 4)? as usize;
+                let start = offset + ELEMENT_HEADER_LEN;
+                let element = data
+                    .get(start..start + size)
+                    .filter(|_| start + size <= target_end)
+                    .ok_or_else(|| dfu_error(format!("DfuSe element at {:#010x} runs past its target", address)))?;
+                elements.push(DfuseElement { address, data: element.to_vec() });
+                offset = start + size;
+            }
+            if offset != target_end {
+                return Err(dfu_error(format!("DfuSe target {} size does not match its elements", index)));
+            }
+
+            targets.push(DfuseTarget {
+                alt_setting: prefix[6],
+                name: named.then(|| String::from_utf8_lossy(&name_field[..name_len]).to_string()),
+                elements,
+            });
+        }
+        Ok(DfuseImage { targets })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(DFUSE_SIGNATURE);
+        bytes.push(0x01);
+        bytes.extend_from_slice(&[0; 4]);
+        bytes.push(self.targets.len() as u8);
+
+        for target in &self.targets {
+            bytes.extend_from_slice(TARGET_SIGNATURE);
+            bytes.push(target.alt_setting);
+            bytes.extend_from_slice(&(target.name.is_some() as u32).to_le_bytes());
+            let mut name = [0u8; TARGET_NAME_LEN];
+            let given = target.name.as_deref().unwrap_or_default().as_bytes();
+            let len = given.len().min(TARGET_NAME_LEN - 1);
+            name[..len].copy_from_slice(&given[..len]);
+            bytes.extend_from_slice(&name);
+
+            let size: usize = target.elements.iter().map(|e| ELEMENT_HEADER_LEN + e.data.len()).sum();
+            bytes.extend_from_slice(&(size as u32).to_le_bytes());
+            bytes.extend_from_slice(&(target.elements.len() as u32).to_le_bytes());
+            for element in &target.elements {
+                bytes.extend_from_slice(&element.address.to_le_bytes());
+                bytes.extend_from_slice(&(element.data.len() as u32).to_le_bytes());
+                bytes.extend_from_slice(&element.data);
+            }
+        }
+
+        let total = bytes.len() as u32;
+        bytes[6..10].copy_from_slice(&total.to_le_bytes());
+        bytes
+    }
+
+    /// A `.dfu` file for `vendor:product` holding this image.
+    pub fn into_file(self, vendor: u16, product: u16) -> DfuFile {
+        let suffix = DfuSuffix { dfu_version: DFU_VERSION_DFUSE, ..DfuSuffix::new(vendor, product) };
+        DfuFile::new(self.to_bytes(), Some(suffix))
+    }
+
+    /// Bytes across all elements.
+    pub fn data_len(&self) -> u64 {
+        self.targets.iter().flat_map(|t| &t.elements).map(|e| e.data.len() as u64).sum()
+    }
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Result<u32> {
+    data.get(offset..offset + 4)
+        .map(|bytes| u32::from_le_bytes(bytes.try_into().unwrap()))
+        .ok_or_else(|| dfu_error(format!("Truncated DfuSe image at offset {}", offset)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_suffix_round_trip() {
+        let file = DfuFile::new(b"firmware".to_vec(), Some(DfuSuffix::new(0x1209, 0x2002)));
+        let bytes = file.to_bytes();
+        assert_eq!(bytes.len(), 8 + SUFFIX_LEN);
+        assert_eq!(&bytes[8..16], &[0xff, 0xff, 0x02, 0x20, 0x09, 0x12, 0x00, 0x01]);
+        assert_eq!(&bytes[16..20], b"UFD\x10");
+
+        let parsed = DfuFile::parse(bytes.clone()).unwrap();
+        assert_eq!(parsed, file);
+        assert!(parsed.matches(0x1209, 0x2002));
+        assert!(!parsed.matches(0x0483, 0xdf11));
+        assert!(DfuFile::new(Vec::new(), Some(DfuSuffix::new(ANY_ID, ANY_ID))).matches(0x0483, 0xdf11));
+
+        let mut corrupt = bytes;
+        corrupt[0] ^= 1;
+        assert!(DfuFile::parse(corrupt).is_err());
+
+        let plain = DfuFile::parse(b"no suffix here, just a binary".to_vec()).unwrap();
+        assert!(plain.suffix.is_none());
+        assert!(plain.matches(0x0483, 0xdf11));
+    }
+
+    #[test]
+    fn test_known_suffix_crc() {
+        // The CRC covers everything before it, suffix fields included
+        let file = DfuFile::new(vec![0xde, 0xad, 0xbe, 0xef], Some(DfuSuffix::new(0x0483, 0xdf11)));
+        let bytes = file.to_bytes();
+        assert_eq!(u32::from_le_bytes(bytes[16..20].try_into().unwrap()), !crc32fast::hash(&bytes[..16]));
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("firmware.dfu");
+        file.write(&path).unwrap();
+        assert_eq!(DfuFile::read(&path).unwrap(), file);
+    }
+
+    #[test]
+    fn test_dfuse_image_round_trip() {
+        let image = DfuseImage {
+            targets: vec![
+                DfuseTarget {
+                    alt_setting: 0,
+                    name: Some("ST...".to_string()),
+                    elements: vec![
+                        DfuseElement { address: 0x0800_0000, data: vec![1; 300] },
+                        DfuseElement { address: 0x0800_4000, data: vec![2; 12] },
+                    ],
+                },
+                DfuseTarget { alt_setting: 1, name: None, elements: vec![DfuseElement { address: 0x1fff_7800, data: vec![3; 16] }] },
+            ],
+        };
+        let bytes = image.to_bytes();
+        assert_eq!(&bytes[..6], b"DfuSe\x01");
+        assert_eq!(read_u32(&bytes, 6).unwrap() as usize, bytes.len());
+        assert_eq!(DfuseImage::parse(&bytes).unwrap(), image);
+        assert_eq!(image.data_len(), 328);
+
+        let file = DfuFile::parse(image.clone().into_file(0x0483, 0xdf11).to_bytes()).unwrap();
+        assert_eq!(file.suffix.unwrap().dfu_version, DFU_VERSION_DFUSE);
+        assert_eq!(file.dfuse_image().unwrap(), Some(image));
+        assert_eq!(DfuFile::new(vec![0; 32], None).dfuse_image().unwrap(), None);
+
+        let mut truncated = bytes.clone();
+        truncated.truncate(bytes.len() - 4);
+        assert!(DfuseImage::parse(&truncated).is_err());
+    }
+}
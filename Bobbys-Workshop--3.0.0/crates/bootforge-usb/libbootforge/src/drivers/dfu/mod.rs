@@ -0,0 +1,920 @@
+//! USB DFU 1.1 client, with ST's DfuSe extensions.
+//!
+//! Everything happens through class requests on the DFU interface. The
+//! device moves through the states in [`DfuState`]; after each `DNLOAD` the
+//! host polls `GETSTATUS`, waiting the `bwPollTimeout` it reports, until the
+//! device is idle again. DfuSe devices additionally take commands (set
+//! address, erase) as `DNLOAD`s to block 0 and data at block 2 onwards.
+
+pub mod descriptor;
+pub mod file;
+
+pub use descriptor::{DfuAltSetting, DfuDescriptors, DfuFunctional, DfuseMemory, DfuseSegment};
+pub use file::{DfuFile, DfuSuffix, DfuseElement, DfuseImage, DfuseTarget};
+
+use crate::usb::{ControlKind, ControlRecipient, ControlRequest, ProtocolType, UsbDeviceInfo, UsbTransport};
+use crate::{BootforgeError, Result};
+use descriptor::SECTOR_ERASABLE;
+use std::time::{Duration, Instant};
+
+const DFU_DETACH: u8 = 0;
+const DFU_DNLOAD: u8 = 1;
+const DFU_UPLOAD: u8 = 2;
+const DFU_GETSTATUS: u8 = 3;
+const DFU_CLRSTATUS: u8 = 4;
+const DFU_GETSTATE: u8 = 5;
+const DFU_ABORT: u8 = 6;
+
+const GET_DESCRIPTOR: u8 = 0x06;
+const DESCRIPTOR_CONFIGURATION: u16 = 0x02;
+const DESCRIPTOR_STRING: u16 = 0x03;
+const LANGID_EN_US: u16 = 0x0409;
+
+const DFUSE_SET_ADDRESS: u8 = 0x21;
+const DFUSE_ERASE: u8 = 0x41;
+/// DfuSe data blocks start here; 0 carries commands and 1 is reserved.
+const DFUSE_DATA_BLOCK: u16 = 2;
+
+/// Longest a device may stay busy (a mass erase takes tens of seconds).
+pub const BUSY_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// `bState` of `GETSTATUS`/`GETSTATE`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DfuState {
+    AppIdle,
+    AppDetach,
+    DfuIdle,
+    DnloadSync,
+    DnBusy,
+    DnloadIdle,
+    ManifestSync,
+    Manifest,
+    ManifestWaitReset,
+    UploadIdle,
+    Error,
+}
+
+impl DfuState {
+    pub fn from_code(code: u8) -> Result<Self> {
+        Ok(match code {
+            0 => DfuState::AppIdle,
+            1 => DfuState::AppDetach,
+            2 => DfuState::DfuIdle,
+            3 => DfuState::DnloadSync,
+            4 => DfuState::DnBusy,
+            5 => DfuState::DnloadIdle,
+            6 => DfuState::ManifestSync,
+            7 => DfuState::Manifest,
+            8 => DfuState::ManifestWaitReset,
+            9 => DfuState::UploadIdle,
+            10 => DfuState::Error,
+            _ => return Err(dfu_error(format!("Unknown state {}", code))),
+        })
+    }
+
+    /// Running its application rather than the DFU bootloader.
+    pub fn is_runtime(&self) -> bool {
+        matches!(self, DfuState::AppIdle | DfuState::AppDetach)
+    }
+
+    /// States the device leaves on its own once it has finished working.
+    fn is_busy(&self) -> bool {
+        matches!(self, DfuState::DnloadSync | DfuState::DnBusy | DfuState::ManifestSync | DfuState::Manifest)
+    }
+}
+
+const STATUS_OK: u8 = 0x00;
+
+/// Reply to `GETSTATUS`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DfuStatus {
+    /// `bStatus`, see [`DfuStatus::description`].
+    pub status: u8,
+    /// How long to wait before the next `GETSTATUS`.
+    pub poll_timeout: Duration,
+    pub state: DfuState,
+    /// `iString` describing a vendor-specific error.
+    pub string_index: u8,
+}
+
+impl DfuStatus {
+    pub fn parse(reply: &[u8]) -> Result<Self> {
+        if reply.len() < 6 {
+            return Err(dfu_error(format!("Short GETSTATUS reply {:02x?}", reply)));
+        }
+        Ok(DfuStatus {
+            status: reply[0],
+            poll_timeout: Duration::from_millis(u32::from_le_bytes([reply[1], reply[2], reply[3], 0]) as u64),
+            state: DfuState::from_code(reply[4])?,
+            string_index: reply[5],
+        })
+    }
+
+    pub fn is_ok(&self) -> bool {
+        self.status == STATUS_OK
+    }
+
+    pub fn description(&self) -> &'static str {
+        match self.status {
+            0x00 => "No error",
+            0x01 => "File is not targeted for use by this device",
+            0x02 => "File is for this device but fails a verification test",
+            0x03 => "Device is unable to write memory",
+            0x04 => "Memory erase function failed",
+            0x05 => "Memory erase check failed",
+            0x06 => "Program memory function failed",
+            0x07 => "Programmed memory failed verification",
+            0x08 => "Received address is out of range",
+            0x09 => "Received the last block before all data",
+            0x0a => "Firmware is corrupt and cannot return to run-time",
+            0x0b => "Vendor-specific error",
+            0x0c => "Unexpected USB reset",
+            0x0d => "Unexpected power on reset",
+            0x0e => "Unknown error",
+            0x0f => "Device stalled an unexpected request",
+            _ => "Invalid status code",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct DfuClient {
+    transport: UsbTransport,
+    interface: u8,
+    alt_settings: Vec<DfuAltSetting>,
+    alt_setting: u8,
+    functional: DfuFunctional,
+}
+
+impl DfuClient {
+    /// Open the DFU (or DFU runtime) interface of `device`.
+    pub async fn open(device: &UsbDeviceInfo) -> Result<Self> {
+        let interface = device
+            .interfaces
+            .iter()
+            .find(|i| i.protocol_type() == Some(ProtocolType::DFU) || i.is_dfu_runtime())
+            .map_or(0, |i| i.number);
+        Self::from_transport(UsbTransport::open(device.clone(), interface)?, interface).await
+    }
+
+    /// Wrap a transport whose interface is `interface`, reading the DFU
+    /// descriptors from the device.
+    pub async fn from_transport(transport: UsbTransport, interface: u8) -> Result<Self> {
+        let config = read_config_descriptor(&transport).await?;
+        let descriptors = DfuDescriptors::parse(&config)?;
+
+        let mut alt_settings: Vec<DfuAltSetting> =
+            descriptors.alt_settings.into_iter().filter(|alt| alt.interface == interface).collect();
+        if alt_settings.is_empty() {
+            return Err(dfu_error(format!("Interface {} is not a DFU interface", interface)));
+        }
+        for alt in alt_settings.iter_mut().filter(|alt| alt.name_index != 0) {
+            match read_string_descriptor(&transport, alt.name_index).await {
+                Ok(name) => alt.name = Some(name),
+                Err(e) => log::warn!("[DFU] No name for alternate setting {}: {}", alt.alt_setting, e),
+            }
+        }
+
+        let functional = descriptors.functional.unwrap_or_else(|| {
+            log::warn!("[DFU] No functional descriptor, assuming {:?}", DfuFunctional::FALLBACK);
+            DfuFunctional::FALLBACK
+        });
+        let alt_setting = alt_settings[0].alt_setting;
+        Ok(DfuClient { transport, interface, alt_settings, alt_setting, functional })
+    }
+
+    pub fn transport(&self) -> &UsbTransport {
+        &self.transport
+    }
+
+    pub fn into_transport(self) -> UsbTransport {
+        self.transport
+    }
+
+    pub fn functional(&self) -> &DfuFunctional {
+        &self.functional
+    }
+
+    pub fn alt_settings(&self) -> &[DfuAltSetting] {
+        &self.alt_settings
+    }
+
+    /// The interface is the runtime one; `detach` before anything else.
+    pub fn is_runtime(&self) -> bool {
+        self.alt_settings.iter().all(|alt| alt.protocol == descriptor::PROTOCOL_RUNTIME)
+    }
+
+    pub fn alt_setting(&self) -> u8 {
+        self.alt_setting
+    }
+
+    pub fn select_alt_setting(&mut self, alt_setting: u8) -> Result<()> {
+        if !self.alt_settings.iter().any(|alt| alt.alt_setting == alt_setting) {
+            return Err(dfu_error(format!("No alternate setting {} on interface {}", alt_setting, self.interface)));
+        }
+        self.transport.set_alt_setting(alt_setting)?;
+        self.alt_setting = alt_setting;
+        Ok(())
+    }
+
+    /// DfuSe memory layout of the current alternate setting.
+    pub fn memory(&self) -> Result<DfuseMemory> {
+        let name = self
+            .alt_settings
+            .iter()
+            .find(|alt| alt.alt_setting == self.alt_setting)
+            .and_then(|alt| alt.name.as_deref())
+            .ok_or_else(|| dfu_error(format!("Alternate setting {} has no memory layout", self.alt_setting)))?;
+        DfuseMemory::parse(name)
+    }
+
+    /// Ask a runtime device to switch to DFU mode. Devices that don't
+    /// detach by themselves wait up to `wDetachTimeOut` for a bus reset.
+    pub async fn detach(&self) -> Result<()> {
+        let timeout = self.functional.detach_timeout;
+        self.transport.control_out(self.request(DFU_DETACH, timeout), &[]).await?;
+        if !self.functional.will_detach() {
+            log::info!("[DFU] Device needs a USB reset within {} ms to enter DFU mode", timeout);
+        }
+        Ok(())
+    }
+
+    pub async fn get_status(&self) -> Result<DfuStatus> {
+        let reply = self.transport.control_in(self.request(DFU_GETSTATUS, 0), 6).await?;
+        DfuStatus::parse(&reply)
+    }
+
+    pub async fn get_state(&self) -> Result<DfuState> {
+        let reply = self.transport.control_in(self.request(DFU_GETSTATE, 0), 1).await?;
+        let code = reply.first().ok_or_else(|| dfu_error("Empty GETSTATE reply".to_string()))?;
+        DfuState::from_code(*code)
+    }
+
+    pub async fn clear_status(&self) -> Result<()> {
+        self.transport.control_out(self.request(DFU_CLRSTATUS, 0), &[]).await?;
+        Ok(())
+    }
+
+    pub async fn abort(&self) -> Result<()> {
+        self.transport.control_out(self.request(DFU_ABORT, 0), &[]).await?;
+        Ok(())
+    }
+
+    /// One `DNLOAD` block; an empty one ends the download.
+    pub async fn dnload(&self, block: u16, data: &[u8]) -> Result<usize> {
+        self.transport.control_out(self.request(DFU_DNLOAD, block), data).await
+    }
+
+    /// One `UPLOAD` block; shorter than `len` means the end of the data.
+    pub async fn upload(&self, block: u16, len: u16) -> Result<Vec<u8>> {
+        self.transport.control_in(self.request(DFU_UPLOAD, block), len).await
+    }
+
+    /// Get the device to dfuIDLE: clear an error, abort a transfer left
+    /// half done.
+    pub async fn ensure_idle(&self) -> Result<()> {
+        let status = self.get_status().await?;
+        match status.state {
+            DfuState::DfuIdle => return Ok(()),
+            DfuState::Error => self.clear_status().await?,
+            DfuState::DnloadIdle | DfuState::UploadIdle => self.abort().await?,
+            state if state.is_runtime() => {
+                return Err(dfu_error("Device is in runtime mode; detach it into DFU mode first".to_string()))
+            }
+            state => return Err(dfu_error(format!("Device is busy ({:?})", state))),
+        }
+
+        match self.get_state().await? {
+            DfuState::DfuIdle => Ok(()),
+            state => Err(dfu_error(format!("Device did not return to dfuIDLE ({:?})", state))),
+        }
+    }
+
+    /// Download `data` the DFU 1.1 way, then let the device manifest it.
+    /// `progress` gets the bytes sent so far and the total.
+    pub async fn download(&mut self, data: &[u8], mut progress: impl FnMut(u64, u64)) -> Result<()> {
+        if !self.functional.can_download() {
+            return Err(dfu_error("Device does not support download".to_string()));
+        }
+        self.ensure_idle().await?;
+
+        let total = data.len() as u64;
+        let mut sent = 0u64;
+        let mut block = 0u16;
+        for chunk in data.chunks(self.transfer_size()) {
+            self.dnload(block, chunk).await?;
+            self.expect_state(DfuState::DnloadIdle).await?;
+            block = block.wrapping_add(1);
+            sent += chunk.len() as u64;
+            progress(sent, total);
+        }
+
+        self.dnload(block, &[]).await?;
+        self.manifest().await
+    }
+
+    /// Read up to `max_len` bytes of firmware back from the device.
+    pub async fn upload_all(&mut self, max_len: usize, mut progress: impl FnMut(u64)) -> Result<Vec<u8>> {
+        if !self.functional.can_upload() {
+            return Err(dfu_error("Device does not support upload".to_string()));
+        }
+        self.ensure_idle().await?;
+        let data = self.upload_blocks(0, max_len, &mut progress).await?;
+        // A short block already returned the device to dfuIDLE
+        if data.len() == max_len {
+            self.abort().await?;
+        }
+        Ok(data)
+    }
+
+    /// Point the DfuSe address pointer at `address`.
+    pub async fn set_address(&self, address: u32) -> Result<()> {
+        self.dfuse_command(DFUSE_SET_ADDRESS, Some(address)).await
+    }
+
+    pub async fn erase_page(&self, address: u32) -> Result<()> {
+        self.dfuse_command(DFUSE_ERASE, Some(address)).await
+    }
+
+    pub async fn mass_erase(&self) -> Result<()> {
+        self.dfuse_command(DFUSE_ERASE, None).await
+    }
+
+    /// Erase the sectors `data` covers and write it at `address` in the
+    /// current alternate setting's memory.
+    pub async fn dfuse_download(&mut self, address: u32, data: &[u8], mut progress: impl FnMut(u64, u64)) -> Result<()> {
+        self.require_dfuse()?;
+        self.ensure_idle().await?;
+        let total = data.len() as u64;
+        self.dfuse_write(address, data, &mut |sent| progress(sent, total)).await?;
+        self.abort().await
+    }
+
+    /// Read `len` bytes at `address` from the current alternate setting's memory.
+    pub async fn dfuse_upload(&mut self, address: u32, len: usize, mut progress: impl FnMut(u64)) -> Result<Vec<u8>> {
+        self.require_dfuse()?;
+        self.ensure_idle().await?;
+        self.set_address(address).await?;
+        // Uploads need dfuIDLE, not the dnIDLE the command left behind
+        self.abort().await?;
+
+        let data = self.upload_blocks(DFUSE_DATA_BLOCK, len, &mut progress).await?;
+        if data.len() < len {
+            return Err(dfu_error(format!("Device returned {} of {} bytes at {:#010x}", data.len(), len, address)));
+        }
+        self.abort().await?;
+        Ok(data)
+    }
+
+    /// Leave DFU mode and start the firmware, at `address` if given. The
+    /// device usually disappears without answering the final `GETSTATUS`.
+    pub async fn dfuse_leave(&mut self, address: Option<u32>) -> Result<()> {
+        self.require_dfuse()?;
+        self.ensure_idle().await?;
+        if let Some(address) = address {
+            self.set_address(address).await?;
+        }
+        self.dnload(DFUSE_DATA_BLOCK, &[]).await?;
+        match self.get_status().await {
+            Ok(status) if !status.is_ok() => Err(status_error(&status)),
+            Ok(_) | Err(BootforgeError::Usb(_)) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Flash a `.dfu` file: each target of a DfuSe image to its alternate
+    /// setting, or a plain payload as a DFU 1.1 download.
+    pub async fn flash_file(&mut self, file: &DfuFile, mut progress: impl FnMut(u64, u64)) -> Result<()> {
+        let (vendor, product) = (self.transport.device.vendor_id, self.transport.device.product_id);
+        if !file.matches(vendor, product) {
+            let suffix = file.suffix.unwrap();
+            return Err(dfu_error(format!(
+                "File is for {:04x}:{:04x}, not {:04x}:{:04x}",
+                suffix.vendor, suffix.product, vendor, product
+            )));
+        }
+
+        let Some(image) = file.dfuse_image()? else {
+            if self.functional.is_dfuse() {
+                return Err(dfu_error("DfuSe devices need a DfuSe image or a start address".to_string()));
+            }
+            return self.download(&file.payload, progress).await;
+        };
+
+        self.require_dfuse()?;
+        let total = image.data_len();
+        let mut done = 0u64;
+        for target in &image.targets {
+            self.select_alt_setting(target.alt_setting)?;
+            self.ensure_idle().await?;
+            for element in &target.elements {
+                log::info!(
+                    "[DFU] Writing {} bytes at {:#010x} (alt {})",
+                    element.data.len(),
+                    element.address,
+                    target.alt_setting
+                );
+                self.dfuse_write(element.address, &element.data, &mut |sent| progress(done + sent, total)).await?;
+                done += element.data.len() as u64;
+            }
+            self.abort().await?;
+        }
+        Ok(())
+    }
+
+    async fn dfuse_write(&mut self, address: u32, data: &[u8], progress: &mut impl FnMut(u64)) -> Result<()> {
+        let memory = self.memory()?;
+        for sector in memory.sectors_in(address, data.len() as u32)? {
+            let erasable = memory.segment_at(sector).is_some_and(|s| s.attributes & SECTOR_ERASABLE != 0);
+            if erasable {
+                self.erase_page(sector).await?;
+            }
+        }
+
+        let mut sent = 0u64;
+        for (index, chunk) in data.chunks(self.transfer_size()).enumerate() {
+            let offset = (index * self.transfer_size()) as u32;
+            self.set_address(address + offset).await?;
+            self.dnload(DFUSE_DATA_BLOCK, chunk).await?;
+            self.expect_state(DfuState::DnloadIdle).await?;
+            sent += chunk.len() as u64;
+            progress(sent);
+        }
+        Ok(())
+    }
+
+    async fn upload_blocks(&self, first_block: u16, max_len: usize, progress: &mut impl FnMut(u64)) -> Result<Vec<u8>> {
+        let transfer_size = self.transfer_size();
+        let mut data = Vec::new();
+        let mut block = first_block;
+        while data.len() < max_len {
+            let want = transfer_size.min(max_len - data.len());
+            let chunk = self.upload(block, want as u16).await?;
+            data.extend_from_slice(&chunk);
+            progress(data.len() as u64);
+            if chunk.len() < want {
+                break;
+            }
+            block = block.wrapping_add(1);
+        }
+        Ok(data)
+    }
+
+    /// A DfuSe command is a `DNLOAD` to block 0, carried out on the next
+    /// `GETSTATUS`.
+    async fn dfuse_command(&self, command: u8, address: Option<u32>) -> Result<()> {
+        let mut payload = vec![command];
+        if let Some(address) = address {
+            payload.extend_from_slice(&address.to_le_bytes());
+        }
+        self.dnload(0, &payload).await?;
+        self.expect_state(DfuState::DnloadIdle).await?;
+        Ok(())
+    }
+
+    /// After the last block: wait out manifestation. Devices that aren't
+    /// manifestation tolerant may reset instead of answering.
+    async fn manifest(&self) -> Result<()> {
+        match self.wait_while_busy().await {
+            Ok(status) if matches!(status.state, DfuState::DfuIdle | DfuState::ManifestWaitReset) => Ok(()),
+            Ok(status) => Err(dfu_error(format!("Unexpected state {:?} after manifestation", status.state))),
+            Err(BootforgeError::Usb(reason)) if !self.functional.manifestation_tolerant() => {
+                log::info!("[DFU] Device reset during manifestation ({})", reason);
+                Ok(())
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn expect_state(&self, expected: DfuState) -> Result<DfuStatus> {
+        let status = self.wait_while_busy().await?;
+        if status.state != expected {
+            return Err(dfu_error(format!("Expected {:?}, device is in {:?}", expected, status.state)));
+        }
+        Ok(status)
+    }
+
+    /// Poll `GETSTATUS` until the device has finished. A failed status is
+    /// cleared and returned as an error.
+    async fn wait_while_busy(&self) -> Result<DfuStatus> {
+        let deadline = Instant::now() + BUSY_TIMEOUT;
+        loop {
+            let status = self.get_status().await?;
+            if !status.is_ok() {
+                if let Err(e) = self.clear_status().await {
+                    log::warn!("[DFU] CLRSTATUS failed: {}", e);
+                }
+                return Err(status_error(&status));
+            }
+            if !status.state.is_busy() {
+                return Ok(status);
+            }
+            if Instant::now() >= deadline {
+                return Err(dfu_error(format!("Device still busy ({:?}) after {:?}", status.state, BUSY_TIMEOUT)));
+            }
+            tokio::time::sleep(status.poll_timeout).await;
+        }
+    }
+
+    fn require_dfuse(&self) -> Result<()> {
+        if !self.functional.is_dfuse() {
+            return Err(dfu_error(format!("Device speaks DFU {:#06x}, not DfuSe", self.functional.dfu_version)));
+        }
+        Ok(())
+    }
+
+    fn transfer_size(&self) -> usize {
+        self.functional.transfer_size.max(1) as usize
+    }
+
+    fn request(&self, request: u8, value: u16) -> ControlRequest {
+        ControlRequest {
+            kind: ControlKind::Class,
+            recipient: ControlRecipient::Interface,
+            request,
+            value,
+            index: self.interface as u16,
+        }
+    }
+}
+
+async fn read_config_descriptor(transport: &UsbTransport) -> Result<Vec<u8>> {
+    let request = standard_get_descriptor(DESCRIPTOR_CONFIGURATION << 8, 0);
+    let header = transport.control_in(request, 9).await?;
+    if header.len() < 4 {
+        return Err(dfu_error(format!("Short configuration descriptor {:02x?}", header)));
+    }
+    let total = u16::from_le_bytes([header[2], header[3]]);
+    transport.control_in(request, total).await
+}
+
+async fn read_string_descriptor(transport: &UsbTransport, index: u8) -> Result<String> {
+    let request = standard_get_descriptor((DESCRIPTOR_STRING << 8) | index as u16, LANGID_EN_US);
+    let reply = transport.control_in(request, 255).await?;
+    let len = reply.first().map_or(0, |&len| len as usize).min(reply.len());
+    if len < 2 {
+        return Err(dfu_error(format!("Empty string descriptor {}", index)));
+    }
+    let units: Vec<u16> = reply[2..len].chunks_exact(2).map(|c| u16::from_le_bytes([c[0], c[1]])).collect();
+    Ok(String::from_utf16_lossy(&units))
+}
+
+fn standard_get_descriptor(value: u16, index: u16) -> ControlRequest {
+    ControlRequest {
+        kind: ControlKind::Standard,
+        recipient: ControlRecipient::Device,
+        request: GET_DESCRIPTOR,
+        value,
+        index,
+    }
+}
+
+fn status_error(status: &DfuStatus) -> BootforgeError {
+    dfu_error(format!("{} (status {:#04x}, state {:?})", status.description(), status.status, status.state))
+}
+
+fn dfu_error(message: String) -> BootforgeError {
+    BootforgeError::Driver(format!("DFU: {}", message))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::pattern;
+    use crate::usb::transport::tests::{simulated_transport, test_device, SimulatedDevice};
+    use crate::usb::DeviceMode;
+    use std::collections::BTreeMap;
+    use std::sync::{Arc, Mutex};
+
+    const FLASH_LAYOUT: &str = "@Internal Flash  /0x08000000/04*016Kg,01*064Kg,07*128Kg";
+    const FLASH_BASE: u32 = 0x0800_0000;
+
+    /// STM32 ROM bootloader: DfuSe commands, erased flash reads as 0xff, and
+    /// writes to unerased sectors fail with errPROG.
+    #[derive(Debug)]
+    struct Bootloader {
+        config: Vec<u8>,
+        strings: BTreeMap<u8, String>,
+        state: DfuState,
+        status: u8,
+        alt_setting: u8,
+        address: u32,
+        flash: Vec<u8>,
+        erased: Vec<u32>,
+        /// Polls a DNLOAD spends in dnBUSY.
+        busy_polls: u32,
+        busy_left: u32,
+        pending: Option<(u16, Vec<u8>)>,
+        /// False for a plain DFU 1.1 device, which collects blocks in order
+        /// and manifests them on the empty one.
+        dfuse: bool,
+        received: Vec<u8>,
+        manifested: Option<Vec<u8>>,
+        left: bool,
+    }
+
+    impl Bootloader {
+        fn dfuse() -> Self {
+            let strings = [(4, FLASH_LAYOUT), (5, "@Option Bytes  /0x1FFFC000/01*016 e")];
+            Bootloader {
+                config: descriptor::tests::stm32_config(),
+                strings: strings.into_iter().map(|(i, s)| (i, s.to_string())).collect(),
+                state: DfuState::DfuIdle,
+                status: STATUS_OK,
+                alt_setting: 0,
+                address: 0,
+                flash: vec![0; 1024 * 1024],
+                erased: Vec::new(),
+                busy_polls: 1,
+                busy_left: 0,
+                pending: None,
+                dfuse: true,
+                received: Vec::new(),
+                manifested: None,
+                left: false,
+            }
+        }
+
+        /// A DFU 1.1 device with 64-byte transfers, manifestation tolerant.
+        fn dfu_1_1() -> Self {
+            let mut config = vec![9, 0x02, 0, 0, 1, 1, 0, 0x80, 50];
+            config.extend_from_slice(&[9, 0x04, 0, 0, 0, 0xfe, 0x01, 0x02, 0]);
+            config.extend_from_slice(&[9, 0x21, 0x07, 0xe8, 0x03, 0x40, 0x00, 0x10, 0x01]);
+            let total = config.len() as u16;
+            config[2..4].copy_from_slice(&total.to_le_bytes());
+            Bootloader { config, strings: BTreeMap::new(), dfuse: false, ..Self::dfuse() }
+        }
+
+        fn fail(&mut self, status: u8) {
+            self.status = status;
+            self.state = DfuState::Error;
+        }
+
+        fn respond(&mut self, request: ControlRequest, length: u16) -> Vec<u8> {
+            let mut reply = match (request.kind, request.request) {
+                (ControlKind::Standard, GET_DESCRIPTOR) => match (request.value >> 8, request.value as u8) {
+                    (DESCRIPTOR_CONFIGURATION, _) => self.config.clone(),
+                    (DESCRIPTOR_STRING, index) if self.strings.contains_key(&index) => {
+                        let units: Vec<u16> = self.strings[&index].encode_utf16().collect();
+                        let mut descriptor = vec![2 + units.len() as u8 * 2, 0x03];
+                        descriptor.extend(units.iter().flat_map(|u| u.to_le_bytes()));
+                        descriptor
+                    }
+                    _ => Vec::new(),
+                },
+                (ControlKind::Class, DFU_GETSTATUS) => self.get_status(),
+                (ControlKind::Class, DFU_GETSTATE) => vec![self.state as u8],
+                (ControlKind::Class, DFU_UPLOAD) => self.upload(request.value, length as usize),
+                _ => Vec::new(),
+            };
+            reply.truncate(length as usize);
+            reply
+        }
+
+        fn handle(&mut self, request: ControlRequest, data: &[u8]) {
+            match request.request {
+                DFU_DNLOAD if matches!(self.state, DfuState::DfuIdle | DfuState::DnloadIdle) => {
+                    if data.is_empty() && (!self.dfuse || request.value == DFUSE_DATA_BLOCK) {
+                        self.state = DfuState::ManifestSync;
+                    } else {
+                        self.pending = Some((request.value, data.to_vec()));
+                        self.state = DfuState::DnloadSync;
+                        self.busy_left = self.busy_polls;
+                    }
+                }
+                DFU_DNLOAD => self.fail(0x0f),
+                DFU_CLRSTATUS => {
+                    self.status = STATUS_OK;
+                    self.state = DfuState::DfuIdle;
+                }
+                DFU_ABORT => self.state = DfuState::DfuIdle,
+                _ => {}
+            }
+        }
+
+        fn get_status(&mut self) -> Vec<u8> {
+            match self.state {
+                DfuState::DnloadSync | DfuState::DnBusy if self.busy_left > 0 => {
+                    self.busy_left -= 1;
+                    self.state = DfuState::DnBusy;
+                }
+                DfuState::DnloadSync | DfuState::DnBusy => {
+                    self.state = DfuState::DnloadIdle;
+                    let (block, data) = self.pending.take().unwrap();
+                    self.apply(block, &data);
+                }
+                DfuState::ManifestSync if self.dfuse => self.left = true,
+                DfuState::ManifestSync => self.state = DfuState::Manifest,
+                DfuState::Manifest => {
+                    self.manifested = Some(std::mem::take(&mut self.received));
+                    self.state = DfuState::DfuIdle;
+                }
+                _ => {}
+            }
+            let mut reply = vec![self.status, 1, 0, 0, self.state as u8, 0];
+            if self.state.is_busy() {
+                reply[1] = 2;
+            }
+            reply
+        }
+
+        fn apply(&mut self, block: u16, data: &[u8]) {
+            if !self.dfuse {
+                self.received.extend_from_slice(data);
+                return;
+            }
+            match (block, data) {
+                (0, [DFUSE_SET_ADDRESS, address @ ..]) => self.address = u32::from_le_bytes(address.try_into().unwrap()),
+                (0, [DFUSE_ERASE]) => {
+                    let memory = DfuseMemory::parse(FLASH_LAYOUT).unwrap();
+                    self.erased = memory.sectors_in(FLASH_BASE, self.flash.len() as u32).unwrap();
+                }
+                (0, [DFUSE_ERASE, address @ ..]) => self.erased.push(u32::from_le_bytes(address.try_into().unwrap())),
+                (0, _) => self.fail(0x0f),
+                (DFUSE_DATA_BLOCK.., _) => {
+                    let address = self.address + (block - DFUSE_DATA_BLOCK) as u32 * 2048;
+                    let Some(offset) = address.checked_sub(FLASH_BASE).map(|o| o as usize) else {
+                        return self.fail(0x08);
+                    };
+                    let memory = DfuseMemory::parse(FLASH_LAYOUT).unwrap();
+                    let sectors = memory.sectors_in(address, data.len() as u32).unwrap();
+                    if !sectors.iter().all(|sector| self.erased.contains(sector)) {
+                        return self.fail(0x06);
+                    }
+                    self.flash[offset..offset + data.len()].copy_from_slice(data);
+                }
+                _ => self.fail(0x0f),
+            }
+        }
+
+        fn upload(&mut self, block: u16, len: usize) -> Vec<u8> {
+            self.state = DfuState::UploadIdle;
+            let start = if self.dfuse {
+                (self.address - FLASH_BASE) as usize + (block - DFUSE_DATA_BLOCK) as usize * 2048
+            } else {
+                block as usize * 64
+            };
+            let end = (start + len).min(self.flash.len());
+            if end - start < len {
+                self.state = DfuState::DfuIdle;
+            }
+            self.flash[start..end].to_vec()
+        }
+    }
+
+    impl SimulatedDevice for Bootloader {
+        fn control_in(&mut self, request: ControlRequest, length: u16) -> Result<Vec<u8>> {
+            if self.left {
+                return Err(BootforgeError::Usb("Device disconnected".to_string()));
+            }
+            Ok(self.respond(request, length))
+        }
+
+        fn control_out(&mut self, request: ControlRequest, data: &[u8]) -> Result<usize> {
+            self.handle(request, data);
+            Ok(data.len())
+        }
+
+        fn set_alt_setting(&mut self, alt_setting: u8) -> Result<()> {
+            self.alt_setting = alt_setting;
+            Ok(())
+        }
+    }
+
+    async fn simulated(bootloader: Bootloader) -> (DfuClient, Arc<Mutex<Bootloader>>) {
+        let info = test_device(0x0483, 0xdf11, "STM32FxSTM32", DeviceMode::DFU, ProtocolType::DFU);
+        let (transport, state) = simulated_transport(info, bootloader);
+        (DfuClient::from_transport(transport, 0).await.unwrap(), state)
+    }
+
+    #[test]
+    fn test_parse_status() {
+        let status = DfuStatus::parse(&[0x00, 0x10, 0x27, 0x00, 0x04, 0x00]).unwrap();
+        assert!(status.is_ok());
+        assert_eq!(status.poll_timeout, Duration::from_millis(10_000));
+        assert_eq!(status.state, DfuState::DnBusy);
+
+        let failed = DfuStatus::parse(&[0x06, 0, 0, 0, 0x0a, 0]).unwrap();
+        assert!(!failed.is_ok());
+        assert_eq!(failed.description(), "Program memory function failed");
+        assert!(DfuStatus::parse(&[0, 0, 0, 0, 11, 0]).is_err());
+        assert!(DfuStatus::parse(&[0, 0]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_open_reads_descriptors() {
+        let (client, _) = simulated(Bootloader::dfuse()).await;
+        assert_eq!(client.alt_settings().len(), 4);
+        assert_eq!(client.alt_settings()[0].name.as_deref(), Some(FLASH_LAYOUT));
+        // No string 6 or 7 on this device
+        assert_eq!(client.alt_settings()[2].name, None);
+        assert!(client.functional().is_dfuse());
+        assert!(!client.is_runtime());
+
+        let memory = client.memory().unwrap();
+        assert_eq!(memory.name, "Internal Flash");
+        assert_eq!(memory.segments.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_dfuse_download_and_upload() {
+        let (mut client, state) = simulated(Bootloader::dfuse()).await;
+        let firmware = pattern(20_000);
+
+        let mut last = (0, 0);
+        client.dfuse_download(FLASH_BASE + 0x3000, &firmware, |sent, total| last = (sent, total)).await.unwrap();
+        assert_eq!(last, (20_000, 20_000));
+        {
+            let bootloader = state.lock().unwrap();
+            assert_eq!(bootloader.erased, [0x0800_0000, 0x0800_4000]);
+            assert_eq!(&bootloader.flash[0x3000..0x3000 + 20_000], &firmware[..]);
+            assert_eq!(bootloader.state, DfuState::DfuIdle);
+        }
+
+        let read = client.dfuse_upload(FLASH_BASE + 0x3000, 20_000, |_| {}).await.unwrap();
+        assert_eq!(read, firmware);
+
+        client.dfuse_leave(Some(FLASH_BASE)).await.unwrap();
+        assert!(state.lock().unwrap().left);
+    }
+
+    #[tokio::test]
+    async fn test_error_status_is_reported_and_cleared() {
+        let (client, state) = simulated(Bootloader::dfuse()).await;
+        client.ensure_idle().await.unwrap();
+        // Writing without erasing first
+        client.set_address(FLASH_BASE).await.unwrap();
+        client.dnload(DFUSE_DATA_BLOCK, &[1, 2, 3, 4]).await.unwrap();
+        let err = client.expect_state(DfuState::DnloadIdle).await.unwrap_err();
+        assert!(err.to_string().contains("Program memory function failed"), "{}", err);
+        assert_eq!(state.lock().unwrap().state, DfuState::DfuIdle);
+
+        state.lock().unwrap().fail(0x0e);
+        client.ensure_idle().await.unwrap();
+        assert_eq!(client.get_state().await.unwrap(), DfuState::DfuIdle);
+
+        state.lock().unwrap().state = DfuState::AppIdle;
+        assert!(client.ensure_idle().await.unwrap_err().to_string().contains("runtime mode"));
+    }
+
+    #[tokio::test]
+    async fn test_flash_dfuse_file() {
+        let (mut client, state) = simulated(Bootloader::dfuse()).await;
+        let image = DfuseImage {
+            targets: vec![DfuseTarget {
+                alt_setting: 0,
+                name: Some("ST...".to_string()),
+                elements: vec![
+                    DfuseElement { address: FLASH_BASE, data: pattern(5000) },
+                    DfuseElement { address: FLASH_BASE + 0x1_0000, data: vec![0x5a; 100] },
+                ],
+            }],
+        };
+
+        let wrong = image.clone().into_file(0x1209, 0x0001);
+        assert!(client.flash_file(&wrong, |_, _| {}).await.unwrap_err().to_string().contains("1209:0001"));
+
+        let file = DfuFile::parse(image.into_file(0x0483, 0xdf11).to_bytes()).unwrap();
+        let mut last = (0, 0);
+        client.flash_file(&file, |done, total| last = (done, total)).await.unwrap();
+        assert_eq!(last, (5100, 5100));
+
+        {
+            let bootloader = state.lock().unwrap();
+            assert_eq!(&bootloader.flash[..5000], &pattern(5000)[..]);
+            assert_eq!(&bootloader.flash[0x1_0000..0x1_0064], &[0x5a; 100][..]);
+            assert_eq!(bootloader.erased, [0x0800_0000, 0x0801_0000]);
+            assert_eq!(bootloader.alt_setting, 0);
+        }
+
+        // A plain payload has no address to go to
+        let plain = DfuFile::new(pattern(16), None);
+        assert!(client.flash_file(&plain, |_, _| {}).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_dfu_1_1_download_and_upload() {
+        let (mut client, state) = simulated(Bootloader::dfu_1_1()).await;
+        assert_eq!(client.functional().transfer_size, 64);
+        assert!(client.functional().manifestation_tolerant());
+        assert!(client.memory().is_err());
+
+        let firmware = pattern(1000);
+        let file = DfuFile::new(firmware.clone(), Some(DfuSuffix::new(0x0483, 0xdf11)));
+        let mut progress = Vec::new();
+        client.flash_file(&file, |sent, _| progress.push(sent)).await.unwrap();
+        assert_eq!(progress.len(), 16);
+        assert_eq!(state.lock().unwrap().manifested.as_deref(), Some(&firmware[..]));
+
+        state.lock().unwrap().flash = pattern(200);
+        let read = client.upload_all(4096, |_| {}).await.unwrap();
+        assert_eq!(read, pattern(200));
+        assert_eq!(client.get_state().await.unwrap(), DfuState::DfuIdle);
+
+        let head = client.upload_all(100, |_| {}).await.unwrap();
+        assert_eq!(head, pattern(100));
+        assert_eq!(client.get_state().await.unwrap(), DfuState::DfuIdle);
+
+        assert!(client.dfuse_upload(FLASH_BASE, 16, |_| {}).await.unwrap_err().to_string().contains("not DfuSe"));
+    }
+}
@@ -3,9 +3,11 @@ pub mod android;
 pub mod samsung;
 pub mod qualcomm;
 pub mod mediatek;
+pub mod dfu;
 
 pub use apple::AppleDriver;
-pub use android::AndroidDriver;
+pub use android::{AdbClient, AndroidDriver, FastbootClient};
 pub use samsung::SamsungDriver;
 pub use qualcomm::QualcommDriver;
 pub use mediatek::MediaTekDriver;
+pub use dfu::{DfuClient, DfuFile};
@@ -0,0 +1,256 @@
+//! Streaming decompression of compressed images.
+
+use super::format::Compression;
+use crate::{BootforgeError, Result};
+use std::fs::File;
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// Compressed bytes consumed so far. Cloning shares the count, so progress
+/// reporting can follow a decoder running elsewhere.
+#[derive(Debug, Clone, Default)]
+pub struct InputCounter {
+    read: Arc<AtomicU64>,
+}
+
+impl InputCounter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self) -> u64 {
+        self.read.load(Ordering::Relaxed)
+    }
+
+    fn set(&self, value: u64) {
+        self.read.store(value, Ordering::Relaxed);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DecodedLengths {
+    /// Compressed bytes the decoder will consume.
+    pub compressed: u64,
+    /// Decompressed size, when the container records it (zip does).
+    pub uncompressed: Option<u64>,
+}
+
+/// Open `path` and pass `f` a reader over its decompressed contents.
+///
+/// Multi-member gzip/bzip2, multi-stream xz and multi-frame zstd files are
+/// read to the end. Zip archives must contain exactly one file. `counter`
+/// follows the compressed bytes consumed.
+pub fn with_decoder<T>(
+    path: &Path,
+    compression: Compression,
+    counter: &InputCounter,
+    f: impl FnOnce(&mut dyn Read, DecodedLengths) -> Result<T>,
+) -> Result<T> {
+    let file = File::open(path)
+        .map_err(|e| BootforgeError::Imaging(format!("Failed to open image {}: {}", path.display(), e)))?;
+    let compressed = file.metadata()?.len();
+    counter.set(0);
+    let input = CountingReader { inner: file, counter: counter.clone() };
+    let lengths = DecodedLengths { compressed, uncompressed: None };
+    let decode_err = |e: std::io::Error| BootforgeError::Imaging(format!("Failed to decompress {}: {}", path.display(), e));
+
+    match compression {
+        Compression::Gzip => f(&mut ErrorContext(flate2::read::MultiGzDecoder::new(BufReader::new(input)), path), lengths),
+        Compression::Xz => f(&mut ErrorContext(xz2::read::XzDecoder::new_multi_decoder(BufReader::new(input)), path), lengths),
+        Compression::Bzip2 => f(&mut ErrorContext(bzip2::read::MultiBzDecoder::new(BufReader::new(input)), path), lengths),
+        Compression::Zstd => {
+            let decoder = zstd::stream::read::Decoder::new(input).map_err(decode_err)?;
+            f(&mut ErrorContext(decoder, path), lengths)
+        }
+        Compression::Zip => {
+            let mut archive = zip::ZipArchive::new(input)
+                .map_err(|e| BootforgeError::Imaging(format!("Failed to read zip {}: {}", path.display(), e)))?;
+            let index = single_entry(&mut archive, path)?;
+            let mut entry = archive
+                .by_index(index)
+                .map_err(|e| BootforgeError::Imaging(format!("Failed to read zip {}: {}", path.display(), e)))?;
+            let lengths = DecodedLengths { compressed: entry.compressed_size(), uncompressed: Some(entry.size()) };
+            // Don't count the central directory against the entry
+            counter.set(0);
+            f(&mut ErrorContext(&mut entry, path), lengths)
+        }
+    }
+}
+
+/// Decompressed length without decoding, where the container records it.
+pub fn uncompressed_len(path: &Path, compression: Compression) -> Result<Option<u64>> {
+    if compression != Compression::Zip {
+        return Ok(None);
+    }
+
+    let file = File::open(path)?;
+    let mut archive = zip::ZipArchive::new(file)
+        .map_err(|e| BootforgeError::Imaging(format!("Failed to read zip {}: {}", path.display(), e)))?;
+    let index = single_entry(&mut archive, path)?;
+    let size = archive
+        .by_index_raw(index)
+        .map_err(|e| BootforgeError::Imaging(format!("Failed to read zip {}: {}", path.display(), e)))?
+        .size();
+    Ok(Some(size))
+}
+
+fn single_entry<R: Read + Seek>(archive: &mut zip::ZipArchive<R>, path: &Path) -> Result<usize> {
+    let mut files = Vec::new();
+    for index in 0..archive.len() {
+        let entry = archive
+            .by_index_raw(index)
+            .map_err(|e| BootforgeError::Imaging(format!("Failed to read zip {}: {}", path.display(), e)))?;
+        if !entry.is_dir() {
+            files.push(index);
+        }
+    }
+
+    match files.as_slice() {
+        [index] => Ok(*index),
+        _ => Err(BootforgeError::Imaging(format!(
+            "{} contains {} files; only single-image zip archives can be written",
+            path.display(),
+            files.len()
+        ))),
+    }
+}
+
+struct CountingReader<R> {
+    inner: R,
+    counter: InputCounter,
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.counter.read.fetch_add(n as u64, Ordering::Relaxed);
+        Ok(n)
+    }
+}
+
+impl<R: Seek> Seek for CountingReader<R> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        self.inner.seek(pos)
+    }
+}
+
+/// Names the image in decoder errors, which otherwise read like I/O errors
+/// on the target.
+struct ErrorContext<'a, R>(R, &'a Path);
+
+impl<R: Read> Read for ErrorContext<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.0.read(buf).map_err(|e| {
+            std::io::Error::new(e.kind(), format!("corrupt compressed image {}: {}", self.1.display(), e))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::pattern;
+    use std::io::Write;
+
+    fn compress(data: &[u8], compression: Compression) -> Vec<u8> {
+        match compression {
+            Compression::Gzip => {
+                let mut enc = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+                enc.write_all(data).unwrap();
+                enc.finish().unwrap()
+            }
+            Compression::Xz => {
+                let mut enc = xz2::write::XzEncoder::new(Vec::new(), 1);
+                enc.write_all(data).unwrap();
+                enc.finish().unwrap()
+            }
+            Compression::Zstd => zstd::encode_all(data, 1).unwrap(),
+            Compression::Bzip2 => {
+                let mut enc = bzip2::write::BzEncoder::new(Vec::new(), bzip2::Compression::fast());
+                enc.write_all(data).unwrap();
+                enc.finish().unwrap()
+            }
+            Compression::Zip => {
+                let mut zip = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+                zip.add_directory("images/", zip::write::SimpleFileOptions::default()).unwrap();
+                zip.start_file("images/disk.img", zip::write::SimpleFileOptions::default()).unwrap();
+                zip.write_all(data).unwrap();
+                zip.finish().unwrap().into_inner()
+            }
+        }
+    }
+
+    #[test]
+    fn test_round_trip_all_formats() {
+        let dir = tempfile::tempdir().unwrap();
+        let data = pattern(300_000);
+
+        for compression in [Compression::Gzip, Compression::Xz, Compression::Zstd, Compression::Bzip2, Compression::Zip] {
+            let path = dir.path().join(format!("image.{:?}", compression));
+            let packed = compress(&data, compression);
+            std::fs::write(&path, &packed).unwrap();
+
+            let counter = InputCounter::new();
+            let (out, lengths) = with_decoder(&path, compression, &counter, |r, lengths| {
+                let mut out = Vec::new();
+                r.read_to_end(&mut out)?;
+                Ok((out, lengths))
+            })
+            .unwrap();
+
+            assert_eq!(out, data, "{:?}", compression);
+            assert!(counter.get() > 0 && counter.get() <= packed.len() as u64);
+            if compression == Compression::Zip {
+                assert_eq!(lengths.uncompressed, Some(data.len() as u64));
+                assert_eq!(uncompressed_len(&path, compression).unwrap(), Some(data.len() as u64));
+            } else {
+                assert_eq!(lengths.compressed, packed.len() as u64);
+            }
+        }
+    }
+
+    #[test]
+    fn test_concatenated_and_corrupt_streams() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("image.img.gz");
+
+        let mut packed = compress(b"first ", Compression::Gzip);
+        packed.extend(compress(b"second", Compression::Gzip));
+        std::fs::write(&path, &packed).unwrap();
+        let out = with_decoder(&path, Compression::Gzip, &InputCounter::new(), |r, _| {
+            let mut out = String::new();
+            r.read_to_string(&mut out)?;
+            Ok(out)
+        })
+        .unwrap();
+        assert_eq!(out, "first second");
+
+        let mut packed = compress(&pattern(100_000), Compression::Xz);
+        let mid = packed.len() / 2;
+        packed[mid] ^= 0xff;
+        std::fs::write(&path, &packed).unwrap();
+        let err = with_decoder(&path, Compression::Xz, &InputCounter::new(), |r, _| {
+            std::io::copy(r, &mut std::io::sink())?;
+            Ok(())
+        })
+        .unwrap_err();
+        assert!(err.to_string().contains("corrupt compressed image"));
+    }
+
+    #[test]
+    fn test_zip_with_several_files_is_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("bundle.zip");
+        let mut zip = zip::ZipWriter::new(File::create(&path).unwrap());
+        for name in ["a.img", "b.img"] {
+            zip.start_file(name, zip::write::SimpleFileOptions::default()).unwrap();
+            zip.write_all(b"data").unwrap();
+        }
+        zip.finish().unwrap();
+
+        let err = uncompressed_len(&path, Compression::Zip).unwrap_err();
+        assert!(err.to_string().contains("contains 2 files"));
+    }
+}
@@ -1,16 +1,15 @@
 use crate::Result;
 use crate::BootforgeError;
+use super::format::{ImageFormat, ImageKind, PartitionTable};
+use super::journal::{SourceFingerprint, WriteJournal};
+use super::partition::{self, DiskLayout, ValidationReport};
+use super::stream::{CancelToken, ImageWriter, PauseToken, WriteOptions};
+use super::verify::{ReadbackVerifier, SigningKey, VerificationRecord};
+use crate::thermal::{ThermalEventType, ThermalState};
+use crate::utils::ChecksumVerifier;
 use serde::{Deserialize, Serialize};
-use std::path::Path;
-
-#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
-pub enum ImageFormat {
-    Raw,
-    Dmg,
-    Wim,
-    Iso,
-    Img,
-}
+use std::path::{Path, PathBuf};
+use tokio::sync::watch;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ImagingProgress {
@@ -18,47 +17,458 @@ pub struct ImagingProgress {
     pub written_bytes: u64,
     pub percentage: f32,
     pub status: String,
+    /// Size of a compressed source. `percentage` follows the compressed
+    /// bytes when the decompressed size is unknown.
+    #[serde(default)]
+    pub compressed_total_bytes: Option<u64>,
+    #[serde(default)]
+    pub compressed_read_bytes: u64,
 }
 
-pub struct ImagingEngine;
+#[derive(Clone, Default)]
+pub struct ImagingEngine {
+    writer: ImageWriter,
+    readback: bool,
+    fit_partition_table: bool,
+    signing_key: Option<SigningKey>,
+    journal_dir: Option<PathBuf>,
+}
 
 impl ImagingEngine {
-    pub fn detect_format(path: &Path) -> Result<ImageFormat> {
-        let ext = path
-            .extension()
-            .and_then(|s| s.to_str())
-            .unwrap_or("")
-            .to_lowercase();
-
-        match ext.as_str() {
-            "dmg" => Ok(ImageFormat::Dmg),
-            "wim" => Ok(ImageFormat::Wim),
-            "iso" => Ok(ImageFormat::Iso),
-            "img" => Ok(ImageFormat::Img),
-            _ => Ok(ImageFormat::Raw),
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_options(options: WriteOptions) -> Self {
+        ImagingEngine { writer: ImageWriter::new(options), ..Self::default() }
+    }
+
+    /// Re-read the target after every `write_image` and fail on any difference.
+    pub fn with_readback(mut self, readback: bool) -> Self {
+        self.readback = readback;
+        self
+    }
+
+    /// Move the backup GPT of an image written to a larger device to the end
+    /// of the device. Off by default, so the target stays a byte-for-byte copy.
+    pub fn with_fit_partition_table(mut self, fit: bool) -> Self {
+        self.fit_partition_table = fit;
+        self
+    }
+
+    /// Key for verification records; defaults to `SigningKey::default_path()`.
+    pub fn with_signing_key(mut self, key: SigningKey) -> Self {
+        self.signing_key = Some(key);
+        self
+    }
+
+    /// Journal writes in `dir` so an interrupted write of the same image to
+    /// the same target resumes after its last verified checkpoint.
+    pub fn with_journal_dir(mut self, dir: PathBuf) -> Self {
+        self.writer = self.writer.with_journal(dir.clone());
+        self.journal_dir = Some(dir);
+        self
+    }
+
+    /// `with_journal_dir` with `WriteJournal::default_dir()`.
+    pub fn with_resume(self) -> Result<Self> {
+        Ok(self.with_journal_dir(WriteJournal::default_dir()?))
+    }
+
+    /// Cancels the write in progress. Every write starts uncancelled, so a
+    /// cancelled write can be retried on the same engine.
+    pub fn cancel_token(&self) -> CancelToken {
+        self.writer.cancel_token()
+    }
+
+    /// Holds the write in progress between blocks.
+    pub fn pause_token(&self) -> PauseToken {
+        self.writer.pause_token()
+    }
+
+    /// Pause, resume or stop the write in progress for a device temperature,
+    /// returning the event to log if anything changed. `Hot` pauses until the
+    /// device is back to `Warm` or below; `Critical` and `Shutdown` cancel,
+    /// and a journaled write can be resumed once the device has cooled.
+    pub fn apply_thermal(&self, state: ThermalState) -> Option<ThermalEventType> {
+        let pause = self.writer.pause_token();
+        match state {
+            ThermalState::Normal | ThermalState::Warm if pause.is_paused() => {
+                pause.resume();
+                Some(ThermalEventType::ImagingResumed)
+            }
+            ThermalState::Normal | ThermalState::Warm => None,
+            ThermalState::Hot if !pause.is_paused() => {
+                pause.pause();
+                Some(ThermalEventType::ImagingPaused)
+            }
+            ThermalState::Hot => None,
+            ThermalState::Critical | ThermalState::Shutdown => {
+                let cancel = self.writer.cancel_token();
+                if cancel.is_cancelled() {
+                    return None;
+                }
+                cancel.cancel();
+                Some(ThermalEventType::Critical)
+            }
         }
     }
 
+    /// Journal of an interrupted write of `image_path` to `target` that the
+    /// next `write_image` would resume, if any.
+    pub fn pending_resume(&self, image_path: &Path, target: &str) -> Result<Option<WriteJournal>> {
+        let Some(dir) = &self.journal_dir else {
+            return Ok(None);
+        };
+        let target = Path::new(target);
+        let source = SourceFingerprint::of(image_path)?;
+        let journal = WriteJournal::load(&WriteJournal::path_for(dir, target))?;
+        Ok(journal.filter(|j| j.matches(&source, target) && j.verified_bytes() > 0))
+    }
+
+    pub fn subscribe(&self) -> watch::Receiver<ImagingProgress> {
+        self.writer.subscribe()
+    }
+
+    /// Identify an image from its contents rather than its extension.
+    pub fn detect_format(path: &Path) -> Result<ImageFormat> {
+        ImageFormat::detect(path)
+    }
+
+    /// Partition table of a target or image file.
+    pub fn partition_layout(target: &Path) -> Result<DiskLayout> {
+        DiskLayout::open(target)
+    }
+
+    /// Check both GPT copies and the partition geometry of `target`.
+    pub fn validate_partitions(target: &Path) -> Result<ValidationReport> {
+        let mut disk = std::fs::File::open(target)
+            .map_err(|e| BootforgeError::Imaging(format!("Failed to open {}: {}", target.display(), e)))?;
+        DiskLayout::validate(&mut disk)
+    }
+
+    /// Write `image_path` to `target` (block device or file).
+    ///
+    /// Raw, disk, ISO9660 and UDF images are copied byte for byte, and
+    /// decompressed on the fly if gzip/xz/zstd/bzip2/zip compressed. Android
+    /// sparse images are expanded as they are written. Virtual disk and
+    /// container formats have to be converted first.
+    ///
+    /// With `with_fit_partition_table`, a GPT written to a larger device has
+    /// its backup copy moved to the end of the device afterwards.
     pub async fn write_image(
         &self,
-        _image_path: &Path,
-        _target: &str,
-        _format: ImageFormat,
+        image_path: &Path,
+        target: &str,
+        format: ImageFormat,
     ) -> Result<()> {
-        log::warn!("Image write operation not yet implemented");
-        // TODO: Implement actual imaging logic using dd or specialized tool
-        // For now, return error to prevent silent failure
-        Err(BootforgeError::Imaging("Image writing not yet implemented. This feature requires integration with system imaging tools.".to_string()))
+        self.write_raw_image(image_path, target, format).await?;
+        if self.readback {
+            let record = self.readback_verifier(format).verify_async(image_path, Path::new(target)).await?;
+            match record.first_mismatch_offset {
+                _ if record.is_verified() => {}
+                Some(offset) => return Err(BootforgeError::Imaging(format!(
+                    "Read-back of {} differs from image at offset {}", target, offset
+                ))),
+                None => return Err(BootforgeError::Imaging(format!(
+                    "Read-back of {} failed: {}", target, record.note.unwrap_or_default()
+                ))),
+            }
+        }
+
+        self.fit_partition_table(target, format).await?;
+        Ok(())
+    }
+
+    /// Write `image_path`, read the written region of `target` back and
+    /// return a signed record of the comparison for `case_id`.
+    ///
+    /// A mismatch is reported in the record rather than as an error. A GPT
+    /// moved by `with_fit_partition_table` is moved after the read-back and
+    /// noted in the record before it is signed.
+    pub async fn write_image_verified(
+        &self,
+        image_path: &Path,
+        target: &str,
+        format: ImageFormat,
+        case_id: Option<&str>,
+    ) -> Result<VerificationRecord> {
+        self.write_raw_image(image_path, target, format).await?;
+
+        let mut record = self
+            .readback_verifier(format)
+            .verify_async(image_path, Path::new(target))
+            .await?
+            .with_case(case_id);
+        // After the read-back, which compares against the primary GPT as written
+        record.partition_table_relocated = self.fit_partition_table(target, format).await?;
+
+        let key = match &self.signing_key {
+            Some(key) => key.clone(),
+            None => SigningKey::load_or_create(&SigningKey::default_path()?)?,
+        };
+        record.sign(&key)?;
+        Ok(record)
+    }
+
+    /// Whether the backup GPT was moved.
+    async fn fit_partition_table(&self, target: &str, format: ImageFormat) -> Result<bool> {
+        if !self.fit_partition_table || format.partition_table != Some(PartitionTable::Gpt) {
+            return Ok(false);
+        }
+
+        let target = Path::new(target).to_path_buf();
+        tokio::task::spawn_blocking(move || partition::relocate_backup_gpt(&target))
+            .await
+            .map_err(|e| BootforgeError::Imaging(format!("Partition table task failed: {}", e)))?
+    }
+
+    async fn write_raw_image(&self, image_path: &Path, target: &str, format: ImageFormat) -> Result<()> {
+        // A cancellation, thermal or not, only ever ends the write it hit
+        self.writer.cancel_token().reset();
+        let target = Path::new(target);
+        match (format.kind, format.compression) {
+            (ImageKind::AndroidSparse, compression) => self.writer.write_sparse_file(image_path, compression, target).await?,
+            (kind, Some(compression)) if kind.is_raw() => self.writer.write_compressed_file(image_path, compression, target).await?,
+            (kind, None) if kind.is_raw() => self.writer.write_file(image_path, target).await?,
+            (kind, _) => return Err(BootforgeError::Imaging(format!(
+                "{:?} images are not raw disk images and cannot be written directly",
+                kind
+            ))),
+        };
+        Ok(())
+    }
+
+    fn readback_verifier(&self, format: ImageFormat) -> ReadbackVerifier {
+        let options = self.writer.options();
+        let mut verifier = ReadbackVerifier::new()
+            .with_cancel(self.writer.cancel_token())
+            .with_compression(format.compression)
+            .with_sparse(format.kind == ImageKind::AndroidSparse);
+        verifier.block_size = options.block_size;
+        verifier.direct_io = options.direct_io;
+        verifier
     }
 
+    /// Check an image against `checksum` (`sha256:<hex>`, or bare hex), or
+    /// against a `SHA256SUMS`-style sidecar when no checksum is given.
     pub async fn verify_image(
         &self,
-        _image_path: &Path,
-        _checksum: Option<&str>,
+        image_path: &Path,
+        checksum: Option<&str>,
     ) -> Result<bool> {
-        log::warn!("Image verification not yet implemented");
-        // TODO: Implement checksum verification (SHA-256)
-        // For now, return error instead of fake success
-        Err(BootforgeError::Imaging("Image verification not yet implemented. Cannot validate image integrity.".to_string()))
+        match checksum {
+            Some(expected) => ChecksumVerifier::verify(image_path, expected).await,
+            None => ChecksumVerifier::verify_with_sidecar(image_path).await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::pattern;
+    use crate::imaging::partition::{NewPartition, PartitionType};
+    use crate::imaging::sparse::SparseImage;
+    use std::io::{Cursor, Read, Write};
+
+    const MIB: usize = 1024 * 1024;
+
+    fn small_blocks() -> ImagingEngine {
+        ImagingEngine::with_options(WriteOptions { block_size: 64 * 1024, direct_io: false, sync: true })
+    }
+
+    /// Write `image` to a fresh `target.img` next to it and return the target.
+    async fn write(engine: &ImagingEngine, image: &Path) -> Result<PathBuf> {
+        let target = image.with_file_name("target.img");
+        let format = ImagingEngine::detect_format(image)?;
+        engine.write_image(image, target.to_str().unwrap(), format).await?;
+        Ok(target)
+    }
+
+    fn journaled(dir: &Path) -> ImagingEngine {
+        let mut engine = small_blocks().with_journal_dir(dir.join("journals"));
+        engine.writer = engine.writer.with_checkpoint_bytes(128 * 1024);
+        engine
+    }
+
+    /// Source that reports a critical temperature to the engine once it has
+    /// handed out `after` bytes.
+    struct OverheatAfter<'a> {
+        inner: Cursor<Vec<u8>>,
+        engine: &'a ImagingEngine,
+        after: u64,
+    }
+
+    impl Read for OverheatAfter<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            if self.inner.position() >= self.after {
+                self.engine.apply_thermal(ThermalState::Critical);
+            }
+            self.inner.read(buf)
+        }
+    }
+
+    /// Start a journaled write of `data` from `image` that is stopped by a
+    /// critical temperature after `after` bytes.
+    fn overheated_write(engine: &ImagingEngine, image: &Path, target: &Path, data: &[u8], after: u64) {
+        let source = OverheatAfter { inner: Cursor::new(data.to_vec()), engine, after };
+        let err = engine.writer.copy(source, Some(data.len() as u64), target, None, Some(image)).unwrap_err();
+        assert!(err.to_string().contains("cancelled"));
+    }
+
+    #[tokio::test]
+    async fn test_write_resumes_after_thermal_cancel() {
+        let dir = tempfile::tempdir().unwrap();
+        let image = dir.path().join("image.img");
+        let target = dir.path().join("target.img");
+        let data = pattern(MIB);
+        std::fs::write(&image, &data).unwrap();
+
+        let engine = journaled(dir.path());
+        overheated_write(&engine, &image, &target, &data, 300 * 1024);
+        assert!(engine.cancel_token().is_cancelled());
+        assert_eq!(engine.apply_thermal(ThermalState::Critical), None);
+        let verified = engine.pending_resume(&image, target.to_str().unwrap()).unwrap().unwrap().verified_bytes();
+
+        // Damage the verified part: a resumed write leaves it alone
+        let mut written = std::fs::read(&target).unwrap();
+        written[0] ^= 0xff;
+        std::fs::write(&target, &written).unwrap();
+
+        let format = ImagingEngine::detect_format(&image).unwrap();
+        engine.write_image(&image, target.to_str().unwrap(), format).await.unwrap();
+
+        let written = std::fs::read(&target).unwrap();
+        assert!(verified > 0);
+        assert_eq!(written[0], data[0] ^ 0xff);
+        assert_eq!(written[1..], data[1..]);
+        assert!(engine.pending_resume(&image, target.to_str().unwrap()).unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_raw_write_with_readback() {
+        let dir = tempfile::tempdir().unwrap();
+        let image = dir.path().join("disk.img");
+        let data = pattern(MIB + 512);
+        std::fs::write(&image, &data).unwrap();
+
+        let target = write(&small_blocks().with_readback(true), &image).await.unwrap();
+        assert_eq!(std::fs::read(target).unwrap(), data);
+    }
+
+    #[tokio::test]
+    async fn test_xz_write_is_decompressed() {
+        let dir = tempfile::tempdir().unwrap();
+        let image = dir.path().join("disk.img.xz");
+        let data = pattern(MIB);
+        let mut enc = xz2::write::XzEncoder::new(Vec::new(), 1);
+        enc.write_all(&data).unwrap();
+        std::fs::write(&image, enc.finish().unwrap()).unwrap();
+
+        let target = write(&small_blocks().with_readback(true), &image).await.unwrap();
+        assert_eq!(std::fs::read(target).unwrap(), data);
+    }
+
+    #[tokio::test]
+    async fn test_sparse_write_is_expanded() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut data = pattern(256 * 1024);
+        data.extend(std::iter::repeat_n(0u8, 512 * 1024));
+        data.extend(pattern(64 * 1024));
+        let sparse = SparseImage::from_raw(&mut Cursor::new(&data), 4096).unwrap();
+        let mut encoded = Vec::new();
+        sparse.write_to(&mut Cursor::new(&data), &mut encoded).unwrap();
+        assert!(encoded.len() < data.len());
+        let image = dir.path().join("system.simg");
+        std::fs::write(&image, encoded).unwrap();
+
+        let target = write(&small_blocks().with_readback(true), &image).await.unwrap();
+        assert_eq!(std::fs::read(target).unwrap(), data);
+    }
+
+    #[tokio::test]
+    async fn test_container_formats_are_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let image = dir.path().join("disk.vhdx");
+        let mut data = b"vhdxfile".to_vec();
+        data.resize(MIB, 0);
+        std::fs::write(&image, data).unwrap();
+
+        let err = write(&small_blocks(), &image).await.unwrap_err();
+        assert!(err.to_string().contains("Vhdx images are not raw disk images"), "{}", err);
+        assert!(!dir.path().join("target.img").exists());
+    }
+
+    #[tokio::test]
+    async fn test_pending_resume_after_interrupted_write() {
+        let dir = tempfile::tempdir().unwrap();
+        let image = dir.path().join("image.img");
+        let target = dir.path().join("target.img");
+        let data = pattern(MIB);
+        std::fs::write(&image, &data).unwrap();
+        let target_str = target.to_str().unwrap();
+
+        let engine = journaled(dir.path());
+        assert!(engine.pending_resume(&image, target_str).unwrap().is_none());
+        overheated_write(&engine, &image, &target, &data, 400 * 1024);
+
+        let journal = engine.pending_resume(&image, target_str).unwrap().unwrap();
+        assert!(journal.verified_bytes() >= 256 * 1024 && journal.verified_bytes() < data.len() as u64);
+        // Another target, a changed image or an engine without a journal has nothing to resume
+        assert!(engine.pending_resume(&image, dir.path().join("other.img").to_str().unwrap()).unwrap().is_none());
+        assert!(small_blocks().pending_resume(&image, target_str).unwrap().is_none());
+        std::fs::write(&image, pattern(MIB - 1)).unwrap();
+        assert!(engine.pending_resume(&image, target_str).unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_verified_write_signs_relocation() {
+        let dir = tempfile::tempdir().unwrap();
+        let image = dir.path().join("gpt.img");
+        let target = dir.path().join("target.img");
+        let mut file = std::fs::File::create(&image).unwrap();
+        file.set_len(4 * MIB as u64).unwrap();
+        let mut layout = DiskLayout::new_gpt(4 * MIB as u64, 512).unwrap();
+        layout.add_partition(&NewPartition::new("data", PartitionType::BASIC_DATA, None)).unwrap();
+        layout.write(&mut file).unwrap();
+        drop(file);
+        let data = std::fs::read(&image).unwrap();
+        let format = ImagingEngine::detect_format(&image).unwrap();
+        assert_eq!(format.partition_table, Some(PartitionTable::Gpt));
+
+        let key = SigningKey::generate().unwrap();
+        let engine = journaled(dir.path()).with_fit_partition_table(true).with_signing_key(key.clone());
+
+        // Interrupted, then resumed in place on a larger disk
+        overheated_write(&engine, &image, &target, &data, MIB as u64);
+        std::fs::OpenOptions::new().write(true).open(&target).unwrap().set_len(8 * MIB as u64).unwrap();
+
+        let record = engine
+            .write_image_verified(&image, target.to_str().unwrap(), format, Some("CASE-42"))
+            .await
+            .unwrap();
+        assert!(record.is_verified());
+        assert_eq!(record.bytes_compared, data.len() as u64);
+        assert_eq!(record.case_id.as_deref(), Some("CASE-42"));
+        assert!(record.partition_table_relocated);
+        assert!(record.verify_signature(&key.verification_key()));
+        assert_eq!(DiskLayout::open(&target).unwrap().disk_sectors, 8 * MIB as u64 / 512);
+
+        // The relocation flag is part of what was signed
+        let mut altered = record.clone();
+        altered.partition_table_relocated = false;
+        assert!(!altered.verify_signature(&key.verification_key()));
+
+        // Same size target: nothing to move
+        let record = small_blocks()
+            .with_fit_partition_table(true)
+            .with_signing_key(key.clone())
+            .write_image_verified(&image, dir.path().join("copy.img").to_str().unwrap(), format, None)
+            .await
+            .unwrap();
+        assert!(record.is_verified() && !record.partition_table_relocated);
+        assert!(record.case_id.is_none());
+        assert!(record.verify_signature(&key.verification_key()));
     }
 }
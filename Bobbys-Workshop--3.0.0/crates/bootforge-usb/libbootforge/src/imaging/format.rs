@@ -0,0 +1,497 @@
+//! Image format detection from file contents.
+
+use super::decompress::{self, InputCounter};
+use crate::{BootforgeError, Result};
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{Cursor, Read, Seek, SeekFrom};
+use std::path::Path;
+
+/// Bytes read from the start of an image. Covers the ISO9660 volume
+/// descriptors and UDF recognition sequence at 32 KiB.
+const HEAD_LEN: usize = 64 * 1024;
+
+/// DMG `koly` and VHD footers live in the last 512 bytes.
+const TAIL_LEN: usize = 512;
+
+const SPARSE_MAGIC: u32 = 0xED26_FF3A;
+const VHDX_REGION_TABLE: u64 = 192 * 1024;
+// GUIDs as stored on disk (first three fields little-endian)
+const VHDX_METADATA_REGION: [u8; 16] = [
+    0x06, 0xa2, 0x7c, 0x8b, 0x90, 0x47, 0x9a, 0x4b, 0xb8, 0xfe, 0x57, 0x5f, 0x05, 0x0f, 0x88, 0x6e,
+];
+const VHDX_VIRTUAL_DISK_SIZE: [u8; 16] = [
+    0x24, 0x42, 0xa5, 0x2f, 0x1b, 0xcd, 0x76, 0x48, 0xb2, 0x11, 0x5d, 0xbe, 0xd8, 0x3b, 0xf4, 0xb8,
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ImageKind {
+    /// No recognisable structure; written as is.
+    Raw,
+    Iso9660,
+    Udf,
+    /// Whole-disk image with a partition table.
+    DiskImage,
+    AndroidSparse,
+    Wim,
+    Dmg,
+    Qcow2,
+    Vhd,
+    Vhdx,
+}
+
+impl ImageKind {
+    /// Whether the image is a byte-for-byte copy of what ends up on the target.
+    pub fn is_raw(&self) -> bool {
+        matches!(self, ImageKind::Raw | ImageKind::Iso9660 | ImageKind::Udf | ImageKind::DiskImage)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Compression {
+    Gzip,
+    Xz,
+    Zstd,
+    Bzip2,
+    /// Zip archive holding a single image.
+    Zip,
+}
+
+impl Compression {
+    fn sniff(head: &[u8]) -> Option<Self> {
+        if head.starts_with(&[0x1f, 0x8b]) {
+            Some(Compression::Gzip)
+        } else if head.starts_with(&[0xfd, b'7', b'z', b'X', b'Z', 0x00]) {
+            Some(Compression::Xz)
+        } else if head.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            Some(Compression::Zstd)
+        } else if head.starts_with(b"BZh") && head.get(3).is_some_and(|b| (b'1'..=b'9').contains(b)) {
+            Some(Compression::Bzip2)
+        } else if head.starts_with(b"PK\x03\x04") {
+            Some(Compression::Zip)
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PartitionTable {
+    Gpt,
+    Mbr,
+}
+
+/// What an image file contains.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ImageFormat {
+    pub kind: ImageKind,
+    /// Outer compression. `kind` and `partition_table` then describe the
+    /// decompressed image as far as its first blocks tell.
+    pub compression: Option<Compression>,
+    pub partition_table: Option<PartitionTable>,
+    /// Size of the file itself.
+    pub file_size: u64,
+    /// Bytes the image occupies once written, when the format records it.
+    pub size: Option<u64>,
+}
+
+impl ImageFormat {
+    /// An uncompressed image of `kind` with unknown sizes.
+    pub fn new(kind: ImageKind) -> Self {
+        ImageFormat { kind, compression: None, partition_table: None, file_size: 0, size: None }
+    }
+
+    /// Whether the file can be copied to a target block for block, without
+    /// decompressing it first.
+    pub fn is_writable_raw(&self) -> bool {
+        self.compression.is_none() && self.kind.is_raw()
+    }
+
+    pub fn detect(path: &Path) -> Result<Self> {
+        let mut file = File::open(path)
+            .map_err(|e| BootforgeError::Imaging(format!("Failed to open image {}: {}", path.display(), e)))?;
+        let mut format = Self::sniff(&mut file)?;
+
+        if let Some(compression) = format.compression {
+            let inner = decompress::with_decoder(path, compression, &InputCounter::new(), |reader, lengths| {
+                let mut head = Vec::with_capacity(HEAD_LEN);
+                reader.take(HEAD_LEN as u64).read_to_end(&mut head)?;
+                let inner = Self::identify(&mut Cursor::new(head), None)?;
+                Ok(ImageFormat { size: inner.size.or(lengths.uncompressed), ..inner })
+            });
+
+            match inner {
+                Ok(inner) => {
+                    format.kind = inner.kind;
+                    format.partition_table = inner.partition_table;
+                    format.size = inner.size;
+                }
+                Err(e) => log::warn!("Could not look inside {}: {}", path.display(), e),
+            }
+        }
+        Ok(format)
+    }
+
+    /// Identify an image from its magic bytes, reading at most the head,
+    /// the tail and (for VHDX) the metadata region. Compressed images are
+    /// reported as such without looking inside; see `detect`.
+    pub fn sniff<R: Read + Seek>(reader: &mut R) -> Result<Self> {
+        let file_size = reader.seek(SeekFrom::End(0))?;
+        Self::identify(reader, Some(file_size))
+    }
+
+    /// `stream_len` is `None` when `reader` holds only the start of the
+    /// image, as with a decompressed prefix: trailer formats are then not
+    /// recognised and sizes are left unknown unless a header records them.
+    fn identify<R: Read + Seek>(reader: &mut R, stream_len: Option<u64>) -> Result<Self> {
+        let file_size = match stream_len {
+            Some(len) => len,
+            None => reader.seek(SeekFrom::End(0))?,
+        };
+        let head = read_at(reader, 0, HEAD_LEN)?;
+        let tail = match stream_len {
+            Some(len) if len >= TAIL_LEN as u64 => read_at(reader, len - TAIL_LEN as u64, TAIL_LEN)?,
+            _ => Vec::new(),
+        };
+
+        let mut format = ImageFormat { file_size, ..Self::new(ImageKind::Raw) };
+
+        if let Some(compression) = Compression::sniff(&head) {
+            format.compression = Some(compression);
+            return Ok(format);
+        }
+
+        if le_u32(&head, 0) == Some(SPARSE_MAGIC) {
+            format.kind = ImageKind::AndroidSparse;
+            // blk_sz at 12, total_blks at 16
+            format.size = le_u32(&head, 12).zip(le_u32(&head, 16)).map(|(b, n)| b as u64 * n as u64);
+        } else if head.starts_with(b"QFI\xfb") {
+            format.kind = ImageKind::Qcow2;
+            format.size = be_u64(&head, 24);
+        } else if head.starts_with(b"vhdxfile") {
+            format.kind = ImageKind::Vhdx;
+            format.size = vhdx_virtual_size(reader)?;
+        } else if head.starts_with(b"MSWIM\0\0\0") {
+            format.kind = ImageKind::Wim;
+        } else if tail.starts_with(b"koly") {
+            format.kind = ImageKind::Dmg;
+            // sector_count at 0x1EC
+            format.size = be_u64(&tail, 0x1ec).map(|sectors| sectors * 512);
+        } else if tail.starts_with(b"conectix") || head.starts_with(b"conectix") {
+            format.kind = ImageKind::Vhd;
+            let footer = if tail.starts_with(b"conectix") { &tail } else { &head };
+            // current_size at 48
+            format.size = be_u64(footer, 48);
+        } else if let Some(size) = optical_volume(&head, &mut format.kind) {
+            // Hybrid ISOs also carry an MBR or GPT so they boot from USB
+            format.size = size.or(stream_len);
+            format.partition_table = partition_table(&head);
+        } else if let Some(table) = partition_table(&head) {
+            format.kind = ImageKind::DiskImage;
+            format.partition_table = Some(table);
+            format.size = stream_len;
+        } else {
+            format.size = stream_len;
+        }
+
+        Ok(format)
+    }
+}
+
+impl From<ImageKind> for ImageFormat {
+    fn from(kind: ImageKind) -> Self {
+        Self::new(kind)
+    }
+}
+
+/// ISO9660 or UDF volume recognition. Sets `kind` and returns the volume
+/// size recorded in the ISO9660 primary descriptor, if any.
+fn optical_volume(head: &[u8], kind: &mut ImageKind) -> Option<Option<u64>> {
+    const SECTOR: usize = 2048;
+    let mut iso_size = None;
+    let mut iso = false;
+    let mut udf = false;
+
+    for sector in 16..head.len() / SECTOR {
+        let desc = &head[sector * SECTOR..(sector + 1) * SECTOR];
+        match &desc[1..6] {
+            b"CD001" => {
+                iso = true;
+                if desc[0] == 1 {
+                    // Primary volume descriptor: space size at 80, block size at 128
+                    let blocks = le_u32(desc, 80)? as u64;
+                    let block_size = u16::from_le_bytes([desc[128], desc[129]]) as u64;
+                    iso_size = Some(blocks * block_size);
+                }
+            }
+            b"NSR02" | b"NSR03" => udf = true,
+            b"BEA01" | b"TEA01" | b"BOOT2" | b"CDW02" => {}
+            _ if iso || udf => break,
+            _ => return None,
+        }
+    }
+
+    match (iso, udf) {
+        (_, true) => *kind = ImageKind::Udf,
+        (true, false) => *kind = ImageKind::Iso9660,
+        (false, false) => return None,
+    }
+    Some(iso_size)
+}
+
+fn partition_table(head: &[u8]) -> Option<PartitionTable> {
+    // GPT header in LBA 1 for 512-byte sectors, or 4Kn
+    if [512, 4096].iter().any(|&at| head.get(at..at + 8) == Some(b"EFI PART")) {
+        return Some(PartitionTable::Gpt);
+    }
+
+    let mbr = head.get(..512)?;
+    if mbr[510..512] != [0x55, 0xaa] || is_volume_boot_record(mbr) {
+        return None;
+    }
+
+    let entries: Vec<&[u8]> = mbr[446..510].chunks(16).collect();
+    let valid = entries.iter().all(|e| e[0] == 0x00 || e[0] == 0x80);
+    let used = entries.iter().any(|e| e[4] != 0);
+    (valid && used).then_some(PartitionTable::Mbr)
+}
+
+/// FAT and NTFS boot sectors also end in 55 AA but have no partition table.
+fn is_volume_boot_record(sector: &[u8]) -> bool {
+    &sector[3..11] == b"NTFS    " || &sector[3..11] == b"EXFAT   " || &sector[54..59] == b"FAT12"
+        || &sector[54..59] == b"FAT16" || &sector[82..87] == b"FAT32"
+}
+
+fn vhdx_virtual_size<R: Read + Seek>(reader: &mut R) -> Result<Option<u64>> {
+    let table = read_at(reader, VHDX_REGION_TABLE, 64 * 1024)?;
+    if !table.starts_with(b"regi") {
+        return Ok(None);
+    }
+
+    // Either table may be cut short by the end of the file
+    let count = le_u32(&table, 8).unwrap_or(0) as usize;
+    let Some(entries) = table.get(16..) else {
+        return Ok(None);
+    };
+    let region = entries
+        .chunks_exact(32)
+        .take(count)
+        .find(|entry| entry[..16] == VHDX_METADATA_REGION)
+        .and_then(|entry| le_u64(entry, 16));
+    let Some(region) = region else {
+        return Ok(None);
+    };
+
+    let metadata = read_at(reader, region, 64 * 1024)?;
+    if !metadata.starts_with(b"metadata") {
+        return Ok(None);
+    }
+
+    let (Some(count), Some(entries)) = (metadata.get(10..12), metadata.get(32..)) else {
+        return Ok(None);
+    };
+    let count = u16::from_le_bytes([count[0], count[1]]) as usize;
+    let offset = entries
+        .chunks_exact(32)
+        .take(count)
+        .find(|entry| entry[..16] == VHDX_VIRTUAL_DISK_SIZE)
+        .and_then(|entry| le_u32(entry, 16));
+
+    Ok(offset.and_then(|offset| le_u64(&metadata, offset as usize)))
+}
+
+/// Read up to `len` bytes at `offset`; shorter at the end of the file.
+fn read_at<R: Read + Seek>(reader: &mut R, offset: u64, len: usize) -> Result<Vec<u8>> {
+    reader.seek(SeekFrom::Start(offset))?;
+    let mut buf = Vec::with_capacity(len);
+    reader.take(len as u64).read_to_end(&mut buf)?;
+    Ok(buf)
+}
+
+fn le_u32(buf: &[u8], at: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(buf.get(at..at + 4)?.try_into().ok()?))
+}
+
+fn le_u64(buf: &[u8], at: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(buf.get(at..at + 8)?.try_into().ok()?))
+}
+
+fn be_u64(buf: &[u8], at: usize) -> Option<u64> {
+    Some(u64::from_be_bytes(buf.get(at..at + 8)?.try_into().ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    fn sniff(data: Vec<u8>) -> ImageFormat {
+        ImageFormat::sniff(&mut Cursor::new(data)).unwrap()
+    }
+
+    fn mbr(data: &mut [u8], part_type: u8) {
+        data[446] = 0x80;
+        data[446 + 4] = part_type;
+        data[510] = 0x55;
+        data[511] = 0xaa;
+    }
+
+    #[test]
+    fn test_disk_images() {
+        let mut data = vec![0u8; 1024 * 1024];
+        mbr(&mut data, 0x0c);
+        let format = sniff(data.clone());
+        assert_eq!(format.kind, ImageKind::DiskImage);
+        assert_eq!(format.partition_table, Some(PartitionTable::Mbr));
+        assert_eq!(format.size, Some(1024 * 1024));
+        assert!(format.is_writable_raw());
+
+        mbr(&mut data, 0xee);
+        data[512..520].copy_from_slice(b"EFI PART");
+        assert_eq!(sniff(data.clone()).partition_table, Some(PartitionTable::Gpt));
+
+        // A FAT32 volume is not a partitioned disk
+        let mut fat = vec![0u8; 4096];
+        fat[3..11].copy_from_slice(b"MSDOS5.0");
+        fat[82..87].copy_from_slice(b"FAT32");
+        mbr(&mut fat, 0x00);
+        assert_eq!(sniff(fat).kind, ImageKind::Raw);
+    }
+
+    #[test]
+    fn test_hybrid_iso_and_udf() {
+        let mut data = vec![0u8; 20 * 2048];
+        mbr(&mut data, 0x17);
+        let pvd = 16 * 2048;
+        data[pvd] = 1;
+        data[pvd + 1..pvd + 6].copy_from_slice(b"CD001");
+        data[pvd + 80..pvd + 84].copy_from_slice(&20u32.to_le_bytes());
+        data[pvd + 128..pvd + 130].copy_from_slice(&2048u16.to_le_bytes());
+        data[pvd + 2048] = 255;
+        data[pvd + 2049..pvd + 2054].copy_from_slice(b"CD001");
+
+        let format = sniff(data.clone());
+        assert_eq!(format.kind, ImageKind::Iso9660);
+        assert_eq!(format.partition_table, Some(PartitionTable::Mbr));
+        assert_eq!(format.size, Some(20 * 2048));
+
+        let vrs = 18 * 2048;
+        data[vrs + 1..vrs + 6].copy_from_slice(b"BEA01");
+        data[vrs + 2049..vrs + 2054].copy_from_slice(b"NSR03");
+        assert_eq!(sniff(data).kind, ImageKind::Udf);
+    }
+
+    #[test]
+    fn test_container_formats() {
+        let mut sparse = vec![0u8; 64];
+        sparse[..4].copy_from_slice(&SPARSE_MAGIC.to_le_bytes());
+        sparse[12..16].copy_from_slice(&4096u32.to_le_bytes());
+        sparse[16..20].copy_from_slice(&1000u32.to_le_bytes());
+        let format = sniff(sparse);
+        assert_eq!((format.kind, format.size), (ImageKind::AndroidSparse, Some(4_096_000)));
+        assert!(!format.is_writable_raw());
+
+        let mut qcow = b"QFI\xfb\0\0\0\x03".to_vec();
+        qcow.resize(64, 0);
+        qcow[24..32].copy_from_slice(&(8u64 << 30).to_be_bytes());
+        assert_eq!(sniff(qcow).size, Some(8 << 30));
+
+        let mut dmg = vec![0u8; 4096];
+        let koly = 4096 - 512;
+        dmg[koly..koly + 4].copy_from_slice(b"koly");
+        dmg[koly + 0x1ec..koly + 0x1f4].copy_from_slice(&2048u64.to_be_bytes());
+        assert_eq!(sniff(dmg).size, Some(2048 * 512));
+
+        let mut vhd = vec![0u8; 8192 + 512];
+        vhd[8192..8200].copy_from_slice(b"conectix");
+        vhd[8192 + 48..8192 + 56].copy_from_slice(&8192u64.to_be_bytes());
+        let format = sniff(vhd);
+        assert_eq!((format.kind, format.size), (ImageKind::Vhd, Some(8192)));
+
+        let mut wim = b"MSWIM\0\0\0".to_vec();
+        wim.resize(1024, 0);
+        assert_eq!(sniff(wim).kind, ImageKind::Wim);
+    }
+
+    #[test]
+    fn test_vhdx_virtual_size() {
+        let mut data = vec![0u8; 320 * 1024];
+        data[..8].copy_from_slice(b"vhdxfile");
+        let region = VHDX_REGION_TABLE as usize;
+        data[region..region + 4].copy_from_slice(b"regi");
+        data[region + 8..region + 12].copy_from_slice(&1u32.to_le_bytes());
+        data[region + 16..region + 32].copy_from_slice(&VHDX_METADATA_REGION);
+        data[region + 32..region + 40].copy_from_slice(&(256u64 * 1024).to_le_bytes());
+
+        let meta = 256 * 1024;
+        data[meta..meta + 8].copy_from_slice(b"metadata");
+        data[meta + 10..meta + 12].copy_from_slice(&1u16.to_le_bytes());
+        data[meta + 32..meta + 48].copy_from_slice(&VHDX_VIRTUAL_DISK_SIZE);
+        data[meta + 48..meta + 52].copy_from_slice(&4096u32.to_le_bytes());
+        data[meta + 4096..meta + 4104].copy_from_slice(&(64u64 << 30).to_le_bytes());
+
+        let format = sniff(data);
+        assert_eq!((format.kind, format.size), (ImageKind::Vhdx, Some(64 << 30)));
+    }
+
+    #[test]
+    fn test_truncated_vhdx() {
+        let region = VHDX_REGION_TABLE as usize;
+        let mut data = vec![0u8; region + 8];
+        data[..8].copy_from_slice(b"vhdxfile");
+        data[region..region + 4].copy_from_slice(b"regi");
+        let format = sniff(data.clone());
+        assert_eq!((format.kind, format.size), (ImageKind::Vhdx, None));
+
+        // Region table intact, metadata cut off after its signature
+        data.resize(region + 64, 0);
+        data[region + 8..region + 12].copy_from_slice(&1u32.to_le_bytes());
+        data[region + 16..region + 32].copy_from_slice(&VHDX_METADATA_REGION);
+        let meta = region + 64;
+        data[region + 32..region + 40].copy_from_slice(&(meta as u64).to_le_bytes());
+        data.extend_from_slice(b"metadata");
+        let format = sniff(data);
+        assert_eq!((format.kind, format.size), (ImageKind::Vhdx, None));
+    }
+
+    #[test]
+    fn test_compressed_and_raw() {
+        for (magic, compression) in [
+            (&[0x1f, 0x8b, 0x08][..], Compression::Gzip),
+            (&[0xfd, b'7', b'z', b'X', b'Z', 0x00][..], Compression::Xz),
+            (&[0x28, 0xb5, 0x2f, 0xfd][..], Compression::Zstd),
+            (&b"BZh9"[..], Compression::Bzip2),
+            (&b"PK\x03\x04"[..], Compression::Zip),
+        ] {
+            let format = sniff(magic.to_vec());
+            assert_eq!(format.compression, Some(compression));
+            assert!(!format.is_writable_raw());
+        }
+
+        let format = sniff(vec![0xab; 10]);
+        assert_eq!(format, ImageFormat { file_size: 10, size: Some(10), ..ImageFormat::new(ImageKind::Raw) });
+    }
+
+    #[test]
+    fn test_detect_looks_inside_compressed_images() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("disk.img.gz");
+        let mut disk = vec![0u8; 256 * 1024];
+        mbr(&mut disk, 0x83);
+
+        let mut enc = flate2::write::GzEncoder::new(File::create(&path).unwrap(), flate2::Compression::fast());
+        enc.write_all(&disk).unwrap();
+        enc.finish().unwrap();
+
+        let format = ImageFormat::detect(&path).unwrap();
+        assert_eq!(format.compression, Some(Compression::Gzip));
+        assert_eq!(format.kind, ImageKind::DiskImage);
+        assert_eq!(format.partition_table, Some(PartitionTable::Mbr));
+        // gzip does not record the uncompressed size reliably
+        assert_eq!(format.size, None);
+        assert!(format.file_size < disk.len() as u64);
+    }
+}
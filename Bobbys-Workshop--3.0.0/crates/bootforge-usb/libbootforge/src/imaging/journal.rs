@@ -0,0 +1,286 @@
+//! Checkpoint journal for resumable writes.
+//!
+//! While an image is written the target is synced every checkpoint interval
+//! and the BLAKE3 digest of the range recorded. After an interruption the
+//! last checkpoint is read back from the target; if it still matches, the
+//! write carries on from there instead of from zero.
+
+use super::stream::read_full;
+use crate::{BootforgeError, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::{ErrorKind, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+/// Bytes written between checkpoints.
+pub const DEFAULT_CHECKPOINT_BYTES: u64 = 256 * 1024 * 1024;
+const JOURNAL_VERSION: u32 = 1;
+
+/// Identifies the image a journal belongs to. A changed size or
+/// modification time invalidates the journal.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SourceFingerprint {
+    pub path: PathBuf,
+    pub size: u64,
+    /// Seconds since the Unix epoch.
+    pub modified: Option<u64>,
+}
+
+impl SourceFingerprint {
+    pub fn of(path: &Path) -> Result<Self> {
+        let meta = fs::metadata(path)
+            .map_err(|e| BootforgeError::Imaging(format!("Failed to open image {}: {}", path.display(), e)))?;
+        let modified = meta
+            .modified()
+            .ok()
+            .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+            .map(|d| d.as_secs());
+        Ok(SourceFingerprint { path: path.to_path_buf(), size: meta.len(), modified })
+    }
+}
+
+/// A synced range of the target and the digest of what was written there.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Checkpoint {
+    pub offset: u64,
+    pub length: u64,
+    /// BLAKE3, hex.
+    pub digest: String,
+}
+
+impl Checkpoint {
+    pub fn end(&self) -> u64 {
+        self.offset + self.length
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WriteJournal {
+    pub version: u32,
+    pub source: SourceFingerprint,
+    pub target: PathBuf,
+    /// Contiguous from offset 0, in order.
+    pub checkpoints: Vec<Checkpoint>,
+    pub updated_at: DateTime<Utc>,
+}
+
+impl WriteJournal {
+    pub fn new(source: SourceFingerprint, target: &Path) -> Self {
+        WriteJournal {
+            version: JOURNAL_VERSION,
+            source,
+            target: target.to_path_buf(),
+            checkpoints: Vec::new(),
+            updated_at: Utc::now(),
+        }
+    }
+
+    /// Bytes of the image known to be on the target.
+    pub fn verified_bytes(&self) -> u64 {
+        self.checkpoints.last().map_or(0, Checkpoint::end)
+    }
+
+    /// Journals live next to the device cache, one per target.
+    pub fn default_dir() -> Result<PathBuf> {
+        let cache = crate::usb::cache::get_cache_path()?;
+        Ok(cache.with_file_name("journals"))
+    }
+
+    pub fn path_for(dir: &Path, target: &Path) -> PathBuf {
+        let id = blake3::hash(target.to_string_lossy().as_bytes()).to_hex();
+        dir.join(format!("{}.json", &id[..16]))
+    }
+
+    /// The journal at `path`, or `None` if there isn't one.
+    pub fn load(path: &Path) -> Result<Option<Self>> {
+        let content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(BootforgeError::Imaging(format!("Failed to read journal {}: {}", path.display(), e))),
+        };
+        serde_json::from_str(&content)
+            .map(Some)
+            .map_err(|e| BootforgeError::Imaging(format!("Invalid journal {}: {}", path.display(), e)))
+    }
+
+    /// Write via a temporary file and rename, so a crash mid-save leaves the
+    /// previous journal intact.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            fs::create_dir_all(parent)?;
+        }
+        let json = serde_json::to_vec_pretty(self)
+            .map_err(|e| BootforgeError::Imaging(format!("Failed to encode journal: {}", e)))?;
+        let tmp = path.with_extension("json.tmp");
+        fs::write(&tmp, json)?;
+        File::open(&tmp)?.sync_all()?;
+        fs::rename(&tmp, path)?;
+        Ok(())
+    }
+
+    pub fn matches(&self, source: &SourceFingerprint, target: &Path) -> bool {
+        self.version == JOURNAL_VERSION && &self.source == source && self.target == target
+    }
+}
+
+/// Journal of the write in progress, driven by `ImageWriter`.
+pub(crate) struct JournalSession {
+    path: PathBuf,
+    journal: WriteJournal,
+    interval: u64,
+    hasher: blake3::Hasher,
+    range_start: u64,
+}
+
+impl JournalSession {
+    /// Continue the journal for `target` in `dir` if it belongs to `source`,
+    /// otherwise start a new one.
+    pub(crate) fn open(dir: &Path, source: &Path, target: &Path, interval: u64) -> Result<Self> {
+        let path = WriteJournal::path_for(dir, target);
+        let fingerprint = SourceFingerprint::of(source)?;
+        let journal = match WriteJournal::load(&path) {
+            Ok(Some(journal)) if journal.matches(&fingerprint, target) => journal,
+            Ok(Some(_)) => {
+                log::info!("Discarding journal for {}: it belongs to another image", target.display());
+                WriteJournal::new(fingerprint, target)
+            }
+            Ok(None) => WriteJournal::new(fingerprint, target),
+            Err(e) => {
+                log::warn!("{}; starting over", e);
+                WriteJournal::new(fingerprint, target)
+            }
+        };
+
+        Ok(JournalSession { path, journal, interval: interval.max(1), hasher: blake3::Hasher::new(), range_start: 0 })
+    }
+
+    /// Offset to resume from: the end of the last checkpoint that still
+    /// reads back correctly from `target`. Checkpoints that don't are dropped.
+    pub(crate) fn resume_offset(&mut self, target: &Path) -> u64 {
+        while let Some(last) = self.journal.checkpoints.last() {
+            match digest_range(target, last.offset, last.length) {
+                Ok(digest) if digest == last.digest => break,
+                Ok(_) => log::warn!(
+                    "Checkpoint at {}..{} of {} no longer matches; rewriting it",
+                    last.offset, last.end(), target.display()
+                ),
+                Err(e) => log::warn!("Failed to re-verify checkpoint on {}: {}", target.display(), e),
+            }
+            self.journal.checkpoints.pop();
+        }
+        self.range_start = self.journal.verified_bytes();
+        self.range_start
+    }
+
+    /// Read `source` up to `offset`, checking the last checkpoint's range
+    /// against the journal on the way.
+    pub(crate) fn skip_source<R: Read>(&mut self, source: &mut R, offset: u64, buffer: &mut [u8]) -> Result<()> {
+        let check = self.journal.checkpoints.last().cloned();
+        let mut hasher = blake3::Hasher::new();
+        let mut pos = 0u64;
+
+        while pos < offset {
+            let want = (offset - pos).min(buffer.len() as u64) as usize;
+            let n = read_full(source, &mut buffer[..want])?;
+            if n == 0 {
+                break;
+            }
+            if let Some(check) = &check {
+                let start = pos.max(check.offset);
+                let end = (pos + n as u64).min(check.end());
+                if start < end {
+                    hasher.update(&buffer[(start - pos) as usize..(end - pos) as usize]);
+                }
+            }
+            pos += n as u64;
+        }
+
+        let matches = check.is_none_or(|c| hasher.finalize().to_hex().as_str() == c.digest);
+        if pos < offset || !matches {
+            self.discard();
+            return Err(BootforgeError::Imaging(
+                "Image no longer matches the interrupted write; the journal was discarded, start the write again".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Account for `data`, just written at the end of the current range.
+    pub(crate) fn record(&mut self, data: &[u8]) {
+        self.hasher.update(data);
+    }
+
+    pub(crate) fn due(&self, written: u64) -> bool {
+        written - self.range_start >= self.interval
+    }
+
+    /// Record `range_start..written` as a checkpoint. The caller has synced
+    /// the target.
+    pub(crate) fn checkpoint(&mut self, written: u64) -> Result<()> {
+        if written == self.range_start {
+            return Ok(());
+        }
+
+        let hasher = std::mem::replace(&mut self.hasher, blake3::Hasher::new());
+        self.journal.checkpoints.push(Checkpoint {
+            offset: self.range_start,
+            length: written - self.range_start,
+            digest: hasher.finalize().to_hex().to_string(),
+        });
+        self.journal.updated_at = Utc::now();
+        self.range_start = written;
+        self.journal.save(&self.path)
+    }
+
+    /// The write finished; the journal is no longer needed.
+    pub(crate) fn complete(self) {
+        self.discard();
+    }
+
+    fn discard(&self) {
+        if let Err(e) = fs::remove_file(&self.path) {
+            if e.kind() != ErrorKind::NotFound {
+                log::warn!("Failed to remove journal {}: {}", self.path.display(), e);
+            }
+        }
+    }
+}
+
+fn digest_range(path: &Path, offset: u64, length: u64) -> Result<String> {
+    let mut file = File::open(path)?;
+    file.seek(SeekFrom::Start(offset))?;
+    let mut hasher = blake3::Hasher::new();
+    let copied = std::io::copy(&mut file.take(length), &mut hasher)?;
+    if copied < length {
+        return Err(BootforgeError::Imaging(format!("{} ends before offset {}", path.display(), offset + length)));
+    }
+    Ok(hasher.finalize().to_hex().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_journal_round_trip_and_mismatch() {
+        let dir = tempfile::tempdir().unwrap();
+        let image = dir.path().join("image.img");
+        fs::write(&image, b"image").unwrap();
+        let target = dir.path().join("target.img");
+
+        let mut journal = WriteJournal::new(SourceFingerprint::of(&image).unwrap(), &target);
+        journal.checkpoints.push(Checkpoint { offset: 0, length: 4096, digest: "ab".to_string() });
+        let path = WriteJournal::path_for(dir.path(), &target);
+        journal.save(&path).unwrap();
+
+        let loaded = WriteJournal::load(&path).unwrap().unwrap();
+        assert_eq!(loaded.verified_bytes(), 4096);
+        assert!(loaded.matches(&SourceFingerprint::of(&image).unwrap(), &target));
+        assert!(!loaded.matches(&SourceFingerprint::of(&image).unwrap(), &dir.path().join("other.img")));
+
+        fs::write(&image, b"a longer image").unwrap();
+        assert!(!loaded.matches(&SourceFingerprint::of(&image).unwrap(), &target));
+        assert!(WriteJournal::load(&dir.path().join("missing.json")).unwrap().is_none());
+    }
+}
@@ -1,5 +1,19 @@
+pub mod decompress;
 pub mod engine;
+pub mod format;
+pub mod journal;
+pub mod partition;
+pub mod sparse;
+pub mod stream;
+pub mod verify;
 pub mod writers;
 
-pub use engine::{ImagingEngine, ImageFormat, ImagingProgress};
+pub use engine::{ImagingEngine, ImagingProgress};
+pub use format::{ImageFormat, ImageKind, Compression, PartitionTable};
 pub use writers::{RawWriter, ApfsWriter, NtfsWriter, ExtWriter};
+pub use stream::{ImageWriter, WriteOptions, WriteSummary, CancelToken, PauseToken, DEFAULT_BLOCK_SIZE, IO_ALIGNMENT};
+pub use verify::{ReadbackVerifier, RecordSignature, SigningKey, VerificationKey, VerificationRecord, VerificationStatus};
+pub use decompress::{InputCounter, DecodedLengths};
+pub use sparse::{SparseImage, SparseHeader, SparseChunk, SparseReader, SparseEncoder, ChunkKind};
+pub use partition::{DiskLayout, Partition, PartitionSlice, PartitionType, NewPartition, ValidationReport};
+pub use journal::{WriteJournal, Checkpoint, SourceFingerprint, DEFAULT_CHECKPOINT_BYTES};
@@ -0,0 +1,1032 @@
+//! GPT and MBR partition tables on block devices and image files.
+//!
+//! Only the tables are touched: creating, removing or resizing an entry does
+//! not format, move or resize the filesystem inside it.
+
+use super::format::PartitionTable;
+use crate::{BootforgeError, Result};
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use uuid::Uuid;
+
+const GPT_SIGNATURE: &[u8; 8] = b"EFI PART";
+const GPT_REVISION: u32 = 0x0001_0000;
+const GPT_HEADER_SIZE: u32 = 92;
+const GPT_ENTRY_COUNT: u32 = 128;
+const GPT_ENTRY_SIZE: u32 = 128;
+const GPT_LABEL_UNITS: usize = 36;
+const MBR_PROTECTIVE: u8 = 0xEE;
+/// New partitions start on 1 MiB boundaries.
+const ALIGNMENT_BYTES: u64 = 1024 * 1024;
+
+/// A partition type with its GPT GUID and the closest MBR system ID.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PartitionType {
+    pub name: &'static str,
+    pub guid: Uuid,
+    pub mbr_id: u8,
+}
+
+impl PartitionType {
+    pub const EFI_SYSTEM: PartitionType = PartitionType {
+        name: "EFI System",
+        guid: Uuid::from_u128(0xC12A7328_F81F_11D2_BA4B_00A0C93EC93B),
+        mbr_id: 0xEF,
+    };
+    pub const BASIC_DATA: PartitionType = PartitionType {
+        name: "Microsoft basic data",
+        guid: Uuid::from_u128(0xEBD0A0A2_B9E5_4433_87C0_68B6B72699C7),
+        mbr_id: 0x07,
+    };
+    pub const LINUX_FILESYSTEM: PartitionType = PartitionType {
+        name: "Linux filesystem",
+        guid: Uuid::from_u128(0x0FC63DAF_8483_4772_8E79_3D69D8477DE4),
+        mbr_id: 0x83,
+    };
+    pub const LINUX_LUKS: PartitionType = PartitionType {
+        name: "Linux LUKS",
+        guid: Uuid::from_u128(0xCA7D7CCB_63ED_4C53_861C_1742536059CC),
+        mbr_id: 0xE8,
+    };
+    pub const BIOS_BOOT: PartitionType = PartitionType {
+        name: "BIOS boot",
+        guid: Uuid::from_u128(0x21686148_6449_6E6F_744E_656564454649),
+        mbr_id: 0xEF,
+    };
+    pub const APPLE_APFS: PartitionType = PartitionType {
+        name: "Apple APFS",
+        guid: Uuid::from_u128(0x7C3457EF_0000_11AA_AA11_00306543ECAC),
+        mbr_id: 0xAF,
+    };
+
+    /// BootForge's encrypted private partition; see `builder::private`.
+    pub const BOOTFORGE_PRIVATE: PartitionType = PartitionType {
+        name: "BootForge private",
+        guid: Uuid::from_u128(0x63C965F2_4060_4E47_974C_8F1DD25A8A0D),
+        mbr_id: 0xDA,
+    };
+
+    pub const KNOWN: [PartitionType; 7] = [
+        Self::EFI_SYSTEM,
+        Self::BASIC_DATA,
+        Self::LINUX_FILESYSTEM,
+        Self::LINUX_LUKS,
+        Self::BIOS_BOOT,
+        Self::APPLE_APFS,
+        Self::BOOTFORGE_PRIVATE,
+    ];
+
+    pub fn from_guid(guid: &Uuid) -> Option<Self> {
+        Self::KNOWN.iter().find(|t| t.guid == *guid).copied()
+    }
+
+    pub fn from_mbr_id(id: u8) -> Option<Self> {
+        match id {
+            0x0B | 0x0C | 0x0E => Some(Self::BASIC_DATA),
+            _ => Self::KNOWN.iter().find(|t| t.mbr_id == id).copied(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Partition {
+    /// 1-based slot in the entry array (GPT) or primary table (MBR).
+    pub number: u32,
+    pub first_lba: u64,
+    /// Inclusive.
+    pub last_lba: u64,
+    /// GPT partition type; `None` on MBR disks.
+    pub type_guid: Option<Uuid>,
+    pub unique_guid: Option<Uuid>,
+    /// MBR system ID; `None` on GPT disks.
+    pub mbr_type: Option<u8>,
+    /// MBR active flag, or GPT legacy-BIOS-bootable attribute.
+    pub bootable: bool,
+    pub attributes: u64,
+    pub label: String,
+}
+
+impl Partition {
+    pub fn sectors(&self) -> u64 {
+        self.last_lba - self.first_lba + 1
+    }
+
+    pub fn size_bytes(&self, sector_size: u64) -> u64 {
+        self.sectors() * sector_size
+    }
+
+    /// Human-readable type, falling back to the raw GUID or ID.
+    pub fn type_name(&self) -> String {
+        let known = match (self.type_guid, self.mbr_type) {
+            (Some(guid), _) => PartitionType::from_guid(&guid),
+            (None, Some(id)) => PartitionType::from_mbr_id(id),
+            (None, None) => None,
+        };
+        match (known, self.type_guid, self.mbr_type) {
+            (Some(t), _, _) => t.name.to_string(),
+            (None, Some(guid), _) => guid.to_string().to_uppercase(),
+            (None, None, Some(id)) => format!("0x{:02X}", id),
+            (None, None, None) => "unknown".to_string(),
+        }
+    }
+}
+
+/// A partition to create. `size` of `None` takes the largest free gap.
+#[derive(Debug, Clone)]
+pub struct NewPartition {
+    pub label: String,
+    pub partition_type: PartitionType,
+    pub size: Option<u64>,
+    pub bootable: bool,
+}
+
+impl NewPartition {
+    pub fn new(label: &str, partition_type: PartitionType, size: Option<u64>) -> Self {
+        NewPartition { label: label.to_string(), partition_type, size, bootable: false }
+    }
+}
+
+/// Result of checking both GPT copies and the partition geometry.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ValidationReport {
+    pub problems: Vec<String>,
+}
+
+impl ValidationReport {
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DiskLayout {
+    pub table: PartitionTable,
+    pub sector_size: u64,
+    /// Sectors the table covers. For GPT this ends with the backup header,
+    /// which is not necessarily the end of the device an image was written to.
+    pub disk_sectors: u64,
+    pub disk_guid: Option<Uuid>,
+    pub disk_signature: Option<u32>,
+    pub first_usable_lba: u64,
+    pub last_usable_lba: u64,
+    pub partitions: Vec<Partition>,
+}
+
+impl DiskLayout {
+    /// Empty GPT covering `disk_bytes`.
+    pub fn new_gpt(disk_bytes: u64, sector_size: u64) -> Result<Self> {
+        let disk_sectors = disk_bytes / sector_size;
+        let entry_sectors = gpt_entry_sectors(sector_size);
+        if disk_sectors < 2 * (entry_sectors + 2) + 1 {
+            return Err(BootforgeError::Imaging(format!("{} bytes is too small for a GPT", disk_bytes)));
+        }
+
+        Ok(DiskLayout {
+            table: PartitionTable::Gpt,
+            sector_size,
+            disk_sectors,
+            disk_guid: Some(Uuid::new_v4()),
+            disk_signature: None,
+            first_usable_lba: 2 + entry_sectors,
+            last_usable_lba: disk_sectors - 2 - entry_sectors,
+            partitions: Vec::new(),
+        })
+    }
+
+    /// Empty MBR covering `disk_bytes` (at most 2 TiB with 512-byte sectors).
+    pub fn new_mbr(disk_bytes: u64, sector_size: u64) -> Result<Self> {
+        let disk_sectors = disk_bytes / sector_size;
+        if disk_sectors < 2 {
+            return Err(BootforgeError::Imaging(format!("{} bytes is too small for an MBR", disk_bytes)));
+        }
+
+        let mut signature = [0u8; 4];
+        getrandom::fill(&mut signature)
+            .map_err(|e| BootforgeError::Imaging(format!("Failed to generate disk signature: {}", e)))?;
+
+        Ok(DiskLayout {
+            table: PartitionTable::Mbr,
+            sector_size,
+            disk_sectors,
+            disk_guid: None,
+            disk_signature: Some(u32::from_le_bytes(signature)),
+            first_usable_lba: 1,
+            last_usable_lba: disk_sectors.min(u32::MAX as u64) - 1,
+            partitions: Vec::new(),
+        })
+    }
+
+    pub fn open(path: &Path) -> Result<Self> {
+        let mut disk = File::open(path)
+            .map_err(|e| BootforgeError::Imaging(format!("Failed to open {}: {}", path.display(), e)))?;
+        Self::read(&mut disk)
+    }
+
+    /// Read the partition table. A GPT with a damaged primary copy is read
+    /// from its backup; `validate` reports the damage.
+    pub fn read<D: Read + Seek>(disk: &mut D) -> Result<Self> {
+        let disk_bytes = disk.seek(SeekFrom::End(0))?;
+        let sector_size = detect_sector_size(disk)?;
+
+        if disk_bytes < sector_size {
+            return Err(BootforgeError::Imaging(format!("{} bytes is too small for a partition table", disk_bytes)));
+        }
+        if let Some(gpt) = read_gpt(disk, sector_size, disk_bytes)? {
+            return Ok(gpt);
+        }
+
+        let mbr = read_sector(disk, 0, sector_size)?;
+        if mbr[510..512] != [0x55, 0xAA] {
+            return Err(BootforgeError::Imaging("No partition table found".to_string()));
+        }
+
+        let mut partitions = Vec::new();
+        for (slot, entry) in mbr[446..510].chunks_exact(16).enumerate() {
+            if entry[4] == MBR_PROTECTIVE {
+                return Err(BootforgeError::Imaging("Protective MBR without a readable GPT".to_string()));
+            }
+            let first = u32_le(entry, 8) as u64;
+            let sectors = u32_le(entry, 12) as u64;
+            if entry[4] == 0 || sectors == 0 {
+                continue;
+            }
+            partitions.push(Partition {
+                number: slot as u32 + 1,
+                first_lba: first,
+                last_lba: first + sectors - 1,
+                type_guid: None,
+                unique_guid: None,
+                mbr_type: Some(entry[4]),
+                bootable: entry[0] == 0x80,
+                attributes: 0,
+                label: String::new(),
+            });
+        }
+
+        let disk_sectors = disk_bytes / sector_size;
+        Ok(DiskLayout {
+            table: PartitionTable::Mbr,
+            sector_size,
+            disk_sectors,
+            disk_guid: None,
+            disk_signature: Some(u32_le(&mbr, 440)),
+            first_usable_lba: 1,
+            last_usable_lba: disk_sectors.min(u32::MAX as u64).saturating_sub(1),
+            partitions,
+        })
+    }
+
+    /// Check header and entry CRCs of both GPT copies, that they agree, and
+    /// that partitions fit the usable area without overlapping.
+    pub fn validate<D: Read + Seek>(disk: &mut D) -> Result<ValidationReport> {
+        let disk_bytes = disk.seek(SeekFrom::End(0))?;
+        let sector_size = detect_sector_size(disk)?;
+        let disk_sectors = disk_bytes / sector_size;
+        let mut report = ValidationReport::default();
+
+        let primary = read_gpt_copy(disk, 1, sector_size)?;
+        let layout = match &primary {
+            Some(primary) => {
+                check_copy(primary, "Primary", &mut report);
+                let backup = read_gpt_copy(disk, primary.header.alternate_lba, sector_size)?;
+                match backup {
+                    Some(backup) => {
+                        check_copy(&backup, "Backup", &mut report);
+                        if backup.entries != primary.entries {
+                            report.problems.push("Backup GPT partition entries differ from the primary".to_string());
+                        }
+                    }
+                    None => report.problems.push(format!(
+                        "Backup GPT header missing at LBA {}", primary.header.alternate_lba
+                    )),
+                }
+                if primary.header.alternate_lba.checked_add(1) != Some(disk_sectors) {
+                    report.problems.push(format!(
+                        "Backup GPT header is at LBA {} but the disk ends at LBA {}",
+                        primary.header.alternate_lba, disk_sectors - 1
+                    ));
+                }
+                Self::read(disk)?
+            }
+            None => {
+                let layout = Self::read(disk)?;
+                if layout.table == PartitionTable::Gpt {
+                    report.problems.push("Primary GPT header missing".to_string());
+                }
+                layout
+            }
+        };
+
+        let mut sorted: Vec<&Partition> = layout.partitions.iter().collect();
+        sorted.sort_by_key(|p| p.first_lba);
+        for p in &sorted {
+            if p.first_lba < layout.first_usable_lba || p.last_lba > layout.last_usable_lba || p.last_lba < p.first_lba {
+                report.problems.push(format!("Partition {} lies outside the usable area", p.number));
+            }
+        }
+        for pair in sorted.windows(2) {
+            if pair[1].first_lba <= pair[0].last_lba {
+                report.problems.push(format!("Partitions {} and {} overlap", pair[0].number, pair[1].number));
+            }
+        }
+
+        Ok(report)
+    }
+
+    pub fn partition(&self, number: u32) -> Option<&Partition> {
+        self.partitions.iter().find(|p| p.number == number)
+    }
+
+    /// Sectors per alignment unit.
+    fn alignment(&self) -> u64 {
+        (ALIGNMENT_BYTES / self.sector_size).max(1)
+    }
+
+    /// Free `(first, last)` LBA ranges, inclusive, in disk order.
+    pub fn free_ranges(&self) -> Vec<(u64, u64)> {
+        let mut sorted: Vec<&Partition> = self.partitions.iter().collect();
+        sorted.sort_by_key(|p| p.first_lba);
+
+        let mut ranges = Vec::new();
+        let mut next = self.first_usable_lba;
+        for p in sorted {
+            if p.first_lba > next {
+                ranges.push((next, p.first_lba - 1));
+            }
+            next = next.max(p.last_lba + 1);
+        }
+        if next <= self.last_usable_lba {
+            ranges.push((next, self.last_usable_lba));
+        }
+        ranges
+    }
+
+    /// Add a partition in the first aligned gap that fits (or the largest
+    /// gap when no size is given). Returns its number.
+    pub fn add_partition(&mut self, spec: &NewPartition) -> Result<u32> {
+        let slots = match self.table {
+            PartitionTable::Gpt => GPT_ENTRY_COUNT,
+            PartitionTable::Mbr => 4,
+        };
+        let number = (1..=slots)
+            .find(|n| self.partition(*n).is_none())
+            .ok_or_else(|| BootforgeError::Imaging(format!("All {} partition slots are in use", slots)))?;
+
+        let align = self.alignment();
+        let wanted = spec.size.map(|bytes| bytes.div_ceil(self.sector_size));
+        let gaps = self.free_ranges().into_iter().filter_map(|(first, last)| {
+            let start = first.div_ceil(align) * align;
+            (start <= last).then_some((start, last))
+        });
+
+        let (first, last) = match wanted {
+            Some(sectors) => gaps
+                .filter(|(start, last)| last - start + 1 >= sectors)
+                .map(|(start, _)| (start, start + sectors - 1))
+                .next(),
+            None => gaps.max_by_key(|(start, last)| last - start),
+        }
+        .ok_or_else(|| BootforgeError::Imaging(format!(
+            "No free space for a {} partition",
+            spec.size.map_or("new".to_string(), |s| format!("{}-byte", s))
+        )))?;
+
+        let gpt = self.table == PartitionTable::Gpt;
+        if !gpt && spec.bootable {
+            self.partitions.iter_mut().for_each(|p| p.bootable = false);
+        }
+        self.partitions.push(Partition {
+            number,
+            first_lba: first,
+            last_lba: last,
+            type_guid: gpt.then_some(spec.partition_type.guid),
+            unique_guid: gpt.then(Uuid::new_v4),
+            mbr_type: (!gpt).then_some(spec.partition_type.mbr_id),
+            bootable: spec.bootable,
+            attributes: if gpt && spec.bootable { 1 << 2 } else { 0 },
+            label: if gpt { truncate_label(&spec.label) } else { String::new() },
+        });
+        self.partitions.sort_by_key(|p| p.number);
+        Ok(number)
+    }
+
+    /// Change the size of partition `number`, keeping its start. `None`
+    /// grows it up to the next partition or the end of the usable area.
+    pub fn resize_partition(&mut self, number: u32, size: Option<u64>) -> Result<()> {
+        let current = self
+            .partition(number)
+            .ok_or_else(|| BootforgeError::Imaging(format!("No partition {}", number)))?;
+        let first = current.first_lba;
+        let limit = self
+            .partitions
+            .iter()
+            .filter(|p| p.first_lba > first)
+            .map(|p| p.first_lba - 1)
+            .min()
+            .unwrap_or(self.last_usable_lba);
+
+        let last = match size {
+            Some(bytes) if bytes < self.sector_size => {
+                return Err(BootforgeError::Imaging(format!("Partition size {} is below one sector", bytes)));
+            }
+            Some(bytes) => first + bytes.div_ceil(self.sector_size) - 1,
+            None => limit,
+        };
+        if last > limit {
+            return Err(BootforgeError::Imaging(format!(
+                "Partition {} can grow to at most {} bytes",
+                number,
+                (limit - first + 1) * self.sector_size
+            )));
+        }
+
+        if let Some(p) = self.partitions.iter_mut().find(|p| p.number == number) {
+            p.last_lba = last;
+        }
+        Ok(())
+    }
+
+    pub fn remove_partition(&mut self, number: u32) -> Result<Partition> {
+        let index = self
+            .partitions
+            .iter()
+            .position(|p| p.number == number)
+            .ok_or_else(|| BootforgeError::Imaging(format!("No partition {}", number)))?;
+        Ok(self.partitions.remove(index))
+    }
+
+    /// Make a GPT cover `disk_bytes`: move the backup header and entries to
+    /// the end and extend the usable area. Needed after writing an image
+    /// to a larger device.
+    pub fn fit_to_disk(&mut self, disk_bytes: u64) -> Result<()> {
+        if self.table != PartitionTable::Gpt {
+            self.disk_sectors = disk_bytes / self.sector_size;
+            return Ok(());
+        }
+
+        let disk_sectors = disk_bytes / self.sector_size;
+        let last_usable = disk_sectors.saturating_sub(2 + gpt_entry_sectors(self.sector_size));
+        if let Some(p) = self.partitions.iter().find(|p| p.last_lba > last_usable) {
+            return Err(BootforgeError::Imaging(format!(
+                "Partition {} extends past the end of a {}-byte disk", p.number, disk_bytes
+            )));
+        }
+        self.disk_sectors = disk_sectors;
+        self.last_usable_lba = last_usable;
+        Ok(())
+    }
+
+    /// Write the table. GPT writes a protective MBR, the primary header and
+    /// entries, and the backup copy at `disk_sectors`. Boot code in the
+    /// first 440 bytes of sector 0 is preserved.
+    pub fn write<D: Read + Write + Seek>(&self, disk: &mut D) -> Result<()> {
+        let ss = self.sector_size;
+        let mut mbr = match read_sector(disk, 0, ss) {
+            Ok(sector) => sector,
+            Err(_) => vec![0u8; ss as usize],
+        };
+        mbr[440..510].fill(0);
+        mbr[510] = 0x55;
+        mbr[511] = 0xAA;
+
+        match self.table {
+            PartitionTable::Mbr => {
+                mbr[440..444].copy_from_slice(&self.disk_signature.unwrap_or(0).to_le_bytes());
+                for p in &self.partitions {
+                    if !(1..=4).contains(&p.number) || p.last_lba >= 1 << 32 {
+                        return Err(BootforgeError::Imaging(format!("Partition {} does not fit an MBR", p.number)));
+                    }
+                    let at = 446 + (p.number as usize - 1) * 16;
+                    write_mbr_entry(&mut mbr[at..at + 16], p.bootable, p.mbr_type.unwrap_or(0x83), p.first_lba, p.sectors());
+                }
+                write_sector(disk, 0, &mbr, ss)?;
+            }
+            PartitionTable::Gpt => {
+                let protective = (self.disk_sectors - 1).min(u32::MAX as u64);
+                write_mbr_entry(&mut mbr[446..462], false, MBR_PROTECTIVE, 1, protective);
+                write_sector(disk, 0, &mbr, ss)?;
+
+                let entries = self.gpt_entries()?;
+                let entry_sectors = gpt_entry_sectors(ss);
+                let backup_lba = self.disk_sectors - 1;
+                let backup_entries_lba = backup_lba - entry_sectors;
+
+                disk.seek(SeekFrom::Start(2 * ss))?;
+                disk.write_all(&entries)?;
+                write_sector(disk, 1, &self.gpt_header(1, backup_lba, 2, &entries), ss)?;
+
+                disk.seek(SeekFrom::Start(backup_entries_lba * ss))?;
+                disk.write_all(&entries)?;
+                write_sector(disk, backup_lba, &self.gpt_header(backup_lba, 1, backup_entries_lba, &entries), ss)?;
+            }
+        }
+
+        disk.flush()?;
+        Ok(())
+    }
+
+    /// Open `path` read-write and write the table to it.
+    pub fn write_to_path(&self, path: &Path) -> Result<()> {
+        let mut disk = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)
+            .map_err(|e| BootforgeError::Imaging(format!("Failed to open {}: {}", path.display(), e)))?;
+        self.write(&mut disk)?;
+        disk.sync_all()?;
+        Ok(())
+    }
+
+    fn gpt_entries(&self) -> Result<Vec<u8>> {
+        let mut entries = vec![0u8; (GPT_ENTRY_COUNT * GPT_ENTRY_SIZE) as usize];
+        for p in &self.partitions {
+            if !(1..=GPT_ENTRY_COUNT).contains(&p.number) {
+                return Err(BootforgeError::Imaging(format!("Partition number {} is out of range", p.number)));
+            }
+            let at = (p.number - 1) as usize * GPT_ENTRY_SIZE as usize;
+            let entry = &mut entries[at..at + GPT_ENTRY_SIZE as usize];
+            let type_guid = p.type_guid.unwrap_or(PartitionType::BASIC_DATA.guid);
+            entry[0..16].copy_from_slice(&type_guid.to_bytes_le());
+            entry[16..32].copy_from_slice(&p.unique_guid.unwrap_or_else(Uuid::new_v4).to_bytes_le());
+            entry[32..40].copy_from_slice(&p.first_lba.to_le_bytes());
+            entry[40..48].copy_from_slice(&p.last_lba.to_le_bytes());
+            entry[48..56].copy_from_slice(&p.attributes.to_le_bytes());
+            for (i, unit) in p.label.encode_utf16().take(GPT_LABEL_UNITS).enumerate() {
+                entry[56 + i * 2..58 + i * 2].copy_from_slice(&unit.to_le_bytes());
+            }
+        }
+        Ok(entries)
+    }
+
+    fn gpt_header(&self, my_lba: u64, alternate_lba: u64, entries_lba: u64, entries: &[u8]) -> Vec<u8> {
+        let mut header = vec![0u8; self.sector_size as usize];
+        header[0..8].copy_from_slice(GPT_SIGNATURE);
+        header[8..12].copy_from_slice(&GPT_REVISION.to_le_bytes());
+        header[12..16].copy_from_slice(&GPT_HEADER_SIZE.to_le_bytes());
+        header[24..32].copy_from_slice(&my_lba.to_le_bytes());
+        header[32..40].copy_from_slice(&alternate_lba.to_le_bytes());
+        header[40..48].copy_from_slice(&self.first_usable_lba.to_le_bytes());
+        header[48..56].copy_from_slice(&self.last_usable_lba.to_le_bytes());
+        header[56..72].copy_from_slice(&self.disk_guid.unwrap_or_default().to_bytes_le());
+        header[72..80].copy_from_slice(&entries_lba.to_le_bytes());
+        header[80..84].copy_from_slice(&GPT_ENTRY_COUNT.to_le_bytes());
+        header[84..88].copy_from_slice(&GPT_ENTRY_SIZE.to_le_bytes());
+        header[88..92].copy_from_slice(&crc32fast::hash(entries).to_le_bytes());
+        let crc = crc32fast::hash(&header[..GPT_HEADER_SIZE as usize]);
+        header[16..20].copy_from_slice(&crc.to_le_bytes());
+        header
+    }
+}
+
+/// One partition of a disk as a stream of its own, for formatting or
+/// copying data into it. Reads and writes stop at the partition's end.
+pub struct PartitionSlice<D> {
+    disk: D,
+    start: u64,
+    len: u64,
+    pos: u64,
+}
+
+impl<D> PartitionSlice<D> {
+    pub fn new(disk: D, partition: &Partition, sector_size: u64) -> Self {
+        PartitionSlice {
+            disk,
+            start: partition.first_lba * sector_size,
+            len: partition.size_bytes(sector_size),
+            pos: 0,
+        }
+    }
+
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn remaining(&self, want: usize) -> usize {
+        (self.len.saturating_sub(self.pos)).min(want as u64) as usize
+    }
+}
+
+impl<D: Read + Seek> Read for PartitionSlice<D> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.remaining(buf.len());
+        if n == 0 {
+            return Ok(0);
+        }
+        self.disk.seek(SeekFrom::Start(self.start + self.pos))?;
+        let n = self.disk.read(&mut buf[..n])?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl<D: Write + Seek> Write for PartitionSlice<D> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.remaining(buf.len());
+        if n == 0 && !buf.is_empty() {
+            return Err(std::io::Error::new(std::io::ErrorKind::WriteZero, "write past the end of the partition"));
+        }
+        self.disk.seek(SeekFrom::Start(self.start + self.pos))?;
+        let n = self.disk.write(&buf[..n])?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.disk.flush()
+    }
+}
+
+impl<D> Seek for PartitionSlice<D> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let target = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(delta) => self.len.checked_add_signed(delta),
+            SeekFrom::Current(delta) => self.pos.checked_add_signed(delta),
+        };
+        self.pos = target.ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::InvalidInput, "seek before the start of the partition")
+        })?;
+        Ok(self.pos)
+    }
+}
+
+/// Move the backup GPT of the disk or image at `path` to its end. Returns
+/// false when there is no GPT or it already covers the whole disk.
+pub fn relocate_backup_gpt(path: &Path) -> Result<bool> {
+    let mut disk = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(path)
+        .map_err(|e| BootforgeError::Imaging(format!("Failed to open {}: {}", path.display(), e)))?;
+    let disk_bytes = disk.seek(SeekFrom::End(0))?;
+
+    let mut layout = match DiskLayout::read(&mut disk) {
+        Ok(layout) if layout.table == PartitionTable::Gpt => layout,
+        _ => return Ok(false),
+    };
+    if layout.disk_sectors == disk_bytes / layout.sector_size {
+        return Ok(false);
+    }
+
+    log::info!(
+        "Moving backup GPT of {} from LBA {} to LBA {}",
+        path.display(), layout.disk_sectors - 1, disk_bytes / layout.sector_size - 1
+    );
+    layout.fit_to_disk(disk_bytes)?;
+    layout.write(&mut disk)?;
+    disk.sync_all()?;
+    Ok(true)
+}
+
+struct GptHeader {
+    my_lba: u64,
+    alternate_lba: u64,
+    first_usable_lba: u64,
+    last_usable_lba: u64,
+    disk_guid: Uuid,
+    entries_lba: u64,
+    entry_count: u32,
+    entry_size: u32,
+    entries_crc: u32,
+}
+
+struct GptCopy {
+    header: GptHeader,
+    header_crc_ok: bool,
+    entries: Vec<u8>,
+}
+
+impl GptCopy {
+    fn entries_crc_ok(&self) -> bool {
+        crc32fast::hash(&self.entries) == self.header.entries_crc
+    }
+}
+
+fn read_gpt_copy<D: Read + Seek>(disk: &mut D, lba: u64, sector_size: u64) -> Result<Option<GptCopy>> {
+    let sector = match read_sector(disk, lba, sector_size) {
+        Ok(sector) => sector,
+        Err(_) => return Ok(None),
+    };
+    if &sector[0..8] != GPT_SIGNATURE {
+        return Ok(None);
+    }
+
+    let header_size = u32_le(&sector, 12);
+    if !(GPT_HEADER_SIZE as u64..=sector_size).contains(&(header_size as u64)) {
+        return Ok(None);
+    }
+    let mut crc_input = sector[..header_size as usize].to_vec();
+    crc_input[16..20].fill(0);
+
+    let header = GptHeader {
+        my_lba: u64_le(&sector, 24),
+        alternate_lba: u64_le(&sector, 32),
+        first_usable_lba: u64_le(&sector, 40),
+        last_usable_lba: u64_le(&sector, 48),
+        disk_guid: Uuid::from_bytes_le(sector[56..72].try_into().expect("16 bytes")),
+        entries_lba: u64_le(&sector, 72),
+        entry_count: u32_le(&sector, 80),
+        entry_size: u32_le(&sector, 84),
+        entries_crc: u32_le(&sector, 88),
+    };
+    let header_crc_ok = crc32fast::hash(&crc_input) == u32_le(&sector, 16);
+
+    let entries_len = header.entry_count as u64 * header.entry_size as u64;
+    if header.entry_size < GPT_ENTRY_SIZE || entries_len > 4 * 1024 * 1024 {
+        return Ok(None);
+    }
+    let entries_offset = match header.entries_lba.checked_mul(sector_size) {
+        Some(offset) => offset,
+        None => return Ok(None),
+    };
+    disk.seek(SeekFrom::Start(entries_offset))?;
+    let mut entries = vec![0u8; entries_len as usize];
+    if disk.read_exact(&mut entries).is_err() {
+        return Ok(None);
+    }
+
+    Ok(Some(GptCopy { header, header_crc_ok, entries }))
+}
+
+fn read_gpt<D: Read + Seek>(disk: &mut D, sector_size: u64, disk_bytes: u64) -> Result<Option<DiskLayout>> {
+    let primary = read_gpt_copy(disk, 1, sector_size)?;
+    let usable = |copy: &GptCopy| copy.header_crc_ok && copy.entries_crc_ok();
+
+    let copy = match primary {
+        Some(primary) if usable(&primary) => primary,
+        primary => {
+            let backup_lba = match &primary {
+                Some(p) if p.header_crc_ok => p.header.alternate_lba,
+                _ => match (disk_bytes / sector_size).checked_sub(1) {
+                    Some(last_lba) => last_lba,
+                    None => return Ok(None),
+                },
+            };
+            match read_gpt_copy(disk, backup_lba, sector_size)? {
+                Some(backup) if usable(&backup) => {
+                    log::warn!("Primary GPT is damaged, using the backup at LBA {}", backup_lba);
+                    backup
+                }
+                _ if primary.is_some() => {
+                    return Err(BootforgeError::Imaging("GPT headers or entries fail their CRC checks".to_string()));
+                }
+                _ => return Ok(None),
+            }
+        }
+    };
+
+    let h = &copy.header;
+    let partitions = copy
+        .entries
+        .chunks_exact(h.entry_size as usize)
+        .enumerate()
+        .filter(|(_, e)| e[0..16].iter().any(|&b| b != 0))
+        .map(|(i, e)| {
+            let units: Vec<u16> = e[56..56 + GPT_LABEL_UNITS * 2]
+                .chunks_exact(2)
+                .map(|u| u16::from_le_bytes([u[0], u[1]]))
+                .take_while(|&u| u != 0)
+                .collect();
+            let attributes = u64_le(e, 48);
+            Partition {
+                number: i as u32 + 1,
+                first_lba: u64_le(e, 32),
+                last_lba: u64_le(e, 40),
+                type_guid: Some(Uuid::from_bytes_le(e[0..16].try_into().expect("16 bytes"))),
+                unique_guid: Some(Uuid::from_bytes_le(e[16..32].try_into().expect("16 bytes"))),
+                mbr_type: None,
+                bootable: attributes & (1 << 2) != 0,
+                attributes,
+                label: String::from_utf16_lossy(&units),
+            }
+        })
+        .collect();
+
+    // The backup header sits at the last LBA the table covers
+    let disk_sectors = h.my_lba.max(h.alternate_lba) + 1;
+    Ok(Some(DiskLayout {
+        table: PartitionTable::Gpt,
+        sector_size,
+        disk_sectors,
+        disk_guid: Some(h.disk_guid),
+        disk_signature: None,
+        first_usable_lba: h.first_usable_lba,
+        last_usable_lba: h.last_usable_lba,
+        partitions,
+    }))
+}
+
+fn check_copy(copy: &GptCopy, which: &str, report: &mut ValidationReport) {
+    if !copy.header_crc_ok {
+        report.problems.push(format!("{} GPT header CRC mismatch", which));
+    }
+    if !copy.entries_crc_ok() {
+        report.problems.push(format!("{} GPT partition entries CRC mismatch", which));
+    }
+}
+
+/// 4Kn images carry their GPT header at byte 4096 rather than 512.
+fn detect_sector_size<D: Read + Seek>(disk: &mut D) -> Result<u64> {
+    for size in [512u64, 4096] {
+        disk.seek(SeekFrom::Start(size))?;
+        let mut sig = [0u8; 8];
+        if disk.read_exact(&mut sig).is_ok() && &sig == GPT_SIGNATURE {
+            return Ok(size);
+        }
+    }
+    Ok(512)
+}
+
+fn gpt_entry_sectors(sector_size: u64) -> u64 {
+    (GPT_ENTRY_COUNT as u64 * GPT_ENTRY_SIZE as u64).div_ceil(sector_size)
+}
+
+fn write_mbr_entry(entry: &mut [u8], bootable: bool, type_id: u8, first_lba: u64, sectors: u64) {
+    entry[0] = if bootable { 0x80 } else { 0x00 };
+    // CHS fields are unused; the maximum value tells tools to use LBA
+    entry[1..4].copy_from_slice(&[0xFE, 0xFF, 0xFF]);
+    entry[4] = type_id;
+    entry[5..8].copy_from_slice(&[0xFE, 0xFF, 0xFF]);
+    entry[8..12].copy_from_slice(&(first_lba as u32).to_le_bytes());
+    entry[12..16].copy_from_slice(&(sectors.min(u32::MAX as u64) as u32).to_le_bytes());
+}
+
+fn truncate_label(label: &str) -> String {
+    let units: Vec<u16> = label.encode_utf16().take(GPT_LABEL_UNITS).collect();
+    String::from_utf16_lossy(&units)
+}
+
+fn read_sector<D: Read + Seek>(disk: &mut D, lba: u64, sector_size: u64) -> Result<Vec<u8>> {
+    let offset = lba
+        .checked_mul(sector_size)
+        .ok_or_else(|| BootforgeError::Imaging(format!("LBA {} is beyond any disk", lba)))?;
+    disk.seek(SeekFrom::Start(offset))?;
+    let mut sector = vec![0u8; sector_size as usize];
+    disk.read_exact(&mut sector)?;
+    Ok(sector)
+}
+
+fn write_sector<D: Write + Seek>(disk: &mut D, lba: u64, data: &[u8], sector_size: u64) -> Result<()> {
+    disk.seek(SeekFrom::Start(lba * sector_size))?;
+    disk.write_all(&data[..sector_size as usize])?;
+    Ok(())
+}
+
+fn u32_le(buf: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(buf[at..at + 4].try_into().expect("4 bytes"))
+}
+
+fn u64_le(buf: &[u8], at: usize) -> u64 {
+    u64::from_le_bytes(buf[at..at + 8].try_into().expect("8 bytes"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MIB: u64 = 1024 * 1024;
+
+    fn image(dir: &tempfile::TempDir, name: &str, size: u64) -> (std::path::PathBuf, File) {
+        let path = dir.path().join(name);
+        let file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).unwrap();
+        file.set_len(size).unwrap();
+        (path, file)
+    }
+
+    #[test]
+    fn test_gpt_create_and_read_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let (path, mut disk) = image(&dir, "disk.img", 64 * MIB);
+
+        let mut layout = DiskLayout::new_gpt(64 * MIB, 512).unwrap();
+        let esp = layout.add_partition(&NewPartition::new("EFI", PartitionType::EFI_SYSTEM, Some(16 * MIB))).unwrap();
+        let data = layout.add_partition(&NewPartition::new("BOOTFORGE", PartitionType::BASIC_DATA, None)).unwrap();
+        layout.write(&mut disk).unwrap();
+
+        let read = DiskLayout::open(&path).unwrap();
+        assert_eq!(read.table, PartitionTable::Gpt);
+        assert_eq!(read.disk_guid, layout.disk_guid);
+        assert_eq!(read.partitions, layout.partitions);
+
+        let esp = read.partition(esp).unwrap();
+        assert_eq!(esp.first_lba, 2048);
+        assert_eq!(esp.size_bytes(512), 16 * MIB);
+        assert_eq!(esp.type_name(), "EFI System");
+        let data = read.partition(data).unwrap();
+        assert_eq!(data.label, "BOOTFORGE");
+        assert_eq!(data.first_lba, esp.last_lba + 1);
+        assert_eq!(data.last_lba, read.last_usable_lba);
+
+        assert!(DiskLayout::validate(&mut disk).unwrap().is_ok());
+        assert_eq!(crate::imaging::ImageFormat::detect(&path).unwrap().partition_table, Some(PartitionTable::Gpt));
+    }
+
+    #[test]
+    fn test_crc_damage_falls_back_to_backup() {
+        let dir = tempfile::tempdir().unwrap();
+        let (_, mut disk) = image(&dir, "disk.img", 16 * MIB);
+        let mut layout = DiskLayout::new_gpt(16 * MIB, 512).unwrap();
+        layout.add_partition(&NewPartition::new("data", PartitionType::LINUX_FILESYSTEM, None)).unwrap();
+        layout.write(&mut disk).unwrap();
+
+        // Corrupt the first primary entry
+        disk.seek(SeekFrom::Start(2 * 512 + 60)).unwrap();
+        disk.write_all(b"X").unwrap();
+
+        let report = DiskLayout::validate(&mut disk).unwrap();
+        assert!(report.problems.iter().any(|p| p == "Primary GPT partition entries CRC mismatch"));
+        assert!(report.problems.iter().any(|p| p.contains("differ from the primary")));
+
+        let read = DiskLayout::read(&mut disk).unwrap();
+        assert_eq!(read.partitions[0].label, "data");
+
+        // Rewriting repairs both copies
+        read.write(&mut disk).unwrap();
+        assert!(DiskLayout::validate(&mut disk).unwrap().is_ok());
+    }
+
+    #[test]
+    fn test_empty_and_tiny_images_are_rejected() {
+        for size in [0, 100, 511] {
+            let mut disk = std::io::Cursor::new(vec![0u8; size]);
+            let err = DiskLayout::read(&mut disk).unwrap_err();
+            assert!(err.to_string().contains("too small for a partition table"), "{}", err);
+            assert!(DiskLayout::validate(&mut disk).is_err());
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let (path, _) = image(&dir, "empty.img", 0);
+        assert!(DiskLayout::open(&path).is_err());
+    }
+
+    #[test]
+    fn test_resize_and_remove() {
+        let mut layout = DiskLayout::new_gpt(64 * MIB, 512).unwrap();
+        let a = layout.add_partition(&NewPartition::new("a", PartitionType::BASIC_DATA, Some(8 * MIB))).unwrap();
+        let b = layout.add_partition(&NewPartition::new("b", PartitionType::BASIC_DATA, Some(8 * MIB))).unwrap();
+
+        assert!(layout.resize_partition(a, Some(9 * MIB)).is_err());
+        layout.resize_partition(a, Some(4 * MIB)).unwrap();
+        assert_eq!(layout.partition(a).unwrap().size_bytes(512), 4 * MIB);
+
+        layout.remove_partition(b).unwrap();
+        layout.resize_partition(a, None).unwrap();
+        assert_eq!(layout.partition(a).unwrap().last_lba, layout.last_usable_lba);
+        assert!(layout.add_partition(&NewPartition::new("c", PartitionType::BASIC_DATA, Some(MIB))).is_err());
+        assert!(layout.remove_partition(b).is_err());
+    }
+
+    #[test]
+    fn test_mbr_keeps_boot_code() {
+        let dir = tempfile::tempdir().unwrap();
+        let (path, mut disk) = image(&dir, "disk.img", 32 * MIB);
+        disk.write_all(&[0xEB; 440]).unwrap();
+
+        let mut layout = DiskLayout::new_mbr(32 * MIB, 512).unwrap();
+        let mut boot = NewPartition::new("", PartitionType::BASIC_DATA, Some(8 * MIB));
+        boot.bootable = true;
+        layout.add_partition(&boot).unwrap();
+        layout.add_partition(&NewPartition::new("", PartitionType::LINUX_LUKS, None)).unwrap();
+        layout.write(&mut disk).unwrap();
+
+        let read = DiskLayout::open(&path).unwrap();
+        assert_eq!(read.table, PartitionTable::Mbr);
+        assert_eq!(read.disk_signature, layout.disk_signature);
+        assert_eq!(read.partitions.len(), 2);
+        assert!(read.partitions[0].bootable);
+        assert_eq!(read.partitions[1].type_name(), "Linux LUKS");
+        assert!(DiskLayout::validate(&mut disk).unwrap().is_ok());
+
+        let sector = read_sector(&mut disk, 0, 512).unwrap();
+        assert!(sector[..440].iter().all(|&b| b == 0xEB));
+    }
+
+    #[test]
+    fn test_relocate_backup_after_writing_to_larger_disk() {
+        let dir = tempfile::tempdir().unwrap();
+        let (path, mut disk) = image(&dir, "disk.img", 16 * MIB);
+        let mut layout = DiskLayout::new_gpt(16 * MIB, 512).unwrap();
+        layout.add_partition(&NewPartition::new("data", PartitionType::BASIC_DATA, None)).unwrap();
+        layout.write(&mut disk).unwrap();
+
+        // As if the image had been written to a 32 MiB device
+        disk.set_len(32 * MIB).unwrap();
+        let report = DiskLayout::validate(&mut disk).unwrap();
+        assert!(report.problems.iter().any(|p| p.contains("disk ends at LBA")));
+
+        assert!(relocate_backup_gpt(&path).unwrap());
+        assert!(!relocate_backup_gpt(&path).unwrap());
+        let read = DiskLayout::open(&path).unwrap();
+        assert_eq!(read.disk_sectors, 32 * MIB / 512);
+        assert!(DiskLayout::validate(&mut disk).unwrap().is_ok());
+        // The partition keeps its size; the new space follows it
+        let (first, last) = *read.free_ranges().last().unwrap();
+        assert_eq!((first, last), (read.partitions[0].last_lba + 1, read.last_usable_lba));
+    }
+}
@@ -0,0 +1,839 @@
+//! Android sparse images (`simg`): parsing, expansion to raw, and encoding
+//! raw images into sparse files no larger than a fastboot download.
+
+use crate::{BootforgeError, Result};
+use std::fs::File;
+use std::io::{self, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+pub const SPARSE_HEADER_MAGIC: u32 = 0xED26_FF3A;
+pub const SPARSE_HEADER_LEN: usize = 28;
+pub const CHUNK_HEADER_LEN: usize = 12;
+/// Block size used by `img2simg` and fastboot.
+pub const DEFAULT_SPARSE_BLOCK_SIZE: u32 = 4096;
+
+const CHUNK_TYPE_RAW: u16 = 0xCAC1;
+const CHUNK_TYPE_FILL: u16 = 0xCAC2;
+const CHUNK_TYPE_DONT_CARE: u16 = 0xCAC3;
+const CHUNK_TYPE_CRC32: u16 = 0xCAC4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SparseHeader {
+    pub major_version: u16,
+    pub minor_version: u16,
+    pub file_header_size: u16,
+    pub chunk_header_size: u16,
+    pub block_size: u32,
+    pub total_blocks: u32,
+    pub total_chunks: u32,
+    /// CRC32 of the expanded image, or 0 when not recorded.
+    pub image_checksum: u32,
+}
+
+impl SparseHeader {
+    fn new(block_size: u32, total_blocks: u32, total_chunks: u32) -> Self {
+        SparseHeader {
+            major_version: 1,
+            minor_version: 0,
+            file_header_size: SPARSE_HEADER_LEN as u16,
+            chunk_header_size: CHUNK_HEADER_LEN as u16,
+            block_size,
+            total_blocks,
+            total_chunks,
+            image_checksum: 0,
+        }
+    }
+
+    pub fn parse(buf: &[u8]) -> Result<Self> {
+        if buf.len() < SPARSE_HEADER_LEN {
+            return Err(invalid("file is shorter than a sparse header"));
+        }
+        let u16_at = |at: usize| u16::from_le_bytes([buf[at], buf[at + 1]]);
+        let u32_at = |at: usize| u32::from_le_bytes([buf[at], buf[at + 1], buf[at + 2], buf[at + 3]]);
+
+        if u32_at(0) != SPARSE_HEADER_MAGIC {
+            return Err(invalid("bad magic"));
+        }
+        let header = SparseHeader {
+            major_version: u16_at(4),
+            minor_version: u16_at(6),
+            file_header_size: u16_at(8),
+            chunk_header_size: u16_at(10),
+            block_size: u32_at(12),
+            total_blocks: u32_at(16),
+            total_chunks: u32_at(20),
+            image_checksum: u32_at(24),
+        };
+
+        if header.major_version != 1 {
+            return Err(invalid(&format!("unsupported version {}.{}", header.major_version, header.minor_version)));
+        }
+        if (header.file_header_size as usize) < SPARSE_HEADER_LEN || (header.chunk_header_size as usize) < CHUNK_HEADER_LEN {
+            return Err(invalid("header sizes are too small"));
+        }
+        if header.block_size == 0 || !header.block_size.is_multiple_of(4) {
+            return Err(invalid(&format!("block size {} is not a multiple of 4", header.block_size)));
+        }
+        Ok(header)
+    }
+
+    pub fn to_bytes(&self) -> [u8; SPARSE_HEADER_LEN] {
+        let mut buf = [0u8; SPARSE_HEADER_LEN];
+        buf[0..4].copy_from_slice(&SPARSE_HEADER_MAGIC.to_le_bytes());
+        buf[4..6].copy_from_slice(&self.major_version.to_le_bytes());
+        buf[6..8].copy_from_slice(&self.minor_version.to_le_bytes());
+        buf[8..10].copy_from_slice(&(SPARSE_HEADER_LEN as u16).to_le_bytes());
+        buf[10..12].copy_from_slice(&(CHUNK_HEADER_LEN as u16).to_le_bytes());
+        buf[12..16].copy_from_slice(&self.block_size.to_le_bytes());
+        buf[16..20].copy_from_slice(&self.total_blocks.to_le_bytes());
+        buf[20..24].copy_from_slice(&self.total_chunks.to_le_bytes());
+        buf[24..28].copy_from_slice(&self.image_checksum.to_le_bytes());
+        buf
+    }
+
+    /// Size of the image once expanded.
+    pub fn expanded_size(&self) -> u64 {
+        self.block_size as u64 * self.total_blocks as u64
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChunkKind {
+    Raw,
+    /// Every 4 bytes of the chunk hold this value (little-endian).
+    Fill(u32),
+    /// Blocks left as they are on the target.
+    DontCare,
+    /// CRC32 of the image expanded up to this chunk.
+    Crc32(u32),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SparseChunk {
+    pub kind: ChunkKind,
+    pub blocks: u32,
+    /// Where the chunk's raw data starts in the source it was built from:
+    /// the sparse file for parsed images, the raw image for encoded ones.
+    pub data_offset: u64,
+}
+
+impl SparseChunk {
+    /// Bytes the chunk occupies in a sparse file, header included.
+    fn encoded_len(&self, block_size: u32) -> u64 {
+        CHUNK_HEADER_LEN as u64
+            + match self.kind {
+                ChunkKind::Raw => self.blocks as u64 * block_size as u64,
+                ChunkKind::Fill(_) | ChunkKind::Crc32(_) => 4,
+                ChunkKind::DontCare => 0,
+            }
+    }
+}
+
+/// The chunk layout of a sparse image.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SparseImage {
+    pub header: SparseHeader,
+    pub chunks: Vec<SparseChunk>,
+}
+
+impl SparseImage {
+    pub fn open(path: &Path) -> Result<Self> {
+        let mut file = File::open(path)
+            .map_err(|e| BootforgeError::Imaging(format!("Failed to open sparse image {}: {}", path.display(), e)))?;
+        Self::parse(&mut file)
+    }
+
+    /// Read the header and chunk table, seeking over chunk data. Checks the
+    /// structure (sizes, block counts, chunk count) but not the CRCs; use
+    /// `validate` for that.
+    pub fn parse<R: Read + Seek>(reader: &mut R) -> Result<Self> {
+        let file_len = reader.seek(SeekFrom::End(0))?;
+        reader.seek(SeekFrom::Start(0))?;
+
+        let mut buf = [0u8; SPARSE_HEADER_LEN];
+        read_exact_or(reader, &mut buf, "file is shorter than a sparse header")?;
+        let header = SparseHeader::parse(&buf)?;
+        let mut offset = reader.seek(SeekFrom::Start(header.file_header_size as u64))?;
+
+        let mut chunks = Vec::with_capacity(header.total_chunks.min(65536) as usize);
+        let mut blocks = 0u64;
+        for index in 0..header.total_chunks {
+            let mut raw = vec![0u8; header.chunk_header_size as usize];
+            read_exact_or(reader, &mut raw, &format!("truncated in chunk {}", index))?;
+            let (chunk, payload) = decode_chunk(&header, &raw, index)?;
+            offset += raw.len() as u64;
+            let data_offset = offset;
+
+            let kind = match chunk.kind {
+                ChunkKind::Fill(_) | ChunkKind::Crc32(_) => {
+                    let mut value = [0u8; 4];
+                    read_exact_or(reader, &mut value, &format!("truncated in chunk {}", index))?;
+                    offset += 4;
+                    let value = u32::from_le_bytes(value);
+                    if matches!(chunk.kind, ChunkKind::Fill(_)) { ChunkKind::Fill(value) } else { ChunkKind::Crc32(value) }
+                }
+                kind => {
+                    if offset + payload > file_len {
+                        return Err(invalid(&format!("chunk {} runs past the end of the file", index)));
+                    }
+                    offset = reader.seek(SeekFrom::Start(offset + payload))?;
+                    kind
+                }
+            };
+
+            chunks.push(SparseChunk { kind, blocks: chunk.blocks, data_offset });
+            blocks += chunk.blocks as u64;
+        }
+
+        if blocks != header.total_blocks as u64 {
+            return Err(invalid(&format!("chunks cover {} blocks, header says {}", blocks, header.total_blocks)));
+        }
+        Ok(SparseImage { header, chunks })
+    }
+
+    pub fn expanded_size(&self) -> u64 {
+        self.header.expanded_size()
+    }
+
+    /// Bytes this layout takes up as a sparse file.
+    pub fn encoded_len(&self) -> u64 {
+        SPARSE_HEADER_LEN as u64 + self.chunks.iter().map(|c| c.encoded_len(self.header.block_size)).sum::<u64>()
+    }
+
+    /// Expand the whole image, checking CRC32 chunks and the image checksum.
+    pub fn validate<R: Read>(sparse: R) -> Result<()> {
+        unsparse(sparse, io::sink()).map(|_| ())
+    }
+
+    /// Encode a raw image. Blocks that repeat a single 32-bit value become
+    /// fill chunks, runs of other blocks become raw chunks. A partial final
+    /// block is zero-padded on output.
+    pub fn from_raw<R: Read>(raw: &mut R, block_size: u32) -> Result<Self> {
+        if block_size == 0 || !block_size.is_multiple_of(4) {
+            return Err(invalid(&format!("block size {} is not a multiple of 4", block_size)));
+        }
+
+        let mut chunks: Vec<SparseChunk> = Vec::new();
+        let mut block = vec![0u8; block_size as usize];
+        let mut offset = 0u64;
+        let mut total_blocks = 0u32;
+
+        loop {
+            let n = super::stream::read_full(raw, &mut block)?;
+            if n == 0 {
+                break;
+            }
+            block[n..].fill(0);
+
+            let kind = match uniform_value(&block) {
+                Some(value) => ChunkKind::Fill(value),
+                None => ChunkKind::Raw,
+            };
+            match chunks.last_mut() {
+                Some(last) if last.kind == kind => last.blocks += 1,
+                _ => chunks.push(SparseChunk { kind, blocks: 1, data_offset: offset }),
+            }
+
+            offset += n as u64;
+            total_blocks = total_blocks.checked_add(1).ok_or_else(|| invalid("image has too many blocks"))?;
+            if n < block.len() {
+                break;
+            }
+        }
+
+        Ok(SparseImage { header: SparseHeader::new(block_size, total_blocks, chunks.len() as u32), chunks })
+    }
+
+    /// Split into sparse images of at most `max_size` bytes each, as fastboot
+    /// does for images larger than the device's download buffer.
+    ///
+    /// Every piece describes the whole image: blocks outside its own range
+    /// are don't-care, so the pieces can be flashed one after another. CRC32
+    /// chunks are dropped because they would not hold for a single piece.
+    pub fn split(&self, max_size: u64) -> Result<Vec<SparseImage>> {
+        let block_size = self.header.block_size;
+        // Header plus leading and trailing don't-care chunks
+        let overhead = (SPARSE_HEADER_LEN + 2 * CHUNK_HEADER_LEN) as u64;
+        let too_small = || invalid(&format!("{} bytes is too small for a sparse image chunk", max_size));
+
+        let mut pieces = Vec::new();
+        let mut current: Vec<SparseChunk> = Vec::new();
+        let mut current_start = 0u32;
+        let mut current_len = overhead;
+        let mut position = 0u32;
+
+        for chunk in &self.chunks {
+            let mut chunk = *chunk;
+            if let ChunkKind::Crc32(_) = chunk.kind {
+                continue;
+            }
+
+            loop {
+                let cost = chunk.encoded_len(block_size);
+                if current_len + cost <= max_size {
+                    current_len += cost;
+                    position += chunk.blocks;
+                    current.push(chunk);
+                    break;
+                }
+
+                // Raw chunks are split at block boundaries to fill the piece
+                let room = max_size.saturating_sub(current_len + CHUNK_HEADER_LEN as u64) / block_size as u64;
+                if chunk.kind == ChunkKind::Raw && room > 0 {
+                    let head = SparseChunk { blocks: room as u32, ..chunk };
+                    position += head.blocks;
+                    current.push(head);
+                    chunk.data_offset += room * block_size as u64;
+                    chunk.blocks -= room as u32;
+                } else if current.is_empty() {
+                    return Err(too_small());
+                }
+
+                pieces.push(self.piece(current_start, position, std::mem::take(&mut current)));
+                current_start = position;
+                current_len = overhead;
+            }
+        }
+
+        if !current.is_empty() || pieces.is_empty() {
+            pieces.push(self.piece(current_start, position, current));
+        }
+        Ok(pieces)
+    }
+
+    fn piece(&self, start: u32, end: u32, chunks: Vec<SparseChunk>) -> SparseImage {
+        let skip = |blocks| SparseChunk { kind: ChunkKind::DontCare, blocks, data_offset: 0 };
+        let mut all = Vec::with_capacity(chunks.len() + 2);
+        if start > 0 {
+            all.push(skip(start));
+        }
+        all.extend(chunks);
+        if end < self.header.total_blocks {
+            all.push(skip(self.header.total_blocks - end));
+        }
+
+        SparseImage {
+            header: SparseHeader::new(self.header.block_size, self.header.total_blocks, all.len() as u32),
+            chunks: all,
+        }
+    }
+
+    /// Write this layout as a sparse file, taking raw chunk data from
+    /// `source` (see `SparseChunk::data_offset`). Returns the bytes written.
+    pub fn write_to<R: Read + Seek, W: Write>(&self, source: &mut R, out: &mut W) -> Result<u64> {
+        let written = io::copy(&mut self.encoder(source), out)?;
+        out.flush()?;
+        Ok(written)
+    }
+
+    /// Stream this layout as a sparse file, reading raw chunk data from
+    /// `source` as it goes; `write_to` for consumers that pull.
+    pub fn encoder<'a, R: Read + Seek>(&'a self, source: &'a mut R) -> SparseEncoder<'a, R> {
+        let mut header = self.header;
+        header.total_chunks = self.chunks.len() as u32;
+        SparseEncoder {
+            image: self,
+            source,
+            pending: header.to_bytes(),
+            pending_start: 0,
+            pending_end: SPARSE_HEADER_LEN,
+            next_chunk: 0,
+            raw_left: 0,
+            pad_left: 0,
+        }
+    }
+}
+
+/// Encodes a `SparseImage` on demand, holding at most one chunk header in
+/// memory. Created by `SparseImage::encoder`.
+pub struct SparseEncoder<'a, R> {
+    image: &'a SparseImage,
+    source: &'a mut R,
+    /// Header bytes not yet emitted: the file header, then each chunk's.
+    pending: [u8; SPARSE_HEADER_LEN],
+    pending_start: usize,
+    pending_end: usize,
+    next_chunk: usize,
+    /// Raw chunk data still to copy from `source`, then zeros to pad a
+    /// partial last block.
+    raw_left: u64,
+    pad_left: u64,
+}
+
+impl<R: Read + Seek> SparseEncoder<'_, R> {
+    /// Queue the next chunk's header; false once every chunk is emitted.
+    fn next_chunk(&mut self) -> io::Result<bool> {
+        let Some(chunk) = self.image.chunks.get(self.next_chunk) else {
+            return Ok(false);
+        };
+        self.next_chunk += 1;
+
+        let (chunk_type, payload) = match chunk.kind {
+            ChunkKind::Raw => (CHUNK_TYPE_RAW, chunk.blocks as u64 * self.image.header.block_size as u64),
+            ChunkKind::Fill(_) => (CHUNK_TYPE_FILL, 4),
+            ChunkKind::DontCare => (CHUNK_TYPE_DONT_CARE, 0),
+            ChunkKind::Crc32(_) => (CHUNK_TYPE_CRC32, 4),
+        };
+        let raw = &mut self.pending;
+        raw.fill(0);
+        raw[0..2].copy_from_slice(&chunk_type.to_le_bytes());
+        raw[4..8].copy_from_slice(&chunk.blocks.to_le_bytes());
+        raw[8..12].copy_from_slice(&((CHUNK_HEADER_LEN as u64 + payload) as u32).to_le_bytes());
+        self.pending_start = 0;
+        self.pending_end = CHUNK_HEADER_LEN;
+
+        match chunk.kind {
+            ChunkKind::Raw => {
+                self.source.seek(SeekFrom::Start(chunk.data_offset))?;
+                self.raw_left = payload;
+            }
+            ChunkKind::Fill(value) | ChunkKind::Crc32(value) => {
+                raw[CHUNK_HEADER_LEN..CHUNK_HEADER_LEN + 4].copy_from_slice(&value.to_le_bytes());
+                self.pending_end += 4;
+            }
+            ChunkKind::DontCare => {}
+        }
+        Ok(true)
+    }
+}
+
+impl<R: Read + Seek> Read for SparseEncoder<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        loop {
+            if self.pending_start < self.pending_end {
+                let n = buf.len().min(self.pending_end - self.pending_start);
+                buf[..n].copy_from_slice(&self.pending[self.pending_start..self.pending_start + n]);
+                self.pending_start += n;
+                return Ok(n);
+            }
+            if self.raw_left > 0 {
+                let len = buf.len().min(self.raw_left.min(usize::MAX as u64) as usize);
+                match self.source.read(&mut buf[..len])? {
+                    // Zero-pad a partial last block
+                    0 => self.pad_left = std::mem::take(&mut self.raw_left),
+                    n => {
+                        self.raw_left -= n as u64;
+                        return Ok(n);
+                    }
+                }
+            }
+            if self.pad_left > 0 {
+                let n = buf.len().min(self.pad_left.min(usize::MAX as u64) as usize);
+                buf[..n].fill(0);
+                self.pad_left -= n as u64;
+                return Ok(n);
+            }
+            if !self.next_chunk()? {
+                return Ok(0);
+            }
+        }
+    }
+}
+
+/// Expand a sparse image into `raw`, don't-care blocks as zeros. Returns
+/// the bytes written.
+pub fn unsparse<R: Read, W: Write>(sparse: R, mut raw: W) -> Result<u64> {
+    let mut reader = SparseReader::new(sparse)?;
+    io::copy(&mut reader, &mut raw)
+        .map_err(|e| BootforgeError::Imaging(format!("Invalid sparse image: {}", e)))
+}
+
+/// Encode `raw` as sparse files of at most `max_size` bytes each, named
+/// `<prefix>.<n>.simg` in `out_dir`. Returns the paths in flashing order.
+pub fn resparse_file(raw: &Path, out_dir: &Path, max_size: u64) -> Result<Vec<std::path::PathBuf>> {
+    let mut source = File::open(raw)
+        .map_err(|e| BootforgeError::Imaging(format!("Failed to open image {}: {}", raw.display(), e)))?;
+    let image = SparseImage::from_raw(&mut io::BufReader::new(&mut source), DEFAULT_SPARSE_BLOCK_SIZE)?;
+    let prefix = raw.file_stem().and_then(|s| s.to_str()).unwrap_or("image");
+
+    let mut paths = Vec::new();
+    for (index, piece) in image.split(max_size)?.iter().enumerate() {
+        let path = out_dir.join(format!("{}.{}.simg", prefix, index));
+        let mut out = io::BufWriter::new(File::create(&path)?);
+        piece.write_to(&mut source, &mut out)?;
+        paths.push(path);
+    }
+    Ok(paths)
+}
+
+/// Streams the expanded contents of a sparse image, verifying CRC32 chunks
+/// and the image checksum on the way. Don't-care blocks read as zeros.
+///
+/// Only needs `Read`, so it can sit on top of a decompressor.
+pub struct SparseReader<R> {
+    inner: R,
+    header: SparseHeader,
+    chunks_read: u32,
+    blocks_done: u64,
+    /// Current chunk and the bytes of it still to emit.
+    current: ChunkKind,
+    remaining: u64,
+    emitted_in_chunk: u64,
+    crc: crc32fast::Hasher,
+    done: bool,
+}
+
+impl<R: Read> SparseReader<R> {
+    pub fn new(mut inner: R) -> Result<Self> {
+        let mut buf = vec![0u8; SPARSE_HEADER_LEN];
+        read_exact_or(&mut inner, &mut buf, "file is shorter than a sparse header")?;
+        let header = SparseHeader::parse(&buf)?;
+        // Skip header fields from newer minor versions
+        let extra = header.file_header_size as usize - SPARSE_HEADER_LEN;
+        read_exact_or(&mut inner, &mut vec![0u8; extra], "truncated header")?;
+
+        Ok(SparseReader {
+            inner,
+            header,
+            chunks_read: 0,
+            blocks_done: 0,
+            current: ChunkKind::DontCare,
+            remaining: 0,
+            emitted_in_chunk: 0,
+            crc: crc32fast::Hasher::new(),
+            done: false,
+        })
+    }
+
+    pub fn header(&self) -> &SparseHeader {
+        &self.header
+    }
+
+    pub fn expanded_size(&self) -> u64 {
+        self.header.expanded_size()
+    }
+
+    /// Load chunk headers until one with data to emit; false at the end.
+    fn next_chunk(&mut self) -> io::Result<bool> {
+        loop {
+            if self.chunks_read == self.header.total_chunks {
+                return self.finish().map(|_| false);
+            }
+
+            let index = self.chunks_read;
+            let mut raw = vec![0u8; self.header.chunk_header_size as usize];
+            self.inner.read_exact(&mut raw).map_err(|e| truncated(e, index))?;
+            let (chunk, _) = decode_chunk(&self.header, &raw, index).map_err(to_io)?;
+            self.chunks_read += 1;
+
+            let kind = match chunk.kind {
+                ChunkKind::Fill(_) | ChunkKind::Crc32(_) => {
+                    let mut value = [0u8; 4];
+                    self.inner.read_exact(&mut value).map_err(|e| truncated(e, index))?;
+                    let value = u32::from_le_bytes(value);
+                    if let ChunkKind::Crc32(_) = chunk.kind {
+                        let actual = self.crc.clone().finalize();
+                        if actual != value {
+                            return Err(invalid_data(format!(
+                                "CRC32 chunk {} expects {:08x}, data so far has {:08x}", index, value, actual
+                            )));
+                        }
+                        continue;
+                    }
+                    ChunkKind::Fill(value)
+                }
+                kind => kind,
+            };
+
+            self.blocks_done += chunk.blocks as u64;
+            if self.blocks_done > self.header.total_blocks as u64 {
+                return Err(invalid_data(format!("chunk {} runs past the {} blocks in the header", index, self.header.total_blocks)));
+            }
+
+            self.current = kind;
+            self.remaining = chunk.blocks as u64 * self.header.block_size as u64;
+            self.emitted_in_chunk = 0;
+            if self.remaining > 0 {
+                return Ok(true);
+            }
+        }
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        if self.blocks_done != self.header.total_blocks as u64 {
+            return Err(invalid_data(format!(
+                "chunks cover {} blocks, header says {}", self.blocks_done, self.header.total_blocks
+            )));
+        }
+        let actual = self.crc.clone().finalize();
+        if self.header.image_checksum != 0 && actual != self.header.image_checksum {
+            return Err(invalid_data(format!(
+                "image checksum is {:08x}, header says {:08x}", actual, self.header.image_checksum
+            )));
+        }
+        self.done = true;
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for SparseReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.done || buf.is_empty() {
+            return Ok(0);
+        }
+        if self.remaining == 0 && !self.next_chunk()? {
+            return Ok(0);
+        }
+
+        let len = buf.len().min(self.remaining.min(usize::MAX as u64) as usize);
+        let out = &mut buf[..len];
+        let n = match self.current {
+            ChunkKind::Raw => match self.inner.read(out)? {
+                0 => return Err(truncated(ErrorKind::UnexpectedEof.into(), self.chunks_read - 1)),
+                n => n,
+            },
+            ChunkKind::Fill(value) => {
+                let pattern = value.to_le_bytes();
+                let phase = (self.emitted_in_chunk % 4) as usize;
+                for (i, byte) in out.iter_mut().enumerate() {
+                    *byte = pattern[(phase + i) % 4];
+                }
+                len
+            }
+            ChunkKind::DontCare | ChunkKind::Crc32(_) => {
+                out.fill(0);
+                len
+            }
+        };
+
+        self.crc.update(&buf[..n]);
+        self.remaining -= n as u64;
+        self.emitted_in_chunk += n as u64;
+        Ok(n)
+    }
+}
+
+/// Decode a chunk header. Returns the chunk (fill and CRC values still
+/// unread, set to 0) and the payload bytes that follow the header.
+fn decode_chunk(header: &SparseHeader, raw: &[u8], index: u32) -> Result<(SparseChunk, u64)> {
+    let chunk_type = u16::from_le_bytes([raw[0], raw[1]]);
+    let blocks = u32::from_le_bytes([raw[4], raw[5], raw[6], raw[7]]);
+    let total_size = u32::from_le_bytes([raw[8], raw[9], raw[10], raw[11]]) as u64;
+    let data_len = total_size.checked_sub(header.chunk_header_size as u64)
+        .ok_or_else(|| invalid(&format!("chunk {} is smaller than its header", index)))?;
+
+    let (kind, expected) = match chunk_type {
+        CHUNK_TYPE_RAW => (ChunkKind::Raw, blocks as u64 * header.block_size as u64),
+        CHUNK_TYPE_FILL => (ChunkKind::Fill(0), 4),
+        CHUNK_TYPE_DONT_CARE => (ChunkKind::DontCare, 0),
+        CHUNK_TYPE_CRC32 => (ChunkKind::Crc32(0), 4),
+        other => return Err(invalid(&format!("chunk {} has unknown type {:#06x}", index, other))),
+    };
+    if data_len != expected {
+        return Err(invalid(&format!("chunk {} is {} bytes, expected {}", index, total_size, expected + header.chunk_header_size as u64)));
+    }
+
+    // Fill and CRC values are read by the caller
+    let payload = if matches!(kind, ChunkKind::Raw) { data_len } else { 0 };
+    Ok((SparseChunk { kind, blocks, data_offset: 0 }, payload))
+}
+
+/// The value every 4-byte word of `block` holds, if they are all the same.
+fn uniform_value(block: &[u8]) -> Option<u32> {
+    let first = &block[..4];
+    block.chunks_exact(4).all(|word| word == first).then_some(u32::from_le_bytes([first[0], first[1], first[2], first[3]]))
+}
+
+fn read_exact_or<R: Read + ?Sized>(reader: &mut R, buf: &mut [u8], what: &str) -> Result<()> {
+    reader.read_exact(buf).map_err(|e| match e.kind() {
+        ErrorKind::UnexpectedEof => invalid(what),
+        _ => BootforgeError::Io(e),
+    })
+}
+
+fn invalid(reason: &str) -> BootforgeError {
+    BootforgeError::Imaging(format!("Invalid sparse image: {}", reason))
+}
+
+fn invalid_data(reason: String) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, reason)
+}
+
+fn truncated(e: io::Error, chunk: u32) -> io::Error {
+    match e.kind() {
+        ErrorKind::UnexpectedEof => invalid_data(format!("truncated in chunk {}", chunk)),
+        _ => e,
+    }
+}
+
+fn to_io(e: BootforgeError) -> io::Error {
+    invalid_data(e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    const BS: usize = 4096;
+
+    /// Raw image with random-looking, zero, fill and partial-block regions.
+    fn fixture() -> Vec<u8> {
+        let mut data = Vec::new();
+        let mut state = 0x1234_5678u32;
+        for _ in 0..5 * BS {
+            state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+            data.push((state >> 16) as u8);
+        }
+        data.extend(vec![0u8; 7 * BS]);
+        data.extend([0xde, 0xad, 0xbe, 0xef].repeat(3 * BS / 4));
+        data.extend((0..2 * BS).map(|i| (i % 253) as u8));
+        data.extend([0x55; 100]);
+        data
+    }
+
+    fn encode(image: &SparseImage, source: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        image.write_to(&mut Cursor::new(source), &mut out).unwrap();
+        out
+    }
+
+    fn expand(sparse: &[u8]) -> Vec<u8> {
+        let mut raw = Vec::new();
+        unsparse(sparse, &mut raw).unwrap();
+        raw
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let raw = fixture();
+        let image = SparseImage::from_raw(&mut Cursor::new(&raw), BS as u32).unwrap();
+
+        let kinds: Vec<_> = image.chunks.iter().map(|c| (c.kind, c.blocks)).collect();
+        assert_eq!(kinds, vec![
+            (ChunkKind::Raw, 5),
+            (ChunkKind::Fill(0), 7),
+            (ChunkKind::Fill(0xefbe_adde), 3),
+            (ChunkKind::Raw, 3),
+        ]);
+
+        let sparse = encode(&image, &raw);
+        assert_eq!(sparse.len() as u64, image.encoded_len());
+        assert!(sparse.len() < raw.len());
+
+        let parsed = SparseImage::parse(&mut Cursor::new(&sparse)).unwrap();
+        assert_eq!(parsed.header.total_blocks, 18);
+        assert_eq!(parsed.chunks.len(), 4);
+        assert_eq!(parsed.chunks[0].data_offset, (SPARSE_HEADER_LEN + CHUNK_HEADER_LEN) as u64);
+
+        let expanded = expand(&sparse);
+        assert_eq!(expanded.len() as u64, parsed.expanded_size());
+        assert_eq!(&expanded[..raw.len()], &raw[..]);
+        assert!(expanded[raw.len()..].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn test_dont_care_and_crc_chunks() {
+        let raw = fixture();
+        let mut image = SparseImage::from_raw(&mut Cursor::new(&raw[..5 * BS]), BS as u32).unwrap();
+        let crc = crc32fast::hash(&raw[..5 * BS]);
+        image.chunks.push(SparseChunk { kind: ChunkKind::Crc32(crc), blocks: 0, data_offset: 0 });
+        image.chunks.push(SparseChunk { kind: ChunkKind::DontCare, blocks: 2, data_offset: 0 });
+        image.header.total_blocks += 2;
+
+        let sparse = encode(&image, &raw);
+        let expanded = expand(&sparse);
+        assert_eq!(expanded.len(), 7 * BS);
+        assert!(expanded[5 * BS..].iter().all(|&b| b == 0));
+
+        // Flip a byte in the raw data: the CRC chunk catches it
+        let mut corrupt = sparse.clone();
+        corrupt[SPARSE_HEADER_LEN + CHUNK_HEADER_LEN + 10] ^= 1;
+        assert!(SparseImage::parse(&mut Cursor::new(&corrupt)).is_ok());
+        let err = SparseImage::validate(&corrupt[..]).unwrap_err();
+        assert!(err.to_string().contains("CRC32 chunk"));
+    }
+
+    #[test]
+    fn test_invalid_images() {
+        let raw = fixture();
+        let image = SparseImage::from_raw(&mut Cursor::new(&raw), BS as u32).unwrap();
+        let sparse = encode(&image, &raw);
+
+        let truncated = &sparse[..sparse.len() - 10];
+        assert!(SparseImage::parse(&mut Cursor::new(truncated)).unwrap_err().to_string().contains("past the end"));
+        assert!(SparseImage::validate(truncated).unwrap_err().to_string().contains("truncated"));
+
+        let mut wrong_blocks = sparse.clone();
+        wrong_blocks[16..20].copy_from_slice(&17u32.to_le_bytes());
+        assert!(SparseImage::parse(&mut Cursor::new(&wrong_blocks)).is_err());
+        assert!(SparseImage::validate(&wrong_blocks[..]).is_err());
+
+        assert!(matches!(SparseReader::new(&raw[..]), Err(e) if e.to_string().contains("bad magic")));
+    }
+
+    #[test]
+    fn test_split_into_download_sized_pieces() {
+        let raw = fixture();
+        let image = SparseImage::from_raw(&mut Cursor::new(&raw), BS as u32).unwrap();
+        let max = 3 * BS as u64;
+        let pieces = image.split(max).unwrap();
+        assert!(pieces.len() > 2);
+
+        // Flash every piece in order onto a target, skipping don't-care blocks
+        let mut target = vec![0xaau8; image.expanded_size() as usize];
+        for piece in &pieces {
+            let sparse = encode(piece, &raw);
+            assert!(sparse.len() as u64 <= max);
+            assert_eq!(piece.header.total_blocks, image.header.total_blocks);
+
+            let expanded = expand(&sparse);
+            let parsed = SparseImage::parse(&mut Cursor::new(&sparse)).unwrap();
+            let mut at = 0usize;
+            for chunk in &parsed.chunks {
+                let len = chunk.blocks as usize * BS;
+                if chunk.kind != ChunkKind::DontCare {
+                    target[at..at + len].copy_from_slice(&expanded[at..at + len]);
+                }
+                at += len;
+            }
+        }
+        assert_eq!(&target[..raw.len()], &raw[..]);
+
+        assert!(image.split(100).is_err());
+    }
+
+    #[test]
+    fn test_encoder_streams_in_small_reads() {
+        let raw = fixture();
+        let image = SparseImage::from_raw(&mut Cursor::new(&raw), BS as u32).unwrap();
+
+        for piece in image.split(3 * BS as u64).unwrap() {
+            let mut source = Cursor::new(&raw);
+            let mut encoder = piece.encoder(&mut source);
+            let mut streamed = Vec::new();
+            let mut buf = [0u8; 5];
+            loop {
+                match encoder.read(&mut buf).unwrap() {
+                    0 => break,
+                    n => streamed.extend_from_slice(&buf[..n]),
+                }
+            }
+            assert_eq!(streamed.len() as u64, piece.encoded_len());
+            assert_eq!(streamed, encode(&piece, &raw));
+        }
+    }
+
+    #[test]
+    fn test_write_to_target_and_read_back() {
+        use crate::imaging::{ImageWriter, ReadbackVerifier, VerificationStatus};
+
+        let dir = tempfile::tempdir().unwrap();
+        let raw = fixture();
+        let image_path = dir.path().join("system.simg");
+        let target = dir.path().join("system.img");
+        let image = SparseImage::from_raw(&mut Cursor::new(&raw), BS as u32).unwrap();
+        std::fs::write(&image_path, encode(&image, &raw)).unwrap();
+
+        let summary = ImageWriter::default().write_unsparsed(&image_path, None, &target).unwrap();
+        assert_eq!(summary.bytes_written, image.expanded_size());
+        assert_eq!(&std::fs::read(&target).unwrap()[..raw.len()], &raw[..]);
+
+        let record = ReadbackVerifier::new().with_sparse(true).verify(&image_path, &target).unwrap();
+        assert_eq!(record.status, VerificationStatus::Verified);
+        assert_eq!(record.bytes_compared, image.expanded_size());
+    }
+}
@@ -0,0 +1,786 @@
+//! Streaming writer for raw images onto block devices and files.
+
+use super::decompress::{self, InputCounter};
+use super::engine::ImagingProgress;
+use super::format::Compression;
+use super::journal::{JournalSession, DEFAULT_CHECKPOINT_BYTES};
+use super::sparse::SparseReader;
+use crate::{BootforgeError, Result};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+
+/// Bytes read from the source and written to the target per I/O call.
+pub const DEFAULT_BLOCK_SIZE: usize = 4 * 1024 * 1024;
+
+/// Alignment of direct I/O buffers and write lengths. 4 KiB covers both 512e
+/// and 4Kn devices.
+pub const IO_ALIGNMENT: usize = 4096;
+
+/// How often a paused writer checks whether it may carry on.
+const PAUSE_POLL: Duration = Duration::from_millis(100);
+
+/// Shared flag checked between blocks. Cloning shares the flag.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken {
+    flag: Arc<AtomicBool>,
+}
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.flag.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.flag.load(Ordering::SeqCst)
+    }
+
+    /// Clear a previous cancellation so the writer can be used again.
+    pub fn reset(&self) {
+        self.flag.store(false, Ordering::SeqCst);
+    }
+}
+
+/// Holds the writer between blocks while set, e.g. to let a hot device cool
+/// down. Cloning shares the flag.
+#[derive(Debug, Clone, Default)]
+pub struct PauseToken {
+    flag: Arc<AtomicBool>,
+}
+
+impl PauseToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn pause(&self) {
+        self.flag.store(true, Ordering::SeqCst);
+    }
+
+    pub fn resume(&self) {
+        self.flag.store(false, Ordering::SeqCst);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.flag.load(Ordering::SeqCst)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WriteOptions {
+    /// Rounded up to a multiple of `IO_ALIGNMENT`.
+    pub block_size: usize,
+    /// Bypass the page cache where the platform and filesystem allow it.
+    pub direct_io: bool,
+    /// fsync the target before reporting completion.
+    pub sync: bool,
+}
+
+impl Default for WriteOptions {
+    fn default() -> Self {
+        WriteOptions {
+            block_size: DEFAULT_BLOCK_SIZE,
+            direct_io: true,
+            sync: true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WriteSummary {
+    pub bytes_written: u64,
+    /// Whether the whole aligned part of the image went through direct I/O.
+    pub direct_io: bool,
+    pub elapsed_ms: u64,
+}
+
+/// Writes an image stream to a block device or file.
+///
+/// Progress is published on a watch channel from `subscribe`; the status is
+/// `writing`, `syncing`, then `complete`, or `cancelled` / `failed: <reason>`.
+/// A resumed write starts out `resuming`, and a held one reports `paused`.
+#[derive(Clone)]
+pub struct ImageWriter {
+    options: WriteOptions,
+    cancel: CancelToken,
+    pause: PauseToken,
+    journal_dir: Option<PathBuf>,
+    checkpoint_bytes: u64,
+    progress: Arc<watch::Sender<ImagingProgress>>,
+}
+
+impl ImageWriter {
+    pub fn new(options: WriteOptions) -> Self {
+        let (progress, _) = watch::channel(ImagingProgress {
+            total_bytes: 0,
+            written_bytes: 0,
+            percentage: 0.0,
+            status: "idle".to_string(),
+            compressed_total_bytes: None,
+            compressed_read_bytes: 0,
+        });
+
+        ImageWriter {
+            options,
+            cancel: CancelToken::new(),
+            pause: PauseToken::new(),
+            journal_dir: None,
+            checkpoint_bytes: DEFAULT_CHECKPOINT_BYTES,
+            progress: Arc::new(progress),
+        }
+    }
+
+    /// Keep a checkpoint journal in `dir` for writes from image files, so an
+    /// interrupted write to the same target picks up where it left off.
+    pub fn with_journal(mut self, dir: PathBuf) -> Self {
+        self.journal_dir = Some(dir);
+        self
+    }
+
+    /// Bytes written between checkpoints; defaults to
+    /// `DEFAULT_CHECKPOINT_BYTES`.
+    pub fn with_checkpoint_bytes(mut self, bytes: u64) -> Self {
+        self.checkpoint_bytes = bytes;
+        self
+    }
+
+    pub fn options(&self) -> &WriteOptions {
+        &self.options
+    }
+
+    pub fn cancel_token(&self) -> CancelToken {
+        self.cancel.clone()
+    }
+
+    pub fn pause_token(&self) -> PauseToken {
+        self.pause.clone()
+    }
+
+    pub fn subscribe(&self) -> watch::Receiver<ImagingProgress> {
+        self.progress.subscribe()
+    }
+
+    /// Latest progress report.
+    pub fn progress(&self) -> ImagingProgress {
+        self.progress.borrow().clone()
+    }
+
+    /// Write the file at `image` to `target` on a blocking thread.
+    pub async fn write_file(&self, image: &Path, target: &Path) -> Result<WriteSummary> {
+        let writer = self.clone();
+        let image = image.to_path_buf();
+        let target = target.to_path_buf();
+
+        tokio::task::spawn_blocking(move || {
+            let file = File::open(&image).map_err(|e| {
+                BootforgeError::Imaging(format!("Failed to open image {}: {}", image.display(), e))
+            })?;
+            let len = file.metadata()?.len();
+            let result = writer.copy(file, Some(len), &target, None, Some(&image));
+            writer.finish(result)
+        })
+        .await
+        .map_err(|e| BootforgeError::Imaging(format!("Image write task failed: {}", e)))?
+    }
+
+    /// Decompress `image` into `target` on a blocking thread, without an
+    /// intermediate file.
+    pub async fn write_compressed_file(&self, image: &Path, compression: Compression, target: &Path) -> Result<WriteSummary> {
+        let writer = self.clone();
+        let image = image.to_path_buf();
+        let target = target.to_path_buf();
+
+        tokio::task::spawn_blocking(move || writer.write_decompressed(&image, compression, &target))
+            .await
+            .map_err(|e| BootforgeError::Imaging(format!("Image write task failed: {}", e)))?
+    }
+
+    /// Blocking form of `write_compressed_file`. Progress carries both the
+    /// decompressed bytes written and the compressed bytes consumed.
+    pub fn write_decompressed(&self, image: &Path, compression: Compression, target: &Path) -> Result<WriteSummary> {
+        let counter = InputCounter::new();
+        let result = decompress::with_decoder(image, compression, &counter, |reader, lengths| {
+            let input = Input { counter: &counter, total: lengths.compressed };
+            self.copy(reader, lengths.uncompressed, target, Some(input), Some(image))
+        });
+        self.finish(result)
+    }
+
+    /// Expand an Android sparse image into `target` on a blocking thread,
+    /// decompressing it first if `compression` is set.
+    pub async fn write_sparse_file(&self, image: &Path, compression: Option<Compression>, target: &Path) -> Result<WriteSummary> {
+        let writer = self.clone();
+        let image = image.to_path_buf();
+        let target = target.to_path_buf();
+
+        tokio::task::spawn_blocking(move || writer.write_unsparsed(&image, compression, &target))
+            .await
+            .map_err(|e| BootforgeError::Imaging(format!("Image write task failed: {}", e)))?
+    }
+
+    /// Blocking form of `write_sparse_file`. Don't-care blocks are written
+    /// as zeros, so the target ends up identical to the unsparsed image.
+    pub fn write_unsparsed(&self, image: &Path, compression: Option<Compression>, target: &Path) -> Result<WriteSummary> {
+        let result = match compression {
+            Some(compression) => {
+                let counter = InputCounter::new();
+                decompress::with_decoder(image, compression, &counter, |reader, lengths| {
+                    let sparse = SparseReader::new(reader)?;
+                    let total = sparse.expanded_size();
+                    let input = Input { counter: &counter, total: lengths.compressed };
+                    self.copy(sparse, Some(total), target, Some(input), Some(image))
+                })
+            }
+            None => File::open(image)
+                .map_err(|e| BootforgeError::Imaging(format!("Failed to open image {}: {}", image.display(), e)))
+                .and_then(|file| SparseReader::new(std::io::BufReader::new(file)))
+                .and_then(|sparse| {
+                    let total = sparse.expanded_size();
+                    self.copy(sparse, Some(total), target, None, Some(image))
+                }),
+        };
+        self.finish(result)
+    }
+
+    /// Copy `source` to `target` until EOF. Blocking.
+    ///
+    /// Regular file targets are created or truncated; block devices are
+    /// written in place and rejected up front if `total_bytes` does not fit.
+    pub fn write_from<R: Read>(&self, source: R, total_bytes: Option<u64>, target: &Path) -> Result<WriteSummary> {
+        let result = self.copy(source, total_bytes, target, None, None);
+        self.finish(result)
+    }
+
+    fn finish(&self, result: Result<WriteSummary>) -> Result<WriteSummary> {
+        if let Err(e) = &result {
+            let status = if self.cancel.is_cancelled() { "cancelled".to_string() } else { format!("failed: {}", e) };
+            self.progress.send_modify(|p| p.status = status);
+        }
+        result
+    }
+
+    /// `image` is the file behind `source`, if any; writes from a file are
+    /// journaled when a journal directory is set.
+    pub(super) fn copy<R: Read>(
+        &self,
+        mut source: R,
+        total_bytes: Option<u64>,
+        target: &Path,
+        input: Option<Input>,
+        image: Option<&Path>,
+    ) -> Result<WriteSummary> {
+        let started = Instant::now();
+        let block_size = self.options.block_size.max(1).div_ceil(IO_ALIGNMENT) * IO_ALIGNMENT;
+
+        let mut journal = match (&self.journal_dir, image) {
+            (Some(dir), Some(image)) => Some(JournalSession::open(dir, image, target, self.checkpoint_bytes)?),
+            _ => None,
+        };
+        let resume_from = journal.as_mut().map_or(0, |j| j.resume_offset(target));
+        let mut out = Target::open(target, self.options.direct_io, resume_from > 0)?;
+
+        if let (Some(total), Some(capacity)) = (total_bytes, out.capacity) {
+            if total > capacity {
+                return Err(BootforgeError::Imaging(format!(
+                    "Image is {} bytes but {} holds only {}",
+                    total, target.display(), capacity
+                )));
+            }
+        }
+
+        let mut buffer = AlignedBuffer::new(block_size);
+        if let Some(journal) = journal.as_mut().filter(|_| resume_from > 0) {
+            self.report(resume_from, total_bytes, input, "resuming");
+            journal.skip_source(&mut source, resume_from, buffer.as_mut_slice())?;
+            out.file.seek(SeekFrom::Start(resume_from))?;
+            log::info!("Resuming write to {} at {} bytes", target.display(), resume_from);
+        }
+
+        let mut written = resume_from;
+        self.report(written, total_bytes, input, "writing");
+
+        loop {
+            if self.pause.is_paused() {
+                self.hold(&mut out, journal.as_mut(), written, total_bytes, input)?;
+            }
+            if self.cancel.is_cancelled() {
+                if let Some(journal) = journal.as_mut() {
+                    if let Err(e) = checkpoint(&mut out, journal, written) {
+                        log::warn!("Failed to checkpoint cancelled write: {}", e);
+                    }
+                }
+                return Err(BootforgeError::Imaging(format!("Write cancelled after {} bytes", written)));
+            }
+
+            let n = read_full(&mut source, buffer.as_mut_slice())?;
+            if n == 0 {
+                break;
+            }
+
+            let data = &buffer.as_mut_slice()[..n];
+            out.write_at(data, written)?;
+            written += n as u64;
+            if let Some(journal) = journal.as_mut() {
+                journal.record(data);
+                if journal.due(written) {
+                    checkpoint(&mut out, journal, written)?;
+                }
+            }
+            self.report(written, total_bytes, input, "writing");
+
+            if n < block_size {
+                break;
+            }
+        }
+
+        if self.options.sync {
+            self.report(written, total_bytes, input, "syncing");
+            out.file.sync_all().map_err(|e| BootforgeError::Imaging(format!("Failed to sync {}: {}", target.display(), e)))?;
+        }
+
+        if let Some(journal) = journal {
+            journal.complete();
+        }
+        self.report(written, Some(written), input, "complete");
+        log::info!(
+            "Wrote {} bytes to {} ({})",
+            written, target.display(), if out.direct { "direct I/O" } else { "buffered" }
+        );
+
+        Ok(WriteSummary {
+            bytes_written: written,
+            direct_io: out.direct,
+            elapsed_ms: started.elapsed().as_millis() as u64,
+        })
+    }
+
+    /// Wait while paused, checkpointing first so nothing is lost if the
+    /// write never resumes. Returns early on cancellation.
+    fn hold(
+        &self,
+        out: &mut Target,
+        journal: Option<&mut JournalSession>,
+        written: u64,
+        total: Option<u64>,
+        input: Option<Input>,
+    ) -> Result<()> {
+        if let Some(journal) = journal {
+            checkpoint(out, journal, written)?;
+        }
+        self.report(written, total, input, "paused");
+        log::info!("Write to {} paused after {} bytes", out.path.display(), written);
+
+        while self.pause.is_paused() && !self.cancel.is_cancelled() {
+            std::thread::sleep(PAUSE_POLL);
+        }
+        self.report(written, total, input, "writing");
+        Ok(())
+    }
+
+    fn report(&self, written: u64, total: Option<u64>, input: Option<Input>, status: &str) {
+        let read = input.map_or(0, |i| i.counter.get());
+        let percentage = match (total, input) {
+            (None, Some(input)) if input.total > 0 => (read as f64 / input.total as f64 * 100.0).min(100.0) as f32,
+            _ => {
+                let total = total.unwrap_or(written).max(written);
+                if total == 0 { 100.0 } else { (written as f64 / total as f64 * 100.0) as f32 }
+            }
+        };
+
+        self.progress.send_replace(ImagingProgress {
+            total_bytes: total.unwrap_or(written).max(written),
+            written_bytes: written,
+            percentage,
+            status: status.to_string(),
+            compressed_total_bytes: input.map(|i| i.total),
+            compressed_read_bytes: read,
+        });
+    }
+}
+
+impl Default for ImageWriter {
+    fn default() -> Self {
+        Self::new(WriteOptions::default())
+    }
+}
+
+/// Sync what has been written so far and record it in the journal.
+fn checkpoint(out: &mut Target, journal: &mut JournalSession, written: u64) -> Result<()> {
+    out.file
+        .sync_data()
+        .map_err(|e| BootforgeError::Imaging(format!("Failed to sync {}: {}", out.path.display(), e)))?;
+    journal.checkpoint(written)
+}
+
+/// Compressed input behind a decoder, for progress reporting.
+#[derive(Clone, Copy)]
+pub(super) struct Input<'a> {
+    counter: &'a InputCounter,
+    total: u64,
+}
+
+struct Target {
+    path: PathBuf,
+    file: File,
+    direct: bool,
+    /// Size of a block device target, `None` for regular files.
+    capacity: Option<u64>,
+}
+
+impl Target {
+    /// `resume` keeps an existing file target's contents.
+    fn open(path: &Path, direct_io: bool, resume: bool) -> Result<Self> {
+        let is_device = fs::metadata(path).is_ok_and(|m| !m.is_file());
+        let in_place = is_device || resume;
+
+        let mut options = buffered_options(in_place);
+        let open_err = |e: std::io::Error| BootforgeError::Imaging(format!("Failed to open {}: {}", path.display(), e));
+
+        let direct_file = if direct_io && set_direct(&mut options) {
+            match options.open(path) {
+                Ok(file) => Some(file),
+                Err(e) => {
+                    log::debug!("Direct I/O unavailable for {}, using buffered writes: {}", path.display(), e);
+                    None
+                }
+            }
+        } else {
+            None
+        };
+
+        let direct = direct_file.is_some();
+        let mut file = match direct_file {
+            Some(file) => file,
+            None => buffered_options(in_place).open(path).map_err(open_err)?,
+        };
+
+        let capacity = if is_device {
+            let size = file.seek(SeekFrom::End(0))?;
+            file.seek(SeekFrom::Start(0))?;
+            Some(size)
+        } else {
+            None
+        };
+
+        Ok(Target { path: path.to_path_buf(), file, direct, capacity })
+    }
+
+    /// Write `data`, which starts `offset` bytes into the target. Direct I/O
+    /// needs aligned lengths, so an unaligned tail goes through a buffered
+    /// handle; a filesystem that rejects direct writes gets the whole chunk
+    /// rewritten buffered.
+    fn write_at(&mut self, data: &[u8], offset: u64) -> Result<()> {
+        if !self.direct {
+            return self.write_buffered(data);
+        }
+
+        let aligned = data.len() / IO_ALIGNMENT * IO_ALIGNMENT;
+        match self.file.write_all(&data[..aligned]) {
+            Ok(()) if aligned == data.len() => Ok(()),
+            Ok(()) => {
+                self.reopen_buffered(offset + aligned as u64)?;
+                self.write_buffered(&data[aligned..])
+            }
+            Err(e) if e.kind() == ErrorKind::InvalidInput => {
+                log::debug!("Direct write rejected for {}, using buffered writes: {}", self.path.display(), e);
+                self.reopen_buffered(offset)?;
+                self.write_buffered(data)
+            }
+            Err(e) => Err(self.write_error(e)),
+        }
+    }
+
+    fn write_buffered(&mut self, data: &[u8]) -> Result<()> {
+        self.file.write_all(data).map_err(|e| self.write_error(e))
+    }
+
+    fn reopen_buffered(&mut self, offset: u64) -> Result<()> {
+        let mut file = buffered_options(true).open(&self.path)
+            .map_err(|e| BootforgeError::Imaging(format!("Failed to reopen {}: {}", self.path.display(), e)))?;
+        file.seek(SeekFrom::Start(offset))?;
+        self.file = file;
+        self.direct = false;
+        Ok(())
+    }
+
+    fn write_error(&self, e: std::io::Error) -> BootforgeError {
+        BootforgeError::Imaging(format!("Failed to write {}: {}", self.path.display(), e))
+    }
+}
+
+fn buffered_options(in_place: bool) -> OpenOptions {
+    let mut options = OpenOptions::new();
+    options.write(true);
+    if !in_place {
+        options.create(true).truncate(true);
+    }
+    options
+}
+
+/// Add the platform's cache-bypass flags. Returns false where there are none.
+#[cfg(target_os = "linux")]
+pub(crate) fn set_direct(options: &mut OpenOptions) -> bool {
+    use std::os::unix::fs::OpenOptionsExt;
+    options.custom_flags(libc::O_DIRECT);
+    true
+}
+
+#[cfg(windows)]
+pub(crate) fn set_direct(options: &mut OpenOptions) -> bool {
+    use std::os::windows::fs::OpenOptionsExt;
+    const FILE_FLAG_NO_BUFFERING: u32 = 0x2000_0000;
+    const FILE_FLAG_WRITE_THROUGH: u32 = 0x8000_0000;
+    options.custom_flags(FILE_FLAG_NO_BUFFERING | FILE_FLAG_WRITE_THROUGH);
+    true
+}
+
+#[cfg(not(any(target_os = "linux", windows)))]
+pub(crate) fn set_direct(_options: &mut OpenOptions) -> bool {
+    false
+}
+
+/// Read until `buf` is full or the source is exhausted.
+pub(crate) fn read_full<R: Read>(source: &mut R, buf: &mut [u8]) -> Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match source.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(BootforgeError::Imaging(format!("Failed to read image: {}", e))),
+        }
+    }
+    Ok(filled)
+}
+
+/// Heap buffer whose start is aligned to `IO_ALIGNMENT`.
+pub(crate) struct AlignedBuffer {
+    storage: Vec<u8>,
+    offset: usize,
+    len: usize,
+}
+
+impl AlignedBuffer {
+    pub(crate) fn new(len: usize) -> Self {
+        let storage = vec![0u8; len + IO_ALIGNMENT];
+        let offset = storage.as_ptr().align_offset(IO_ALIGNMENT).min(IO_ALIGNMENT);
+        AlignedBuffer { storage, offset, len }
+    }
+
+    pub(crate) fn as_mut_slice(&mut self) -> &mut [u8] {
+        &mut self.storage[self.offset..self.offset + self.len]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::pattern;
+    use crate::imaging::journal::WriteJournal;
+    use std::io::Cursor;
+
+    fn small_blocks(direct_io: bool) -> WriteOptions {
+        WriteOptions { block_size: 64 * 1024, direct_io, sync: true }
+    }
+
+    #[test]
+    fn test_write_with_unaligned_tail() {
+        let dir = tempfile::tempdir().unwrap();
+        let data = pattern(1024 * 1024 + 123);
+
+        for direct_io in [true, false] {
+            let target = dir.path().join(format!("disk-{}.img", direct_io));
+            let writer = ImageWriter::new(small_blocks(direct_io));
+            let summary = writer.write_from(Cursor::new(&data), Some(data.len() as u64), &target).unwrap();
+
+            assert_eq!(summary.bytes_written, data.len() as u64);
+            assert_eq!(fs::read(&target).unwrap(), data);
+            let progress = writer.progress();
+            assert_eq!(progress.status, "complete");
+            assert_eq!(progress.written_bytes, data.len() as u64);
+            assert_eq!(progress.percentage, 100.0);
+        }
+    }
+
+    #[test]
+    fn test_existing_file_is_truncated() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("disk.img");
+        fs::write(&target, pattern(300_000)).unwrap();
+
+        ImageWriter::default().write_from(Cursor::new(b"boot".to_vec()), None, &target).unwrap();
+        assert_eq!(fs::read(&target).unwrap(), b"boot");
+    }
+
+    /// Source that cancels the write once it has handed out `after` bytes.
+    struct CancelAfter {
+        inner: Cursor<Vec<u8>>,
+        token: CancelToken,
+        after: u64,
+    }
+
+    impl Read for CancelAfter {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            if self.inner.position() >= self.after {
+                self.token.cancel();
+            }
+            self.inner.read(buf)
+        }
+    }
+
+    #[test]
+    fn test_cancellation() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("disk.img");
+        let writer = ImageWriter::new(small_blocks(false));
+        let source = CancelAfter { inner: Cursor::new(pattern(1024 * 1024)), token: writer.cancel_token(), after: 128 * 1024 };
+
+        let err = writer.write_from(source, Some(1024 * 1024), &target).unwrap_err();
+        assert!(err.to_string().contains("cancelled"));
+        let progress = writer.progress();
+        assert_eq!(progress.status, "cancelled");
+        assert!(progress.written_bytes < 1024 * 1024);
+    }
+
+    fn journaled(dir: &Path) -> ImageWriter {
+        ImageWriter::new(small_blocks(false))
+            .with_journal(dir.join("journals"))
+            .with_checkpoint_bytes(128 * 1024)
+    }
+
+    #[test]
+    fn test_interrupted_write_resumes_after_checkpoint() {
+        let dir = tempfile::tempdir().unwrap();
+        let image = dir.path().join("image.img");
+        let target = dir.path().join("target.img");
+        let data = pattern(1024 * 1024);
+        fs::write(&image, &data).unwrap();
+
+        let writer = journaled(dir.path());
+        let source = CancelAfter { inner: Cursor::new(data.clone()), token: writer.cancel_token(), after: 300 * 1024 };
+        writer.copy(source, Some(data.len() as u64), &target, None, Some(&image)).unwrap_err();
+
+        let journal_path = WriteJournal::path_for(&dir.path().join("journals"), &target);
+        let journal = WriteJournal::load(&journal_path).unwrap().unwrap();
+        let verified = journal.verified_bytes();
+        assert!(verified >= 300 * 1024 && verified < data.len() as u64);
+        assert_eq!(journal.checkpoints[0].offset, 0);
+
+        // A damaged last checkpoint is rewritten; earlier ones are trusted
+        let mut damaged = fs::read(&target).unwrap();
+        damaged[0] ^= 0xff;
+        damaged[verified as usize - 1] ^= 0xff;
+        fs::write(&target, &damaged).unwrap();
+
+        writer.cancel_token().reset();
+        let file = File::open(&image).unwrap();
+        let summary = writer.copy(file, Some(data.len() as u64), &target, None, Some(&image)).unwrap();
+        assert_eq!(summary.bytes_written, data.len() as u64);
+
+        let written = fs::read(&target).unwrap();
+        assert_eq!(written[0], data[0] ^ 0xff);
+        assert_eq!(written[1..], data[1..]);
+        assert!(!journal_path.exists());
+    }
+
+    /// Source that pauses the write once it has handed out `after` bytes.
+    struct PauseAfter {
+        inner: Cursor<Vec<u8>>,
+        token: PauseToken,
+        after: u64,
+    }
+
+    impl Read for PauseAfter {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            if self.inner.position() == self.after {
+                self.token.pause();
+            }
+            self.inner.read(buf)
+        }
+    }
+
+    #[test]
+    fn test_pause_checkpoints_and_holds() {
+        let dir = tempfile::tempdir().unwrap();
+        let image = dir.path().join("image.img");
+        let target = dir.path().join("target.img");
+        let data = pattern(512 * 1024);
+        fs::write(&image, &data).unwrap();
+
+        let writer = journaled(dir.path());
+        let source = PauseAfter { inner: Cursor::new(data.clone()), token: writer.pause_token(), after: 64 * 1024 };
+        let handle = {
+            let writer = writer.clone();
+            let (image, target) = (image.clone(), target.clone());
+            std::thread::spawn(move || writer.copy(source, Some(512 * 1024), &target, None, Some(&image)))
+        };
+
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while writer.progress().status != "paused" {
+            assert!(Instant::now() < deadline, "writer never paused");
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        let progress = writer.progress();
+        let journal_path = WriteJournal::path_for(&dir.path().join("journals"), &target);
+        let journal = WriteJournal::load(&journal_path).unwrap().unwrap();
+        assert_eq!(journal.verified_bytes(), progress.written_bytes);
+
+        writer.pause_token().resume();
+        handle.join().unwrap().unwrap();
+        assert_eq!(fs::read(&target).unwrap(), data);
+        assert_eq!(writer.progress().status, "complete");
+    }
+
+    #[tokio::test]
+    async fn test_write_file_reports_progress() {
+        let dir = tempfile::tempdir().unwrap();
+        let image = dir.path().join("image.img");
+        let target = dir.path().join("target.img");
+        let data = pattern(200_000);
+        fs::write(&image, &data).unwrap();
+
+        let writer = ImageWriter::new(small_blocks(true));
+        let mut progress = writer.subscribe();
+        writer.write_file(&image, &target).await.unwrap();
+
+        assert!(progress.has_changed().unwrap());
+        let last = progress.borrow_and_update().clone();
+        assert_eq!(last.status, "complete");
+        assert_eq!(last.total_bytes, 200_000);
+        assert_eq!(fs::read(&target).unwrap(), data);
+
+        let err = writer.write_file(&dir.path().join("missing.img"), &target).await.unwrap_err();
+        assert!(err.to_string().contains("Failed to open image"));
+    }
+
+    #[test]
+    fn test_write_decompressed_reports_both_sizes() {
+        let dir = tempfile::tempdir().unwrap();
+        let image = dir.path().join("image.img.zst");
+        let target = dir.path().join("target.img");
+        let data = pattern(700_000);
+        let packed = zstd::encode_all(&data[..], 3).unwrap();
+        fs::write(&image, &packed).unwrap();
+
+        let writer = ImageWriter::new(small_blocks(true));
+        let summary = writer.write_decompressed(&image, Compression::Zstd, &target).unwrap();
+
+        assert_eq!(summary.bytes_written, data.len() as u64);
+        assert_eq!(fs::read(&target).unwrap(), data);
+        let progress = writer.progress();
+        assert_eq!(progress.status, "complete");
+        assert_eq!(progress.written_bytes, data.len() as u64);
+        assert_eq!(progress.compressed_total_bytes, Some(packed.len() as u64));
+        assert_eq!(progress.compressed_read_bytes, packed.len() as u64);
+        assert_eq!(progress.percentage, 100.0);
+    }
+}
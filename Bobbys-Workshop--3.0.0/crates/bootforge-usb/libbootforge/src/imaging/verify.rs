@@ -0,0 +1,580 @@
+//! Read-back verification of written images and signed verification records.
+
+use super::decompress::{self, InputCounter};
+use super::format::Compression;
+use super::sparse::SparseReader;
+use super::stream::{read_full, set_direct, AlignedBuffer, CancelToken, DEFAULT_BLOCK_SIZE, IO_ALIGNMENT};
+use crate::utils::hash::{ExpectedHash, HashAlgorithm, StreamHasher};
+use crate::{BootforgeError, Result};
+use chrono::{DateTime, Utc};
+use rsa::pkcs8::{DecodePrivateKey, DecodePublicKey, EncodePrivateKey, EncodePublicKey, LineEnding};
+use rsa::{Pkcs1v15Sign, RsaPrivateKey, RsaPublicKey};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufReader, ErrorKind, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
+
+const SIGNATURE_ALGORITHM: &str = "rsa-pkcs1v15-sha256";
+/// Size of generated signing keys.
+pub const SIGNING_KEY_BITS: usize = 2048;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum VerificationStatus {
+    /// Every byte read back matches the source.
+    Verified,
+    /// The target differs from the source, or the source from its expected digest.
+    Mismatch,
+    /// The target could not be read back (unsupported transport or format).
+    Unverifiable,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RecordSignature {
+    pub algorithm: String,
+    /// First 16 hex digits of SHA-256 over the public key, to tell keys apart.
+    pub key_id: String,
+    pub value: String,
+    /// PEM public key of the signer, so the record can be checked without
+    /// access to this install. Compare `key_id` with a key you trust first.
+    pub public_key: String,
+}
+
+/// Outcome of comparing a written target against its source image, suitable
+/// for attaching to a case.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VerificationRecord {
+    pub id: Uuid,
+    pub created_at: DateTime<Utc>,
+    pub case_id: Option<String>,
+    pub source: String,
+    pub target: String,
+    pub algorithm: HashAlgorithm,
+    pub source_digest: Option<String>,
+    pub target_digest: Option<String>,
+    pub bytes_compared: u64,
+    pub status: VerificationStatus,
+    pub first_mismatch_offset: Option<u64>,
+    pub note: Option<String>,
+    /// The backup GPT was moved to the end of the target after the
+    /// read-back, so the target's GPT headers no longer match the image.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub partition_table_relocated: bool,
+    pub signature: Option<RecordSignature>,
+}
+
+impl VerificationRecord {
+    fn new(source: &str, target: &str, algorithm: HashAlgorithm) -> Self {
+        VerificationRecord {
+            id: Uuid::new_v4(),
+            created_at: Utc::now(),
+            case_id: None,
+            source: source.to_string(),
+            target: target.to_string(),
+            algorithm,
+            source_digest: None,
+            target_digest: None,
+            bytes_compared: 0,
+            status: VerificationStatus::Unverifiable,
+            first_mismatch_offset: None,
+            note: None,
+            partition_table_relocated: false,
+            signature: None,
+        }
+    }
+
+    /// Record that `target` could not be read back, with the reason.
+    pub fn unverifiable(source: &str, target: &str, reason: &str) -> Self {
+        let mut record = Self::new(source, target, HashAlgorithm::Sha256);
+        record.note = Some(reason.to_string());
+        record
+    }
+
+    pub fn is_verified(&self) -> bool {
+        self.status == VerificationStatus::Verified
+    }
+
+    pub fn with_case(mut self, case_id: Option<&str>) -> Self {
+        self.case_id = case_id.map(str::to_string);
+        self
+    }
+
+    /// Sign every field except the signature itself. Any later change
+    /// invalidates it.
+    pub fn sign(&mut self, key: &SigningKey) -> Result<()> {
+        let payload = self.signing_payload()?;
+        let value = key.private
+            .sign(Pkcs1v15Sign::new::<Sha256>(), &Sha256::digest(&payload))
+            .map_err(|e| BootforgeError::Imaging(format!("Failed to sign verification record: {}", e)))?;
+        let public = key.verification_key();
+        self.signature = Some(RecordSignature {
+            algorithm: SIGNATURE_ALGORITHM.to_string(),
+            key_id: public.key_id(),
+            value: hex::encode(value),
+            public_key: public.to_pem()?,
+        });
+        Ok(())
+    }
+
+    /// Whether the record carries a valid signature from `key`.
+    pub fn verify_signature(&self, key: &VerificationKey) -> bool {
+        let Some(signature) = &self.signature else {
+            return false;
+        };
+        let (Ok(payload), Ok(value)) = (self.signing_payload(), hex::decode(&signature.value)) else {
+            return false;
+        };
+
+        signature.algorithm == SIGNATURE_ALGORITHM
+            && signature.key_id == key.key_id()
+            && key.public.verify(Pkcs1v15Sign::new::<Sha256>(), &Sha256::digest(&payload), &value).is_ok()
+    }
+
+    /// The public key the record says it was signed with. Only proves
+    /// anything once its `key_id` is matched against a trusted key.
+    pub fn signer(&self) -> Option<VerificationKey> {
+        VerificationKey::from_pem(&self.signature.as_ref()?.public_key).ok()
+    }
+
+    pub fn to_json(&self) -> Result<String> {
+        serde_json::to_string_pretty(self)
+            .map_err(|e| BootforgeError::Imaging(format!("Failed to serialize verification record: {}", e)))
+    }
+
+    fn signing_payload(&self) -> Result<Vec<u8>> {
+        let unsigned = VerificationRecord { signature: None, ..self.clone() };
+        serde_json::to_vec(&unsigned)
+            .map_err(|e| BootforgeError::Imaging(format!("Failed to serialize verification record: {}", e)))
+    }
+}
+
+/// Per-install RSA key that signs verification records. Anyone holding the
+/// matching `VerificationKey` can check a record; only this install can
+/// produce one.
+#[derive(Clone)]
+pub struct SigningKey {
+    private: RsaPrivateKey,
+}
+
+impl SigningKey {
+    pub fn generate() -> Result<Self> {
+        let private = RsaPrivateKey::new(&mut rsa::rand_core::OsRng, SIGNING_KEY_BITS)
+            .map_err(|e| BootforgeError::Imaging(format!("Failed to generate signing key: {}", e)))?;
+        Ok(SigningKey { private })
+    }
+
+    /// `verification-key.pem` next to the device cache.
+    pub fn default_path() -> Result<PathBuf> {
+        let cache = crate::usb::cache::get_cache_path()?;
+        Ok(cache.with_file_name("verification-key.pem"))
+    }
+
+    /// Read the PKCS#8 PEM key at `path`, creating it (owner-only on Unix)
+    /// and its public half at `<path>.pub` if missing.
+    pub fn load_or_create(path: &Path) -> Result<Self> {
+        match fs::read_to_string(path) {
+            Ok(pem) => {
+                let private = RsaPrivateKey::from_pkcs8_pem(&pem)
+                    .map_err(|e| BootforgeError::Imaging(format!("Invalid signing key in {}: {}", path.display(), e)))?;
+                Ok(SigningKey { private })
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                let key = Self::generate()?;
+                key.save(path)?;
+                log::info!("Created verification signing key {} at {}", key.key_id(), path.display());
+                Ok(key)
+            }
+            Err(e) => Err(BootforgeError::Imaging(format!("Failed to read signing key {}: {}", path.display(), e))),
+        }
+    }
+
+    fn save(&self, path: &Path) -> Result<()> {
+        use std::io::Write;
+
+        if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            fs::create_dir_all(parent)?;
+        }
+
+        let pem = self.private
+            .to_pkcs8_pem(LineEnding::LF)
+            .map_err(|e| BootforgeError::Imaging(format!("Failed to encode signing key: {}", e)))?;
+
+        let mut options = OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+
+        let mut file = options.open(path)
+            .map_err(|e| BootforgeError::Imaging(format!("Failed to create signing key {}: {}", path.display(), e)))?;
+        file.write_all(pem.as_bytes())?;
+        file.sync_all()?;
+
+        let mut public = path.as_os_str().to_owned();
+        public.push(".pub");
+        fs::write(PathBuf::from(public), self.verification_key().to_pem()?)?;
+        Ok(())
+    }
+
+    /// The public half, for checking records this key signs.
+    pub fn verification_key(&self) -> VerificationKey {
+        VerificationKey { public: self.private.to_public_key() }
+    }
+
+    pub fn key_id(&self) -> String {
+        self.verification_key().key_id()
+    }
+}
+
+impl fmt::Debug for SigningKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SigningKey").field("key_id", &self.key_id()).finish()
+    }
+}
+
+/// Public key that checks verification records.
+#[derive(Clone, PartialEq, Eq)]
+pub struct VerificationKey {
+    public: RsaPublicKey,
+}
+
+impl VerificationKey {
+    /// Read an SPKI PEM public key, as written to `<signing key>.pub`.
+    pub fn from_pem(pem: &str) -> Result<Self> {
+        let public = RsaPublicKey::from_public_key_pem(pem)
+            .map_err(|e| BootforgeError::Imaging(format!("Invalid verification key: {}", e)))?;
+        Ok(VerificationKey { public })
+    }
+
+    pub fn to_pem(&self) -> Result<String> {
+        self.public
+            .to_public_key_pem(LineEnding::LF)
+            .map_err(|e| BootforgeError::Imaging(format!("Failed to encode verification key: {}", e)))
+    }
+
+    pub fn key_id(&self) -> String {
+        let der = self.public.to_public_key_der().map(|der| der.into_vec()).unwrap_or_default();
+        hex::encode(Sha256::digest(der))[..16].to_string()
+    }
+}
+
+impl fmt::Debug for VerificationKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("VerificationKey").field("key_id", &self.key_id()).finish()
+    }
+}
+
+/// Re-reads the written region of a target and compares it byte for byte
+/// with the source, hashing both sides on the way.
+///
+/// The target is read with direct I/O where possible so the comparison sees
+/// the medium rather than pages still cached from the write.
+#[derive(Debug, Clone)]
+pub struct ReadbackVerifier {
+    pub algorithm: HashAlgorithm,
+    pub block_size: usize,
+    pub direct_io: bool,
+    /// Known digest of the source (from a manifest), checked as well. For
+    /// compressed sources this is the digest of the decompressed image.
+    pub expected_source: Option<ExpectedHash>,
+    /// Decompress the source on the fly before comparing.
+    pub compression: Option<Compression>,
+    /// The source is an Android sparse image; compare its expanded form.
+    pub sparse: bool,
+    cancel: CancelToken,
+}
+
+impl ReadbackVerifier {
+    pub fn new() -> Self {
+        ReadbackVerifier {
+            algorithm: HashAlgorithm::Sha256,
+            block_size: DEFAULT_BLOCK_SIZE,
+            direct_io: true,
+            expected_source: None,
+            compression: None,
+            sparse: false,
+            cancel: CancelToken::new(),
+        }
+    }
+
+    /// Share a cancel token, e.g. the one of the writer that produced the target.
+    pub fn with_cancel(mut self, cancel: CancelToken) -> Self {
+        self.cancel = cancel;
+        self
+    }
+
+    pub fn with_compression(mut self, compression: Option<Compression>) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    pub fn with_sparse(mut self, sparse: bool) -> Self {
+        self.sparse = sparse;
+        self
+    }
+
+    pub fn with_expected_source(mut self, expected: ExpectedHash) -> Self {
+        self.algorithm = expected.algorithm;
+        self.expected_source = Some(expected);
+        self
+    }
+
+    pub fn cancel_token(&self) -> CancelToken {
+        self.cancel.clone()
+    }
+
+    /// Compare the first `len(source)` bytes of `target` with `source`. Blocking.
+    pub fn verify(&self, source: &Path, target: &Path) -> Result<VerificationRecord> {
+        let record = VerificationRecord::new(&source.display().to_string(), &target.display().to_string(), self.algorithm);
+
+        match self.compression {
+            Some(compression) => decompress::with_decoder(source, compression, &InputCounter::new(), |reader, _| {
+                self.compare_source(reader, record, target)
+            }),
+            None => {
+                let mut src = File::open(source)
+                    .map_err(|e| BootforgeError::Imaging(format!("Failed to open image {}: {}", source.display(), e)))?;
+                self.compare_source(&mut src, record, target)
+            }
+        }
+    }
+
+    fn compare_source(&self, src: &mut dyn Read, record: VerificationRecord, target: &Path) -> Result<VerificationRecord> {
+        if self.sparse {
+            self.compare(SparseReader::new(BufReader::new(src))?, record, target)
+        } else {
+            self.compare(src, record, target)
+        }
+    }
+
+    fn compare<R: Read>(&self, mut src: R, mut record: VerificationRecord, target: &Path) -> Result<VerificationRecord> {
+        let mut tgt = ReadbackTarget::open(target, self.direct_io)?;
+
+        let block_size = self.block_size.max(1).div_ceil(IO_ALIGNMENT) * IO_ALIGNMENT;
+        let mut src_buf = vec![0u8; block_size];
+        let mut tgt_buf = AlignedBuffer::new(block_size);
+        let mut src_hash = StreamHasher::new(self.algorithm);
+        let mut tgt_hash = StreamHasher::new(self.algorithm);
+        let mut offset = 0u64;
+        let mut target_ended = false;
+
+        loop {
+            if self.cancel.is_cancelled() {
+                return Err(BootforgeError::Imaging(format!("Verification cancelled after {} bytes", offset)));
+            }
+
+            let n_src = read_full(&mut src, &mut src_buf)?;
+            if n_src == 0 {
+                break;
+            }
+            // Once the target has ended, keep hashing the source so its digest is complete
+            let n_tgt = if target_ended { 0 } else { tgt.read_block(tgt_buf.as_mut_slice(), offset)? };
+
+            let compared = n_src.min(n_tgt);
+            let src_chunk = &src_buf[..n_src];
+            let tgt_chunk = &tgt_buf.as_mut_slice()[..compared];
+            src_hash.update(src_chunk);
+            tgt_hash.update(tgt_chunk);
+
+            if record.first_mismatch_offset.is_none() {
+                let diff = src_chunk.iter().zip(tgt_chunk).position(|(a, b)| a != b);
+                record.first_mismatch_offset = match diff {
+                    Some(pos) => Some(offset + pos as u64),
+                    None if compared < n_src => Some(offset + compared as u64),
+                    None => None,
+                };
+            }
+
+            record.bytes_compared += compared as u64;
+            offset += n_src as u64;
+
+            if compared < n_src && !target_ended {
+                target_ended = true;
+                record.note = Some(format!("Target ended after {} bytes", record.bytes_compared));
+            }
+        }
+
+        let source_digest = src_hash.finalize();
+        record.target_digest = Some(tgt_hash.finalize());
+
+        let source_ok = match &self.expected_source {
+            Some(expected) if expected.digest != source_digest => {
+                record.note = Some(format!("Source image does not match expected {}", expected));
+                false
+            }
+            _ => true,
+        };
+
+        record.status = if source_ok && record.first_mismatch_offset.is_none() {
+            VerificationStatus::Verified
+        } else {
+            VerificationStatus::Mismatch
+        };
+        record.source_digest = Some(source_digest);
+
+        match record.first_mismatch_offset {
+            Some(at) => log::warn!("Read-back of {} differs from {} at offset {}", target.display(), record.source, at),
+            None => log::info!("Read-back of {} {:?} ({} bytes)", target.display(), record.status, record.bytes_compared),
+        }
+        Ok(record)
+    }
+
+    /// `verify` on a blocking thread.
+    pub async fn verify_async(&self, source: &Path, target: &Path) -> Result<VerificationRecord> {
+        let verifier = self.clone();
+        let source = source.to_path_buf();
+        let target = target.to_path_buf();
+
+        tokio::task::spawn_blocking(move || verifier.verify(&source, &target))
+            .await
+            .map_err(|e| BootforgeError::Imaging(format!("Verification task failed: {}", e)))?
+    }
+}
+
+impl Default for ReadbackVerifier {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+struct ReadbackTarget {
+    path: PathBuf,
+    file: File,
+    direct: bool,
+}
+
+impl ReadbackTarget {
+    fn open(path: &Path, direct_io: bool) -> Result<Self> {
+        let open_err = |e: std::io::Error| BootforgeError::Imaging(format!("Failed to open {}: {}", path.display(), e));
+
+        if direct_io {
+            let mut options = OpenOptions::new();
+            options.read(true);
+            if set_direct(&mut options) {
+                if let Ok(file) = options.open(path) {
+                    return Ok(ReadbackTarget { path: path.to_path_buf(), file, direct: true });
+                }
+            }
+        }
+
+        let file = File::open(path).map_err(open_err)?;
+        Ok(ReadbackTarget { path: path.to_path_buf(), file, direct: false })
+    }
+
+    /// Fill `buf` from `offset`; short only at the end of the target. Falls
+    /// back to buffered reads if the filesystem rejects direct ones.
+    fn read_block(&mut self, buf: &mut [u8], offset: u64) -> Result<usize> {
+        match read_full(&mut self.file, buf) {
+            Err(e) if self.direct => {
+                log::debug!("Direct read rejected for {}, using buffered reads: {}", self.path.display(), e);
+                self.file = File::open(&self.path)?;
+                self.file.seek(SeekFrom::Start(offset))?;
+                self.direct = false;
+                read_full(&mut self.file, buf)
+            }
+            result => result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::pattern;
+    use crate::utils::hash::hash_bytes;
+
+    fn small_blocks() -> ReadbackVerifier {
+        ReadbackVerifier { block_size: 8192, ..ReadbackVerifier::new() }
+    }
+
+    #[test]
+    fn test_identical_target_verifies() {
+        let dir = tempfile::tempdir().unwrap();
+        let (source, target) = (dir.path().join("image.img"), dir.path().join("disk.img"));
+        let data = pattern(50_000);
+        fs::write(&source, &data).unwrap();
+        // Target larger than the image, as a device would be
+        let mut on_disk = data.clone();
+        on_disk.extend_from_slice(&[0xff; 9000]);
+        fs::write(&target, &on_disk).unwrap();
+
+        let record = small_blocks().verify(&source, &target).unwrap();
+        assert_eq!(record.status, VerificationStatus::Verified);
+        assert_eq!(record.bytes_compared, 50_000);
+        assert_eq!(record.source_digest, record.target_digest);
+        assert_eq!(record.source_digest.as_deref(), Some(hash_bytes(&data, HashAlgorithm::Sha256).as_str()));
+    }
+
+    #[test]
+    fn test_first_mismatch_offset() {
+        let dir = tempfile::tempdir().unwrap();
+        let (source, target) = (dir.path().join("image.img"), dir.path().join("disk.img"));
+        let data = pattern(50_000);
+        fs::write(&source, &data).unwrap();
+
+        let mut corrupt = data.clone();
+        corrupt[20_001] ^= 0x01;
+        corrupt[40_000] ^= 0x80;
+        fs::write(&target, &corrupt).unwrap();
+        let record = small_blocks().verify(&source, &target).unwrap();
+        assert_eq!(record.status, VerificationStatus::Mismatch);
+        assert_eq!(record.first_mismatch_offset, Some(20_001));
+        assert_ne!(record.source_digest, record.target_digest);
+
+        // Short target: mismatch where it ends
+        fs::write(&target, &data[..30_000]).unwrap();
+        let record = small_blocks().verify(&source, &target).unwrap();
+        assert_eq!(record.first_mismatch_offset, Some(30_000));
+        assert_eq!(record.bytes_compared, 30_000);
+        assert_eq!(record.source_digest.as_deref(), Some(hash_bytes(&data, HashAlgorithm::Sha256).as_str()));
+    }
+
+    #[test]
+    fn test_expected_source_digest() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("image.img");
+        fs::write(&path, b"hello").unwrap();
+
+        let wrong = ExpectedHash::new(HashAlgorithm::Sha256, &hash_bytes(b"hellO", HashAlgorithm::Sha256)).unwrap();
+        let record = small_blocks().with_expected_source(wrong).verify(&path, &path).unwrap();
+        assert_eq!(record.status, VerificationStatus::Mismatch);
+        assert_eq!(record.first_mismatch_offset, None);
+        assert!(record.note.unwrap().contains("expected"));
+    }
+
+    #[test]
+    fn test_signed_record() {
+        let dir = tempfile::tempdir().unwrap();
+        let key_path = dir.path().join("keys").join("verification-key.pem");
+        let signing = SigningKey::load_or_create(&key_path).unwrap();
+        assert_eq!(SigningKey::load_or_create(&key_path).unwrap().key_id(), signing.key_id());
+        // Checking needs only the exported public key
+        let public_pem = std::fs::read_to_string(dir.path().join("keys").join("verification-key.pem.pub")).unwrap();
+        let key = VerificationKey::from_pem(&public_pem).unwrap();
+        assert_eq!(key, signing.verification_key());
+
+        let mut record = VerificationRecord::unverifiable("boot.img", "fastboot:boot", "no read-back").with_case(Some("CASE-7"));
+        assert!(!record.verify_signature(&key));
+        record.sign(&signing).unwrap();
+        assert!(record.verify_signature(&key));
+
+        let parsed: VerificationRecord = serde_json::from_str(&record.to_json().unwrap()).unwrap();
+        assert!(parsed.verify_signature(&key));
+        assert_eq!(parsed.signer().unwrap().key_id(), signing.key_id());
+
+        let mut tampered = parsed.clone();
+        tampered.status = VerificationStatus::Verified;
+        assert!(!tampered.verify_signature(&key));
+        // Only written when set, so records signed before the field existed still verify
+        assert!(!record.to_json().unwrap().contains("partition_table_relocated"));
+        let mut relocated = parsed.clone();
+        relocated.partition_table_relocated = true;
+        assert!(!relocated.verify_signature(&key));
+        assert!(!parsed.verify_signature(&SigningKey::generate().unwrap().verification_key()));
+    }
+}
@@ -1,4 +1,7 @@
-use crate::Result;
+use super::stream::{ImageWriter, WriteSummary};
+use crate::{BootforgeError, Result};
+use std::io::Cursor;
+use std::path::Path;
 
 pub struct RawWriter;
 pub struct ApfsWriter;
@@ -6,29 +9,39 @@ pub struct NtfsWriter;
 pub struct ExtWriter;
 
 impl RawWriter {
-    pub async fn write_raw(_data: &[u8], _target: &str) -> Result<()> {
-        log::info!("Writing raw image to {}", _target);
-        Ok(())
+    pub async fn write_raw(data: &[u8], target: &str) -> Result<WriteSummary> {
+        log::info!("Writing raw image to {}", target);
+        let data = data.to_vec();
+        let target = target.to_string();
+
+        tokio::task::spawn_blocking(move || {
+            let len = data.len() as u64;
+            ImageWriter::default().write_from(Cursor::new(data), Some(len), Path::new(&target))
+        })
+        .await
+        .map_err(|e| BootforgeError::Imaging(format!("Image write task failed: {}", e)))?
     }
 }
 
+// Filesystem images are complete volumes, so they are written block for block.
+
 impl ApfsWriter {
-    pub async fn write_apfs(_image: &str, _target: &str) -> Result<()> {
-        log::info!("Writing APFS image to {}", _target);
-        Ok(())
+    pub async fn write_apfs(image: &str, target: &str) -> Result<WriteSummary> {
+        log::info!("Writing APFS image to {}", target);
+        ImageWriter::default().write_file(Path::new(image), Path::new(target)).await
     }
 }
 
 impl NtfsWriter {
-    pub async fn write_ntfs(_image: &str, _target: &str) -> Result<()> {
-        log::info!("Writing NTFS image to {}", _target);
-        Ok(())
+    pub async fn write_ntfs(image: &str, target: &str) -> Result<WriteSummary> {
+        log::info!("Writing NTFS image to {}", target);
+        ImageWriter::default().write_file(Path::new(image), Path::new(target)).await
     }
 }
 
 impl ExtWriter {
-    pub async fn write_ext(_image: &str, _target: &str) -> Result<()> {
-        log::info!("Writing EXT image to {}", _target);
-        Ok(())
+    pub async fn write_ext(image: &str, target: &str) -> Result<WriteSummary> {
+        log::info!("Writing EXT image to {}", target);
+        ImageWriter::default().write_file(Path::new(image), Path::new(target)).await
     }
 }
@@ -6,6 +6,10 @@ pub mod utils;
 pub mod bridge;
 pub mod thermal;
 pub mod storage;
+pub mod builder;
+
+#[cfg(test)]
+mod test_util;
 
 use thiserror::Error;
 
@@ -43,7 +47,11 @@ pub use usb::{
     DeviceWatcher,
     WatcherConfig,
     detect_devices,
+    detect_devices_with,
     detect_mobile_devices,
+    UsbBackend,
+    NusbBackend,
+    FixtureBackend,
 };
 
 pub use thermal::{
@@ -330,7 +330,7 @@ impl SmartParser {
             }
 
             if let Some(cap_str) = line.split("size:").nth(1) {
-                if let Ok(cap) = cap_str.trim().split_whitespace().next()
+                if let Ok(cap) = cap_str.split_whitespace().next()
                     .unwrap_or("0").parse::<u64>() 
                 {
                     capacity = cap;
@@ -0,0 +1,7 @@
+//! Fixtures shared by test modules across the crate.
+
+/// Deterministic filler bytes. The prime period of 251 keeps the pattern
+/// from lining up with block boundaries.
+pub(crate) fn pattern(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i * 31 % 251) as u8).collect()
+}
@@ -181,7 +181,7 @@ impl ThermalMonitor {
 
     pub fn record_snapshot(&mut self, snapshot: ThermalSnapshot) {
         let device_id = snapshot.device_id.clone();
-        let history = self.history.entry(device_id).or_insert_with(Vec::new);
+        let history = self.history.entry(device_id).or_default();
         
         history.push(snapshot);
         
@@ -330,9 +330,9 @@ mod tests {
     fn test_thermal_state_classification() {
         assert_eq!(ThermalState::from_celsius(25.0), ThermalState::Normal);
         assert_eq!(ThermalState::from_celsius(45.0), ThermalState::Warm);
-        assert_eq!(ThermalState::from_celsius(55.0), ThermalState::Hot);
-        assert_eq!(ThermalState::from_celsius(65.0), ThermalState::Critical);
-        assert_eq!(ThermalState::from_celsius(75.0), ThermalState::Shutdown);
+        assert_eq!(ThermalState::from_celsius(52.0), ThermalState::Hot);
+        assert_eq!(ThermalState::from_celsius(55.0), ThermalState::Critical);
+        assert_eq!(ThermalState::from_celsius(65.0), ThermalState::Shutdown);
     }
 
     #[test]
@@ -356,12 +356,12 @@ mod tests {
     fn test_thermal_snapshot() {
         let readings = vec![
             ThermalReading::new(ThermalZone::Battery, 35.0),
-            ThermalReading::new(ThermalZone::CPU, 55.0),
+            ThermalReading::new(ThermalZone::CPU, 52.0),
         ];
         
         let snapshot = ThermalSnapshot::from_readings("test-device".to_string(), readings);
         
-        assert_eq!(snapshot.max_temperature, 55.0);
+        assert_eq!(snapshot.max_temperature, 52.0);
         assert_eq!(snapshot.overall_state, ThermalState::Hot);
         assert!(!snapshot.safe_for_imaging);
     }
@@ -1,5 +1,5 @@
-/// Bridge module for integrating trapdoor with bobby_dev Python/TypeScript layers
-/// This provides FFI-friendly interfaces for cross-language communication
+//! Bridge module for integrating trapdoor with bobby_dev Python/TypeScript layers
+//! This provides FFI-friendly interfaces for cross-language communication
 
 use super::{TrapdoorTool, TrapdoorRunner, TrapdoorConfig};
 use serde::{Deserialize, Serialize};
@@ -1,5 +1,5 @@
-/// Automatic tool downloader module
-/// Downloads and installs trapdoor tools from configured sources
+//! Automatic tool downloader module
+//! Downloads and installs trapdoor tools from configured sources
 
 use crate::Result;
 use super::TrapdoorTool;
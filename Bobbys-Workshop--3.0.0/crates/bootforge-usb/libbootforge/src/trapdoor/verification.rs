@@ -1,10 +1,8 @@
-/// Tool signature verification module
-/// Verifies tool integrity using SHA-256 checksums
+//! Tool signature verification module
+//! Verifies tool integrity using SHA-256 checksums
 
 use crate::Result;
-use sha2::{Sha256, Digest};
-use std::fs::File;
-use std::io::Read;
+use crate::utils::hash::{HashAlgorithm, HashService};
 use std::path::Path;
 
 /// Tool signature information
@@ -58,24 +56,13 @@ impl Default for SignatureDatabase {
 
 /// Compute SHA-256 hash of a file
 pub fn compute_file_hash(path: &Path) -> Result<String> {
-    let mut file = File::open(path)
-        .map_err(|e| crate::BootforgeError::Trapdoor(format!("Failed to open file: {}", e)))?;
-    
-    let mut hasher = Sha256::new();
-    let mut buffer = [0u8; 8192];
-    
-    loop {
-        let n = file.read(&mut buffer)
-            .map_err(|e| crate::BootforgeError::Trapdoor(format!("Failed to read file: {}", e)))?;
-        
-        if n == 0 {
-            break;
-        }
-        
-        hasher.update(&buffer[..n]);
-    }
-    
-    Ok(format!("{:x}", hasher.finalize()))
+    let digests = HashService::new()
+        .hash_file_blocking(path, &[HashAlgorithm::Sha256])
+        .map_err(|e| crate::BootforgeError::Trapdoor(e.to_string()))?;
+
+    digests.get(HashAlgorithm::Sha256)
+        .map(str::to_string)
+        .ok_or_else(|| crate::BootforgeError::Trapdoor(format!("Failed to hash {}", path.display())))
 }
 
 /// Verify a tool against a known signature
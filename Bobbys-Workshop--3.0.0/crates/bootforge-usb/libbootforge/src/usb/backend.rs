@@ -0,0 +1,382 @@
+use crate::{BootforgeError, Result};
+use super::classify::{classify_protocol, InterfaceHint};
+use super::detect::{ProtocolType, UsbDeviceInfo};
+use serde::Deserialize;
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+/// How long `NusbBackend::probe_protocol` waits for each interface string.
+const PROBE_STRING_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// A single device arriving on or leaving the bus.
+#[derive(Debug, Clone)]
+pub enum HotplugEvent {
+    Attached(UsbDeviceInfo),
+    Detached { unique_key: String },
+}
+
+pub type HotplugReceiver = mpsc::UnboundedReceiver<HotplugEvent>;
+
+/// Source of USB enumeration data for `detect_devices_with`, `DeviceWatcher` and `BootforgeBridge`.
+pub trait UsbBackend: Send + Sync {
+    /// Short name used in log output.
+    fn name(&self) -> &'static str;
+
+    /// Enumerate the devices currently present on the bus.
+    fn list_devices(&self) -> Result<Vec<UsbDeviceInfo>>;
+
+    /// Whether scans from this backend should update the on-disk device cache.
+    /// Simulated buses return `false` so they never pollute real device history.
+    fn persist_cache(&self) -> bool {
+        true
+    }
+
+    /// Subscribe to per-device arrival/removal events.
+    ///
+    /// Returns `Ok(None)` when the backend cannot deliver hotplug events, in
+    /// which case callers fall back to polling `list_devices`. Must be called
+    /// from within a Tokio runtime.
+    fn hotplug(&self) -> Result<Option<HotplugReceiver>> {
+        Ok(None)
+    }
+
+    /// Determine which protocol the device speaks. Called by `DeviceWatcher`
+    /// when `WatcherConfig::auto_probe` is set. Defaults to the protocol
+    /// inferred at enumeration time.
+    fn probe_protocol(&self, device: &UsbDeviceInfo) -> Result<ProtocolType> {
+        Ok(device.protocol)
+    }
+}
+
+/// Live enumeration through nusb.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NusbBackend;
+
+impl UsbBackend for NusbBackend {
+    fn name(&self) -> &'static str {
+        "nusb"
+    }
+
+    fn list_devices(&self) -> Result<Vec<UsbDeviceInfo>> {
+        let now = chrono::Utc::now();
+        let device_list = nusb::list_devices().map_err(|e| {
+            log::error!("[BootForge] USB enumeration failed: {}", e);
+            BootforgeError::Usb(format!("Failed to enumerate USB devices: {}", e))
+        })?;
+
+        let device_list: Vec<nusb::DeviceInfo> = device_list.collect();
+        Ok(device_list
+            .iter()
+            .map(|d| super::detect::device_info_from_nusb(d, &device_list, now))
+            .collect())
+    }
+
+    /// Uses nusb's hotplug watch (netlink uevents on Linux, IOKit/cfgmgr32
+    /// notifications elsewhere) and only builds info for the device that changed.
+    fn hotplug(&self) -> Result<Option<HotplugReceiver>> {
+        use futures_lite::StreamExt;
+
+        // Create the watch before listing so no arrival falls between the two.
+        let mut watch = nusb::watch_devices()
+            .map_err(|e| BootforgeError::Usb(format!("Failed to start USB hotplug watch: {}", e)))?;
+
+        let now = chrono::Utc::now();
+        let device_list: Vec<nusb::DeviceInfo> = nusb::list_devices()
+            .map_err(|e| BootforgeError::Usb(format!("Failed to enumerate USB devices: {}", e)))?
+            .collect();
+        let mut known: HashMap<nusb::DeviceId, String> = device_list
+            .iter()
+            .map(|d| (d.id(), super::detect::device_info_from_nusb(d, &device_list, now).unique_key()))
+            .collect();
+
+        let (tx, rx) = mpsc::unbounded_channel();
+
+        tokio::spawn(async move {
+            while let Some(event) = watch.next().await {
+                let event = match event {
+                    nusb::hotplug::HotplugEvent::Connected(d) => {
+                        let enumerated = super::detect::enumerated_for_chain();
+                        let info = super::detect::device_info_from_nusb(&d, &enumerated, chrono::Utc::now());
+                        known.insert(d.id(), info.unique_key());
+                        HotplugEvent::Attached(info)
+                    }
+                    nusb::hotplug::HotplugEvent::Disconnected(id) => match known.remove(&id) {
+                        Some(unique_key) => HotplugEvent::Detached { unique_key },
+                        None => continue,
+                    },
+                };
+
+                if tx.send(event).is_err() {
+                    break;
+                }
+            }
+            log::debug!("[BootForge] nusb hotplug watch ended");
+        });
+
+        Ok(Some(rx))
+    }
+
+    /// Classify the device from the interfaces of its active configuration,
+    /// reading the interface strings the OS had not cached at enumeration.
+    /// Devices this process may not open are classified from the
+    /// descriptors seen at enumeration instead.
+    fn probe_protocol(&self, device: &UsbDeviceInfo) -> Result<ProtocolType> {
+        let info = super::transport::find_nusb_device(device)?;
+        let table = super::detect::table_protocol(device.vendor_id, device.product_id, &device.mode);
+
+        let handle = match info.open() {
+            Ok(handle) => handle,
+            Err(e) => {
+                log::debug!("[BootForge] Cannot open {} to probe it ({}), using enumeration descriptors", device.unique_key(), e);
+                return Ok(classify_protocol(&device.interfaces, table));
+            }
+        };
+        let config = handle.active_configuration()
+            .map_err(|e| BootforgeError::Usb(format!("Failed to read configuration of {}: {}", device.unique_key(), e)))?;
+
+        let interfaces: Vec<InterfaceHint> = config.interface_alt_settings()
+            .filter(|alt| alt.alternate_setting() == 0)
+            .map(|alt| {
+                let mut hint = InterfaceHint::new(alt.interface_number(), alt.class(), alt.subclass(), alt.protocol());
+                hint.name = alt.string_index().and_then(|index| {
+                    handle.get_string_descriptor(index, nusb::descriptors::language_id::US_ENGLISH, PROBE_STRING_TIMEOUT).ok()
+                });
+                hint
+            })
+            .collect();
+
+        Ok(classify_protocol(&interfaces, table))
+    }
+}
+
+/// A JSON capture is either a single device list or a sequence of scans.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum FixtureCapture {
+    Sequence(Vec<Vec<UsbDeviceInfo>>),
+    Single(Vec<UsbDeviceInfo>),
+}
+
+/// In-memory bus for tests and replaying recorded captures.
+///
+/// Queued snapshots are consumed one per `list_devices` call; once the queue is
+/// empty the last snapshot (or whatever was set via `connect`/`disconnect`) is
+/// returned on every subsequent scan. `connect` and `disconnect` also emit
+/// hotplug events to subscribers.
+#[derive(Debug, Default)]
+pub struct FixtureBackend {
+    current: Mutex<Vec<UsbDeviceInfo>>,
+    pending: Mutex<VecDeque<Vec<UsbDeviceInfo>>>,
+    subscribers: Mutex<Vec<mpsc::UnboundedSender<HotplugEvent>>>,
+    probe_results: Mutex<HashMap<String, ProtocolType>>,
+}
+
+impl FixtureBackend {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_devices(devices: Vec<UsbDeviceInfo>) -> Self {
+        FixtureBackend {
+            current: Mutex::new(devices),
+            ..Default::default()
+        }
+    }
+
+    pub fn from_snapshots(snapshots: Vec<Vec<UsbDeviceInfo>>) -> Self {
+        FixtureBackend {
+            pending: Mutex::new(snapshots.into()),
+            ..Default::default()
+        }
+    }
+
+    pub fn from_json(json: &str) -> Result<Self> {
+        let capture: FixtureCapture = serde_json::from_str(json)
+            .map_err(|e| BootforgeError::Usb(format!("Invalid USB fixture: {}", e)))?;
+
+        Ok(match capture {
+            FixtureCapture::Sequence(snapshots) => Self::from_snapshots(snapshots),
+            FixtureCapture::Single(devices) => Self::with_devices(devices),
+        })
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let json = fs::read_to_string(path).map_err(|e| {
+            BootforgeError::Usb(format!("Failed to read USB fixture {:?}: {}", path, e))
+        })?;
+        Self::from_json(&json)
+    }
+
+    /// Queue a scan result to be returned after any already-queued snapshots.
+    pub fn push_snapshot(&self, devices: Vec<UsbDeviceInfo>) {
+        self.pending.lock().unwrap_or_else(|p| p.into_inner()).push_back(devices);
+    }
+
+    pub fn set_devices(&self, devices: Vec<UsbDeviceInfo>) {
+        *self.current.lock().unwrap_or_else(|p| p.into_inner()) = devices;
+    }
+
+    pub fn connect(&self, device: UsbDeviceInfo) {
+        self.current.lock().unwrap_or_else(|p| p.into_inner()).push(device.clone());
+        self.emit(HotplugEvent::Attached(device));
+    }
+
+    pub fn disconnect(&self, unique_key: &str) -> Option<UsbDeviceInfo> {
+        let removed = {
+            let mut current = self.current.lock().unwrap_or_else(|p| p.into_inner());
+            let pos = current.iter().position(|d| d.unique_key() == unique_key)?;
+            current.remove(pos)
+        };
+        self.emit(HotplugEvent::Detached { unique_key: unique_key.to_string() });
+        Some(removed)
+    }
+
+    /// Override what `probe_protocol` reports for the device with `unique_key`.
+    pub fn set_probe_result(&self, unique_key: &str, protocol: ProtocolType) {
+        self.probe_results
+            .lock()
+            .unwrap_or_else(|p| p.into_inner())
+            .insert(unique_key.to_string(), protocol);
+    }
+
+    fn emit(&self, event: HotplugEvent) {
+        self.subscribers
+            .lock()
+            .unwrap_or_else(|p| p.into_inner())
+            .retain(|tx| tx.send(event.clone()).is_ok());
+    }
+}
+
+impl UsbBackend for FixtureBackend {
+    fn name(&self) -> &'static str {
+        "fixture"
+    }
+
+    fn list_devices(&self) -> Result<Vec<UsbDeviceInfo>> {
+        let mut current = self.current.lock().unwrap_or_else(|p| p.into_inner());
+        if let Some(next) = self.pending.lock().unwrap_or_else(|p| p.into_inner()).pop_front() {
+            *current = next;
+        }
+        Ok(current.clone())
+    }
+
+    fn persist_cache(&self) -> bool {
+        false
+    }
+
+    fn hotplug(&self) -> Result<Option<HotplugReceiver>> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.subscribers.lock().unwrap_or_else(|p| p.into_inner()).push(tx);
+        Ok(Some(rx))
+    }
+
+    fn probe_protocol(&self, device: &UsbDeviceInfo) -> Result<ProtocolType> {
+        Ok(self.probe_results
+            .lock()
+            .unwrap_or_else(|p| p.into_inner())
+            .get(&device.unique_key())
+            .copied()
+            .unwrap_or(device.protocol))
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::usb::{DevicePlatform, DeviceMode, DeviceState, ProtocolType};
+
+    pub(crate) fn fixture_device(vid: u16, pid: u16, serial: &str, mode: DeviceMode) -> UsbDeviceInfo {
+        let now = chrono::Utc::now();
+        UsbDeviceInfo {
+            id: uuid::Uuid::new_v4(),
+            vendor_id: vid,
+            product_id: pid,
+            serial: Some(serial.to_string()),
+            manufacturer: None,
+            product: None,
+            platform: crate::usb::map_vendor_to_platform(vid, pid),
+            mode,
+            state: DeviceState::Identified,
+            protocol: ProtocolType::Unknown,
+            bus: Some(1),
+            port: None,
+            port_path: Vec::new(),
+            parent_hub: None,
+            interfaces: Vec::new(),
+            speed: None,
+            first_seen: now,
+            last_seen: now,
+        }
+    }
+
+    #[test]
+    fn test_snapshots_replay_in_order() {
+        let pixel = fixture_device(0x18d1, 0x4ee1, "PIXEL", DeviceMode::Normal);
+        let backend = FixtureBackend::from_snapshots(vec![vec![], vec![pixel.clone()], vec![]]);
+
+        assert!(backend.list_devices().unwrap().is_empty());
+        assert_eq!(backend.list_devices().unwrap().len(), 1);
+        assert!(backend.list_devices().unwrap().is_empty());
+        // Queue exhausted: the last snapshot sticks
+        assert!(backend.list_devices().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_connect_disconnect() {
+        let backend = FixtureBackend::new();
+        let pixel = fixture_device(0x18d1, 0x4ee1, "PIXEL", DeviceMode::Normal);
+        let key = pixel.unique_key();
+
+        backend.connect(pixel);
+        assert_eq!(backend.list_devices().unwrap().len(), 1);
+        assert!(backend.disconnect(&key).is_some());
+        assert!(backend.list_devices().unwrap().is_empty());
+        assert!(backend.disconnect(&key).is_none());
+    }
+
+    #[test]
+    fn test_connect_disconnect_emit_hotplug_events() {
+        let backend = FixtureBackend::new();
+        let mut rx = backend.hotplug().unwrap().unwrap();
+        let pixel = fixture_device(0x18d1, 0x4ee1, "PIXEL", DeviceMode::Normal);
+        let key = pixel.unique_key();
+
+        backend.connect(pixel);
+        backend.disconnect(&key);
+
+        assert!(matches!(rx.try_recv().unwrap(), HotplugEvent::Attached(ref d) if d.unique_key() == key));
+        assert!(matches!(rx.try_recv().unwrap(), HotplugEvent::Detached { ref unique_key } if *unique_key == key));
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_load_json_capture() {
+        let pixel = fixture_device(0x18d1, 0x4ee1, "PIXEL", DeviceMode::Normal);
+        let single = serde_json::to_string(&vec![pixel.clone()]).unwrap();
+        let sequence = serde_json::to_string(&vec![vec![pixel.clone()], vec![]]).unwrap();
+
+        let backend = FixtureBackend::from_json(&single).unwrap();
+        assert_eq!(backend.list_devices().unwrap()[0].platform, DevicePlatform::Google);
+
+        let backend = FixtureBackend::from_json(&sequence).unwrap();
+        assert_eq!(backend.list_devices().unwrap().len(), 1);
+        assert!(backend.list_devices().unwrap().is_empty());
+
+        assert!(FixtureBackend::from_json("{\"not\": \"a capture\"}").is_err());
+    }
+
+    #[test]
+    fn test_detect_devices_with_fixture() {
+        let backend = FixtureBackend::with_devices(vec![
+            fixture_device(0x18d1, 0x4ee1, "PIXEL", DeviceMode::Normal),
+            fixture_device(0x1d6b, 0x0002, "HUB", DeviceMode::Unknown),
+        ]);
+
+        let devices = crate::usb::detect_devices_with(&backend).unwrap();
+        assert_eq!(devices.len(), 2);
+    }
+}
@@ -1,9 +1,29 @@
 use crate::{BootforgeError, Result};
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 use std::collections::HashMap;
-use std::fs;
+use std::fs::{self, File, OpenOptions};
+use std::io::{ErrorKind, Write};
 use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+use uuid::Uuid;
+
+use super::detect::{DeviceMode, UsbDeviceInfo};
+use super::identity::REENUMERATION_WINDOW_SECS;
+
+/// Schema version written by this build. Older caches are migrated on load.
+pub const CACHE_VERSION: u32 = 3;
+
+/// Overrides the cache file location (full path to the JSON file).
+pub const CACHE_PATH_ENV: &str = "BOOTFORGE_CACHE_PATH";
+
+/// Oldest mode transitions are dropped once the cache holds this many.
+pub const MAX_MODE_TRANSITIONS: usize = 5000;
+/// Oldest connection sessions are dropped once the cache holds this many.
+pub const MAX_SESSIONS: usize = 5000;
+
+static CACHE_PATH_OVERRIDE: RwLock<Option<PathBuf>> = RwLock::new(None);
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DeviceCacheEntry {
@@ -11,19 +31,88 @@ pub struct DeviceCacheEntry {
     pub first_seen: DateTime<Utc>,
     pub last_seen: DateTime<Utc>,
     pub seen_count: u64,
+    /// Logical device this enumeration belongs to, shared by every mode of one
+    /// handset. Assigned by `identity::DeviceCorrelator`.
+    #[serde(default)]
+    pub logical_id: Option<Uuid>,
+    #[serde(default)]
+    pub vendor_id: Option<u16>,
+    #[serde(default)]
+    pub serial: Option<String>,
+    #[serde(default)]
+    pub bus: Option<u8>,
+    #[serde(default)]
+    pub port_path: Vec<u8>,
+    /// Mode the device was in when last seen with this key.
+    #[serde(default)]
+    pub mode: Option<DeviceMode>,
+    /// Times the logical device was plugged in while enumerating with this
+    /// key. `seen_count` counts every scan that saw it.
+    #[serde(default)]
+    pub connection_count: u64,
+}
+
+impl DeviceCacheEntry {
+    fn new(unique_key: String, first_seen: DateTime<Utc>) -> Self {
+        DeviceCacheEntry {
+            unique_key,
+            first_seen,
+            last_seen: first_seen,
+            seen_count: 0,
+            logical_id: None,
+            vendor_id: None,
+            serial: None,
+            bus: None,
+            port_path: Vec::new(),
+            mode: None,
+            connection_count: 0,
+        }
+    }
+}
+
+/// A logical device re-enumerating in a different mode.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ModeTransition {
+    pub logical_id: Uuid,
+    pub at: DateTime<Utc>,
+    pub from_mode: DeviceMode,
+    pub to_mode: DeviceMode,
+    pub from_product_id: Option<u16>,
+    pub to_product_id: u16,
+}
+
+/// One connection of a logical device: from the sighting that counted as a
+/// new connection to the last sighting before it went away.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DeviceSession {
+    pub logical_id: Uuid,
+    pub connected_at: DateTime<Utc>,
+    pub last_seen: DateTime<Utc>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DeviceCache {
     pub devices: HashMap<String, DeviceCacheEntry>,
     pub version: u32,
+    /// Human labels for physical ports, keyed by location (`1-2.3`).
+    #[serde(default)]
+    pub port_labels: HashMap<String, String>,
+    /// Mode changes of logical devices, oldest first.
+    #[serde(default)]
+    pub transitions: Vec<ModeTransition>,
+    /// Connections of logical devices, oldest first.
+    #[serde(default)]
+    pub sessions: Vec<DeviceSession>,
 }
 
 impl DeviceCache {
     pub fn new() -> Self {
         DeviceCache {
             devices: HashMap::new(),
-            version: 1,
+            version: CACHE_VERSION,
+            port_labels: HashMap::new(),
+            transitions: Vec::new(),
+            sessions: Vec::new(),
         }
     }
 
@@ -32,14 +121,8 @@ impl DeviceCache {
     }
 
     pub fn update_entry(&mut self, unique_key: String, last_seen: DateTime<Utc>) {
-        let entry = self.devices.entry(unique_key.clone()).or_insert_with(|| {
-            DeviceCacheEntry {
-                unique_key: unique_key.clone(),
-                first_seen: last_seen,
-                last_seen,
-                seen_count: 0,
-            }
-        });
+        let entry = self.devices.entry(unique_key.clone())
+            .or_insert_with(|| DeviceCacheEntry::new(unique_key, last_seen));
 
         entry.last_seen = last_seen;
         entry.seen_count += 1;
@@ -49,20 +132,128 @@ impl DeviceCache {
         match self.devices.get(unique_key) {
             Some(entry) => entry.first_seen,
             None => {
-                let entry = DeviceCacheEntry {
-                    unique_key: unique_key.to_string(),
-                    first_seen: default,
-                    last_seen: default,
-                    seen_count: 0,
-                };
+                let entry = DeviceCacheEntry::new(unique_key.to_string(), default);
                 self.devices.insert(unique_key.to_string(), entry);
                 default
             }
         }
     }
+
+    /// Record a correlated enumeration, keeping the identifying details the
+    /// correlator matches on for next time.
+    ///
+    /// A sighting counts as a new connection when the logical device was not
+    /// seen in any mode for longer than the re-enumeration window, and as a
+    /// mode transition when its previous enumeration was in another mode.
+    pub fn record_device(&mut self, info: &UsbDeviceInfo, first_seen: DateTime<Utc>, now: DateTime<Utc>) {
+        let unique_key = info.unique_key();
+
+        let previous = self.entries_for(info.id)
+            .filter(|e| e.last_seen <= now)
+            .max_by_key(|e| e.last_seen)
+            .map(|e| (e.last_seen, e.mode, parse_product_id(&e.unique_key)));
+
+        let reconnected = match previous {
+            Some((last_seen, _, _)) => now - last_seen > chrono::Duration::seconds(REENUMERATION_WINDOW_SECS),
+            None => true,
+        };
+
+        if let Some((_, Some(from_mode), from_product_id)) = previous {
+            if from_mode != info.mode {
+                self.push_transition(ModeTransition {
+                    logical_id: info.id,
+                    at: now,
+                    from_mode,
+                    to_mode: info.mode,
+                    from_product_id,
+                    to_product_id: info.product_id,
+                });
+            }
+        }
+
+        let entry = self.devices.entry(unique_key.clone())
+            .or_insert_with(|| DeviceCacheEntry::new(unique_key, first_seen));
+
+        entry.first_seen = entry.first_seen.min(first_seen);
+        entry.last_seen = now;
+        entry.seen_count += 1;
+        entry.logical_id = Some(info.id);
+        entry.vendor_id = Some(info.vendor_id);
+        entry.serial = info.serial.clone().filter(|s| !s.is_empty());
+        entry.bus = info.bus;
+        entry.port_path = info.port_path.clone();
+        entry.mode = Some(info.mode);
+        if reconnected {
+            entry.connection_count += 1;
+        }
+        self.record_session(info.id, reconnected, now);
+    }
+
+    /// Start a session on a new connection, otherwise extend the device's
+    /// latest one. Caches from before sessions were recorded get one started
+    /// at the first sighting after the upgrade.
+    fn record_session(&mut self, logical_id: Uuid, reconnected: bool, now: DateTime<Utc>) {
+        let current = self.sessions.iter_mut().rev().find(|s| s.logical_id == logical_id);
+        match current {
+            Some(session) if !reconnected => session.last_seen = session.last_seen.max(now),
+            _ => {
+                self.sessions.push(DeviceSession { logical_id, connected_at: now, last_seen: now });
+                if self.sessions.len() > MAX_SESSIONS {
+                    let excess = self.sessions.len() - MAX_SESSIONS;
+                    self.sessions.drain(..excess);
+                }
+            }
+        }
+    }
+
+    fn push_transition(&mut self, transition: ModeTransition) {
+        self.transitions.push(transition);
+        if self.transitions.len() > MAX_MODE_TRANSITIONS {
+            let excess = self.transitions.len() - MAX_MODE_TRANSITIONS;
+            self.transitions.drain(..excess);
+        }
+    }
+
+    /// All enumerations recorded for one logical device.
+    pub fn entries_for(&self, logical_id: Uuid) -> impl Iterator<Item = &DeviceCacheEntry> {
+        self.devices.values().filter(move |e| e.logical_id == Some(logical_id))
+    }
+
+    /// Earliest sighting of a logical device in any mode.
+    pub fn first_seen_of(&self, logical_id: Uuid) -> Option<DateTime<Utc>> {
+        self.entries_for(logical_id).map(|e| e.first_seen).min()
+    }
 }
 
+impl Default for DeviceCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Use `path` for the device cache instead of the platform default, or go
+/// back to the default with `None`. Takes precedence over `BOOTFORGE_CACHE_PATH`.
+pub fn set_cache_path(path: Option<PathBuf>) {
+    let mut current = CACHE_PATH_OVERRIDE.write().unwrap_or_else(|e| e.into_inner());
+    *current = path;
+}
+
+/// Location of the device cache: `set_cache_path`, then `BOOTFORGE_CACHE_PATH`,
+/// then the per-user data directory.
 pub fn get_cache_path() -> Result<PathBuf> {
+    let configured = CACHE_PATH_OVERRIDE.read().unwrap_or_else(|e| e.into_inner()).clone();
+    if let Some(path) = configured {
+        return Ok(path);
+    }
+
+    if let Some(path) = std::env::var_os(CACHE_PATH_ENV).filter(|p| !p.is_empty()) {
+        return Ok(PathBuf::from(path));
+    }
+
+    default_cache_path()
+}
+
+fn default_cache_path() -> Result<PathBuf> {
     #[cfg(target_os = "windows")]
     {
         let local_app_data = std::env::var("LOCALAPPDATA")
@@ -73,7 +264,7 @@ pub fn get_cache_path() -> Result<PathBuf> {
         Ok(cache_dir.join("devices.json"))
     }
 
-    #[cfg(any(target_os = "macos", target_os = "linux"))]
+    #[cfg(not(target_os = "windows"))]
     {
         let home = std::env::var("HOME")
             .map_err(|_| BootforgeError::Usb("Could not determine home directory".to_string()))?;
@@ -84,51 +275,388 @@ pub fn get_cache_path() -> Result<PathBuf> {
 }
 
 pub fn load_cache() -> Result<DeviceCache> {
-    let cache_path = get_cache_path()?;
-    
-    if !cache_path.exists() {
-        log::debug!("Cache file does not exist, creating new cache: {:?}", cache_path);
+    load_cache_from(&get_cache_path()?)
+}
+
+pub fn save_cache(cache: &DeviceCache) -> Result<()> {
+    save_cache_to(&get_cache_path()?, cache)
+}
+
+/// Load, modify and save the cache while holding its lock, so concurrent
+/// scans (CLI, Tauri app, watcher) do not overwrite each other's entries.
+/// Prefer this over a separate `load_cache` / `save_cache` pair.
+pub fn update_cache<T>(f: impl FnOnce(&mut DeviceCache) -> T) -> Result<T> {
+    update_cache_at(&get_cache_path()?, f)
+}
+
+/// Read the cache at `path` under a shared lock. A missing file is an empty
+/// cache; an unreadable one is moved aside and replaced by an empty cache.
+pub fn load_cache_from(path: &Path) -> Result<DeviceCache> {
+    if !path.exists() {
+        log::debug!("Cache file does not exist, creating new cache: {:?}", path);
         return Ok(DeviceCache::new());
     }
 
-    match fs::read_to_string(&cache_path) {
-        Ok(content) => {
-            match serde_json::from_str::<DeviceCache>(&content) {
-                Ok(cache) => {
-                    log::debug!("Loaded device cache: {} entries", cache.devices.len());
-                    Ok(cache)
-                }
-                Err(e) => {
-                    log::warn!("Failed to parse cache file, creating new cache: {}", e);
-                    Ok(DeviceCache::new())
-                }
-            }
+    {
+        let _lock = CacheLock::acquire(path, false)?;
+        if let CacheFile::Loaded(cache) = parse_cache_file(path)? {
+            return Ok(cache);
+        }
+    }
+
+    // Moving the file aside needs the exclusive lock. Read again under it:
+    // another process may have replaced or quarantined the file meanwhile.
+    let _lock = CacheLock::acquire(path, true)?;
+    read_cache_file(path)
+}
+
+/// Write `cache` to `path` under an exclusive lock.
+pub fn save_cache_to(path: &Path, cache: &DeviceCache) -> Result<()> {
+    let _lock = CacheLock::acquire(path, true)?;
+    write_cache_file(path, cache)
+}
+
+/// `update_cache` against an explicit path.
+pub fn update_cache_at<T>(path: &Path, f: impl FnOnce(&mut DeviceCache) -> T) -> Result<T> {
+    let _lock = CacheLock::acquire(path, true)?;
+    let mut cache = read_cache_file(path)?;
+    let result = f(&mut cache);
+    write_cache_file(path, &cache)?;
+    Ok(result)
+}
+
+/// Advisory lock on a `<cache>.lock` sidecar. The cache file itself is
+/// replaced by rename on every save, so it cannot carry the lock. Released
+/// when dropped.
+struct CacheLock {
+    _file: File,
+}
+
+impl CacheLock {
+    fn acquire(path: &Path, exclusive: bool) -> Result<Self> {
+        if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            fs::create_dir_all(parent).map_err(|e| {
+                BootforgeError::Usb(format!("Failed to create cache directory: {}", e))
+            })?;
+        }
+
+        let lock_path = sibling(path, "lock");
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&lock_path)
+            .map_err(|e| BootforgeError::Usb(format!("Failed to open cache lock {:?}: {}", lock_path, e)))?;
+
+        let locked = if exclusive { file.lock() } else { file.lock_shared() };
+        locked.map_err(|e| BootforgeError::Usb(format!("Failed to lock device cache: {}", e)))?;
+
+        Ok(CacheLock { _file: file })
+    }
+}
+
+fn sibling(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".");
+    name.push(suffix);
+    path.with_file_name(name)
+}
+
+/// Contents of the cache file, which may not parse.
+enum CacheFile {
+    Loaded(DeviceCache),
+    Corrupt(String),
+}
+
+fn parse_cache_file(path: &Path) -> Result<CacheFile> {
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(CacheFile::Loaded(DeviceCache::new())),
+        Err(e) => return Err(BootforgeError::Usb(format!("Failed to read cache file: {}", e))),
+    };
+
+    let parsed = serde_json::from_str::<Value>(&content)
+        .map_err(|e| MigrateError::Invalid(e.to_string()))
+        .and_then(migrate);
+
+    match parsed {
+        Ok(cache) => {
+            log::debug!("Loaded device cache: {} entries", cache.devices.len());
+            Ok(CacheFile::Loaded(cache))
         }
-        Err(e) => {
-            log::warn!("Failed to read cache file, creating new cache: {}", e);
+        Err(MigrateError::TooNew(version)) => Err(BootforgeError::Usb(format!(
+            "Device cache {:?} has version {}, this build supports up to {}",
+            path, version, CACHE_VERSION
+        ))),
+        Err(MigrateError::Invalid(e)) => Ok(CacheFile::Corrupt(e)),
+    }
+}
+
+/// Read the cache, quarantining it if it does not parse. The caller must
+/// hold the exclusive lock.
+fn read_cache_file(path: &Path) -> Result<DeviceCache> {
+    match parse_cache_file(path)? {
+        CacheFile::Loaded(cache) => Ok(cache),
+        CacheFile::Corrupt(reason) => {
+            quarantine(path, &reason);
             Ok(DeviceCache::new())
         }
     }
 }
 
-pub fn save_cache(cache: &DeviceCache) -> Result<()> {
-    let cache_path = get_cache_path()?;
-    
-    // Create parent directory if it doesn't exist
-    if let Some(parent) = cache_path.parent() {
-        fs::create_dir_all(parent).map_err(|e| {
-            BootforgeError::Usb(format!("Failed to create cache directory: {}", e))
-        })?;
+/// Move an unparseable cache out of the way so its history can still be
+/// recovered by hand, rather than overwriting it on the next save.
+fn quarantine(path: &Path, reason: &str) {
+    let backup = sibling(path, &format!("corrupt-{}", Utc::now().format("%Y%m%d%H%M%S")));
+    match fs::rename(path, &backup) {
+        Ok(()) => log::warn!("Device cache is unreadable ({}), moved to {:?}", reason, backup),
+        Err(e) => log::warn!("Device cache is unreadable ({}) and could not be moved aside: {}", reason, e),
     }
+}
 
+fn write_cache_file(path: &Path, cache: &DeviceCache) -> Result<()> {
     let json = serde_json::to_string_pretty(cache).map_err(|e| {
         BootforgeError::Usb(format!("Failed to serialize cache: {}", e))
     })?;
 
-    fs::write(&cache_path, json).map_err(|e| {
-        BootforgeError::Usb(format!("Failed to write cache file: {}", e))
-    })?;
+    // Write next to the cache and rename over it so a crash mid-write never
+    // leaves a truncated file behind
+    let tmp_path = sibling(path, &format!("tmp.{}", std::process::id()));
+    let written = File::create(&tmp_path)
+        .and_then(|mut file| {
+            file.write_all(json.as_bytes())?;
+            file.sync_all()
+        })
+        .and_then(|()| fs::rename(&tmp_path, path));
+
+    if let Err(e) = written {
+        let _ = fs::remove_file(&tmp_path);
+        return Err(BootforgeError::Usb(format!("Failed to write cache file: {}", e)));
+    }
+
+    #[cfg(unix)]
+    if let Some(dir) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        let _ = File::open(dir).and_then(|d| d.sync_all());
+    }
 
-    log::debug!("Saved device cache: {} entries to {:?}", cache.devices.len(), cache_path);
+    log::debug!("Saved device cache: {} entries to {:?}", cache.devices.len(), path);
     Ok(())
 }
+
+#[derive(Debug)]
+enum MigrateError {
+    TooNew(u64),
+    Invalid(String),
+}
+
+/// Bring a cache written by any earlier version up to `CACHE_VERSION`.
+/// Files without a `version` field predate versioning and are treated as v1.
+fn migrate(value: Value) -> std::result::Result<DeviceCache, MigrateError> {
+    let version = value.get("version").and_then(Value::as_u64).unwrap_or(1);
+    if version > u64::from(CACHE_VERSION) {
+        return Err(MigrateError::TooNew(version));
+    }
+
+    let mut cache: DeviceCache = serde_json::from_value(value)
+        .map_err(|e| MigrateError::Invalid(e.to_string()))?;
+
+    if version < 2 {
+        migrate_v1_to_v2(&mut cache);
+    }
+    if version < 3 {
+        migrate_v2_to_v3(&mut cache);
+    }
+
+    if version < u64::from(CACHE_VERSION) {
+        log::info!("Migrated device cache from version {} to {}", version, CACHE_VERSION);
+    }
+    cache.version = CACHE_VERSION;
+    Ok(cache)
+}
+
+/// v1 entries only carried the `vvvv:pppp:serial` key. Recover vendor and
+/// serial from it and give every mode of one handset a shared logical ID, so
+/// existing history survives the switch to correlated identities.
+fn migrate_v1_to_v2(cache: &mut DeviceCache) {
+    let mut by_serial: HashMap<(u16, String), Uuid> = HashMap::new();
+
+    let mut keys: Vec<String> = cache.devices.keys().cloned().collect();
+    keys.sort();
+
+    for key in keys {
+        let Some(entry) = cache.devices.get_mut(&key) else { continue };
+
+        let mut parts = key.splitn(3, ':');
+        let vendor_id = parts.next().and_then(|v| u16::from_str_radix(v, 16).ok());
+        let serial = parts.nth(1).filter(|s| !s.is_empty() && *s != "unknown").map(str::to_string);
+
+        entry.vendor_id = entry.vendor_id.or(vendor_id);
+        entry.serial = entry.serial.take().or(serial);
+
+        if entry.logical_id.is_none() {
+            entry.logical_id = Some(match (entry.vendor_id, &entry.serial) {
+                (Some(vid), Some(serial)) => *by_serial.entry((vid, serial.clone())).or_insert_with(Uuid::new_v4),
+                _ => Uuid::new_v4(),
+            });
+        }
+    }
+}
+
+/// v3 counts connections separately from scans. Older entries were seen at
+/// least once, which is the best lower bound available.
+fn migrate_v2_to_v3(cache: &mut DeviceCache) {
+    for entry in cache.devices.values_mut() {
+        if entry.connection_count == 0 && entry.seen_count > 0 {
+            entry.connection_count = 1;
+        }
+    }
+}
+
+/// Product ID from a `vvvv:pppp:serial` cache key.
+pub(crate) fn parse_product_id(unique_key: &str) -> Option<u16> {
+    unique_key.split(':').nth(1).and_then(|p| u16::from_str_radix(p, 16).ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_save_and_load_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("nested").join("devices.json");
+        let now = Utc::now();
+
+        let mut cache = DeviceCache::new();
+        cache.update_entry("18d1:4ee1:PIXEL".to_string(), now);
+        cache.port_labels.insert("1-2".to_string(), "Bench 1".to_string());
+        save_cache_to(&path, &cache).unwrap();
+
+        let loaded = load_cache_from(&path).unwrap();
+        assert_eq!(loaded.version, CACHE_VERSION);
+        assert_eq!(loaded.devices["18d1:4ee1:PIXEL"].seen_count, 1);
+        assert_eq!(loaded.port_labels["1-2"], "Bench 1");
+        // Only the cache and its lock remain, no temp files
+        let mut names: Vec<_> = fs::read_dir(path.parent().unwrap()).unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        assert_eq!(names, ["devices.json", "devices.json.lock"]);
+    }
+
+    #[test]
+    fn test_concurrent_updates_are_not_lost() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = Arc::new(dir.path().join("devices.json"));
+
+        let handles: Vec<_> = (0..4).map(|_| {
+            let path = Arc::clone(&path);
+            std::thread::spawn(move || {
+                for _ in 0..25 {
+                    update_cache_at(&path, |cache| cache.update_entry("05c6:9008:unknown".to_string(), Utc::now()))
+                        .unwrap();
+                }
+            })
+        }).collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        assert_eq!(load_cache_from(&path).unwrap().devices["05c6:9008:unknown"].seen_count, 100);
+    }
+
+    #[test]
+    fn test_v1_cache_is_migrated() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("devices.json");
+        fs::write(&path, r#"{
+            "version": 1,
+            "devices": {
+                "18d1:4ee1:PIXEL": {"unique_key": "18d1:4ee1:PIXEL", "first_seen": "2024-01-01T00:00:00Z",
+                    "last_seen": "2024-02-01T00:00:00Z", "seen_count": 7},
+                "18d1:4ee0:PIXEL": {"unique_key": "18d1:4ee0:PIXEL", "first_seen": "2024-01-05T00:00:00Z",
+                    "last_seen": "2024-01-05T00:00:00Z", "seen_count": 1},
+                "05c6:9008:unknown": {"unique_key": "05c6:9008:unknown", "first_seen": "2024-01-03T00:00:00Z",
+                    "last_seen": "2024-01-03T00:00:00Z", "seen_count": 2}
+            }
+        }"#).unwrap();
+
+        let cache = update_cache_at(&path, |cache| cache.clone()).unwrap();
+        let normal = &cache.devices["18d1:4ee1:PIXEL"];
+        let fastboot = &cache.devices["18d1:4ee0:PIXEL"];
+        let edl = &cache.devices["05c6:9008:unknown"];
+
+        assert_eq!(cache.version, CACHE_VERSION);
+        assert_eq!(normal.seen_count, 7);
+        assert_eq!(normal.vendor_id, Some(0x18d1));
+        assert_eq!(normal.serial.as_deref(), Some("PIXEL"));
+        assert_eq!(normal.logical_id, fastboot.logical_id);
+        assert_eq!(normal.connection_count, 1);
+        assert!(edl.serial.is_none());
+        assert!(edl.logical_id.is_some() && edl.logical_id != normal.logical_id);
+
+        // Migrated form was written back
+        let on_disk: Value = serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(on_disk["version"], CACHE_VERSION);
+    }
+
+    #[test]
+    fn test_corrupt_cache_is_moved_aside() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("devices.json");
+        fs::write(&path, "{ not json").unwrap();
+
+        let cache = load_cache_from(&path).unwrap();
+        assert!(cache.devices.is_empty());
+        assert!(!path.exists());
+        let backups = fs::read_dir(dir.path()).unwrap()
+            .filter(|e| e.as_ref().unwrap().file_name().to_string_lossy().starts_with("devices.json.corrupt-"))
+            .count();
+        assert_eq!(backups, 1);
+    }
+
+    #[test]
+    fn test_corrupt_cache_waits_for_readers() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("devices.json");
+        fs::write(&path, "{ not json").unwrap();
+
+        // Another reader still holds the shared lock; the file stays put until it lets go
+        let reader = CacheLock::acquire(&path, false).unwrap();
+        let loader = std::thread::spawn({
+            let path = path.clone();
+            move || load_cache_from(&path).unwrap()
+        });
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        assert!(path.exists());
+
+        drop(reader);
+        assert!(loader.join().unwrap().devices.is_empty());
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_newer_cache_is_left_alone() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("devices.json");
+        let newer = r#"{"version": 99, "devices": {}}"#;
+        fs::write(&path, newer).unwrap();
+
+        assert!(update_cache_at(&path, |_| ()).is_err());
+        assert_eq!(fs::read_to_string(&path).unwrap(), newer);
+    }
+
+    #[test]
+    fn test_configured_cache_path() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("custom.json");
+
+        set_cache_path(Some(path.clone()));
+        let configured = get_cache_path().unwrap();
+        set_cache_path(None);
+
+        assert_eq!(configured, path);
+    }
+}
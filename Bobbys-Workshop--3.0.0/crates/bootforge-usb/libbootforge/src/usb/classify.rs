@@ -0,0 +1,190 @@
+use super::detect::{DeviceMode, ProtocolType};
+use serde::{Deserialize, Serialize};
+
+pub const CLASS_CDC: u8 = 0x02;
+pub const CLASS_STILL_IMAGE: u8 = 0x06;
+pub const CLASS_APPLICATION: u8 = 0xfe;
+pub const CLASS_VENDOR: u8 = 0xff;
+
+const SUBCLASS_CDC_ACM: u8 = 0x02;
+const SUBCLASS_ANDROID: u8 = 0x42;
+const PROTOCOL_ADB: u8 = 0x01;
+const PROTOCOL_FASTBOOT: u8 = 0x03;
+const SUBCLASS_DFU: u8 = 0x01;
+const PROTOCOL_DFU_RUNTIME: u8 = 0x01;
+const PROTOCOL_DFU_MODE: u8 = 0x02;
+const SUBCLASS_APPLE_USBMUX: u8 = 0xfe;
+const PROTOCOL_APPLE_USBMUX: u8 = 0x02;
+
+/// Class/subclass/protocol of one interface in the active configuration.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InterfaceHint {
+    pub number: u8,
+    pub class: u8,
+    pub subclass: u8,
+    pub protocol: u8,
+    /// Interface string, when the OS has it cached ("MTP", "ADB Interface").
+    #[serde(default)]
+    pub name: Option<String>,
+}
+
+impl InterfaceHint {
+    pub fn new(number: u8, class: u8, subclass: u8, protocol: u8) -> Self {
+        InterfaceHint { number, class, subclass, protocol, name: None }
+    }
+
+    pub fn with_name(mut self, name: &str) -> Self {
+        self.name = Some(name.to_string());
+        self
+    }
+
+    /// Protocol this interface speaks, if its descriptor identifies one.
+    pub fn protocol_type(&self) -> Option<ProtocolType> {
+        let named_mtp = self.name.as_deref().is_some_and(|n| n.trim().eq_ignore_ascii_case("MTP"));
+
+        match (self.class, self.subclass, self.protocol) {
+            (CLASS_VENDOR, SUBCLASS_ANDROID, PROTOCOL_FASTBOOT) => Some(ProtocolType::Fastboot),
+            (CLASS_VENDOR, SUBCLASS_ANDROID, PROTOCOL_ADB) => Some(ProtocolType::ADB),
+            (CLASS_VENDOR, SUBCLASS_APPLE_USBMUX, PROTOCOL_APPLE_USBMUX) => Some(ProtocolType::AppleLockdown),
+            // The DFU runtime interface (protocol 1) only means the device can be
+            // switched into DFU; it is not what the device speaks right now
+            (CLASS_APPLICATION, SUBCLASS_DFU, PROTOCOL_DFU_MODE) => Some(ProtocolType::DFU),
+            (CLASS_STILL_IMAGE, _, _) if named_mtp => Some(ProtocolType::MTP),
+            (CLASS_STILL_IMAGE, 0x01, 0x01) => Some(ProtocolType::PTP),
+            // Android's MTP function is vendor-specific and only identified by name
+            (CLASS_VENDOR, _, _) if named_mtp => Some(ProtocolType::MTP),
+            (CLASS_CDC, SUBCLASS_CDC_ACM, _) => Some(ProtocolType::CdcAcm),
+            _ => None,
+        }
+    }
+
+    /// DFU runtime interface on a device that can be detached into DFU mode.
+    pub fn is_dfu_runtime(&self) -> bool {
+        self.class == CLASS_APPLICATION && self.subclass == SUBCLASS_DFU && self.protocol == PROTOCOL_DFU_RUNTIME
+    }
+}
+
+/// Order used when a composite device exposes several protocols: the one a
+/// flashing tool would talk to comes first.
+const PRIORITY: [ProtocolType; 7] = [
+    ProtocolType::Fastboot,
+    ProtocolType::DFU,
+    ProtocolType::AppleLockdown,
+    ProtocolType::ADB,
+    ProtocolType::MTP,
+    ProtocolType::PTP,
+    ProtocolType::CdcAcm,
+];
+
+/// Protocols advertised by the interface descriptors, highest priority first.
+pub fn descriptor_protocols(interfaces: &[InterfaceHint]) -> Vec<ProtocolType> {
+    let found: Vec<ProtocolType> = interfaces.iter().filter_map(InterfaceHint::protocol_type).collect();
+    PRIORITY.into_iter().filter(|p| found.contains(p)).collect()
+}
+
+/// Choose a device's protocol from its descriptors, using `table` (the VID/PID
+/// database entry or the mode-based guess) only to break ties and fill gaps.
+///
+/// - No recognised descriptor: `table`.
+/// - `table` is among the advertised protocols: `table`.
+/// - Vendor flashing protocols (Odin, EDL) run over CDC-ACM, so a CDC-ACM
+///   descriptor confirms rather than contradicts them.
+/// - Otherwise the highest priority descriptor protocol.
+pub fn classify_protocol(interfaces: &[InterfaceHint], table: ProtocolType) -> ProtocolType {
+    let found = descriptor_protocols(interfaces);
+
+    let Some(&best) = found.first() else {
+        return table;
+    };
+
+    if table != ProtocolType::Unknown && found.contains(&table) {
+        return table;
+    }
+
+    if matches!(table, ProtocolType::Odin | ProtocolType::EDL) && found.contains(&ProtocolType::CdcAcm) {
+        return table;
+    }
+
+    best
+}
+
+/// Mode implied by the descriptors, if they identify one. Only Fastboot and
+/// DFU are unambiguous; see `classify_mode` for how the others are weighed.
+pub fn mode_from_descriptors(interfaces: &[InterfaceHint]) -> Option<DeviceMode> {
+    match descriptor_protocols(interfaces).first()? {
+        ProtocolType::DFU => Some(DeviceMode::DFU),
+        ProtocolType::Fastboot => Some(DeviceMode::Fastboot),
+        ProtocolType::ADB | ProtocolType::AppleLockdown => Some(DeviceMode::Normal),
+        ProtocolType::MTP => Some(DeviceMode::MTP),
+        ProtocolType::PTP => Some(DeviceMode::PTP),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn adb() -> InterfaceHint {
+        InterfaceHint::new(1, 0xff, 0x42, 0x01)
+    }
+
+    fn mtp() -> InterfaceHint {
+        InterfaceHint::new(0, 0xff, 0xff, 0x00).with_name("MTP")
+    }
+
+    #[test]
+    fn test_single_interface_protocols() {
+        assert_eq!(InterfaceHint::new(0, 0xff, 0x42, 0x03).protocol_type(), Some(ProtocolType::Fastboot));
+        assert_eq!(adb().protocol_type(), Some(ProtocolType::ADB));
+        assert_eq!(InterfaceHint::new(0, 0xfe, 0x01, 0x02).protocol_type(), Some(ProtocolType::DFU));
+        assert_eq!(InterfaceHint::new(0, 0x06, 0x01, 0x01).protocol_type(), Some(ProtocolType::PTP));
+        assert_eq!(InterfaceHint::new(0, 0x06, 0x01, 0x01).with_name("MTP").protocol_type(), Some(ProtocolType::MTP));
+        assert_eq!(mtp().protocol_type(), Some(ProtocolType::MTP));
+        assert_eq!(InterfaceHint::new(0, 0x02, 0x02, 0x01).protocol_type(), Some(ProtocolType::CdcAcm));
+        assert_eq!(InterfaceHint::new(2, 0xff, 0xfe, 0x02).protocol_type(), Some(ProtocolType::AppleLockdown));
+        assert_eq!(InterfaceHint::new(0, 0xff, 0xff, 0xff).protocol_type(), None);
+        assert_eq!(InterfaceHint::new(0, 0x08, 0x06, 0x50).protocol_type(), None);
+    }
+
+    #[test]
+    fn test_descriptors_beat_table() {
+        // ADB switched off: the PID table still guesses ADB
+        assert_eq!(classify_protocol(&[mtp()], ProtocolType::ADB), ProtocolType::MTP);
+        // Unknown PID in fastboot
+        let fastboot = [InterfaceHint::new(0, 0xff, 0x42, 0x03)];
+        assert_eq!(classify_protocol(&fastboot, ProtocolType::Unknown), ProtocolType::Fastboot);
+        assert_eq!(mode_from_descriptors(&fastboot), Some(DeviceMode::Fastboot));
+    }
+
+    #[test]
+    fn test_table_breaks_ties() {
+        let composite = [mtp(), adb()];
+        assert_eq!(classify_protocol(&composite, ProtocolType::Unknown), ProtocolType::ADB);
+        assert_eq!(classify_protocol(&composite, ProtocolType::MTP), ProtocolType::MTP);
+
+        let acm = [InterfaceHint::new(0, 0x02, 0x02, 0x01), InterfaceHint::new(1, 0x0a, 0x00, 0x00)];
+        assert_eq!(classify_protocol(&acm, ProtocolType::Odin), ProtocolType::Odin);
+        assert_eq!(classify_protocol(&acm, ProtocolType::Unknown), ProtocolType::CdcAcm);
+    }
+
+    #[test]
+    fn test_no_descriptors_falls_back_to_table() {
+        assert_eq!(classify_protocol(&[], ProtocolType::EDL), ProtocolType::EDL);
+        let vendor = [InterfaceHint::new(0, 0xff, 0xff, 0xff)];
+        assert_eq!(classify_protocol(&vendor, ProtocolType::EDL), ProtocolType::EDL);
+        assert_eq!(mode_from_descriptors(&vendor), None);
+    }
+
+    #[test]
+    fn test_dfu_runtime_vs_dfu_mode() {
+        let runtime = [adb(), InterfaceHint::new(3, 0xfe, 0x01, 0x01)];
+        let dfu = [InterfaceHint::new(0, 0xfe, 0x01, 0x02)];
+        assert_eq!(mode_from_descriptors(&dfu), Some(DeviceMode::DFU));
+        assert_eq!(classify_protocol(&dfu, ProtocolType::Unknown), ProtocolType::DFU);
+        // The runtime interface only means "can be switched to DFU"
+        assert!(runtime[1].is_dfu_runtime());
+        assert_eq!(classify_protocol(&runtime, ProtocolType::Unknown), ProtocolType::ADB);
+        assert_eq!(mode_from_descriptors(&runtime), Some(DeviceMode::Normal));
+    }
+}
@@ -1,4 +1,7 @@
-use crate::{BootforgeError, Result};
+use crate::Result;
+use super::backend::UsbBackend;
+use super::classify::{classify_protocol, mode_from_descriptors, InterfaceHint};
+use super::identity::DeviceCorrelator;
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 use chrono::{DateTime, Utc};
@@ -62,6 +65,8 @@ pub enum ProtocolType {
     PTP,
     EDL,
     Odin,
+    /// CDC-ACM serial, identified from descriptors with no more specific protocol.
+    CdcAcm,
     Unknown,
 }
 
@@ -78,7 +83,19 @@ pub struct UsbDeviceInfo {
     pub state: DeviceState,
     pub protocol: ProtocolType,
     pub bus: Option<u8>,
+    /// Port on the parent hub (the last element of `port_path`).
     pub port: Option<u8>,
+    /// Port chain from the root hub down, e.g. `[2, 3]` for `1-2.3`. Empty for
+    /// root hubs and when the platform does not expose it.
+    #[serde(default)]
+    pub port_path: Vec<u8>,
+    /// Location of the hub this device hangs off (`usb1`, `1-2`), or the
+    /// parent instance ID on Windows.
+    #[serde(default)]
+    pub parent_hub: Option<String>,
+    /// Interface descriptors of the active configuration.
+    #[serde(default)]
+    pub interfaces: Vec<InterfaceHint>,
     pub speed: Option<String>,
     pub first_seen: DateTime<Utc>,
     pub last_seen: DateTime<Utc>,
@@ -92,6 +109,12 @@ impl UsbDeviceInfo {
             self.serial.as_deref().unwrap_or("unknown")
         )
     }
+
+    /// Physical location in `bus-port.port.port` form, e.g. `1-2.3`.
+    pub fn location(&self) -> Option<String> {
+        let bus = self.bus?;
+        Some(super::topology::format_location(bus, &self.port_path))
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -104,110 +127,203 @@ pub enum DeviceEvent {
 }
 
 pub fn detect_devices() -> Result<Vec<UsbDeviceInfo>> {
-    log::info!("[BootForge] Scanning USB devices with nusb...");
+    detect_devices_with(&super::backend::NusbBackend)
+}
+
+pub fn detect_devices_with(backend: &dyn UsbBackend) -> Result<Vec<UsbDeviceInfo>> {
+    log::info!("[BootForge] Scanning USB devices with {} backend...", backend.name());
     
-    // Load device cache
-    let mut cache = super::cache::load_cache().unwrap_or_else(|e| {
-        log::warn!("Failed to load device cache: {}, using empty cache", e);
-        super::cache::DeviceCache::new()
-    });
+    let scanned = backend.list_devices()?;
+    
+    if !backend.persist_cache() {
+        log::info!("[BootForge] Found {} USB devices", scanned.len());
+        return Ok(scanned);
+    }
     
-    let mut devices = Vec::new();
     let now = Utc::now();
+    let mut devices = scanned;
+    let mut correlated = false;
     
-    match nusb::list_devices() {
-        Ok(device_list) => {
-            for device_info in device_list {
-                let vendor_id = device_info.vendor_id();
-                let product_id = device_info.product_id();
-                
-                let platform = super::vendor_map::map_vendor_to_platform(vendor_id, product_id);
-                let mode = detect_device_mode(vendor_id, product_id);
-                let protocol = detect_protocol(vendor_id, product_id, &mode);
-                
-                let serial = device_info.serial_number().map(|s| s.to_string());
-                let manufacturer = device_info.manufacturer_string().map(|s| s.to_string());
-                let product = device_info.product_string().map(|s| s.to_string());
-                
-                let speed = match device_info.speed() {
-                    Some(nusb::Speed::Low) => Some("Low (1.5 Mbps)".to_string()),
-                    Some(nusb::Speed::Full) => Some("Full (12 Mbps)".to_string()),
-                    Some(nusb::Speed::High) => Some("High (480 Mbps)".to_string()),
-                    Some(nusb::Speed::Super) => Some("SuperSpeed (5 Gbps)".to_string()),
-                    Some(nusb::Speed::SuperPlus) => Some("SuperSpeed+ (10 Gbps)".to_string()),
-                    _ => None,
-                };
-                
-                // Create device info first to get unique_key
-                let temp_info = UsbDeviceInfo {
-                    id: Uuid::new_v4(),
-                    vendor_id,
-                    product_id,
-                    serial: serial.clone(),
-                    manufacturer,
-                    product: product.clone(),
-                    platform,
-                    mode,
-                    state: DeviceState::Identified,
-                    protocol,
-                    bus: Some(device_info.bus_number()),
-                    port: None,
-                    speed,
-                    first_seen: now,
-                    last_seen: now,
-                };
-                
-                let unique_key = temp_info.unique_key();
-                
-                // Get first_seen from cache, or use current time if new device
-                let first_seen = cache.get_or_create_first_seen(&unique_key, now);
-                
-                // Update cache entry
-                cache.update_entry(unique_key.clone(), now);
-                
-                let info = UsbDeviceInfo {
-                    id: temp_info.id,
-                    vendor_id: temp_info.vendor_id,
-                    product_id: temp_info.product_id,
-                    serial: temp_info.serial,
-                    manufacturer: temp_info.manufacturer,
-                    product: temp_info.product,
-                    platform: temp_info.platform,
-                    mode: temp_info.mode,
-                    state: temp_info.state,
-                    protocol: temp_info.protocol,
-                    bus: temp_info.bus,
-                    port: temp_info.port,
-                    speed: temp_info.speed,
-                    first_seen,
-                    last_seen: now,
-                };
-                
-                log::info!(
-                    "[BootForge] Found: {:04x}:{:04x} {} ({:?}/{:?})",
-                    vendor_id, product_id,
-                    info.product.as_deref().unwrap_or("Unknown"),
-                    platform, mode
-                );
-                
-                devices.push(info);
-            }
-        }
-        Err(e) => {
-            log::error!("[BootForge] USB enumeration failed: {}", e);
-            return Err(BootforgeError::Usb(format!("Failed to enumerate USB devices: {}", e)));
+    // Correlate and save under the cache lock so parallel scans don't drop entries
+    let updated = super::cache::update_cache(|cache| {
+        correlate_all(cache, &mut devices, now);
+        correlated = true;
+    });
+    
+    if let Err(e) = updated {
+        log::warn!("Failed to update device cache: {}", e);
+        if !correlated {
+            correlate_all(&mut super::cache::DeviceCache::new(), &mut devices, now);
         }
     }
     
-    // Save cache after scan
-    if let Err(e) = super::cache::save_cache(&cache) {
-        log::warn!("Failed to save device cache: {}", e);
+    for info in &devices {
+        log::info!(
+            "[BootForge] Found: {:04x}:{:04x} {} ({:?}/{:?})",
+            info.vendor_id, info.product_id,
+            info.product.as_deref().unwrap_or("Unknown"),
+            info.platform, info.mode
+        );
     }
     
     log::info!("[BootForge] Found {} USB devices", devices.len());
     Ok(devices)
 }
 
+/// Record a single device reported by a hotplug event, touching only its cache entry.
+///
+/// `present` are the logical IDs of devices already on the bus; the new
+/// enumeration is never matched to one of them.
+pub fn record_device_with(backend: &dyn UsbBackend, info: &mut UsbDeviceInfo, present: &[Uuid]) {
+    if !backend.persist_cache() {
+        return;
+    }
+    
+    let now = Utc::now();
+    let mut correlated = false;
+    
+    let mut correlator = DeviceCorrelator::new();
+    correlator.claim(present.iter().copied());
+    
+    let updated = super::cache::update_cache(|cache| {
+        correlator.correlate(cache, info, now);
+        correlated = true;
+    });
+    
+    if let Err(e) = updated {
+        log::warn!("Failed to update device cache: {}", e);
+        if !correlated {
+            correlator.correlate(&mut super::cache::DeviceCache::new(), info, now);
+        }
+    }
+}
+
+fn correlate_all(cache: &mut super::cache::DeviceCache, devices: &mut [UsbDeviceInfo], now: DateTime<Utc>) {
+    let mut correlator = DeviceCorrelator::new();
+    for info in devices.iter_mut() {
+        correlator.correlate(cache, info, now);
+    }
+}
+
+/// Build a `UsbDeviceInfo` from a live nusb enumeration entry. `enumerated`
+/// is the rest of the bus, which Windows needs to resolve the port chain.
+pub(crate) fn device_info_from_nusb(
+    device_info: &nusb::DeviceInfo,
+    enumerated: &[nusb::DeviceInfo],
+    now: DateTime<Utc>,
+) -> UsbDeviceInfo {
+    let vendor_id = device_info.vendor_id();
+    let product_id = device_info.product_id();
+    
+    let interfaces: Vec<InterfaceHint> = device_info.interfaces()
+        .map(|i| InterfaceHint {
+            number: i.interface_number(),
+            class: i.class(),
+            subclass: i.subclass(),
+            protocol: i.protocol(),
+            name: i.interface_string().map(|s| s.to_string()),
+        })
+        .collect();
+    
+    // Descriptors decide; the VID/PID database only breaks ties and fills gaps
+    let db = super::device_db::active();
+    let platform = db.platform(vendor_id, product_id);
+    let mode = classify_mode(vendor_id, product_id, &interfaces);
+    let protocol = classify_protocol(&interfaces, table_protocol(vendor_id, product_id, &mode));
+    
+    let speed = match device_info.speed() {
+        Some(nusb::Speed::Low) => Some("Low (1.5 Mbps)".to_string()),
+        Some(nusb::Speed::Full) => Some("Full (12 Mbps)".to_string()),
+        Some(nusb::Speed::High) => Some("High (480 Mbps)".to_string()),
+        Some(nusb::Speed::Super) => Some("SuperSpeed (5 Gbps)".to_string()),
+        Some(nusb::Speed::SuperPlus) => Some("SuperSpeed+ (10 Gbps)".to_string()),
+        _ => None,
+    };
+    
+    let (port_path, parent_hub) = port_chain(device_info, enumerated);
+    
+    UsbDeviceInfo {
+        id: Uuid::new_v4(),
+        vendor_id,
+        product_id,
+        serial: device_info.serial_number().map(|s| s.to_string()),
+        manufacturer: device_info.manufacturer_string().map(|s| s.to_string()),
+        product: device_info.product_string().map(|s| s.to_string()),
+        platform,
+        mode,
+        state: DeviceState::Identified,
+        protocol,
+        bus: Some(device_info.bus_number()),
+        port: port_path.last().copied(),
+        port_path,
+        parent_hub,
+        interfaces,
+        speed,
+        first_seen: now,
+        last_seen: now,
+    }
+}
+
+/// Port chain and parent hub for a live device.
+///
+/// Linux reads the chain from the sysfs name (`1-2.3`), macOS decodes the
+/// IOKit location ID (one nibble per hub tier). Windows only exposes the port
+/// on the immediate parent, so the chain is built by walking parent instance
+/// IDs up through the enumerated hubs, and the parent is identified by its
+/// instance ID.
+#[cfg_attr(not(target_os = "windows"), allow(unused_variables))]
+fn port_chain(device_info: &nusb::DeviceInfo, enumerated: &[nusb::DeviceInfo]) -> (Vec<u8>, Option<String>) {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    {
+        let bus = device_info.bus_number();
+        let port_path = device_info.sysfs_path()
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(super::topology::parse_location)
+            .map(|(_, path)| path)
+            .unwrap_or_default();
+        let parent_hub = super::topology::parent_location(bus, &port_path);
+        (port_path, parent_hub)
+    }
+    
+    #[cfg(target_os = "macos")]
+    {
+        let location_id = device_info.location_id();
+        let port_path: Vec<u8> = (0..6)
+            .map(|tier| ((location_id >> (20 - 4 * tier)) & 0xf) as u8)
+            .take_while(|&port| port != 0)
+            .collect();
+        let parent_hub = super::topology::parent_location(device_info.bus_number(), &port_path);
+        (port_path, parent_hub)
+    }
+    
+    #[cfg(target_os = "windows")]
+    {
+        let parent = device_info.parent_instance_id().to_string_lossy().into_owned();
+        let port_path = super::topology::chain_through_hubs(device_info.port_number(), &parent, |id| {
+            enumerated
+                .iter()
+                .find(|hub| hub.instance_id().to_string_lossy() == id)
+                .map(|hub| (hub.port_number(), hub.parent_instance_id().to_string_lossy().into_owned()))
+        });
+        (port_path, Some(parent))
+    }
+}
+
+/// The bus a hotplugged device's port chain is resolved against. Only
+/// Windows needs it, to walk up through the parent hubs.
+pub(crate) fn enumerated_for_chain() -> Vec<nusb::DeviceInfo> {
+    #[cfg(target_os = "windows")]
+    {
+        nusb::list_devices().map(|list| list.collect()).unwrap_or_default()
+    }
+
+    #[cfg(not(target_os = "windows"))]
+    {
+        Vec::new()
+    }
+}
+
 pub fn detect_mobile_devices() -> Result<Vec<UsbDeviceInfo>> {
     let all_devices = detect_devices()?;
     Ok(all_devices.into_iter().filter(|d| {
@@ -236,36 +352,34 @@ pub fn detect_mobile_devices() -> Result<Vec<UsbDeviceInfo>> {
 pub fn detect_device_by_serial(serial: &str) -> Result<Option<UsbDeviceInfo>> {
     let devices = detect_devices()?;
     Ok(devices.into_iter().find(|d| {
-        d.serial.as_ref().map_or(false, |s| s == serial)
+        d.serial.as_deref() == Some(serial)
     }))
 }
 
-fn detect_device_mode(vendor_id: u16, product_id: u16) -> DeviceMode {
-    match (vendor_id, product_id) {
-        (0x05ac, 0x1227) => DeviceMode::DFU,
-        (0x05ac, 0x1281) => DeviceMode::Recovery,
-        (0x05ac, 0x12a8) => DeviceMode::Normal,
-        (0x18d1, 0x4ee0) => DeviceMode::Fastboot,
-        (0x18d1, 0xd001) => DeviceMode::Sideload,
-        (0x18d1, 0x4ee1) => DeviceMode::Normal,
-        (0x18d1, 0x4ee2) => DeviceMode::Normal,
-        (0x04e8, 0x6860) => DeviceMode::MTP,
-        (0x04e8, 0x685d) => DeviceMode::Download,
-        (0x04e8, 0x685e) => DeviceMode::Download,
-        (0x22b8, 0x2e76) => DeviceMode::Fastboot,
-        (0x22b8, 0x2e81) => DeviceMode::MTP,
-        (0x2717, 0xff40) => DeviceMode::Fastboot,
-        (0x2717, 0xff48) => DeviceMode::MTP,
-        (0x2a70, 0x9011) => DeviceMode::Fastboot,
-        (0x2a70, 0x4ee7) => DeviceMode::MTP,
-        (0x05c6, 0x9008) => DeviceMode::Download,
-        (0x05c6, 0x9025) => DeviceMode::Fastboot,
-        (0x0e8d, 0x0003) => DeviceMode::Download,
-        (0x0e8d, 0x2000) => DeviceMode::Download,
-        _ => DeviceMode::Unknown,
+/// Protocol the device database declares for a VID/PID, or the one its mode
+/// implies. Descriptors override it through `classify_protocol`.
+pub(crate) fn table_protocol(vendor_id: u16, product_id: u16, mode: &DeviceMode) -> ProtocolType {
+    super::device_db::active()
+        .protocol(vendor_id, product_id)
+        .unwrap_or_else(|| detect_protocol(vendor_id, product_id, mode))
+}
+
+/// Mode of a device from its descriptors and the device database.
+///
+/// Fastboot and DFU interfaces only exist in those modes, so they override the
+/// database. ADB, Lockdown, MTP and PTP interfaces are exposed in several
+/// modes (adbd runs in recovery and sideload too), so the database mode wins
+/// for them when it knows the VID/PID.
+pub(crate) fn classify_mode(vendor_id: u16, product_id: u16, interfaces: &[InterfaceHint]) -> DeviceMode {
+    let table = super::device_db::active().mode(vendor_id, product_id);
+    match mode_from_descriptors(interfaces) {
+        Some(mode @ (DeviceMode::Fastboot | DeviceMode::DFU)) => mode,
+        Some(mode) if table == DeviceMode::Unknown => mode,
+        _ => table,
     }
 }
 
+/// Protocol implied by the mode when the device database does not declare one.
 fn detect_protocol(vendor_id: u16, _product_id: u16, mode: &DeviceMode) -> ProtocolType {
     match mode {
         DeviceMode::DFU => ProtocolType::DFU,
@@ -289,3 +403,41 @@ fn detect_protocol(vendor_id: u16, _product_id: u16, mode: &DeviceMode) -> Proto
         _ => ProtocolType::Unknown,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mode_prefers_descriptors() {
+        // A fastboot interface settles the mode whatever the table says
+        let fastboot = [InterfaceHint::new(0, 0xff, 0x42, 0x03)];
+        assert_eq!(classify_mode(0x04e8, 0x6860, &fastboot), DeviceMode::Fastboot);
+        assert_eq!(classify_mode(0x1234, 0x5678, &[InterfaceHint::new(0, 0xfe, 0x01, 0x02)]), DeviceMode::DFU);
+
+        // An ADB interface only decides for PIDs the table doesn't know
+        let adb = [InterfaceHint::new(1, 0xff, 0x42, 0x01)];
+        assert_eq!(classify_mode(0x1234, 0x5678, &adb), DeviceMode::Normal);
+
+        // Vendor-specific descriptors leave it to the table
+        let vendor = [InterfaceHint::new(0, 0xff, 0xff, 0xff)];
+        assert_eq!(classify_mode(0x18d1, 0x4ee0, &vendor), DeviceMode::Fastboot);
+        assert_eq!(classify_mode(0x05ac, 0x1227, &[]), DeviceMode::DFU);
+        assert_eq!(classify_mode(0x1234, 0x5678, &[]), DeviceMode::Unknown);
+    }
+
+    #[test]
+    fn test_adb_and_mtp_interfaces_keep_table_mode() {
+        // adbd runs in sideload and recovery too
+        let adb = [InterfaceHint::new(0, 0xff, 0x42, 0x01)];
+        assert_eq!(classify_mode(0x18d1, 0xd001, &adb), DeviceMode::Sideload);
+        assert_eq!(classify_mode(0x18d1, 0x4ee0, &adb), DeviceMode::Fastboot);
+
+        // Composite MTP+ADB handset
+        let mtp_adb = [
+            InterfaceHint::new(0, 0xff, 0xff, 0x00).with_name("MTP"),
+            InterfaceHint::new(1, 0xff, 0x42, 0x01),
+        ];
+        assert_eq!(classify_mode(0x04e8, 0x6860, &mtp_adb), DeviceMode::MTP);
+    }
+}
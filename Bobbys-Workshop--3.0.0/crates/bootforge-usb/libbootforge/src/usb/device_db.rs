@@ -0,0 +1,565 @@
+use crate::{BootforgeError, Result};
+use super::detect::{DeviceMode, DevicePlatform, ProtocolType};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, OnceLock, RwLock};
+
+pub const DEVICE_DB_SCHEMA_VERSION: u32 = 1;
+
+/// Extra override files, separated like `PATH`.
+pub const DEVICE_DB_ENV: &str = "BOOTFORGE_USB_DB";
+
+const EMBEDDED_DB: &str = include_str!("../../data/usb_devices.toml");
+const EMBEDDED_SOURCE: &str = "<embedded>";
+const DB_FILE_STEM: &str = "usb_devices";
+
+/// A USB vendor or product ID. Accepts integers (`0x18d1` in TOML) or hex
+/// strings (`"0x18d1"`, `"18d1"`) so JSON files can stay readable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct UsbId(pub u16);
+
+impl fmt::Display for UsbId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "0x{:04x}", self.0)
+    }
+}
+
+impl Serialize for UsbId {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for UsbId {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Raw {
+            Int(i64),
+            Str(String),
+        }
+
+        let value = match Raw::deserialize(deserializer)? {
+            Raw::Int(n) => u16::try_from(n).ok(),
+            Raw::Str(s) => {
+                let s = s.trim();
+                let hex = s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")).unwrap_or(s);
+                u16::from_str_radix(hex, 16).ok()
+            }
+        };
+
+        value.map(UsbId).ok_or_else(|| serde::de::Error::custom("USB ID must be a 16-bit value"))
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ProductEntry {
+    pub id: UsbId,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    pub mode: DeviceMode,
+    /// Inferred from `mode` when absent.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub protocol: Option<ProtocolType>,
+    /// Overrides the vendor's platform for this product.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub platform: Option<DevicePlatform>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct VendorEntry {
+    pub id: UsbId,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub platform: Option<DevicePlatform>,
+    #[serde(default)]
+    pub products: Vec<ProductEntry>,
+}
+
+/// One database layer as written on disk.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DeviceDbFile {
+    pub schema_version: u32,
+    #[serde(default)]
+    pub vendors: Vec<VendorEntry>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DbFormat {
+    Json,
+    Toml,
+}
+
+impl DbFormat {
+    pub fn from_path(path: &Path) -> Option<Self> {
+        match path.extension()?.to_str()?.to_ascii_lowercase().as_str() {
+            "json" => Some(DbFormat::Json),
+            "toml" => Some(DbFormat::Toml),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum IssueSeverity {
+    Error,
+    Warning,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DbIssue {
+    pub severity: IssueSeverity,
+    pub message: String,
+}
+
+impl DbIssue {
+    fn error(message: String) -> Self {
+        DbIssue { severity: IssueSeverity::Error, message }
+    }
+
+    fn warning(message: String) -> Self {
+        DbIssue { severity: IssueSeverity::Warning, message }
+    }
+}
+
+impl DeviceDbFile {
+    pub fn parse(text: &str, format: DbFormat) -> Result<Self> {
+        match format {
+            DbFormat::Json => serde_json::from_str(text).map_err(|e| e.to_string()),
+            DbFormat::Toml => toml::from_str(text).map_err(|e| e.to_string()),
+        }
+        .map_err(|e| BootforgeError::Usb(format!("Invalid device database: {}", e)))
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let format = DbFormat::from_path(path).ok_or_else(|| {
+            BootforgeError::Usb(format!("Device database {:?} must be .toml or .json", path))
+        })?;
+
+        let text = fs::read_to_string(path).map_err(|e| {
+            BootforgeError::Usb(format!("Failed to read device database {:?}: {}", path, e))
+        })?;
+
+        Self::parse(&text, format)
+            .map_err(|e| BootforgeError::Usb(format!("{:?}: {}", path, e)))
+    }
+
+    /// Check a layer on its own. Layers with errors are not merged.
+    pub fn validate(&self) -> Vec<DbIssue> {
+        let mut issues = Vec::new();
+
+        if self.schema_version != DEVICE_DB_SCHEMA_VERSION {
+            issues.push(DbIssue::error(format!(
+                "unsupported schema_version {} (expected {})",
+                self.schema_version, DEVICE_DB_SCHEMA_VERSION
+            )));
+        }
+
+        let mut vendor_ids = HashSet::new();
+        for vendor in &self.vendors {
+            if vendor.id.0 == 0 {
+                issues.push(DbIssue::error("vendor ID 0x0000 is reserved".to_string()));
+            }
+            if !vendor_ids.insert(vendor.id) {
+                issues.push(DbIssue::error(format!("vendor {} is listed more than once", vendor.id)));
+            }
+            if vendor.name.is_none() && vendor.platform.is_none() && vendor.products.is_empty() {
+                issues.push(DbIssue::warning(format!("vendor {} sets nothing", vendor.id)));
+            }
+
+            let mut product_ids = HashSet::new();
+            for product in &vendor.products {
+                if !product_ids.insert(product.id) {
+                    issues.push(DbIssue::error(format!(
+                        "product {}:{} is listed more than once", vendor.id, product.id
+                    )));
+                }
+                if let Some(protocol) = product.protocol {
+                    if !protocol_fits_mode(product.mode, protocol) {
+                        issues.push(DbIssue::warning(format!(
+                            "product {}:{} uses protocol {:?} in {:?} mode",
+                            vendor.id, product.id, protocol, product.mode
+                        )));
+                    }
+                }
+            }
+        }
+
+        issues
+    }
+}
+
+/// Whether a declared protocol is plausible for a mode. Used only for lint
+/// warnings; the database may still override it.
+fn protocol_fits_mode(mode: DeviceMode, protocol: ProtocolType) -> bool {
+    match mode {
+        DeviceMode::DFU => protocol == ProtocolType::DFU,
+        DeviceMode::Fastboot => protocol == ProtocolType::Fastboot,
+        DeviceMode::Download => matches!(
+            protocol,
+            ProtocolType::EDL | ProtocolType::Odin | ProtocolType::CdcAcm | ProtocolType::Unknown
+        ),
+        DeviceMode::MTP => matches!(protocol, ProtocolType::MTP | ProtocolType::ADB),
+        DeviceMode::PTP => matches!(protocol, ProtocolType::PTP | ProtocolType::ADB),
+        DeviceMode::Normal | DeviceMode::Recovery | DeviceMode::Sideload => !matches!(
+            protocol,
+            ProtocolType::Fastboot | ProtocolType::DFU | ProtocolType::EDL | ProtocolType::Odin
+        ),
+        DeviceMode::Charging | DeviceMode::Unknown => true,
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+struct VendorRecord {
+    name: Option<String>,
+    platform: Option<DevicePlatform>,
+    products: HashMap<u16, ProductEntry>,
+}
+
+/// Merged VID/PID lookup table.
+#[derive(Debug, Clone, Default)]
+pub struct DeviceDatabase {
+    vendors: HashMap<u16, VendorRecord>,
+    sources: Vec<String>,
+}
+
+impl DeviceDatabase {
+    /// The database compiled into the library.
+    pub fn embedded() -> Self {
+        let file = DeviceDbFile::parse(EMBEDDED_DB, DbFormat::Toml)
+            .expect("embedded usb_devices.toml must parse");
+        let mut db = DeviceDatabase::default();
+        db.merge(file, EMBEDDED_SOURCE);
+        db
+    }
+
+    /// Embedded defaults plus every override file found by `override_paths`.
+    ///
+    /// Override files that fail to parse or validate are skipped with a
+    /// warning so a bad local edit never breaks detection.
+    pub fn load() -> Self {
+        let mut db = Self::embedded();
+
+        for path in override_paths() {
+            if !path.exists() {
+                continue;
+            }
+
+            let file = match DeviceDbFile::load(&path) {
+                Ok(file) => file,
+                Err(e) => {
+                    log::warn!("[BootForge] Ignoring device database override: {}", e);
+                    continue;
+                }
+            };
+
+            let issues = file.validate();
+            for issue in &issues {
+                log::warn!("[BootForge] {:?}: {:?}: {}", path, issue.severity, issue.message);
+            }
+            if issues.iter().any(|i| i.severity == IssueSeverity::Error) {
+                log::warn!("[BootForge] Ignoring device database override {:?}", path);
+                continue;
+            }
+
+            db.merge(file, &path.display().to_string());
+        }
+
+        db
+    }
+
+    /// Layer `file` over the current contents.
+    pub fn merge(&mut self, file: DeviceDbFile, source: &str) {
+        for vendor in file.vendors {
+            let record = self.vendors.entry(vendor.id.0).or_default();
+            if vendor.name.is_some() {
+                record.name = vendor.name;
+            }
+            if vendor.platform.is_some() {
+                record.platform = vendor.platform;
+            }
+            for product in vendor.products {
+                record.products.insert(product.id.0, product);
+            }
+        }
+
+        log::debug!("[BootForge] Merged device database layer {}", source);
+        self.sources.push(source.to_string());
+    }
+
+    /// Layers merged so far, in order.
+    pub fn sources(&self) -> &[String] {
+        &self.sources
+    }
+
+    pub fn product(&self, vendor_id: u16, product_id: u16) -> Option<&ProductEntry> {
+        self.vendors.get(&vendor_id)?.products.get(&product_id)
+    }
+
+    /// Every product listed for `vendor_id`, in no particular order.
+    pub fn products(&self, vendor_id: u16) -> impl Iterator<Item = &ProductEntry> {
+        self.vendors.get(&vendor_id).into_iter().flat_map(|v| v.products.values())
+    }
+
+    pub fn vendor_name(&self, vendor_id: u16) -> Option<&str> {
+        self.vendors.get(&vendor_id)?.name.as_deref()
+    }
+
+    pub fn platform(&self, vendor_id: u16, product_id: u16) -> DevicePlatform {
+        let Some(vendor) = self.vendors.get(&vendor_id) else {
+            return DevicePlatform::Unknown;
+        };
+
+        vendor.products.get(&product_id)
+            .and_then(|p| p.platform)
+            .or(vendor.platform)
+            .unwrap_or(DevicePlatform::Unknown)
+    }
+
+    pub fn mode(&self, vendor_id: u16, product_id: u16) -> DeviceMode {
+        self.product(vendor_id, product_id)
+            .map(|p| p.mode)
+            .unwrap_or(DeviceMode::Unknown)
+    }
+
+    /// Protocol declared for this VID/PID, if the database states one.
+    pub fn protocol(&self, vendor_id: u16, product_id: u16) -> Option<ProtocolType> {
+        self.product(vendor_id, product_id)?.protocol
+    }
+
+    pub fn vendor_count(&self) -> usize {
+        self.vendors.len()
+    }
+
+    pub fn product_count(&self) -> usize {
+        self.vendors.values().map(|v| v.products.len()).sum()
+    }
+}
+
+/// Override file locations in merge order: system, user, then `$BOOTFORGE_USB_DB`.
+pub fn override_paths() -> Vec<PathBuf> {
+    let mut dirs = Vec::new();
+
+    #[cfg(target_os = "windows")]
+    {
+        if let Ok(program_data) = std::env::var("PROGRAMDATA") {
+            dirs.push(Path::new(&program_data).join("BobbysWorkshop"));
+        }
+    }
+
+    #[cfg(any(target_os = "macos", target_os = "linux"))]
+    {
+        dirs.push(PathBuf::from("/etc/bobbys-workshop"));
+    }
+
+    if let Some(user_dir) = super::cache::get_cache_path().ok().and_then(|p| p.parent().map(Path::to_path_buf)) {
+        dirs.push(user_dir);
+    }
+
+    let mut paths: Vec<PathBuf> = dirs.into_iter()
+        .flat_map(|dir| {
+            ["toml", "json"].map(|ext| dir.join(format!("{}.{}", DB_FILE_STEM, ext)))
+        })
+        .collect();
+
+    if let Some(extra) = std::env::var_os(DEVICE_DB_ENV) {
+        paths.extend(std::env::split_paths(&extra).filter(|p| !p.as_os_str().is_empty()));
+    }
+
+    paths
+}
+
+fn active_slot() -> &'static RwLock<Arc<DeviceDatabase>> {
+    static ACTIVE: OnceLock<RwLock<Arc<DeviceDatabase>>> = OnceLock::new();
+    ACTIVE.get_or_init(|| RwLock::new(Arc::new(DeviceDatabase::load())))
+}
+
+/// The database used by detection. Loaded on first use.
+pub fn active() -> Arc<DeviceDatabase> {
+    active_slot().read().unwrap_or_else(|p| p.into_inner()).clone()
+}
+
+/// Re-read override files, e.g. after the user edited one.
+pub fn reload() -> Arc<DeviceDatabase> {
+    install(DeviceDatabase::load())
+}
+
+/// Replace the active database.
+pub fn install(db: DeviceDatabase) -> Arc<DeviceDatabase> {
+    let db = Arc::new(db);
+    *active_slot().write().unwrap_or_else(|p| p.into_inner()) = db.clone();
+    db
+}
+
+/// Lint results for one layer.
+#[derive(Debug, Clone, Serialize)]
+pub struct LayerReport {
+    pub source: String,
+    pub vendors: usize,
+    pub products: usize,
+    pub issues: Vec<DbIssue>,
+}
+
+impl LayerReport {
+    pub fn has_errors(&self) -> bool {
+        self.issues.iter().any(|i| i.severity == IssueSeverity::Error)
+    }
+}
+
+/// Validate the given files, or when `paths` is empty, the embedded database
+/// and every override file that exists.
+pub fn lint(paths: &[PathBuf]) -> Vec<LayerReport> {
+    let mut reports = Vec::new();
+
+    if paths.is_empty() {
+        let embedded = DeviceDbFile::parse(EMBEDDED_DB, DbFormat::Toml);
+        reports.push(lint_layer(EMBEDDED_SOURCE.to_string(), embedded));
+        for path in override_paths().into_iter().filter(|p| p.exists()) {
+            reports.push(lint_layer(path.display().to_string(), DeviceDbFile::load(&path)));
+        }
+    } else {
+        for path in paths {
+            reports.push(lint_layer(path.display().to_string(), DeviceDbFile::load(path)));
+        }
+    }
+
+    reports
+}
+
+fn lint_layer(source: String, file: Result<DeviceDbFile>) -> LayerReport {
+    match file {
+        Ok(file) => LayerReport {
+            source,
+            vendors: file.vendors.len(),
+            products: file.vendors.iter().map(|v| v.products.len()).sum(),
+            issues: file.validate(),
+        },
+        Err(e) => LayerReport {
+            source,
+            vendors: 0,
+            products: 0,
+            issues: vec![DbIssue::error(e.to_string())],
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::usb::vendor_map::VendorProfile;
+
+    #[test]
+    fn test_embedded_database_is_clean() {
+        let file = DeviceDbFile::parse(EMBEDDED_DB, DbFormat::Toml).unwrap();
+        assert!(file.validate().is_empty(), "{:?}", file.validate());
+    }
+
+    #[test]
+    fn test_embedded_matches_previous_tables() {
+        let db = DeviceDatabase::embedded();
+
+        assert_eq!(db.mode(0x05ac, 0x1227), DeviceMode::DFU);
+        assert_eq!(db.mode(0x18d1, 0x4ee0), DeviceMode::Fastboot);
+        assert_eq!(db.mode(0x18d1, 0xd001), DeviceMode::Sideload);
+        assert_eq!(db.mode(0x04e8, 0x685d), DeviceMode::Download);
+        assert_eq!(db.mode(0x2717, 0xff40), DeviceMode::Fastboot);
+        assert_eq!(db.mode(0x05c6, 0x9008), DeviceMode::Download);
+        assert_eq!(db.mode(0x1234, 0x5678), DeviceMode::Unknown);
+
+        assert_eq!(db.platform(0x22b8, 0x2e76), DevicePlatform::Motorola);
+        assert_eq!(db.platform(0x2d95, 0x0001), DevicePlatform::Xiaomi);
+        assert_eq!(db.platform(0x1d6b, 0x0002), DevicePlatform::Android);
+        assert_eq!(db.platform(0xffff, 0x0001), DevicePlatform::Unknown);
+
+        assert_eq!(db.vendor_name(0x18d1), Some("Google Inc."));
+        assert_eq!(db.vendor_name(0x0414), None);
+    }
+
+    #[test]
+    fn test_json_override_merges_over_embedded() {
+        let json = r#"{
+            "schema_version": 1,
+            "vendors": [
+                { "id": "0x2717", "products": [
+                    { "id": "ff99", "mode": "Fastboot" },
+                    { "id": "0xff48", "mode": "Normal", "protocol": "ADB" }
+                ]},
+                { "id": 4660, "name": "Bench Rig", "platform": "LinuxPc" }
+            ]
+        }"#;
+        let file = DeviceDbFile::parse(json, DbFormat::Json).unwrap();
+        assert!(file.validate().is_empty());
+
+        let mut db = DeviceDatabase::embedded();
+        db.merge(file, "test");
+
+        assert_eq!(db.mode(0x2717, 0xff99), DeviceMode::Fastboot);
+        assert_eq!(db.mode(0x2717, 0xff48), DeviceMode::Normal);
+        assert_eq!(db.protocol(0x2717, 0xff48), Some(ProtocolType::ADB));
+        // Untouched products and vendor fields survive
+        assert_eq!(db.mode(0x2717, 0xff40), DeviceMode::Fastboot);
+        assert_eq!(db.vendor_name(0x2717), Some("Xiaomi Inc."));
+        assert_eq!(db.platform(0x1234, 0), DevicePlatform::LinuxPc);
+        assert_eq!(db.sources(), &[EMBEDDED_SOURCE.to_string(), "test".to_string()]);
+
+        let profile = VendorProfile::from_db(&db, 0x2717).unwrap();
+        assert!(profile.fastboot_pids.contains(&0xff99));
+        assert!(profile.normal_pids.contains(&0xff48));
+        assert!(VendorProfile::from_db(&db, 0xffff).is_none());
+    }
+
+    #[test]
+    fn test_validation_reports_problems() {
+        let toml = r#"
+            schema_version = 2
+
+            [[vendors]]
+            id = 0x18d1
+            products = [
+                { id = 0x4ee0, mode = "Fastboot", protocol = "ADB" },
+                { id = 0x4ee0, mode = "Fastboot" },
+            ]
+
+            [[vendors]]
+            id = 0x18d1
+        "#;
+        let issues = DeviceDbFile::parse(toml, DbFormat::Toml).unwrap().validate();
+        let errors: Vec<_> = issues.iter().filter(|i| i.severity == IssueSeverity::Error).collect();
+        let warnings: Vec<_> = issues.iter().filter(|i| i.severity == IssueSeverity::Warning).collect();
+
+        assert_eq!(errors.len(), 3, "{:?}", issues);
+        assert!(errors[0].message.contains("schema_version"));
+        assert_eq!(warnings.len(), 2, "{:?}", issues);
+    }
+
+    #[test]
+    fn test_parse_rejects_bad_input() {
+        assert!(DeviceDbFile::parse(r#"{"schema_version": 1, "vendors": [{"id": "0x1ffff"}]}"#, DbFormat::Json).is_err());
+        assert!(DeviceDbFile::parse(r#"{"schema_version": 1, "vendors": [{"id": 1, "bogus": true}]}"#, DbFormat::Json).is_err());
+        assert!(DeviceDbFile::parse("schema_version = 1\n[[vendors]]\nid = 1\nproducts = [{ id = 2, mode = \"Warp\" }]", DbFormat::Toml).is_err());
+    }
+
+    #[test]
+    fn test_lint_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let good = dir.path().join("good.toml");
+        let bad = dir.path().join("bad.json");
+        let unknown = dir.path().join("db.yaml");
+        fs::write(&good, "schema_version = 1\n[[vendors]]\nid = 0x1234\nname = \"Rig\"\n").unwrap();
+        fs::write(&bad, "{ not json").unwrap();
+        fs::write(&unknown, "").unwrap();
+
+        let reports = lint(&[good, bad, unknown]);
+        assert!(!reports[0].has_errors());
+        assert_eq!(reports[0].vendors, 1);
+        assert!(reports[1].has_errors());
+        assert!(reports[2].has_errors());
+    }
+}
@@ -0,0 +1,386 @@
+use crate::{BootforgeError, Result};
+use super::cache::{load_cache, parse_product_id, DeviceCache, DeviceSession, ModeTransition};
+use super::detect::DeviceMode;
+use chrono::{DateTime, NaiveDate, NaiveTime, Utc};
+use device_analysis::device_state::DeviceHistory;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use uuid::Uuid;
+
+/// Which devices to report. Empty fields match everything.
+///
+/// A device matches a date range when it was connected or changed mode
+/// within it, not merely because it was first seen before the range and
+/// last seen after it.
+#[derive(Debug, Clone, Default)]
+pub struct HistoryQuery {
+    /// Only devices connected at or after this time.
+    pub since: Option<DateTime<Utc>>,
+    /// Only devices connected at or before this time.
+    pub until: Option<DateTime<Utc>>,
+    /// Logical device ID or serial number.
+    pub device: Option<String>,
+}
+
+impl HistoryQuery {
+    fn overlaps(&self, first_seen: DateTime<Utc>, last_seen: DateTime<Utc>) -> bool {
+        self.since.is_none_or(|since| last_seen >= since) && self.until.is_none_or(|until| first_seen <= until)
+    }
+
+    fn contains(&self, at: DateTime<Utc>) -> bool {
+        self.overlaps(at, at)
+    }
+}
+
+/// Everything the cache knows about one logical device.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DeviceHistoryRecord {
+    pub logical_id: Uuid,
+    pub vendor_id: Option<u16>,
+    pub serial: Option<String>,
+    pub first_seen: DateTime<Utc>,
+    pub last_seen: DateTime<Utc>,
+    /// Times the device was plugged in.
+    pub connection_count: u64,
+    /// Scans that saw the device, in any mode.
+    pub sightings: u64,
+    pub product_ids: Vec<u16>,
+    /// Modes the device has enumerated in, most recent last.
+    pub modes: Vec<DeviceMode>,
+    /// Connections within the queried range, oldest first.
+    #[serde(default)]
+    pub sessions: Vec<DeviceSession>,
+    /// Mode changes within the queried range, oldest first.
+    pub transitions: Vec<ModeTransition>,
+}
+
+impl DeviceHistoryRecord {
+    /// Summary in the unified device state schema.
+    pub fn to_device_history(&self) -> DeviceHistory {
+        DeviceHistory {
+            first_seen: Some(self.first_seen),
+            last_seen: Some(self.last_seen),
+            connection_count: Some(i32::try_from(self.connection_count).unwrap_or(i32::MAX)),
+            last_operation: None,
+            case_ids: None,
+        }
+    }
+}
+
+/// Group cache entries by logical device and apply `query`, most recently
+/// seen first. Entries written before logical IDs existed are skipped; the
+/// v2 cache migration assigns IDs to all of them.
+pub fn device_history(cache: &DeviceCache, query: &HistoryQuery) -> Vec<DeviceHistoryRecord> {
+    let mut grouped: BTreeMap<Uuid, Vec<_>> = BTreeMap::new();
+    for entry in cache.devices.values() {
+        if let Some(logical_id) = entry.logical_id {
+            grouped.entry(logical_id).or_default().push(entry);
+        }
+    }
+
+    let mut records: Vec<DeviceHistoryRecord> = grouped.into_iter()
+        .filter_map(|(logical_id, mut entries)| {
+            entries.sort_by_key(|e| e.last_seen);
+            let latest = entries.last()?;
+
+            let mut product_ids: Vec<u16> = entries.iter().filter_map(|e| parse_product_id(&e.unique_key)).collect();
+            product_ids.sort_unstable();
+            product_ids.dedup();
+
+            let mut modes: Vec<DeviceMode> = Vec::new();
+            for mode in entries.iter().filter_map(|e| e.mode) {
+                modes.retain(|m| *m != mode);
+                modes.push(mode);
+            }
+
+            let recorded_sessions: Vec<&DeviceSession> = cache.sessions.iter()
+                .filter(|s| s.logical_id == logical_id)
+                .collect();
+
+            let record = DeviceHistoryRecord {
+                logical_id,
+                vendor_id: latest.vendor_id,
+                serial: entries.iter().rev().find_map(|e| e.serial.clone()),
+                first_seen: entries.iter().map(|e| e.first_seen).min()?,
+                last_seen: latest.last_seen,
+                connection_count: entries.iter().map(|e| e.connection_count).sum(),
+                sightings: entries.iter().map(|e| e.seen_count).sum(),
+                product_ids,
+                modes,
+                sessions: recorded_sessions.iter()
+                    .filter(|s| query.overlaps(s.connected_at, s.last_seen))
+                    .map(|s| (*s).clone())
+                    .collect(),
+                transitions: cache.transitions.iter()
+                    .filter(|t| t.logical_id == logical_id && query.contains(t.at))
+                    .cloned()
+                    .collect(),
+            };
+
+            // Caches from before sessions were recorded only know when the
+            // device was first and last seen
+            let in_range = !record.sessions.is_empty()
+                || !record.transitions.is_empty()
+                || (recorded_sessions.is_empty() && (query.contains(record.first_seen) || query.contains(record.last_seen)));
+
+            in_range.then_some(record)
+        })
+        .filter(|r| match query.device.as_deref() {
+            Some(device) => r.logical_id.to_string() == device || r.serial.as_deref() == Some(device),
+            None => true,
+        })
+        .collect();
+
+    records.sort_by_key(|r| std::cmp::Reverse(r.last_seen));
+    records
+}
+
+/// `device_history` against the on-disk cache.
+pub fn load_history(query: &HistoryQuery) -> Result<Vec<DeviceHistoryRecord>> {
+    Ok(device_history(&load_cache()?, query))
+}
+
+/// History entry for one device, by logical ID or serial number, ready for
+/// `UnifiedDeviceState.history`.
+pub fn history_for_device(device: &str) -> Result<Option<DeviceHistory>> {
+    let query = HistoryQuery {
+        device: Some(device.to_string()),
+        ..Default::default()
+    };
+    Ok(load_history(&query)?.first().map(DeviceHistoryRecord::to_device_history))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    Csv,
+    Json,
+}
+
+/// Serialize records, one row per device for CSV.
+pub fn export_history(records: &[DeviceHistoryRecord], format: ExportFormat) -> Result<String> {
+    match format {
+        ExportFormat::Json => serde_json::to_string_pretty(records)
+            .map_err(|e| BootforgeError::Usb(format!("Failed to serialize history: {}", e))),
+        ExportFormat::Csv => {
+            let mut out = String::from(
+                "logical_id,vendor_id,serial,first_seen,last_seen,connection_count,sightings,product_ids,modes,transitions\n",
+            );
+            for r in records {
+                let product_ids: Vec<String> = r.product_ids.iter().map(|p| format!("{:04x}", p)).collect();
+                let modes: Vec<String> = r.modes.iter().map(|m| format!("{:?}", m)).collect();
+                let _ = writeln!(
+                    out,
+                    "{},{},{},{},{},{},{},{},{},{}",
+                    r.logical_id,
+                    r.vendor_id.map(|v| format!("{:04x}", v)).unwrap_or_default(),
+                    csv_field(r.serial.as_deref().unwrap_or("")),
+                    r.first_seen.to_rfc3339(),
+                    r.last_seen.to_rfc3339(),
+                    r.connection_count,
+                    r.sightings,
+                    product_ids.join(";"),
+                    modes.join(";"),
+                    r.transitions.len(),
+                );
+            }
+            Ok(out)
+        }
+    }
+}
+
+/// Serialize the mode transitions of `records`, one row per transition for CSV.
+pub fn export_transitions(records: &[DeviceHistoryRecord], format: ExportFormat) -> Result<String> {
+    let transitions: Vec<&ModeTransition> = records.iter().flat_map(|r| &r.transitions).collect();
+
+    match format {
+        ExportFormat::Json => serde_json::to_string_pretty(&transitions)
+            .map_err(|e| BootforgeError::Usb(format!("Failed to serialize transitions: {}", e))),
+        ExportFormat::Csv => {
+            let mut out = String::from("logical_id,at,from_mode,to_mode,from_product_id,to_product_id\n");
+            for t in transitions {
+                let _ = writeln!(
+                    out,
+                    "{},{},{:?},{:?},{},{:04x}",
+                    t.logical_id,
+                    t.at.to_rfc3339(),
+                    t.from_mode,
+                    t.to_mode,
+                    t.from_product_id.map(|p| format!("{:04x}", p)).unwrap_or_default(),
+                    t.to_product_id,
+                );
+            }
+            Ok(out)
+        }
+    }
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+/// Parse an RFC 3339 timestamp or a `YYYY-MM-DD` date. A bare date means the
+/// start of that day, or its end when `end_of_day` is set, so `--until
+/// 2024-05-01` includes all of May 1st.
+pub fn parse_history_date(value: &str, end_of_day: bool) -> Result<DateTime<Utc>> {
+    if let Ok(at) = DateTime::parse_from_rfc3339(value) {
+        return Ok(at.with_timezone(&Utc));
+    }
+
+    let date = NaiveDate::parse_from_str(value, "%Y-%m-%d")
+        .map_err(|_| BootforgeError::Usb(format!("Invalid date '{}', expected YYYY-MM-DD or RFC 3339", value)))?;
+    let time = if end_of_day {
+        NaiveTime::from_hms_milli_opt(23, 59, 59, 999).unwrap_or_default()
+    } else {
+        NaiveTime::MIN
+    };
+    Ok(date.and_time(time).and_utc())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::usb::backend::tests::fixture_device;
+    use crate::usb::identity::DeviceCorrelator;
+    use chrono::Duration;
+
+    fn day(d: u32) -> DateTime<Utc> {
+        parse_history_date(&format!("2024-05-{:02}", d), false).unwrap() + Duration::hours(12)
+    }
+
+    /// Pixel seen on May 1st, switched to fastboot and back, then replugged on
+    /// May 10th. An EDL device seen only on May 20th.
+    fn populated_cache() -> DeviceCache {
+        let mut cache = DeviceCache::new();
+        let normal = fixture_device(0x18d1, 0x4ee1, "PIXEL", DeviceMode::Normal);
+        let fastboot = fixture_device(0x18d1, 0x4ee0, "PIXEL", DeviceMode::Fastboot);
+        let mut edl = fixture_device(0x05c6, 0x9008, "", DeviceMode::Download);
+        edl.serial = None;
+
+        let sightings = [
+            (normal.clone(), day(1)),
+            (normal.clone(), day(1) + Duration::seconds(1)),
+            (fastboot, day(1) + Duration::seconds(10)),
+            (normal.clone(), day(1) + Duration::seconds(20)),
+            (normal, day(10)),
+            (edl, day(20)),
+        ];
+        for (mut info, at) in sightings {
+            DeviceCorrelator::new().correlate(&mut cache, &mut info, at);
+        }
+        cache
+    }
+
+    #[test]
+    fn test_connections_and_transitions() {
+        let cache = populated_cache();
+        let records = device_history(&cache, &HistoryQuery::default());
+
+        assert_eq!(records.len(), 2);
+        let pixel = &records[1];
+        assert_eq!(pixel.serial.as_deref(), Some("PIXEL"));
+        assert_eq!(pixel.first_seen, day(1));
+        assert_eq!(pixel.last_seen, day(10));
+        assert_eq!(pixel.sightings, 5);
+        assert_eq!(pixel.connection_count, 2);
+        let sessions: Vec<_> = pixel.sessions.iter().map(|s| (s.connected_at, s.last_seen)).collect();
+        assert_eq!(sessions, vec![(day(1), day(1) + Duration::seconds(20)), (day(10), day(10))]);
+        assert_eq!(pixel.product_ids, vec![0x4ee0, 0x4ee1]);
+        assert_eq!(pixel.modes, vec![DeviceMode::Fastboot, DeviceMode::Normal]);
+
+        let modes: Vec<_> = pixel.transitions.iter().map(|t| (t.from_mode, t.to_mode)).collect();
+        assert_eq!(modes, vec![(DeviceMode::Normal, DeviceMode::Fastboot), (DeviceMode::Fastboot, DeviceMode::Normal)]);
+        assert_eq!(pixel.transitions[0].from_product_id, Some(0x4ee1));
+
+        let summary = pixel.to_device_history();
+        assert_eq!(summary.connection_count, Some(2));
+        assert_eq!(summary.first_seen, Some(day(1)));
+    }
+
+    #[test]
+    fn test_date_range_and_device_filter() {
+        let cache = populated_cache();
+
+        let may_15 = HistoryQuery { since: Some(day(15)), ..Default::default() };
+        let records = device_history(&cache, &may_15);
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].vendor_id, Some(0x05c6));
+
+        let early_may = HistoryQuery {
+            since: Some(parse_history_date("2024-05-02", false).unwrap()),
+            until: Some(parse_history_date("2024-05-10", true).unwrap()),
+            ..Default::default()
+        };
+        let records = device_history(&cache, &early_may);
+        assert_eq!(records.len(), 1);
+        // Connected again on the 10th, but its mode changes happened before the range
+        assert_eq!(records[0].sessions.len(), 1);
+        assert_eq!(records[0].sessions[0].connected_at, day(10));
+        assert!(records[0].transitions.is_empty());
+
+        // First seen before and last seen after, but not connected in between
+        let mid_may = HistoryQuery {
+            since: Some(parse_history_date("2024-05-03", false).unwrap()),
+            until: Some(parse_history_date("2024-05-08", true).unwrap()),
+            ..Default::default()
+        };
+        assert!(device_history(&cache, &mid_may).is_empty());
+
+        let by_serial = HistoryQuery { device: Some("PIXEL".to_string()), ..Default::default() };
+        let pixel = device_history(&cache, &by_serial);
+        assert_eq!(pixel.len(), 1);
+        let by_id = HistoryQuery { device: Some(pixel[0].logical_id.to_string()), ..Default::default() };
+        assert_eq!(device_history(&cache, &by_id), pixel);
+    }
+
+    #[test]
+    fn test_date_range_without_sessions() {
+        let mut cache = populated_cache();
+        cache.sessions.clear();
+
+        let mid_may = HistoryQuery {
+            since: Some(parse_history_date("2024-05-03", false).unwrap()),
+            until: Some(parse_history_date("2024-05-08", true).unwrap()),
+            ..Default::default()
+        };
+        assert!(device_history(&cache, &mid_may).is_empty());
+
+        let may_10 = HistoryQuery { since: Some(day(9)), until: Some(day(11)), ..Default::default() };
+        let records = device_history(&cache, &may_10);
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].serial.as_deref(), Some("PIXEL"));
+    }
+
+    #[test]
+    fn test_export() {
+        let mut records = device_history(&populated_cache(), &HistoryQuery::default());
+        records[1].serial = Some("A,\"B\"".to_string());
+
+        let csv = export_history(&records, ExportFormat::Csv).unwrap();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].starts_with("logical_id,vendor_id,serial,"));
+        assert!(lines[2].contains(",18d1,\"A,\"\"B\"\"\","));
+        assert!(lines[2].ends_with(",2,5,4ee0;4ee1,Fastboot;Normal,2"));
+
+        let transitions = export_transitions(&records, ExportFormat::Csv).unwrap();
+        assert_eq!(transitions.lines().count(), 3);
+        assert!(transitions.lines().nth(1).unwrap().ends_with(",Normal,Fastboot,4ee1,4ee0"));
+
+        let json = export_history(&records, ExportFormat::Json).unwrap();
+        let parsed: Vec<DeviceHistoryRecord> = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed, records);
+    }
+
+    #[test]
+    fn test_parse_history_date() {
+        assert_eq!(parse_history_date("2024-05-01", false).unwrap().to_rfc3339(), "2024-05-01T00:00:00+00:00");
+        assert_eq!(parse_history_date("2024-05-01", true).unwrap().to_rfc3339(), "2024-05-01T23:59:59.999+00:00");
+        assert_eq!(parse_history_date("2024-05-01T10:00:00+02:00", false).unwrap().to_rfc3339(), "2024-05-01T08:00:00+00:00");
+        assert!(parse_history_date("May 1st", false).is_err());
+    }
+}
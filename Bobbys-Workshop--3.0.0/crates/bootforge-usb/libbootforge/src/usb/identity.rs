@@ -0,0 +1,273 @@
+use super::cache::{DeviceCache, DeviceCacheEntry};
+use super::detect::UsbDeviceInfo;
+use chrono::{DateTime, Duration, Utc};
+use std::collections::HashSet;
+use uuid::Uuid;
+
+/// How long after an enumeration was last seen another enumeration on the
+/// same port is still treated as the same handset re-enumerating.
+pub const REENUMERATION_WINDOW_SECS: i64 = 30;
+
+/// Assigns persistent logical device IDs to enumerations.
+///
+/// A handset switching modes (normal -> fastboot, ADB toggled, EDL) usually
+/// changes PID and sometimes loses its serial, so `unique_key()` alone sees a
+/// new device. Enumerations are matched to existing logical devices in the
+/// `DeviceCache` by, in order:
+///
+/// 1. the exact `unique_key`,
+/// 2. the same serial from the same vendor,
+/// 3. the same bus and port path, seen within the re-enumeration window, unless both
+///    sides report conflicting serials from the same vendor.
+///
+/// Serial-less enumerations share a `unique_key` with every other unit of the
+/// same model, so for those the port match is tried before the exact key.
+///
+/// One correlator should be used per scan: a logical ID is handed out at most
+/// once, so two handsets present at the same time never merge.
+#[derive(Debug, Clone)]
+pub struct DeviceCorrelator {
+    window: Duration,
+    claimed: HashSet<Uuid>,
+}
+
+impl DeviceCorrelator {
+    pub fn new() -> Self {
+        Self::with_window(Duration::seconds(REENUMERATION_WINDOW_SECS))
+    }
+
+    pub fn with_window(window: Duration) -> Self {
+        DeviceCorrelator {
+            window,
+            claimed: HashSet::new(),
+        }
+    }
+
+    /// Treat `ids` as taken, e.g. by devices still on the bus from an earlier
+    /// scan, so no enumeration is matched to them.
+    pub fn claim(&mut self, ids: impl IntoIterator<Item = Uuid>) {
+        self.claimed.extend(ids);
+    }
+
+    /// Set `info.id`, `first_seen` and `last_seen` from the logical device it
+    /// belongs to and record the enumeration in `cache`.
+    pub fn correlate(&mut self, cache: &mut DeviceCache, info: &mut UsbDeviceInfo, now: DateTime<Utc>) {
+        let unique_key = info.unique_key();
+
+        let (logical_id, first_seen) = self
+            .find_match(cache, info, &unique_key, now)
+            .unwrap_or_else(|| (Uuid::new_v4(), now));
+
+        if cache.get_entry(&unique_key).and_then(|e| e.logical_id) != Some(logical_id) {
+            log::debug!("[BootForge] {} correlated to logical device {}", unique_key, logical_id);
+        }
+
+        info.id = logical_id;
+        info.first_seen = first_seen;
+        info.last_seen = now;
+
+        cache.record_device(info, first_seen, now);
+        self.claimed.insert(logical_id);
+    }
+
+    fn find_match(
+        &self,
+        cache: &DeviceCache,
+        info: &UsbDeviceInfo,
+        unique_key: &str,
+        now: DateTime<Utc>,
+    ) -> Option<(Uuid, DateTime<Utc>)> {
+        let by_key = || {
+            cache.get_entry(unique_key).and_then(|entry| {
+                let logical_id = entry.logical_id.unwrap_or_else(Uuid::new_v4);
+                (!self.claimed.contains(&logical_id)).then_some((logical_id, entry.first_seen))
+            })
+        };
+
+        if has_serial(info) {
+            by_key()
+                .or_else(|| self.by_serial(cache, info))
+                .or_else(|| self.by_port(cache, info, now))
+        } else {
+            self.by_port(cache, info, now).or_else(by_key)
+        }
+    }
+
+    fn by_serial(&self, cache: &DeviceCache, info: &UsbDeviceInfo) -> Option<(Uuid, DateTime<Utc>)> {
+        self.most_recent(cache, |entry| {
+            entry.vendor_id == Some(info.vendor_id) && entry.serial.is_some() && entry.serial == info.serial
+        })
+    }
+
+    fn by_port(&self, cache: &DeviceCache, info: &UsbDeviceInfo, now: DateTime<Utc>) -> Option<(Uuid, DateTime<Utc>)> {
+        if info.bus.is_none() || info.port_path.is_empty() {
+            return None;
+        }
+
+        self.most_recent(cache, |entry| {
+            entry.bus == info.bus
+                && entry.port_path == info.port_path
+                && now - entry.last_seen <= self.window
+                && !serials_conflict(entry, info)
+        })
+    }
+
+    fn most_recent(
+        &self,
+        cache: &DeviceCache,
+        matches: impl Fn(&DeviceCacheEntry) -> bool,
+    ) -> Option<(Uuid, DateTime<Utc>)> {
+        cache.devices.values()
+            .filter(|entry| entry.logical_id.is_some_and(|id| !self.claimed.contains(&id)))
+            .filter(|entry| matches(entry))
+            .max_by_key(|entry| entry.last_seen)
+            .and_then(|entry| Some((entry.logical_id?, cache.first_seen_of(entry.logical_id?)?)))
+    }
+}
+
+impl Default for DeviceCorrelator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn has_serial(info: &UsbDeviceInfo) -> bool {
+    info.serial.as_deref().is_some_and(|s| !s.is_empty())
+}
+
+/// Two enumerations from the same vendor with different serials are different
+/// handsets. Serials across vendors (e.g. a phone and its SoC's download mode)
+/// are not comparable.
+fn serials_conflict(entry: &DeviceCacheEntry, info: &UsbDeviceInfo) -> bool {
+    entry.vendor_id == Some(info.vendor_id)
+        && entry.serial.is_some()
+        && has_serial(info)
+        && entry.serial != info.serial
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::usb::backend::tests::fixture_device;
+    use crate::usb::DeviceMode;
+
+    fn on_port(mut info: UsbDeviceInfo, port: u8) -> UsbDeviceInfo {
+        info.port = Some(port);
+        info.port_path = vec![1, port];
+        info
+    }
+
+    #[test]
+    fn test_same_key_keeps_id() {
+        let mut cache = DeviceCache::new();
+        let now = Utc::now();
+        let mut first = fixture_device(0x18d1, 0x4ee1, "PIXEL", DeviceMode::Normal);
+        let mut again = first.clone();
+        again.id = Uuid::new_v4();
+
+        DeviceCorrelator::new().correlate(&mut cache, &mut first, now);
+        DeviceCorrelator::new().correlate(&mut cache, &mut again, now + Duration::hours(1));
+
+        assert_eq!(first.id, again.id);
+        assert_eq!(again.first_seen, now);
+        assert_eq!(cache.get_entry(&again.unique_key()).unwrap().seen_count, 2);
+    }
+
+    #[test]
+    fn test_pid_change_with_same_serial_keeps_id() {
+        let mut cache = DeviceCache::new();
+        let now = Utc::now();
+        let mut normal = fixture_device(0x18d1, 0x4ee1, "PIXEL", DeviceMode::Normal);
+        let mut fastboot = fixture_device(0x18d1, 0x4ee0, "PIXEL", DeviceMode::Fastboot);
+
+        DeviceCorrelator::new().correlate(&mut cache, &mut normal, now);
+        // Hours later so only the serial can tie them together
+        DeviceCorrelator::new().correlate(&mut cache, &mut fastboot, now + Duration::hours(5));
+
+        assert_eq!(normal.id, fastboot.id);
+        assert_eq!(fastboot.first_seen, now);
+        assert_eq!(cache.devices.len(), 2);
+    }
+
+    #[test]
+    fn test_serial_less_reenumeration_on_same_port() {
+        let mut cache = DeviceCache::new();
+        let now = Utc::now();
+        let mut phone = on_port(fixture_device(0x2717, 0xff48, "XIAOMI", DeviceMode::MTP), 3);
+        let mut edl = on_port(fixture_device(0x05c6, 0x9008, "", DeviceMode::Download), 3);
+        edl.serial = None;
+
+        DeviceCorrelator::new().correlate(&mut cache, &mut phone, now);
+        DeviceCorrelator::new().correlate(&mut cache, &mut edl, now + Duration::seconds(4));
+        assert_eq!(phone.id, edl.id);
+
+        // Too late: a different serial-less device on the same port is a new handset
+        let later = now + Duration::seconds(REENUMERATION_WINDOW_SECS * 10);
+        let mut fresh = on_port(fixture_device(0x0e8d, 0x2000, "", DeviceMode::Download), 3);
+        fresh.serial = None;
+        DeviceCorrelator::new().correlate(&mut cache, &mut fresh, later);
+        assert_ne!(fresh.id, phone.id);
+    }
+
+    #[test]
+    fn test_conflicting_serials_on_same_port_are_different_devices() {
+        let mut cache = DeviceCache::new();
+        let now = Utc::now();
+        let mut a = on_port(fixture_device(0x18d1, 0x4ee1, "AAAA", DeviceMode::Normal), 1);
+        let mut b = on_port(fixture_device(0x18d1, 0x4ee0, "BBBB", DeviceMode::Fastboot), 1);
+
+        DeviceCorrelator::new().correlate(&mut cache, &mut a, now);
+        DeviceCorrelator::new().correlate(&mut cache, &mut b, now + Duration::seconds(1));
+
+        assert_ne!(a.id, b.id);
+    }
+
+    #[test]
+    fn test_ids_are_not_shared_within_one_scan() {
+        let mut cache = DeviceCache::new();
+        let now = Utc::now();
+        let mut first = fixture_device(0x05c6, 0x9008, "", DeviceMode::Download);
+        first.serial = None;
+        DeviceCorrelator::new().correlate(&mut cache, &mut first, now);
+
+        // Two identical serial-less units attached at once
+        let mut correlator = DeviceCorrelator::new();
+        let mut a = first.clone();
+        let mut b = first.clone();
+        correlator.correlate(&mut cache, &mut a, now);
+        correlator.correlate(&mut cache, &mut b, now);
+
+        assert_eq!(a.id, first.id);
+        assert_ne!(a.id, b.id);
+    }
+
+    #[test]
+    fn test_claimed_ids_are_not_reused_across_events() {
+        let mut cache = DeviceCache::new();
+        let now = Utc::now();
+        let mut first = on_port(fixture_device(0x05c6, 0x9008, "", DeviceMode::Download), 2);
+        first.serial = None;
+        DeviceCorrelator::new().correlate(&mut cache, &mut first, now);
+
+        // A second identical unit arrives in its own hotplug event while the first is present
+        let mut second = on_port(first.clone(), 3);
+        let mut correlator = DeviceCorrelator::new();
+        correlator.claim([first.id]);
+        correlator.correlate(&mut cache, &mut second, now + Duration::seconds(2));
+
+        assert_ne!(second.id, first.id);
+    }
+
+    #[test]
+    fn test_legacy_entry_without_logical_id() {
+        let mut cache = DeviceCache::new();
+        let then = Utc::now() - Duration::days(30);
+        let mut pixel = fixture_device(0x18d1, 0x4ee1, "PIXEL", DeviceMode::Normal);
+        cache.update_entry(pixel.unique_key(), then);
+
+        DeviceCorrelator::new().correlate(&mut cache, &mut pixel, Utc::now());
+
+        assert_eq!(pixel.first_seen, then);
+        assert_eq!(cache.get_entry(&pixel.unique_key()).unwrap().logical_id, Some(pixel.id));
+    }
+}
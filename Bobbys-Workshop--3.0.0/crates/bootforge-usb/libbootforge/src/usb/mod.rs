@@ -1,11 +1,20 @@
+pub mod backend;
 pub mod cache;
+pub mod classify;
 pub mod detect;
+pub mod device_db;
+pub mod history;
+pub mod identity;
+pub mod topology;
 pub mod transport;
 pub mod vendor_map;
 pub mod watcher;
 
+pub use backend::{UsbBackend, NusbBackend, FixtureBackend, HotplugEvent, HotplugReceiver};
 pub use detect::{
     detect_devices, 
+    detect_devices_with,
+    record_device_with,
     detect_mobile_devices,
     detect_device_by_serial,
     UsbDeviceInfo, 
@@ -15,7 +24,60 @@ pub use detect::{
     ProtocolType,
     DeviceEvent,
 };
-pub use transport::{UsbTransport, UsbEndpoint};
+pub use cache::{
+    DeviceCache,
+    DeviceCacheEntry,
+    DeviceSession,
+    ModeTransition,
+    get_cache_path,
+    set_cache_path,
+    update_cache,
+    CACHE_PATH_ENV,
+    CACHE_VERSION,
+};
+pub use classify::{InterfaceHint, classify_protocol, descriptor_protocols};
+pub use device_db::{
+    DeviceDatabase,
+    DeviceDbFile,
+    DbIssue,
+    IssueSeverity,
+    LayerReport,
+    DEVICE_DB_ENV,
+};
+pub use history::{
+    DeviceHistoryRecord,
+    HistoryQuery,
+    ExportFormat,
+    device_history,
+    load_history,
+    history_for_device,
+    export_history,
+    export_transitions,
+    parse_history_date,
+};
+pub use identity::{DeviceCorrelator, REENUMERATION_WINDOW_SECS};
+pub use topology::{
+    UsbTopology,
+    TopologyNode,
+    detect_topology,
+    detect_topology_with,
+    format_location,
+    parse_location,
+    port_labels,
+    set_port_label,
+    clear_port_label,
+};
+pub use transport::{
+    UsbTransport,
+    UsbEndpoint,
+    TransportBackend,
+    NusbTransportBackend,
+    CancelHandle,
+    ControlRequest,
+    ControlKind,
+    ControlRecipient,
+    DEFAULT_TRANSFER_TIMEOUT,
+};
 pub use vendor_map::{
     map_vendor_to_platform, 
     get_vendor_name, 
@@ -23,6 +85,5 @@ pub use vendor_map::{
     get_vendor_profile,
     is_mobile_device,
     VendorProfile,
-    VENDOR_PROFILES,
 };
 pub use watcher::{DeviceWatcher, WatcherConfig};
@@ -0,0 +1,373 @@
+use crate::{BootforgeError, Result};
+use super::backend::{NusbBackend, UsbBackend};
+use super::cache::{load_cache, update_cache};
+use super::detect::{detect_devices_with, UsbDeviceInfo};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt::Write;
+
+/// Format a port chain as `bus-port.port.port`, or `usbN` for a root hub.
+pub fn format_location(bus: u8, port_path: &[u8]) -> String {
+    if port_path.is_empty() {
+        return format!("usb{}", bus);
+    }
+
+    let ports: Vec<String> = port_path.iter().map(|p| p.to_string()).collect();
+    format!("{}-{}", bus, ports.join("."))
+}
+
+/// Parse a location written by `format_location` (also the Linux sysfs
+/// device name). Interface suffixes such as `1-2.3:1.0` are rejected.
+pub fn parse_location(location: &str) -> Option<(u8, Vec<u8>)> {
+    if let Some(bus) = location.strip_prefix("usb") {
+        return Some((bus.parse().ok()?, Vec::new()));
+    }
+
+    let (bus, ports) = location.split_once('-')?;
+    let bus = bus.parse().ok()?;
+    let port_path = ports.split('.')
+        .map(|p| p.parse::<u8>().ok().filter(|&p| p != 0))
+        .collect::<Option<Vec<_>>>()?;
+
+    Some((bus, port_path))
+}
+
+/// Location of the hub a device on `port_path` is plugged into.
+pub fn parent_location(bus: u8, port_path: &[u8]) -> Option<String> {
+    let (_, parent) = port_path.split_last()?;
+    Some(format_location(bus, parent))
+}
+
+/// Port chain of a device on `port` of the hub `parent`, for platforms that
+/// only report the port on the immediate parent. `hub` looks up an enumerated
+/// hub's own port and parent; the walk ends at the root hub, which is not
+/// enumerated as a device.
+#[cfg(any(target_os = "windows", test))]
+pub(crate) fn chain_through_hubs(
+    port: u32,
+    parent: &str,
+    hub: impl Fn(&str) -> Option<(u32, String)>,
+) -> Vec<u8> {
+    // USB allows five hubs between the root and a device
+    const MAX_TIERS: usize = 7;
+
+    let Some(port) = u8::try_from(port).ok().filter(|&p| p != 0) else {
+        return Vec::new();
+    };
+    let mut chain = vec![port];
+    let mut parent = parent.to_string();
+    while let Some((port, grandparent)) = hub(&parent) {
+        let Some(port) = u8::try_from(port).ok().filter(|&p| p != 0) else {
+            break;
+        };
+        if chain.len() == MAX_TIERS {
+            log::warn!("Port chain through {} is deeper than USB allows, truncating", parent);
+            break;
+        }
+        chain.insert(0, port);
+        parent = grandparent;
+    }
+    chain
+}
+
+/// A port in the USB tree. `device` is `None` for hubs that were not
+/// enumerated themselves but have enumerated children.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TopologyNode {
+    pub location: String,
+    pub bus: u8,
+    pub port_path: Vec<u8>,
+    pub device: Option<UsbDeviceInfo>,
+    pub label: Option<String>,
+    pub children: Vec<TopologyNode>,
+}
+
+impl TopologyNode {
+    fn new(bus: u8, port_path: Vec<u8>, labels: &HashMap<String, String>) -> Self {
+        let location = format_location(bus, &port_path);
+        TopologyNode {
+            label: labels.get(&location).cloned(),
+            location,
+            bus,
+            port_path,
+            device: None,
+            children: Vec::new(),
+        }
+    }
+
+    /// Place `device` at its port. A port already holding a device keeps it
+    /// and the newcomer goes to `unplaced`.
+    fn insert(
+        &mut self,
+        device: UsbDeviceInfo,
+        depth: usize,
+        labels: &HashMap<String, String>,
+        unplaced: &mut Vec<UsbDeviceInfo>,
+    ) {
+        if depth == device.port_path.len() {
+            match &self.device {
+                Some(occupant) => {
+                    log::warn!(
+                        "Two devices report port {}: keeping {:04x}:{:04x}, leaving {:04x}:{:04x} unplaced",
+                        self.location, occupant.vendor_id, occupant.product_id, device.vendor_id, device.product_id
+                    );
+                    unplaced.push(device);
+                }
+                None => self.device = Some(device),
+            }
+            return;
+        }
+
+        let port = device.port_path[depth];
+        let pos = match self.children.iter().position(|c| c.port_path[depth] == port) {
+            Some(pos) => pos,
+            None => {
+                let child = TopologyNode::new(self.bus, device.port_path[..=depth].to_vec(), labels);
+                self.children.push(child);
+                self.children.sort_by_key(|c| c.port_path[depth]);
+                self.children.iter().position(|c| c.port_path[depth] == port).unwrap_or_default()
+            }
+        };
+
+        self.children[pos].insert(device, depth + 1, labels, unplaced);
+    }
+
+    fn find(&self, location: &str) -> Option<&TopologyNode> {
+        if self.location == location {
+            return Some(self);
+        }
+        self.children.iter().find_map(|c| c.find(location))
+    }
+
+    fn render(&self, out: &mut String, prefix: &str, last: bool, root: bool) {
+        let (branch, indent) = match (root, last) {
+            (true, _) => ("", ""),
+            (false, true) => ("└── ", "    "),
+            (false, false) => ("├── ", "│   "),
+        };
+
+        let _ = write!(out, "{}{}{}", prefix, branch, self.location);
+        match &self.device {
+            Some(d) => {
+                let _ = write!(out, "  {:04x}:{:04x} {}", d.vendor_id, d.product_id,
+                    d.product.as_deref().unwrap_or("Unknown"));
+                if let Some(serial) = d.serial.as_deref() {
+                    let _ = write!(out, " ({})", serial);
+                }
+            }
+            None => out.push_str("  (hub)"),
+        }
+        if let Some(label) = &self.label {
+            let _ = write!(out, "  [{}]", label);
+        }
+        out.push('\n');
+
+        let child_prefix = format!("{}{}", prefix, indent);
+        for (i, child) in self.children.iter().enumerate() {
+            child.render(out, &child_prefix, i + 1 == self.children.len(), false);
+        }
+    }
+}
+
+/// Hub tree of every enumerated device, one root per bus.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct UsbTopology {
+    pub buses: Vec<TopologyNode>,
+    /// Devices whose bus or port chain the platform did not report.
+    pub unplaced: Vec<UsbDeviceInfo>,
+}
+
+impl UsbTopology {
+    pub fn build(devices: &[UsbDeviceInfo], labels: &HashMap<String, String>) -> Self {
+        let mut topology = UsbTopology::default();
+        let mut sorted = devices.to_vec();
+        sorted.sort_by(|a, b| (a.bus, &a.port_path).cmp(&(b.bus, &b.port_path)));
+
+        for device in sorted {
+            let Some(bus) = device.bus else {
+                topology.unplaced.push(device);
+                continue;
+            };
+
+            let pos = match topology.buses.iter().position(|b| b.bus == bus) {
+                Some(pos) => pos,
+                None => {
+                    topology.buses.push(TopologyNode::new(bus, Vec::new(), labels));
+                    topology.buses.len() - 1
+                }
+            };
+
+            // Only a root hub legitimately sits at an empty port path
+            if device.port_path.is_empty()
+                && (device.parent_hub.is_some() || topology.buses[pos].device.is_some())
+            {
+                topology.unplaced.push(device);
+                continue;
+            }
+
+            topology.buses[pos].insert(device, 0, labels, &mut topology.unplaced);
+        }
+
+        topology
+    }
+
+    pub fn find(&self, location: &str) -> Option<&TopologyNode> {
+        self.buses.iter().find_map(|b| b.find(location))
+    }
+
+    /// Find the port a device is on by serial.
+    pub fn locate(&self, serial: &str) -> Option<&TopologyNode> {
+        fn walk<'a>(node: &'a TopologyNode, serial: &str) -> Option<&'a TopologyNode> {
+            if node.device.as_ref().is_some_and(|d| d.serial.as_deref() == Some(serial)) {
+                return Some(node);
+            }
+            node.children.iter().find_map(|c| walk(c, serial))
+        }
+        self.buses.iter().find_map(|b| walk(b, serial))
+    }
+
+    /// Render as an indented tree, one port per line.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        for bus in &self.buses {
+            bus.render(&mut out, "", true, true);
+        }
+        if !self.unplaced.is_empty() {
+            out.push_str("unknown port\n");
+            for (i, d) in self.unplaced.iter().enumerate() {
+                let branch = if i + 1 == self.unplaced.len() { "└── " } else { "├── " };
+                let _ = writeln!(out, "{}{:04x}:{:04x} {}", branch, d.vendor_id, d.product_id,
+                    d.product.as_deref().unwrap_or("Unknown"));
+            }
+        }
+        out
+    }
+}
+
+pub fn detect_topology() -> Result<UsbTopology> {
+    detect_topology_with(&NusbBackend)
+}
+
+/// Scan with `backend` and arrange the result by hub, applying saved labels.
+pub fn detect_topology_with(backend: &dyn UsbBackend) -> Result<UsbTopology> {
+    let devices = detect_devices_with(backend)?;
+    Ok(UsbTopology::build(&devices, &port_labels()?))
+}
+
+/// Labels saved with the device cache, keyed by location.
+pub fn port_labels() -> Result<HashMap<String, String>> {
+    Ok(load_cache()?.port_labels)
+}
+
+/// Attach a human label ("Bench 3, slot 2") to a physical port.
+pub fn set_port_label(location: &str, label: &str) -> Result<()> {
+    let key = label_key(location)?;
+    update_cache(|cache| {
+        cache.port_labels.insert(key, label.to_string());
+    })
+}
+
+/// Remove a port label. Returns the label that was removed, if any.
+pub fn clear_port_label(location: &str) -> Result<Option<String>> {
+    let key = label_key(location)?;
+    update_cache(|cache| cache.port_labels.remove(&key))
+}
+
+/// Canonical form of a location as stored in `port_labels`.
+fn label_key(location: &str) -> Result<String> {
+    let (bus, port_path) = parse_location(location)
+        .ok_or_else(|| BootforgeError::Usb(format!("Invalid port location: {}", location)))?;
+    Ok(format_location(bus, &port_path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::usb::backend::tests::fixture_device;
+    use crate::usb::DeviceMode;
+
+    fn at(mut device: UsbDeviceInfo, bus: u8, port_path: &[u8]) -> UsbDeviceInfo {
+        device.bus = Some(bus);
+        device.port_path = port_path.to_vec();
+        device.port = port_path.last().copied();
+        device.parent_hub = parent_location(bus, port_path);
+        device
+    }
+
+    #[test]
+    fn test_location_round_trip() {
+        assert_eq!(format_location(1, &[2, 3, 1]), "1-2.3.1");
+        assert_eq!(format_location(2, &[]), "usb2");
+        assert_eq!(parse_location("1-2.3.1"), Some((1, vec![2, 3, 1])));
+        assert_eq!(parse_location("usb2"), Some((2, vec![])));
+        assert_eq!(parse_location("1-2.3:1.0"), None);
+        assert_eq!(parse_location("1-0"), None);
+        assert_eq!(parent_location(1, &[2, 3]), Some("1-2".to_string()));
+        assert_eq!(parent_location(1, &[2]), Some("usb1".to_string()));
+        assert_eq!(parent_location(1, &[]), None);
+
+        // Labels are set and cleared under the same key however the location is written
+        assert_eq!(label_key("01-02.3").unwrap(), "1-2.3");
+        assert_eq!(label_key("usb01").unwrap(), "usb1");
+        assert!(label_key("1-2.3:1.0").is_err());
+    }
+
+    #[test]
+    fn test_build_and_render_tree() {
+        let hub = at(fixture_device(0x05e3, 0x0610, "HUB", DeviceMode::Unknown), 1, &[1]);
+        let pixel = at(fixture_device(0x18d1, 0x4ee1, "PIXEL", DeviceMode::Normal), 1, &[1, 2]);
+        // Parent hub 1-3 was not enumerated
+        let galaxy = at(fixture_device(0x04e8, 0x6860, "GALAXY", DeviceMode::MTP), 1, &[3, 4]);
+        let mut floating = fixture_device(0x2717, 0xff48, "XIAOMI", DeviceMode::MTP);
+        floating.bus = None;
+
+        let labels = HashMap::from([("1-1.2".to_string(), "Bench 3, slot 2".to_string())]);
+        let topology = UsbTopology::build(&[galaxy, pixel, hub, floating], &labels);
+
+        assert_eq!(topology.buses.len(), 1);
+        assert_eq!(topology.unplaced.len(), 1);
+        let slot = topology.find("1-1.2").unwrap();
+        assert_eq!(slot.label.as_deref(), Some("Bench 3, slot 2"));
+        assert_eq!(topology.locate("GALAXY").unwrap().location, "1-3.4");
+        assert!(topology.find("1-3").unwrap().device.is_none());
+
+        let rendered = topology.render();
+        let lines: Vec<&str> = rendered.lines().collect();
+        assert_eq!(lines[0], "usb1  (hub)");
+        assert!(lines[1].starts_with("├── 1-1  05e3:0610"));
+        assert!(lines[2].starts_with("│   └── 1-1.2  18d1:4ee1"));
+        assert!(lines[2].ends_with("[Bench 3, slot 2]"));
+        assert_eq!(lines[3], "└── 1-3  (hub)");
+        assert!(lines[4].starts_with("    └── 1-3.4  04e8:6860"));
+        assert_eq!(lines[5], "unknown port");
+    }
+
+    #[test]
+    fn test_duplicate_port_is_not_overwritten() {
+        let first = at(fixture_device(0x18d1, 0x4ee1, "FIRST", DeviceMode::Normal), 1, &[2]);
+        let second = at(fixture_device(0x18d1, 0x4ee1, "SECOND", DeviceMode::Normal), 1, &[2]);
+
+        let topology = UsbTopology::build(&[first, second], &HashMap::new());
+        assert_eq!(topology.locate("FIRST").unwrap().location, "1-2");
+        assert!(topology.locate("SECOND").is_none());
+        assert_eq!(topology.unplaced.len(), 1);
+        assert_eq!(topology.unplaced[0].serial.as_deref(), Some("SECOND"));
+    }
+
+    #[test]
+    fn test_chain_through_hubs() {
+        // Two external hubs on root ports 1 and 2, each with a handset on its port 3
+        let hubs = HashMap::from([
+            ("HUB_A", (1, "ROOT")),
+            ("HUB_B", (2, "ROOT")),
+            ("HUB_B2", (4, "HUB_B")),
+        ]);
+        let lookup = |id: &str| hubs.get(id).map(|&(port, parent)| (port, parent.to_string()));
+
+        assert_eq!(chain_through_hubs(3, "HUB_A", lookup), vec![1, 3]);
+        assert_eq!(chain_through_hubs(3, "HUB_B", lookup), vec![2, 3]);
+        assert_eq!(chain_through_hubs(3, "HUB_B2", lookup), vec![2, 4, 3]);
+        assert_eq!(chain_through_hubs(5, "ROOT", lookup), vec![5]);
+        assert_eq!(chain_through_hubs(0, "HUB_A", lookup), Vec::<u8>::new());
+    }
+}
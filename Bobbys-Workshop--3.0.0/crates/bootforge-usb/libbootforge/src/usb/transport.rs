@@ -1,6 +1,15 @@
 use crate::Result;
 use crate::BootforgeError;
 use super::detect::UsbDeviceInfo;
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch;
+
+/// Default per-transfer timeout, matching `usb.timeout_ms` in `configs/bootforge.toml`.
+pub const DEFAULT_TRANSFER_TIMEOUT: Duration = Duration::from_millis(30_000);
 
 #[derive(Debug, Clone)]
 pub struct UsbEndpoint {
@@ -10,10 +19,183 @@ pub struct UsbEndpoint {
     pub max_packet_size: u16,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ControlKind {
+    Standard,
+    Class,
+    Vendor,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ControlRecipient {
+    Device,
+    Interface,
+    Endpoint,
+    Other,
+}
+
+/// SETUP packet fields for a control transfer (direction and length are implied by the call).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ControlRequest {
+    pub kind: ControlKind,
+    pub recipient: ControlRecipient,
+    pub request: u8,
+    pub value: u16,
+    pub index: u16,
+}
+
+/// Boxed future returned by a [`TransportBackend`].
+pub type TransferFuture<'a, T> = Pin<Box<dyn Future<Output = Result<T>> + Send + 'a>>;
+
+/// Raw transfer operations on a claimed interface.
+///
+/// `UsbTransport` layers timeouts and cancellation on top; dropping a returned
+/// future must cancel the underlying transfer.
+pub trait TransportBackend: Send + Sync + fmt::Debug {
+    fn bulk_out<'a>(&'a self, endpoint: u8, data: &'a [u8]) -> TransferFuture<'a, usize>;
+    fn bulk_in(&self, endpoint: u8, max_len: usize) -> TransferFuture<'_, Vec<u8>>;
+    fn control_in(&self, request: ControlRequest, length: u16) -> TransferFuture<'_, Vec<u8>>;
+    fn control_out<'a>(&'a self, request: ControlRequest, data: &'a [u8]) -> TransferFuture<'a, usize>;
+    fn clear_halt(&self, endpoint: u8) -> Result<()>;
+
+    /// Select an alternate setting of the claimed interface. Backends that
+    /// cannot switch settings keep this default, which fails.
+    fn set_alt_setting(&self, _alt_setting: u8) -> Result<()> {
+        Err(BootforgeError::Usb("Alternate settings are not supported by this transport".to_string()))
+    }
+}
+
+/// Handle that aborts in-flight and future transfers on a `UsbTransport`.
+#[derive(Debug, Clone)]
+pub struct CancelHandle {
+    tx: Arc<watch::Sender<bool>>,
+}
+
+impl CancelHandle {
+    pub fn cancel(&self) {
+        self.tx.send_replace(true);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        *self.tx.borrow()
+    }
+}
+
+/// nusb-backed implementation of [`TransportBackend`].
+pub struct NusbTransportBackend {
+    interface: nusb::Interface,
+}
+
+impl NusbTransportBackend {
+    pub fn new(interface: nusb::Interface) -> Self {
+        NusbTransportBackend { interface }
+    }
+}
+
+impl fmt::Debug for NusbTransportBackend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("NusbTransportBackend")
+            .field("interface", &self.interface.interface_number())
+            .finish()
+    }
+}
+
+fn transfer_error(op: &str, e: nusb::transfer::TransferError) -> BootforgeError {
+    BootforgeError::Usb(format!("{} failed: {}", op, e))
+}
+
+fn nusb_control_type(kind: ControlKind) -> nusb::transfer::ControlType {
+    match kind {
+        ControlKind::Standard => nusb::transfer::ControlType::Standard,
+        ControlKind::Class => nusb::transfer::ControlType::Class,
+        ControlKind::Vendor => nusb::transfer::ControlType::Vendor,
+    }
+}
+
+fn nusb_recipient(recipient: ControlRecipient) -> nusb::transfer::Recipient {
+    match recipient {
+        ControlRecipient::Device => nusb::transfer::Recipient::Device,
+        ControlRecipient::Interface => nusb::transfer::Recipient::Interface,
+        ControlRecipient::Endpoint => nusb::transfer::Recipient::Endpoint,
+        ControlRecipient::Other => nusb::transfer::Recipient::Other,
+    }
+}
+
+impl TransportBackend for NusbTransportBackend {
+    fn bulk_out<'a>(&'a self, endpoint: u8, data: &'a [u8]) -> TransferFuture<'a, usize> {
+        Box::pin(async move {
+            let completion = self.interface.bulk_out(endpoint, data.to_vec()).await;
+            completion.status.map_err(|e| transfer_error("Bulk OUT", e))?;
+            Ok(completion.data.actual_length())
+        })
+    }
+
+    fn bulk_in(&self, endpoint: u8, max_len: usize) -> TransferFuture<'_, Vec<u8>> {
+        Box::pin(async move {
+            let buf = nusb::transfer::RequestBuffer::new(max_len);
+            self.interface
+                .bulk_in(endpoint, buf)
+                .await
+                .into_result()
+                .map_err(|e| transfer_error("Bulk IN", e))
+        })
+    }
+
+    fn control_in(&self, request: ControlRequest, length: u16) -> TransferFuture<'_, Vec<u8>> {
+        Box::pin(async move {
+            self.interface
+                .control_in(nusb::transfer::ControlIn {
+                    control_type: nusb_control_type(request.kind),
+                    recipient: nusb_recipient(request.recipient),
+                    request: request.request,
+                    value: request.value,
+                    index: request.index,
+                    length,
+                })
+                .await
+                .into_result()
+                .map_err(|e| transfer_error("Control IN", e))
+        })
+    }
+
+    fn control_out<'a>(&'a self, request: ControlRequest, data: &'a [u8]) -> TransferFuture<'a, usize> {
+        Box::pin(async move {
+            let completion = self
+                .interface
+                .control_out(nusb::transfer::ControlOut {
+                    control_type: nusb_control_type(request.kind),
+                    recipient: nusb_recipient(request.recipient),
+                    request: request.request,
+                    value: request.value,
+                    index: request.index,
+                    data,
+                })
+                .await;
+            completion.status.map_err(|e| transfer_error("Control OUT", e))?;
+            Ok(completion.data.actual_length())
+        })
+    }
+
+    fn clear_halt(&self, endpoint: u8) -> Result<()> {
+        self.interface
+            .clear_halt(endpoint)
+            .map_err(|e| BootforgeError::Usb(format!("Failed to clear halt on {:#04x}: {}", endpoint, e)))
+    }
+
+    fn set_alt_setting(&self, alt_setting: u8) -> Result<()> {
+        self.interface
+            .set_alt_setting(alt_setting)
+            .map_err(|e| BootforgeError::Usb(format!("Failed to select alternate setting {}: {}", alt_setting, e)))
+    }
+}
+
 #[derive(Debug)]
 pub struct UsbTransport {
     pub device: UsbDeviceInfo,
     pub endpoints: Vec<UsbEndpoint>,
+    backend: Option<Box<dyn TransportBackend>>,
+    timeout: Duration,
+    cancel: Arc<watch::Sender<bool>>,
 }
 
 impl UsbTransport {
@@ -21,24 +203,389 @@ impl UsbTransport {
         UsbTransport {
             device,
             endpoints: Vec::new(),
+            backend: None,
+            timeout: DEFAULT_TRANSFER_TIMEOUT,
+            cancel: Arc::new(watch::Sender::new(false)),
         }
     }
 
+    /// Wrap an already-claimed interface. Endpoints must be added with `add_endpoint`.
+    pub fn with_backend(device: UsbDeviceInfo, backend: Box<dyn TransportBackend>) -> Self {
+        let mut transport = Self::new(device);
+        transport.backend = Some(backend);
+        transport
+    }
+
+    /// Open `device`, claim `interface` and discover its endpoints.
+    pub fn open(device: UsbDeviceInfo, interface: u8) -> Result<Self> {
+        let info = find_nusb_device(&device)?;
+
+        let handle = info.open().map_err(|e| {
+            BootforgeError::Usb(format!("Failed to open {}: {}", device.unique_key(), e))
+        })?;
+
+        let claimed = handle.detach_and_claim_interface(interface).map_err(|e| {
+            BootforgeError::Usb(format!(
+                "Failed to claim interface {} on {}: {}",
+                interface, device.unique_key(), e
+            ))
+        })?;
+
+        let endpoints = discover_endpoints(&claimed);
+        log::info!(
+            "[UsbTransport] Opened {} interface {} ({} endpoints)",
+            device.unique_key(), interface, endpoints.len()
+        );
+
+        let mut transport = Self::with_backend(device, Box::new(NusbTransportBackend::new(claimed)));
+        transport.endpoints = endpoints;
+        Ok(transport)
+    }
+
     pub fn add_endpoint(&mut self, ep: UsbEndpoint) {
         self.endpoints.push(ep);
     }
 
+    pub fn is_open(&self) -> bool {
+        self.backend.is_some()
+    }
+
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    pub fn cancel_handle(&self) -> CancelHandle {
+        CancelHandle { tx: self.cancel.clone() }
+    }
+
+    /// Clear a previous cancellation so the transport can be used again.
+    pub fn reset_cancel(&self) {
+        self.cancel.send_replace(false);
+    }
+
+    pub fn bulk_in_endpoint(&self) -> Option<&UsbEndpoint> {
+        self.endpoints.iter().find(|ep| ep.is_bulk && ep.is_in)
+    }
+
+    pub fn bulk_out_endpoint(&self) -> Option<&UsbEndpoint> {
+        self.endpoints.iter().find(|ep| ep.is_bulk && !ep.is_in)
+    }
+
     pub async fn send(&self, data: &[u8]) -> Result<usize> {
-        log::warn!("USB send not yet implemented (attempted {} bytes)", data.len());
-        // TODO: Implement actual USB write using libusb or rusb
-        // For now, return error to prevent silent failure
-        Err(BootforgeError::Usb("USB transport not yet implemented. Cannot send data to device.".to_string()))
+        let ep = self.bulk_out_endpoint()
+            .ok_or_else(|| BootforgeError::Usb("No bulk OUT endpoint available".to_string()))?
+            .address;
+        let backend = self.backend()?;
+        self.run(backend.bulk_out(ep, data)).await
     }
 
     pub async fn receive(&self, max_len: usize) -> Result<Vec<u8>> {
-        log::warn!("USB receive not yet implemented (requested {} bytes)", max_len);
-        // TODO: Implement actual USB read using libusb or rusb
-        // For now, return error instead of empty data
-        Err(BootforgeError::Usb("USB transport not yet implemented. Cannot receive data from device.".to_string()))
+        let ep = self.bulk_in_endpoint()
+            .ok_or_else(|| BootforgeError::Usb("No bulk IN endpoint available".to_string()))?
+            .address;
+        let backend = self.backend()?;
+        self.run(backend.bulk_in(ep, max_len)).await
+    }
+
+    pub async fn control_in(&self, request: ControlRequest, length: u16) -> Result<Vec<u8>> {
+        let backend = self.backend()?;
+        self.run(backend.control_in(request, length)).await
+    }
+
+    pub async fn control_out(&self, request: ControlRequest, data: &[u8]) -> Result<usize> {
+        let backend = self.backend()?;
+        self.run(backend.control_out(request, data)).await
+    }
+
+    pub fn clear_halt(&self, endpoint: u8) -> Result<()> {
+        self.backend()?.clear_halt(endpoint)
+    }
+
+    pub fn set_alt_setting(&self, alt_setting: u8) -> Result<()> {
+        self.backend()?.set_alt_setting(alt_setting)
+    }
+
+    fn backend(&self) -> Result<&dyn TransportBackend> {
+        self.backend
+            .as_deref()
+            .ok_or_else(|| BootforgeError::Usb("USB transport is not open".to_string()))
+    }
+
+    async fn run<T>(&self, transfer: TransferFuture<'_, T>) -> Result<T> {
+        let mut cancel_rx = self.cancel.subscribe();
+        if *cancel_rx.borrow() {
+            return Err(BootforgeError::Usb("Transfer cancelled".to_string()));
+        }
+
+        // Dropping the transfer future on timeout or cancellation aborts it in the backend.
+        tokio::select! {
+            result = tokio::time::timeout(self.timeout, transfer) => {
+                result.map_err(|_| {
+                    BootforgeError::Usb(format!("Transfer timed out after {:?}", self.timeout))
+                })?
+            }
+            _ = cancel_rx.wait_for(|cancelled| *cancelled) => {
+                Err(BootforgeError::Usb("Transfer cancelled".to_string()))
+            }
+        }
+    }
+}
+
+pub(crate) fn find_nusb_device(device: &UsbDeviceInfo) -> Result<nusb::DeviceInfo> {
+    let mut candidates = nusb::list_devices()
+        .map_err(|e| BootforgeError::Usb(format!("Failed to enumerate USB devices: {}", e)))?
+        .filter(|d| d.vendor_id() == device.vendor_id && d.product_id() == device.product_id)
+        .filter(|d| device.bus.is_none_or(|bus| d.bus_number() == bus))
+        .filter(|d| match device.serial.as_deref() {
+            Some(serial) => d.serial_number() == Some(serial),
+            None => true,
+        });
+
+    candidates.next().ok_or_else(|| {
+        BootforgeError::Usb(format!("Device {} is no longer connected", device.unique_key()))
+    })
+}
+
+fn discover_endpoints(interface: &nusb::Interface) -> Vec<UsbEndpoint> {
+    interface
+        .descriptors()
+        .find(|alt| alt.alternate_setting() == 0)
+        .map(|alt| {
+            alt.endpoints()
+                .map(|ep| UsbEndpoint {
+                    address: ep.address(),
+                    is_in: ep.direction() == nusb::transfer::Direction::In,
+                    is_bulk: ep.transfer_type() == nusb::transfer::EndpointType::Bulk,
+                    max_packet_size: ep.max_packet_size() as u16,
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::usb::backend::tests::fixture_device;
+    use crate::usb::{DeviceMode, ProtocolType};
+    use std::collections::VecDeque;
+    use std::sync::Mutex;
+
+    /// Device end of a test transport, driven by `MockBackend`. Anything a
+    /// device doesn't implement fails like an unsupported transfer.
+    pub(crate) trait SimulatedDevice: Send + fmt::Debug + 'static {
+        fn bulk_out(&mut self, _data: &[u8]) -> Result<()> {
+            Err(unsupported("Bulk OUT"))
+        }
+
+        /// Next packet of at most `max_len` bytes.
+        fn bulk_in(&mut self, _max_len: usize) -> Result<Vec<u8>> {
+            Err(unsupported("Bulk IN"))
+        }
+
+        fn control_in(&mut self, _request: ControlRequest, _length: u16) -> Result<Vec<u8>> {
+            Err(unsupported("Control IN"))
+        }
+
+        fn control_out(&mut self, _request: ControlRequest, _data: &[u8]) -> Result<usize> {
+            Err(unsupported("Control OUT"))
+        }
+
+        fn set_alt_setting(&mut self, _alt_setting: u8) -> Result<()> {
+            Err(unsupported("Alternate settings"))
+        }
+    }
+
+    fn unsupported(what: &str) -> BootforgeError {
+        BootforgeError::Usb(format!("{} not supported by this device", what))
+    }
+
+    /// `TransportBackend` over a `SimulatedDevice` shared with the test.
+    #[derive(Debug)]
+    pub(crate) struct MockBackend<D> {
+        device: Arc<Mutex<D>>,
+        /// Never complete a bulk transfer.
+        hang: bool,
+    }
+
+    impl<D> MockBackend<D> {
+        pub(crate) fn new(device: Arc<Mutex<D>>) -> Self {
+            MockBackend { device, hang: false }
+        }
+
+        async fn stall(&self) {
+            if self.hang {
+                std::future::pending::<()>().await;
+            }
+        }
+    }
+
+    impl<D: SimulatedDevice> TransportBackend for MockBackend<D> {
+        fn bulk_out<'a>(&'a self, _endpoint: u8, data: &'a [u8]) -> TransferFuture<'a, usize> {
+            Box::pin(async move {
+                self.stall().await;
+                self.device.lock().unwrap().bulk_out(data)?;
+                Ok(data.len())
+            })
+        }
+
+        fn bulk_in(&self, _endpoint: u8, max_len: usize) -> TransferFuture<'_, Vec<u8>> {
+            Box::pin(async move {
+                self.stall().await;
+                self.device.lock().unwrap().bulk_in(max_len)
+            })
+        }
+
+        fn control_in(&self, request: ControlRequest, length: u16) -> TransferFuture<'_, Vec<u8>> {
+            Box::pin(async move { self.device.lock().unwrap().control_in(request, length) })
+        }
+
+        fn control_out<'a>(&'a self, request: ControlRequest, data: &'a [u8]) -> TransferFuture<'a, usize> {
+            Box::pin(async move { self.device.lock().unwrap().control_out(request, data) })
+        }
+
+        fn clear_halt(&self, _endpoint: u8) -> Result<()> {
+            Ok(())
+        }
+
+        fn set_alt_setting(&self, alt_setting: u8) -> Result<()> {
+            self.device.lock().unwrap().set_alt_setting(alt_setting)
+        }
+    }
+
+    /// `UsbDeviceInfo` for a device on a test transport.
+    pub(crate) fn test_device(vid: u16, pid: u16, serial: &str, mode: DeviceMode, protocol: ProtocolType) -> UsbDeviceInfo {
+        UsbDeviceInfo { protocol, ..fixture_device(vid, pid, serial, mode) }
+    }
+
+    /// Transport to `device` with bulk IN 0x81 and OUT 0x01, and the device
+    /// state for the test to inspect.
+    pub(crate) fn simulated_transport<D: SimulatedDevice>(info: UsbDeviceInfo, device: D) -> (UsbTransport, Arc<Mutex<D>>) {
+        let device = Arc::new(Mutex::new(device));
+        let mut transport = UsbTransport::with_backend(info, Box::new(MockBackend::new(device.clone())));
+        transport.add_endpoint(UsbEndpoint { address: 0x81, is_in: true, is_bulk: true, max_packet_size: 512 });
+        transport.add_endpoint(UsbEndpoint { address: 0x01, is_in: false, is_bulk: true, max_packet_size: 512 });
+        (transport, device)
+    }
+
+    /// Records what the host sends and answers bulk reads from a queue.
+    #[derive(Debug, Default)]
+    struct Recorder {
+        written: Vec<Vec<u8>>,
+        reads: VecDeque<Vec<u8>>,
+        controls: Vec<ControlRequest>,
+    }
+
+    impl SimulatedDevice for Recorder {
+        fn bulk_out(&mut self, data: &[u8]) -> Result<()> {
+            self.written.push(data.to_vec());
+            Ok(())
+        }
+
+        fn bulk_in(&mut self, max_len: usize) -> Result<Vec<u8>> {
+            let mut data = self.reads.pop_front().unwrap_or_default();
+            data.truncate(max_len);
+            Ok(data)
+        }
+
+        fn control_in(&mut self, request: ControlRequest, length: u16) -> Result<Vec<u8>> {
+            self.controls.push(request);
+            Ok(vec![0xAB; length as usize])
+        }
+
+        fn control_out(&mut self, request: ControlRequest, data: &[u8]) -> Result<usize> {
+            self.controls.push(request);
+            Ok(data.len())
+        }
+    }
+
+    fn recorder_device() -> UsbDeviceInfo {
+        test_device(0x18d1, 0x4ee0, "TEST123", DeviceMode::Fastboot, ProtocolType::Fastboot)
+    }
+
+    fn mock_transport(recorder: Recorder) -> (UsbTransport, Arc<Mutex<Recorder>>) {
+        simulated_transport(recorder_device(), recorder)
+    }
+
+    fn hanging_transport() -> UsbTransport {
+        let backend = MockBackend { device: Arc::new(Mutex::new(Recorder::default())), hang: true };
+        let mut transport = UsbTransport::with_backend(recorder_device(), Box::new(backend));
+        transport.add_endpoint(UsbEndpoint { address: 0x81, is_in: true, is_bulk: true, max_packet_size: 512 });
+        transport.add_endpoint(UsbEndpoint { address: 0x01, is_in: false, is_bulk: true, max_packet_size: 512 });
+        transport
+    }
+
+    #[tokio::test]
+    async fn test_send_and_receive() {
+        let recorder = Recorder { reads: VecDeque::from([b"OKAY".to_vec()]), ..Default::default() };
+        let (transport, device) = mock_transport(recorder);
+
+        assert_eq!(transport.send(b"getvar:product").await.unwrap(), 14);
+        assert_eq!(transport.receive(64).await.unwrap(), b"OKAY".to_vec());
+        assert_eq!(device.lock().unwrap().written, vec![b"getvar:product".to_vec()]);
+    }
+
+    #[tokio::test]
+    async fn test_control_transfers() {
+        let (transport, device) = mock_transport(Recorder::default());
+        let request = ControlRequest {
+            kind: ControlKind::Class,
+            recipient: ControlRecipient::Interface,
+            request: 0x03,
+            value: 0,
+            index: 0,
+        };
+
+        assert_eq!(transport.control_in(request, 6).await.unwrap().len(), 6);
+        assert_eq!(transport.control_out(request, &[1, 2, 3]).await.unwrap(), 3);
+        assert_eq!(device.lock().unwrap().controls.len(), 2);
+
+        let err = transport.set_alt_setting(1).unwrap_err();
+        assert!(err.to_string().contains("not supported"));
+    }
+
+    #[tokio::test]
+    async fn test_unopened_transport_errors() {
+        let transport = UsbTransport::new(recorder_device());
+        assert!(!transport.is_open());
+        assert!(transport.send(b"data").await.is_err());
+        assert!(transport.receive(16).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_missing_endpoint_errors() {
+        let backend = MockBackend::new(Arc::new(Mutex::new(Recorder::default())));
+        let transport = UsbTransport::with_backend(recorder_device(), Box::new(backend));
+        assert!(transport.send(b"data").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_transfer_timeout() {
+        let mut transport = hanging_transport();
+        transport.set_timeout(Duration::from_millis(20));
+
+        let err = transport.receive(64).await.unwrap_err();
+        assert!(err.to_string().contains("timed out"));
+    }
+
+    #[tokio::test]
+    async fn test_transfer_cancellation() {
+        let transport = hanging_transport();
+        let cancel = transport.cancel_handle();
+
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            cancel.cancel();
+        });
+
+        let err = transport.send(b"data").await.unwrap_err();
+        assert!(err.to_string().contains("cancelled"));
+
+        transport.reset_cancel();
+        assert!(!transport.cancel_handle().is_cancelled());
     }
 }
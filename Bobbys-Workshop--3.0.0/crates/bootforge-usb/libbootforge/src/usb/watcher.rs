@@ -1,15 +1,27 @@
-use super::detect::{detect_devices, UsbDeviceInfo, DeviceEvent};
-use std::collections::HashMap;
+use crate::Result;
+use super::backend::{HotplugEvent, HotplugReceiver, NusbBackend, UsbBackend};
+use super::detect::{
+    detect_devices_with, record_device_with, DeviceEvent, DeviceState, ProtocolType, UsbDeviceInfo,
+};
+use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
 use std::time::Duration;
-use tokio::sync::{broadcast, RwLock};
-use tokio::time::interval;
+use tokio::sync::{broadcast, Notify, RwLock};
+use tokio::time::{interval, Instant};
+use uuid::Uuid;
 
 #[derive(Debug, Clone)]
 pub struct WatcherConfig {
     pub poll_interval: Duration,
     pub event_buffer_size: usize,
     pub auto_probe: bool,
+    /// Prefer backend hotplug events over periodic full rescans. Falls back to
+    /// polling when the backend has no hotplug support or the watch fails.
+    pub hotplug: bool,
+    /// How long a device that left the bus is held in `DeviceState::Disconnected`
+    /// before `DeviceEvent::Disconnected` is sent. A handset rebooting into
+    /// another mode within this window is reported as `ModeChanged` instead.
+    pub reconnect_window: Duration,
 }
 
 impl Default for WatcherConfig {
@@ -18,45 +30,97 @@ impl Default for WatcherConfig {
             poll_interval: Duration::from_millis(500),
             event_buffer_size: 256,
             auto_probe: true,
+            hotplug: true,
+            reconnect_window: Duration::from_secs(15),
         }
     }
 }
 
+/// Devices keyed by logical ID (see `identity::DeviceCorrelator`).
+#[derive(Debug, Default)]
+struct WatchState {
+    present: HashMap<Uuid, UsbDeviceInfo>,
+    /// Devices that left the bus recently and may come back in another mode.
+    departed: HashMap<Uuid, (UsbDeviceInfo, Instant)>,
+}
+
+impl WatchState {
+    fn next_expiry(&self, window: Duration) -> Option<Instant> {
+        self.departed.values().map(|(_, left)| *left + window).min()
+    }
+
+    /// Whether `track_device` would probe `device`: it is new, re-enumerated,
+    /// or was identified without a probe result.
+    fn needs_probe(&self, device: &UsbDeviceInfo) -> bool {
+        if self.departed.contains_key(&device.id) {
+            return true;
+        }
+        match self.present.get(&device.id) {
+            None => true,
+            Some(existing) => existing.mode != device.mode
+                || existing.product_id != device.product_id
+                || matches!(existing.state, DeviceState::Identified | DeviceState::Disconnected),
+        }
+    }
+
+    /// Logical IDs a newly attached `device` must not take over: everything
+    /// present, except a device on the same port, which can only be `device`
+    /// re-enumerating before its detach event arrived.
+    fn claimed_ids(&self, device: &UsbDeviceInfo) -> Vec<Uuid> {
+        let same_port = |d: &UsbDeviceInfo| {
+            device.bus.is_some() && !device.port_path.is_empty() && d.bus == device.bus && d.port_path == device.port_path
+        };
+        self.present.values().filter(|d| !same_port(d)).map(|d| d.id).collect()
+    }
+}
+
 pub struct DeviceWatcher {
     config: WatcherConfig,
-    devices: Arc<RwLock<HashMap<String, UsbDeviceInfo>>>,
+    backend: Arc<dyn UsbBackend>,
+    devices: Arc<RwLock<WatchState>>,
     event_tx: broadcast::Sender<DeviceEvent>,
     running: Arc<RwLock<bool>>,
+    stop_signal: Arc<Notify>,
 }
-
 impl DeviceWatcher {
     pub fn new(config: WatcherConfig) -> Self {
+        Self::with_backend(config, Arc::new(NusbBackend))
+    }
+
+    pub fn with_backend(config: WatcherConfig, backend: Arc<dyn UsbBackend>) -> Self {
         let (event_tx, _) = broadcast::channel(config.event_buffer_size);
-        
+
         DeviceWatcher {
             config,
-            devices: Arc::new(RwLock::new(HashMap::new())),
+            backend,
+            devices: Arc::new(RwLock::new(WatchState::default())),
             event_tx,
             running: Arc::new(RwLock::new(false)),
+            stop_signal: Arc::new(Notify::new()),
         }
     }
-    
+
     pub fn subscribe(&self) -> broadcast::Receiver<DeviceEvent> {
         self.event_tx.subscribe()
     }
-    
+
     pub async fn get_devices(&self) -> Vec<UsbDeviceInfo> {
         let devices = self.devices.read().await;
-        devices.values().cloned().collect()
+        devices.present.values().cloned().collect()
     }
-    
+
     pub async fn get_device(&self, serial: &str) -> Option<UsbDeviceInfo> {
         let devices = self.devices.read().await;
-        devices.values().find(|d| {
-            d.serial.as_ref().map_or(false, |s| s == serial)
+        devices.present.values().find(|d| {
+            d.serial.as_deref() == Some(serial)
         }).cloned()
     }
-    
+
+    /// Run a single scan, broadcast the resulting events and return them.
+    pub async fn refresh(&self) -> Result<Vec<DeviceEvent>> {
+        scan_once(&self.backend, &self.devices, &self.event_tx, &self.config).await
+    }
+
     pub async fn start(&self) {
         {
             let mut running = self.running.write().await;
@@ -66,94 +130,77 @@ impl DeviceWatcher {
             }
             *running = true;
         }
-        
-        log::info!("[DeviceWatcher] Starting USB device monitoring (interval: {:?})", 
-            self.config.poll_interval);
-        
+
+        let backend = self.backend.clone();
         let devices = self.devices.clone();
         let event_tx = self.event_tx.clone();
-        let poll_interval = self.config.poll_interval;
+        let config = self.config.clone();
         let running = self.running.clone();
-        
+        let stop_signal = self.stop_signal.clone();
+
         tokio::spawn(async move {
-            let mut interval = interval(poll_interval);
-            
-            loop {
-                interval.tick().await;
-                
-                {
-                    let is_running = running.read().await;
-                    if !*is_running {
-                        log::info!("[DeviceWatcher] Stopping...");
-                        break;
-                    }
-                }
-                
-                match detect_devices() {
-                    Ok(current_devices) => {
-                        let mut known_devices = devices.write().await;
-                        
-                        let current_keys: std::collections::HashSet<String> = 
-                            current_devices.iter()
-                                .map(|d| d.unique_key())
-                                .collect();
-                        
-                        let known_keys: std::collections::HashSet<String> = 
-                            known_devices.keys().cloned().collect();
-                        
-                        for device in &current_devices {
-                            let key = device.unique_key();
-                            if !known_keys.contains(&key) {
-                                log::info!("[DeviceWatcher] Device connected: {}", key);
-                                let _ = event_tx.send(DeviceEvent::Connected(device.clone()));
-                                known_devices.insert(key, device.clone());
-                            } else {
-                                let existing = known_devices.get(&key).unwrap();
-                                if existing.mode != device.mode {
-                                    log::info!("[DeviceWatcher] Mode changed: {:?} -> {:?}", 
-                                        existing.mode, device.mode);
-                                    let _ = event_tx.send(DeviceEvent::ModeChanged {
-                                        id: device.id,
-                                        old_mode: existing.mode,
-                                        new_mode: device.mode,
-                                    });
-                                }
-                                known_devices.insert(key, device.clone());
-                            }
-                        }
-                        
-                        let disconnected: Vec<_> = known_keys.difference(&current_keys)
-                            .cloned()
-                            .collect();
-                        
-                        for key in disconnected {
-                            if let Some(device) = known_devices.remove(&key) {
-                                log::info!("[DeviceWatcher] Device disconnected: {}", key);
-                                let _ = event_tx.send(DeviceEvent::Disconnected {
-                                    id: device.id,
-                                    serial: device.serial,
-                                });
-                            }
-                        }
-                    }
+            // Subscribe before the initial scan so no arrival slips between the two.
+            let hotplug_rx = if config.hotplug {
+                match backend.hotplug() {
+                    Ok(rx) => rx,
                     Err(e) => {
-                        log::error!("[DeviceWatcher] Scan error: {}", e);
+                        log::warn!("[DeviceWatcher] Hotplug unavailable ({}), falling back to polling", e);
+                        None
                     }
                 }
+            } else {
+                None
+            };
+
+            if let Some(rx) = hotplug_rx {
+                log::info!("[DeviceWatcher] Starting USB device monitoring (hotplug, {} backend)",
+                    backend.name());
+
+                if let Err(e) = scan_once(&backend, &devices, &event_tx, &config).await {
+                    log::error!("[DeviceWatcher] Initial scan error: {}", e);
+                }
+
+                if !run_hotplug(rx, &backend, &devices, &event_tx, &running, &stop_signal, &config).await {
+                    log::info!("[DeviceWatcher] Stopping...");
+                    return;
+                }
+                log::warn!("[DeviceWatcher] Hotplug stream ended, falling back to polling");
+            }
+
+            log::info!("[DeviceWatcher] Starting USB device monitoring (interval: {:?})",
+                config.poll_interval);
+
+            let mut interval = interval(config.poll_interval);
+
+            loop {
+                tokio::select! {
+                    _ = interval.tick() => {}
+                    _ = stop_signal.notified() => {}
+                }
+
+                if !*running.read().await {
+                    log::info!("[DeviceWatcher] Stopping...");
+                    break;
+                }
+
+                if let Err(e) = scan_once(&backend, &devices, &event_tx, &config).await {
+                    log::error!("[DeviceWatcher] Scan error: {}", e);
+                }
             }
         });
     }
-    
+
     pub async fn stop(&self) {
         let mut running = self.running.write().await;
         *running = false;
+        self.stop_signal.notify_one();
         log::info!("[DeviceWatcher] Stop signal sent");
     }
-    
+
     pub async fn is_running(&self) -> bool {
         *self.running.read().await
     }
-    
+
     pub fn device_count(&self) -> usize {
         self.event_tx.receiver_count()
     }
@@ -164,3 +211,645 @@ impl Default for DeviceWatcher {
         Self::new(WatcherConfig::default())
     }
 }
+
+/// Apply hotplug events until stopped (returns `false`) or the stream closes (returns `true`).
+async fn run_hotplug(
+    mut rx: HotplugReceiver,
+    backend: &Arc<dyn UsbBackend>,
+    devices: &RwLock<WatchState>,
+    event_tx: &broadcast::Sender<DeviceEvent>,
+    running: &RwLock<bool>,
+    stop_signal: &Notify,
+    config: &WatcherConfig,
+) -> bool {
+    loop {
+        let next_expiry = devices.read().await.next_expiry(config.reconnect_window);
+        let expiry = async move {
+            match next_expiry {
+                Some(deadline) => tokio::time::sleep_until(deadline).await,
+                None => std::future::pending().await,
+            }
+        };
+
+        let events = tokio::select! {
+            event = rx.recv() => {
+                if !*running.read().await {
+                    return false;
+                }
+                let Some(event) = event else {
+                    return true;
+                };
+                apply_hotplug_event(backend, devices, event, config).await
+            }
+            _ = stop_signal.notified() => {
+                if !*running.read().await {
+                    return false;
+                }
+                continue;
+            }
+            _ = expiry => {
+                let mut state = devices.write().await;
+                expire_departed(&mut state, Instant::now(), config.reconnect_window)
+            }
+        };
+
+        for device_event in events {
+            let _ = event_tx.send(device_event);
+        }
+    }
+}
+
+/// Update the device map for a single changed device without rescanning the bus.
+///
+/// The cache write and protocol probe run on the blocking pool before the
+/// device map is locked, so readers never wait on USB or disk I/O.
+async fn apply_hotplug_event(
+    backend: &Arc<dyn UsbBackend>,
+    devices: &RwLock<WatchState>,
+    event: HotplugEvent,
+    config: &WatcherConfig,
+) -> Vec<DeviceEvent> {
+    match event {
+        HotplugEvent::Attached(device) => {
+            let present = devices.read().await.claimed_ids(&device);
+            let recorder = backend.clone();
+            let device = match tokio::task::spawn_blocking(move || {
+                let mut device = device;
+                record_device_with(recorder.as_ref(), &mut device, &present);
+                device
+            })
+            .await
+            {
+                Ok(device) => device,
+                Err(e) => {
+                    log::error!("[DeviceWatcher] Recording attached device failed: {}", e);
+                    return Vec::new();
+                }
+            };
+
+            let mut probes = probe_untracked(backend, devices, std::slice::from_ref(&device), config.auto_probe).await;
+            let probe = probes.remove(&device.id);
+            let mut state = devices.write().await;
+            track_device(&mut state, device, probe)
+        }
+        HotplugEvent::Detached { unique_key } => {
+            let mut state = devices.write().await;
+            let id = state.present.values()
+                .find(|d| d.unique_key() == unique_key)
+                .map(|d| d.id);
+
+            match id {
+                Some(id) => depart(&mut state, id, Instant::now(), config.reconnect_window),
+                None => Vec::new(),
+            }
+        }
+    }
+}
+
+/// Rescan the bus. Enumeration, the cache update and probes run on the
+/// blocking pool; the device map is only locked to apply their results.
+async fn scan_once(
+    backend: &Arc<dyn UsbBackend>,
+    devices: &RwLock<WatchState>,
+    event_tx: &broadcast::Sender<DeviceEvent>,
+    config: &WatcherConfig,
+) -> Result<Vec<DeviceEvent>> {
+    let scanner = backend.clone();
+    let current_devices = tokio::task::spawn_blocking(move || detect_devices_with(scanner.as_ref()))
+        .await
+        .map_err(|e| crate::BootforgeError::Usb(format!("USB scan task failed: {}", e)))??;
+    let mut probes = probe_untracked(backend, devices, &current_devices, config.auto_probe).await;
+    let mut state = devices.write().await;
+    let mut events = Vec::new();
+    let now = Instant::now();
+
+    let current_ids: HashSet<Uuid> = current_devices.iter()
+        .map(|d| d.id)
+        .collect();
+
+    for device in current_devices {
+        let probe = probes.remove(&device.id);
+        events.extend(track_device(&mut state, device, probe));
+    }
+
+    let gone: Vec<Uuid> = state.present.keys()
+        .filter(|id| !current_ids.contains(id))
+        .copied()
+        .collect();
+
+    for id in gone {
+        events.extend(depart(&mut state, id, now, config.reconnect_window));
+    }
+
+    events.extend(expire_departed(&mut state, now, config.reconnect_window));
+
+    for event in &events {
+        let _ = event_tx.send(event.clone());
+    }
+
+    Ok(events)
+}
+
+/// Probe, on the blocking pool, every device in `found` that `track_device`
+/// would identify and probe. Keyed by logical ID; empty without `auto_probe`.
+async fn probe_untracked(
+    backend: &Arc<dyn UsbBackend>,
+    devices: &RwLock<WatchState>,
+    found: &[UsbDeviceInfo],
+    auto_probe: bool,
+) -> HashMap<Uuid, Result<ProtocolType>> {
+    if !auto_probe {
+        return HashMap::new();
+    }
+    let to_probe: Vec<UsbDeviceInfo> = {
+        let state = devices.read().await;
+        found.iter().filter(|d| state.needs_probe(d)).cloned().collect()
+    };
+    if to_probe.is_empty() {
+        return HashMap::new();
+    }
+
+    let prober = backend.clone();
+    tokio::task::spawn_blocking(move || {
+        to_probe.into_iter().map(|d| (d.id, prober.probe_protocol(&d))).collect()
+    })
+    .await
+    .unwrap_or_else(|e| {
+        log::error!("[DeviceWatcher] Probe task failed: {}", e);
+        HashMap::new()
+    })
+}
+
+/// Merge a freshly enumerated device into the known set and walk it through
+/// the `Attached -> Identified -> Probed -> Ready` lifecycle.
+///
+/// Devices already present in the same mode and PID keep their state and
+/// protocol. Anything else under a known logical ID re-enumerated, either
+/// while present or within the reconnect window, so it is reported as the
+/// same device and identified again. `probe` is the result of probing the
+/// device, gathered by `probe_untracked` beforehand; a device identified
+/// without one stays `Identified` and is probed on its next sighting.
+fn track_device(
+    state: &mut WatchState,
+    mut device: UsbDeviceInfo,
+    probe: Option<Result<ProtocolType>>,
+) -> Vec<DeviceEvent> {
+    let mut events = Vec::new();
+
+    let previous = match state.present.get(&device.id) {
+        Some(existing) => Some(existing.clone()),
+        None => state.departed.remove(&device.id).map(|(departed, _)| departed),
+    };
+
+    match previous {
+        None => {
+            log::info!("[DeviceWatcher] Device connected: {}", device.unique_key());
+            device.state = DeviceState::Attached;
+            events.push(DeviceEvent::Connected(device.clone()));
+        }
+        Some(existing) => {
+            let reenumerated = existing.mode != device.mode
+                || existing.product_id != device.product_id
+                || existing.state == DeviceState::Disconnected;
+
+            if !reenumerated {
+                device.state = existing.state;
+                device.protocol = existing.protocol;
+                if let (DeviceState::Identified, Some(probe)) = (device.state, probe) {
+                    apply_probe(&mut device, probe, &mut events);
+                }
+                state.present.insert(device.id, device);
+                return events;
+            }
+
+            log::info!("[DeviceWatcher] Device re-enumerated: {} -> {}",
+                existing.unique_key(), device.unique_key());
+
+            if existing.mode != device.mode {
+                log::info!("[DeviceWatcher] Mode changed: {:?} -> {:?}",
+                    existing.mode, device.mode);
+                events.push(DeviceEvent::ModeChanged {
+                    id: device.id,
+                    old_mode: existing.mode,
+                    new_mode: device.mode,
+                });
+            }
+            device.state = existing.state;
+        }
+    }
+
+    transition(&mut device, DeviceState::Identified, &mut events);
+
+    if let Some(probe) = probe {
+        apply_probe(&mut device, probe, &mut events);
+    }
+
+    state.present.insert(device.id, device);
+    events
+}
+
+/// Move a device that left the bus into the departed set, or report it gone
+/// straight away when there is no reconnect window.
+fn depart(state: &mut WatchState, id: Uuid, now: Instant, window: Duration) -> Vec<DeviceEvent> {
+    let Some(mut device) = state.present.remove(&id) else {
+        return Vec::new();
+    };
+
+    if window.is_zero() {
+        return vec![disconnected(device)];
+    }
+
+    log::info!("[DeviceWatcher] Device left the bus: {}", device.unique_key());
+    let mut events = Vec::new();
+    transition(&mut device, DeviceState::Disconnected, &mut events);
+    state.departed.insert(id, (device, now));
+    events
+}
+
+/// Report departed devices that did not come back within the reconnect window.
+fn expire_departed(state: &mut WatchState, now: Instant, window: Duration) -> Vec<DeviceEvent> {
+    let expired: Vec<Uuid> = state.departed.iter()
+        .filter(|(_, (_, left))| now.duration_since(*left) >= window)
+        .map(|(id, _)| *id)
+        .collect();
+
+    expired.into_iter()
+        .filter_map(|id| state.departed.remove(&id))
+        .map(|(device, _)| disconnected(device))
+        .collect()
+}
+
+fn disconnected(device: UsbDeviceInfo) -> DeviceEvent {
+    log::info!("[DeviceWatcher] Device disconnected: {}", device.unique_key());
+    DeviceEvent::Disconnected {
+        id: device.id,
+        serial: device.serial,
+    }
+}
+
+/// Apply the result of probing the device's protocol. Devices with a known
+/// protocol become `Ready`; anything else (hubs, unrecognised peripherals)
+/// stops at `Probed`.
+fn apply_probe(device: &mut UsbDeviceInfo, probe: Result<ProtocolType>, events: &mut Vec<DeviceEvent>) {
+    match probe {
+        Ok(protocol) => {
+            device.protocol = protocol;
+            if protocol != ProtocolType::Unknown {
+                log::info!("[DeviceWatcher] Protocol detected for {}: {:?}",
+                    device.unique_key(), protocol);
+                events.push(DeviceEvent::ProtocolDetected { id: device.id, protocol });
+            }
+
+            transition(device, DeviceState::Probed, events);
+            if protocol != ProtocolType::Unknown {
+                transition(device, DeviceState::Ready, events);
+            }
+        }
+        Err(e) => {
+            log::warn!("[DeviceWatcher] Probe failed for {}: {}", device.unique_key(), e);
+            transition(device, DeviceState::Error, events);
+        }
+    }
+}
+
+fn transition(device: &mut UsbDeviceInfo, new_state: DeviceState, events: &mut Vec<DeviceEvent>) {
+    if device.state == new_state {
+        return;
+    }
+
+    log::debug!("[DeviceWatcher] {} state: {:?} -> {:?}",
+        device.unique_key(), device.state, new_state);
+    events.push(DeviceEvent::StateChanged {
+        id: device.id,
+        old_state: device.state,
+        new_state,
+    });
+    device.state = new_state;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::usb::backend::tests::fixture_device;
+    use crate::usb::{DeviceMode, FixtureBackend};
+
+    fn state_changes(events: &[DeviceEvent]) -> Vec<(DeviceState, DeviceState)> {
+        events.iter().filter_map(|e| match e {
+            DeviceEvent::StateChanged { old_state, new_state, .. } => Some((*old_state, *new_state)),
+            _ => None,
+        }).collect()
+    }
+
+    #[tokio::test]
+    async fn test_connect_mode_change_disconnect_sequence() {
+        let normal = fixture_device(0x18d1, 0x4ee1, "PIXEL", DeviceMode::Normal);
+        let mut recovery = normal.clone();
+        recovery.mode = DeviceMode::Recovery;
+
+        let backend = Arc::new(FixtureBackend::from_snapshots(vec![
+            vec![normal],
+            vec![recovery],
+            vec![],
+        ]));
+        let config = WatcherConfig { reconnect_window: Duration::ZERO, ..Default::default() };
+        let watcher = DeviceWatcher::with_backend(config, backend);
+        let mut rx = watcher.subscribe();
+
+        let events = watcher.refresh().await.unwrap();
+        assert!(matches!(events.first(), Some(DeviceEvent::Connected(_))));
+        assert_eq!(watcher.get_devices().await.len(), 1);
+
+        let events = watcher.refresh().await.unwrap();
+        assert!(matches!(
+            events.first(),
+            Some(DeviceEvent::ModeChanged { old_mode: DeviceMode::Normal, new_mode: DeviceMode::Recovery, .. })
+        ));
+
+        let events = watcher.refresh().await.unwrap();
+        assert!(matches!(events.as_slice(), [DeviceEvent::Disconnected { .. }]));
+        assert!(watcher.get_devices().await.is_empty());
+
+        // Everything returned was also broadcast
+        let mut broadcast = Vec::new();
+        while let Ok(event) = rx.try_recv() {
+            broadcast.push(event);
+        }
+        assert!(matches!(broadcast.first(), Some(DeviceEvent::Connected(_))));
+        assert!(broadcast.iter().any(|e| matches!(e, DeviceEvent::ModeChanged { .. })));
+        assert!(matches!(broadcast.last(), Some(DeviceEvent::Disconnected { .. })));
+    }
+
+    #[test]
+    fn test_two_identical_serial_less_devices_stay_separate() {
+        use crate::usb::cache::DeviceCache;
+        use crate::usb::identity::DeviceCorrelator;
+
+        let mut cache = DeviceCache::new();
+        let mut state = WatchState::default();
+        let now = chrono::Utc::now();
+
+        let mut edl = fixture_device(0x05c6, 0x9008, "", DeviceMode::Download);
+        edl.serial = None;
+        edl.port_path = vec![2];
+        let mut other = edl.clone();
+        other.port_path = vec![3];
+
+        // Each phone arrives in its own hotplug event, as `apply_hotplug_event` records them
+        for device in [&mut edl, &mut other] {
+            let mut correlator = DeviceCorrelator::new();
+            correlator.claim(state.claimed_ids(device));
+            correlator.correlate(&mut cache, device, now);
+            track_device(&mut state, device.clone(), None);
+        }
+
+        assert_ne!(edl.id, other.id);
+        assert_eq!(state.present.len(), 2);
+
+        // The same port re-enumerating before its detach may keep its ID
+        let mut again = edl.clone();
+        again.product_id = 0x900e;
+        assert!(!state.claimed_ids(&again).contains(&edl.id));
+        assert!(state.claimed_ids(&again).contains(&other.id));
+    }
+
+    #[tokio::test]
+    async fn test_unchanged_bus_emits_nothing() {
+        let backend = Arc::new(FixtureBackend::with_devices(vec![
+            fixture_device(0x04e8, 0x6860, "GALAXY", DeviceMode::MTP),
+        ]));
+        let watcher = DeviceWatcher::with_backend(WatcherConfig::default(), backend);
+
+        assert!(!watcher.refresh().await.unwrap().is_empty());
+        assert!(watcher.refresh().await.unwrap().is_empty());
+        assert!(watcher.get_device("GALAXY").await.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_auto_probe_walks_lifecycle_to_ready() {
+        let pixel = fixture_device(0x18d1, 0x4ee0, "PIXEL", DeviceMode::Fastboot);
+        let backend = Arc::new(FixtureBackend::with_devices(vec![pixel.clone()]));
+        backend.set_probe_result(&pixel.unique_key(), ProtocolType::Fastboot);
+        let watcher = DeviceWatcher::with_backend(WatcherConfig::default(), backend);
+
+        let events = watcher.refresh().await.unwrap();
+        assert!(matches!(events[0], DeviceEvent::Connected(ref d) if d.state == DeviceState::Attached));
+        assert!(events.iter().any(|e| matches!(e, DeviceEvent::ProtocolDetected { protocol: ProtocolType::Fastboot, .. })));
+        assert_eq!(state_changes(&events), vec![
+            (DeviceState::Attached, DeviceState::Identified),
+            (DeviceState::Identified, DeviceState::Probed),
+            (DeviceState::Probed, DeviceState::Ready),
+        ]);
+
+        // Every event refers to the same device id
+        let id = watcher.get_device("PIXEL").await.unwrap().id;
+        assert!(events.iter().all(|e| match e {
+            DeviceEvent::Connected(d) => d.id == id,
+            DeviceEvent::StateChanged { id: i, .. } | DeviceEvent::ProtocolDetected { id: i, .. } => *i == id,
+            _ => false,
+        }));
+
+        let device = watcher.get_device("PIXEL").await.unwrap();
+        assert_eq!(device.state, DeviceState::Ready);
+        assert_eq!(device.protocol, ProtocolType::Fastboot);
+    }
+
+    #[tokio::test]
+    async fn test_unknown_protocol_stops_at_probed() {
+        let backend = Arc::new(FixtureBackend::with_devices(vec![
+            fixture_device(0x1d6b, 0x0002, "HUB", DeviceMode::Unknown),
+        ]));
+        let watcher = DeviceWatcher::with_backend(WatcherConfig::default(), backend);
+
+        let events = watcher.refresh().await.unwrap();
+        assert!(!events.iter().any(|e| matches!(e, DeviceEvent::ProtocolDetected { .. })));
+        assert_eq!(watcher.get_device("HUB").await.unwrap().state, DeviceState::Probed);
+    }
+
+    #[tokio::test]
+    async fn test_auto_probe_disabled_stops_at_identified() {
+        let pixel = fixture_device(0x18d1, 0x4ee1, "PIXEL", DeviceMode::Normal);
+        let backend = Arc::new(FixtureBackend::with_devices(vec![pixel.clone()]));
+        backend.set_probe_result(&pixel.unique_key(), ProtocolType::ADB);
+        let config = WatcherConfig { auto_probe: false, ..Default::default() };
+        let watcher = DeviceWatcher::with_backend(config, backend);
+
+        let events = watcher.refresh().await.unwrap();
+        assert_eq!(state_changes(&events), vec![(DeviceState::Attached, DeviceState::Identified)]);
+        assert!(!events.iter().any(|e| matches!(e, DeviceEvent::ProtocolDetected { .. })));
+        assert_eq!(watcher.get_device("PIXEL").await.unwrap().state, DeviceState::Identified);
+    }
+
+    #[tokio::test]
+    async fn test_mode_change_reprobes_device() {
+        let normal = fixture_device(0x18d1, 0x4ee1, "PIXEL", DeviceMode::Normal);
+        let mut sideload = normal.clone();
+        sideload.mode = DeviceMode::Sideload;
+        let key = normal.unique_key();
+
+        let backend = Arc::new(FixtureBackend::from_snapshots(vec![vec![normal], vec![sideload]]));
+        backend.set_probe_result(&key, ProtocolType::ADB);
+        let watcher = DeviceWatcher::with_backend(WatcherConfig::default(), backend);
+
+        watcher.refresh().await.unwrap();
+        let events = watcher.refresh().await.unwrap();
+        assert_eq!(state_changes(&events), vec![
+            (DeviceState::Ready, DeviceState::Identified),
+            (DeviceState::Identified, DeviceState::Probed),
+            (DeviceState::Probed, DeviceState::Ready),
+        ]);
+        assert!(events.iter().any(|e| matches!(e, DeviceEvent::ProtocolDetected { protocol: ProtocolType::ADB, .. })));
+    }
+
+    #[tokio::test]
+    async fn test_pid_change_under_same_logical_id_is_mode_change() {
+        let normal = fixture_device(0x18d1, 0x4ee1, "PIXEL", DeviceMode::Normal);
+        // Same handset after `adb reboot bootloader`, as the correlator reports it
+        let mut fastboot = fixture_device(0x18d1, 0x4ee0, "PIXEL", DeviceMode::Fastboot);
+        fastboot.id = normal.id;
+
+        let backend = Arc::new(FixtureBackend::from_snapshots(vec![vec![normal], vec![fastboot]]));
+        let watcher = DeviceWatcher::with_backend(WatcherConfig::default(), backend);
+
+        watcher.refresh().await.unwrap();
+        let events = watcher.refresh().await.unwrap();
+
+        assert!(matches!(
+            events.first(),
+            Some(DeviceEvent::ModeChanged { old_mode: DeviceMode::Normal, new_mode: DeviceMode::Fastboot, .. })
+        ));
+        assert!(!events.iter().any(|e| matches!(e, DeviceEvent::Connected(_) | DeviceEvent::Disconnected { .. })));
+        assert_eq!(watcher.get_devices().await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_return_within_reconnect_window() {
+        let normal = fixture_device(0x18d1, 0x4ee1, "PIXEL", DeviceMode::Normal);
+        let mut fastboot = fixture_device(0x18d1, 0x4ee0, "PIXEL", DeviceMode::Fastboot);
+        fastboot.id = normal.id;
+
+        let backend = Arc::new(FixtureBackend::from_snapshots(vec![vec![normal], vec![], vec![fastboot]]));
+        let watcher = DeviceWatcher::with_backend(WatcherConfig::default(), backend);
+
+        watcher.refresh().await.unwrap();
+
+        let events = watcher.refresh().await.unwrap();
+        assert_eq!(state_changes(&events), vec![(DeviceState::Probed, DeviceState::Disconnected)]);
+        assert!(watcher.get_devices().await.is_empty());
+
+        let events = watcher.refresh().await.unwrap();
+        assert!(matches!(events.first(), Some(DeviceEvent::ModeChanged { new_mode: DeviceMode::Fastboot, .. })));
+        assert_eq!(state_changes(&events)[0], (DeviceState::Disconnected, DeviceState::Identified));
+        assert!(!events.iter().any(|e| matches!(e, DeviceEvent::Connected(_) | DeviceEvent::Disconnected { .. })));
+    }
+
+    #[tokio::test]
+    async fn test_departed_device_expires_after_window() {
+        let backend = Arc::new(FixtureBackend::from_snapshots(vec![
+            vec![fixture_device(0x18d1, 0x4ee1, "PIXEL", DeviceMode::Normal)],
+            vec![],
+        ]));
+        let config = WatcherConfig { reconnect_window: Duration::from_millis(10), ..Default::default() };
+        let watcher = DeviceWatcher::with_backend(config, backend);
+
+        watcher.refresh().await.unwrap();
+        let events = watcher.refresh().await.unwrap();
+        assert!(!events.iter().any(|e| matches!(e, DeviceEvent::Disconnected { .. })));
+
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        let events = watcher.refresh().await.unwrap();
+        assert!(matches!(events.as_slice(), [DeviceEvent::Disconnected { .. }]));
+    }
+
+    async fn recv(rx: &mut broadcast::Receiver<DeviceEvent>) -> DeviceEvent {
+        tokio::time::timeout(Duration::from_secs(2), rx.recv()).await.unwrap().unwrap()
+    }
+
+    /// Skip lifecycle events and return the next connect/disconnect/mode event.
+    async fn recv_presence(rx: &mut broadcast::Receiver<DeviceEvent>) -> DeviceEvent {
+        loop {
+            match recv(rx).await {
+                DeviceEvent::StateChanged { .. } | DeviceEvent::ProtocolDetected { .. } => continue,
+                event => return event,
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_polling_loop_uses_backend() {
+        let backend = Arc::new(FixtureBackend::new());
+        let config = WatcherConfig {
+            poll_interval: Duration::from_millis(10),
+            hotplug: false,
+            ..Default::default()
+        };
+        let watcher = DeviceWatcher::with_backend(config, backend.clone());
+        let mut rx = watcher.subscribe();
+
+        watcher.start().await;
+        backend.connect(fixture_device(0x18d1, 0x4ee0, "PIXEL", DeviceMode::Fastboot));
+
+        let event = recv(&mut rx).await;
+        assert!(matches!(event, DeviceEvent::Connected(ref d) if d.serial.as_deref() == Some("PIXEL")));
+
+        watcher.stop().await;
+        assert!(!watcher.is_running().await);
+    }
+
+    #[tokio::test]
+    async fn test_hotplug_mode_only_reacts_to_changes() {
+        let hub = fixture_device(0x1d6b, 0x0002, "HUB", DeviceMode::Unknown);
+        let backend = Arc::new(FixtureBackend::with_devices(vec![hub]));
+        // A poll interval this long means any event must have come from hotplug
+        let config = WatcherConfig {
+            poll_interval: Duration::from_secs(3600),
+            reconnect_window: Duration::ZERO,
+            ..Default::default()
+        };
+        let watcher = DeviceWatcher::with_backend(config, backend.clone());
+        let mut rx = watcher.subscribe();
+
+        watcher.start().await;
+        assert!(matches!(recv_presence(&mut rx).await, DeviceEvent::Connected(ref d) if d.serial.as_deref() == Some("HUB")));
+
+        let phone = fixture_device(0x18d1, 0x4ee1, "PIXEL", DeviceMode::Normal);
+        let key = phone.unique_key();
+        backend.set_probe_result(&key, ProtocolType::ADB);
+        backend.connect(phone);
+        assert!(matches!(recv_presence(&mut rx).await, DeviceEvent::Connected(ref d) if d.serial.as_deref() == Some("PIXEL")));
+        assert!(matches!(recv(&mut rx).await, DeviceEvent::StateChanged { new_state: DeviceState::Identified, .. }));
+        assert!(matches!(recv(&mut rx).await, DeviceEvent::ProtocolDetected { protocol: ProtocolType::ADB, .. }));
+
+        backend.disconnect(&key);
+        assert!(matches!(recv_presence(&mut rx).await, DeviceEvent::Disconnected { ref serial, .. } if serial.as_deref() == Some("PIXEL")));
+        assert_eq!(watcher.get_devices().await.len(), 1);
+
+        watcher.stop().await;
+    }
+
+    #[tokio::test]
+    async fn test_hotplug_reattach_with_new_mode() {
+        let fixture = Arc::new(FixtureBackend::new());
+        let devices = RwLock::new(WatchState::default());
+        let config = WatcherConfig::default();
+        let normal = fixture_device(0x18d1, 0x4ee1, "PIXEL", DeviceMode::Normal);
+        let mut sideload = normal.clone();
+        sideload.mode = DeviceMode::Sideload;
+        fixture.set_probe_result(&normal.unique_key(), ProtocolType::ADB);
+        let backend: Arc<dyn UsbBackend> = fixture;
+
+        let events = apply_hotplug_event(&backend, &devices, HotplugEvent::Attached(normal.clone()), &config).await;
+        assert!(matches!(events.first(), Some(DeviceEvent::Connected(_))));
+        assert!(events.iter().any(|e| matches!(e, DeviceEvent::ProtocolDetected { protocol: ProtocolType::ADB, .. })));
+        assert_eq!(devices.read().await.present.values().next().unwrap().state, DeviceState::Ready);
+
+        let events = apply_hotplug_event(&backend, &devices, HotplugEvent::Attached(normal), &config).await;
+        assert!(events.is_empty());
+
+        let events = apply_hotplug_event(&backend, &devices, HotplugEvent::Attached(sideload), &config).await;
+        assert!(matches!(events.first(), Some(DeviceEvent::ModeChanged { new_mode: DeviceMode::Sideload, .. })));
+
+        let events = apply_hotplug_event(&backend, &devices,
+            HotplugEvent::Detached { unique_key: "ffff:ffff:missing".to_string() }, &config).await;
+        assert!(events.is_empty());
+    }
+}
@@ -1,3 +1,4 @@
+use super::hash::{expected_from_sidecars, ExpectedHash, HashAlgorithm, HashService};
 use crate::Result;
 use crate::BootforgeError;
 use std::path::Path;
@@ -5,21 +6,28 @@ use std::path::Path;
 pub struct ChecksumVerifier;
 
 impl ChecksumVerifier {
-    pub async fn compute_sha256(_path: &Path) -> Result<String> {
-        log::warn!("SHA256 checksum computation not yet implemented");
-        // TODO: Implement using sha2 crate:
-        // use sha2::{Sha256, Digest};
-        // let mut file = File::open(path)?;
-        // let mut hasher = Sha256::new();
-        // io::copy(&mut file, &mut hasher)?;
-        // Ok(format!("{:x}", hasher.finalize()))
-        Err(BootforgeError::Storage("Checksum computation not yet implemented. Cannot verify file integrity.".to_string()))
+    pub async fn compute_sha256(path: &Path) -> Result<String> {
+        let digests = HashService::new().hash_file(path, &[HashAlgorithm::Sha256]).await?;
+        digests.get(HashAlgorithm::Sha256)
+            .map(str::to_string)
+            .ok_or_else(|| BootforgeError::Storage("SHA-256 digest missing".to_string()))
     }
 
-    pub async fn verify(_path: &Path, _expected: &str) -> Result<bool> {
-        log::warn!("Checksum verification not yet implemented");
-        // TODO: Compute actual checksum and compare with expected
-        // For now, return error instead of fake success
-        Err(BootforgeError::Storage("Checksum verification not yet implemented. Cannot validate file integrity.".to_string()))
+    /// Check `path` against `expected`, given as `algorithm:hex` or bare hex
+    /// (algorithm guessed from its length).
+    pub async fn verify(path: &Path, expected: &str) -> Result<bool> {
+        let expected = ExpectedHash::parse(expected)?;
+        HashService::new().verify_file(path, &[expected]).await
+    }
+
+    /// Check `path` against a `SHA256SUMS`-style file or `<name>.sha256`
+    /// next to it. Errors if no sidecar lists the file.
+    pub async fn verify_with_sidecar(path: &Path) -> Result<bool> {
+        let (sidecar, expected) = expected_from_sidecars(path)?.ok_or_else(|| {
+            BootforgeError::Storage(format!("No checksum file found for {}", path.display()))
+        })?;
+
+        log::info!("Verifying {} against {}", path.display(), sidecar.display());
+        HashService::new().verify_file(path, &expected).await
     }
 }
@@ -0,0 +1,619 @@
+//! Streaming file hashing and checksum manifests.
+//!
+//! SHA-2 and MD5 digests are inherently sequential, so a large file is read
+//! in big chunks on one thread and every requested algorithm consumes those
+//! chunks on its own worker. Reading overlaps hashing, several algorithms
+//! cost one pass over the file, and BLAKE3 additionally spreads each chunk
+//! across cores.
+
+use crate::imaging::CancelToken;
+use crate::{BootforgeError, Result};
+use md5::Md5;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256, Sha512};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{ErrorKind, Read};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::mpsc::sync_channel;
+use std::sync::Arc;
+use tokio::sync::watch;
+
+/// Bytes read per chunk handed to the hash workers.
+pub const DEFAULT_HASH_CHUNK_SIZE: usize = 4 * 1024 * 1024;
+
+/// Chunks in flight per worker before the reader blocks.
+const CHUNKS_IN_FLIGHT: usize = 2;
+
+/// BLAKE3 chunks at least this large are hashed on multiple threads.
+const BLAKE3_PARALLEL_MIN: usize = 128 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HashAlgorithm {
+    Sha256,
+    Sha512,
+    Blake3,
+    /// Only for legacy vendor manifests; never trust it on its own.
+    Md5,
+}
+
+impl HashAlgorithm {
+    pub const ALL: [HashAlgorithm; 4] = [HashAlgorithm::Sha256, HashAlgorithm::Sha512, HashAlgorithm::Blake3, HashAlgorithm::Md5];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            HashAlgorithm::Sha256 => "sha256",
+            HashAlgorithm::Sha512 => "sha512",
+            HashAlgorithm::Blake3 => "blake3",
+            HashAlgorithm::Md5 => "md5",
+        }
+    }
+
+    /// Length of the hex digest.
+    pub fn hex_len(&self) -> usize {
+        match self {
+            HashAlgorithm::Sha256 | HashAlgorithm::Blake3 => 64,
+            HashAlgorithm::Sha512 => 128,
+            HashAlgorithm::Md5 => 32,
+        }
+    }
+
+    /// Guess from a bare hex digest. 64 characters is taken as SHA-256, the
+    /// common case; BLAKE3 needs an explicit prefix.
+    pub fn from_hex_len(len: usize) -> Option<Self> {
+        match len {
+            32 => Some(HashAlgorithm::Md5),
+            64 => Some(HashAlgorithm::Sha256),
+            128 => Some(HashAlgorithm::Sha512),
+            _ => None,
+        }
+    }
+
+    /// Algorithm implied by a sums file name (`SHA256SUMS`, `image.iso.sha512`,
+    /// `MD5SUMS.txt`).
+    pub fn from_sums_file_name(name: &str) -> Option<Self> {
+        let lower = name.to_ascii_lowercase();
+        [
+            ("sha256", HashAlgorithm::Sha256),
+            ("sha512", HashAlgorithm::Sha512),
+            ("blake3", HashAlgorithm::Blake3),
+            ("b3sum", HashAlgorithm::Blake3),
+            ("md5", HashAlgorithm::Md5),
+        ]
+        .into_iter()
+        .find(|(tag, _)| lower.contains(tag))
+        .map(|(_, algorithm)| algorithm)
+    }
+}
+
+impl fmt::Display for HashAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for HashAlgorithm {
+    type Err = BootforgeError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim().to_ascii_lowercase().replace('-', "").as_str() {
+            "sha256" => Ok(HashAlgorithm::Sha256),
+            "sha512" => Ok(HashAlgorithm::Sha512),
+            "blake3" | "b3" => Ok(HashAlgorithm::Blake3),
+            "md5" => Ok(HashAlgorithm::Md5),
+            other => Err(BootforgeError::Storage(format!("Unknown hash algorithm: {}", other))),
+        }
+    }
+}
+
+/// A digest to check against, written as `sha256:<hex>` or bare hex.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ExpectedHash {
+    pub algorithm: HashAlgorithm,
+    /// Lowercase hex.
+    pub digest: String,
+}
+
+impl ExpectedHash {
+    pub fn new(algorithm: HashAlgorithm, digest: &str) -> Result<Self> {
+        let digest = digest.trim().to_ascii_lowercase();
+        if digest.len() != algorithm.hex_len() || !digest.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(BootforgeError::Storage(format!("Invalid {} digest: {}", algorithm, digest)));
+        }
+        Ok(ExpectedHash { algorithm, digest })
+    }
+
+    pub fn parse(value: &str) -> Result<Self> {
+        let value = value.trim();
+        match value.split_once(':') {
+            Some((algorithm, digest)) => Self::new(algorithm.parse()?, digest),
+            None => {
+                let algorithm = HashAlgorithm::from_hex_len(value.len())
+                    .ok_or_else(|| BootforgeError::Storage(format!("Cannot tell the hash algorithm of '{}'", value)))?;
+                Self::new(algorithm, value)
+            }
+        }
+    }
+
+    pub fn matches(&self, digests: &FileDigests) -> bool {
+        digests.get(self.algorithm) == Some(self.digest.as_str())
+    }
+}
+
+impl fmt::Display for ExpectedHash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.algorithm, self.digest)
+    }
+}
+
+/// Digests of one input, lowercase hex, keyed by algorithm.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileDigests {
+    pub bytes: u64,
+    pub digests: BTreeMap<HashAlgorithm, String>,
+}
+
+impl FileDigests {
+    pub fn get(&self, algorithm: HashAlgorithm) -> Option<&str> {
+        self.digests.get(&algorithm).map(String::as_str)
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct HashProgress {
+    pub total_bytes: u64,
+    pub hashed_bytes: u64,
+    pub percentage: f32,
+}
+
+/// Incremental digest for one algorithm, for callers that feed data themselves.
+pub enum StreamHasher {
+    Sha256(Sha256),
+    Sha512(Sha512),
+    Blake3(Box<blake3::Hasher>),
+    Md5(Md5),
+}
+
+impl StreamHasher {
+    pub fn new(algorithm: HashAlgorithm) -> Self {
+        match algorithm {
+            HashAlgorithm::Sha256 => StreamHasher::Sha256(Sha256::new()),
+            HashAlgorithm::Sha512 => StreamHasher::Sha512(Sha512::new()),
+            HashAlgorithm::Blake3 => StreamHasher::Blake3(Box::new(blake3::Hasher::new())),
+            HashAlgorithm::Md5 => StreamHasher::Md5(Md5::new()),
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            StreamHasher::Sha256(h) => h.update(data),
+            StreamHasher::Sha512(h) => h.update(data),
+            StreamHasher::Blake3(h) if data.len() >= BLAKE3_PARALLEL_MIN => {
+                h.update_rayon(data);
+            }
+            StreamHasher::Blake3(h) => {
+                h.update(data);
+            }
+            StreamHasher::Md5(h) => h.update(data),
+        }
+    }
+
+    /// Lowercase hex digest.
+    pub fn finalize(self) -> String {
+        match self {
+            StreamHasher::Sha256(h) => hex::encode(h.finalize()),
+            StreamHasher::Sha512(h) => hex::encode(h.finalize()),
+            StreamHasher::Blake3(h) => h.finalize().to_hex().to_string(),
+            StreamHasher::Md5(h) => hex::encode(h.finalize()),
+        }
+    }
+}
+
+/// Hex digest of an in-memory buffer.
+pub fn hash_bytes(data: &[u8], algorithm: HashAlgorithm) -> String {
+    let mut state = StreamHasher::new(algorithm);
+    state.update(data);
+    state.finalize()
+}
+
+/// Hashes streams and files with progress and cancellation. Cloning shares
+/// the progress channel and cancel token.
+#[derive(Clone)]
+pub struct HashService {
+    chunk_size: usize,
+    cancel: CancelToken,
+    progress: Arc<watch::Sender<HashProgress>>,
+}
+
+impl HashService {
+    pub fn new() -> Self {
+        Self::with_chunk_size(DEFAULT_HASH_CHUNK_SIZE)
+    }
+
+    pub fn with_chunk_size(chunk_size: usize) -> Self {
+        let (progress, _) = watch::channel(HashProgress::default());
+        HashService {
+            chunk_size: chunk_size.max(1),
+            cancel: CancelToken::new(),
+            progress: Arc::new(progress),
+        }
+    }
+
+    pub fn cancel_token(&self) -> CancelToken {
+        self.cancel.clone()
+    }
+
+    pub fn subscribe(&self) -> watch::Receiver<HashProgress> {
+        self.progress.subscribe()
+    }
+
+    /// Hash `reader` to EOF with every algorithm in `algorithms`. Blocking.
+    pub fn hash_reader<R: Read>(&self, mut reader: R, total_bytes: Option<u64>, algorithms: &[HashAlgorithm]) -> Result<FileDigests> {
+        let mut algorithms = algorithms.to_vec();
+        algorithms.sort();
+        algorithms.dedup();
+        if algorithms.is_empty() {
+            return Err(BootforgeError::Storage("No hash algorithm requested".to_string()));
+        }
+
+        std::thread::scope(|scope| {
+            let workers: Vec<_> = algorithms.iter().map(|&algorithm| {
+                let (tx, rx) = sync_channel::<Arc<Vec<u8>>>(CHUNKS_IN_FLIGHT);
+                let handle = scope.spawn(move || {
+                    let mut state = StreamHasher::new(algorithm);
+                    for chunk in rx {
+                        state.update(&chunk);
+                    }
+                    (algorithm, state.finalize())
+                });
+                (tx, handle)
+            }).collect();
+
+            let mut hashed = 0u64;
+            self.report(hashed, total_bytes);
+
+            let read_result = loop {
+                if self.cancel.is_cancelled() {
+                    break Err(BootforgeError::Storage(format!("Hashing cancelled after {} bytes", hashed)));
+                }
+
+                let chunk = match read_chunk(&mut reader, self.chunk_size) {
+                    Ok(chunk) if chunk.is_empty() => break Ok(()),
+                    Ok(chunk) => Arc::new(chunk),
+                    Err(e) => break Err(e),
+                };
+
+                hashed += chunk.len() as u64;
+                for (tx, _) in &workers {
+                    // A worker only hangs up by panicking, which join reports
+                    let _ = tx.send(Arc::clone(&chunk));
+                }
+                self.report(hashed, total_bytes);
+            };
+
+            let mut digests = FileDigests { bytes: hashed, digests: BTreeMap::new() };
+            for (tx, handle) in workers {
+                drop(tx);
+                let (algorithm, digest) = handle.join()
+                    .map_err(|_| BootforgeError::Storage("Hash worker panicked".to_string()))?;
+                digests.digests.insert(algorithm, digest);
+            }
+
+            read_result.map(|()| digests)
+        })
+    }
+
+    /// `hash_reader` over a file. Blocking.
+    pub fn hash_file_blocking(&self, path: &Path, algorithms: &[HashAlgorithm]) -> Result<FileDigests> {
+        let file = File::open(path)
+            .map_err(|e| BootforgeError::Storage(format!("Failed to open {}: {}", path.display(), e)))?;
+        let len = file.metadata()?.len();
+        self.hash_reader(file, Some(len), algorithms)
+    }
+
+    pub async fn hash_file(&self, path: &Path, algorithms: &[HashAlgorithm]) -> Result<FileDigests> {
+        let service = self.clone();
+        let path = path.to_path_buf();
+        let algorithms = algorithms.to_vec();
+
+        tokio::task::spawn_blocking(move || service.hash_file_blocking(&path, &algorithms))
+            .await
+            .map_err(|e| BootforgeError::Storage(format!("Hash task failed: {}", e)))?
+    }
+
+    /// Hash `path` once and check it against every expected digest.
+    pub async fn verify_file(&self, path: &Path, expected: &[ExpectedHash]) -> Result<bool> {
+        let algorithms: Vec<HashAlgorithm> = expected.iter().map(|e| e.algorithm).collect();
+        let digests = self.hash_file(path, &algorithms).await?;
+        Ok(expected.iter().all(|e| e.matches(&digests)))
+    }
+
+    fn report(&self, hashed: u64, total: Option<u64>) {
+        let total = total.unwrap_or(hashed).max(hashed);
+        let percentage = if total == 0 { 100.0 } else { (hashed as f64 / total as f64 * 100.0) as f32 };
+        self.progress.send_replace(HashProgress { total_bytes: total, hashed_bytes: hashed, percentage });
+    }
+}
+
+impl Default for HashService {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn read_chunk<R: Read>(reader: &mut R, size: usize) -> Result<Vec<u8>> {
+    let mut chunk = vec![0u8; size];
+    let mut filled = 0;
+    while filled < size {
+        match reader.read(&mut chunk[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(BootforgeError::Storage(format!("Failed to read input: {}", e))),
+        }
+    }
+    chunk.truncate(filled);
+    Ok(chunk)
+}
+
+/// One line of a checksum manifest.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChecksumEntry {
+    pub expected: ExpectedHash,
+    pub file_name: String,
+    /// Marked `*` (binary mode) in GNU format. Informational only.
+    pub binary: bool,
+}
+
+/// A `SHA256SUMS`-style sidecar file.
+///
+/// Accepts GNU coreutils lines (`<hex>  name`, `<hex> *name`, `\`-escaped
+/// names), BSD tagged lines (`SHA256 (name) = <hex>`), `#` comments, and
+/// PGP clearsigned wrappers, whose signature is not checked here.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChecksumManifest {
+    pub entries: Vec<ChecksumEntry>,
+}
+
+impl ChecksumManifest {
+    /// Parse manifest text. Untagged lines use `default_algorithm`, or are
+    /// guessed from the digest length.
+    pub fn parse(content: &str, default_algorithm: Option<HashAlgorithm>) -> Result<Self> {
+        let mut entries = Vec::new();
+        let mut in_pgp_header = false;
+
+        for (index, raw) in content.lines().enumerate() {
+            let line = raw.trim_end_matches('\r');
+            let trimmed = line.trim();
+
+            if trimmed == "-----BEGIN PGP SIGNED MESSAGE-----" {
+                in_pgp_header = true;
+                continue;
+            }
+            if in_pgp_header {
+                // Armor headers ("Hash: SHA256") run until the first blank line
+                in_pgp_header = !trimmed.is_empty();
+                continue;
+            }
+            if trimmed == "-----BEGIN PGP SIGNATURE-----" {
+                break;
+            }
+            if trimmed.is_empty() || trimmed.starts_with('#') {
+                continue;
+            }
+
+            let entry = parse_bsd_line(trimmed)
+                .or_else(|| parse_gnu_line(line, default_algorithm))
+                .ok_or_else(|| BootforgeError::Storage(format!("Malformed checksum line {}: {}", index + 1, trimmed)))??;
+            entries.push(entry);
+        }
+
+        Ok(ChecksumManifest { entries })
+    }
+
+    /// Read a manifest, taking the default algorithm from its file name.
+    pub fn load(path: &Path) -> Result<Self> {
+        let content = fs::read_to_string(path)
+            .map_err(|e| BootforgeError::Storage(format!("Failed to read {}: {}", path.display(), e)))?;
+        let default = path.file_name().and_then(|n| n.to_str()).and_then(HashAlgorithm::from_sums_file_name);
+        Self::parse(&content, default)
+    }
+
+    /// Entries for `file_name`, matched on the whole name or its last component.
+    pub fn find(&self, file_name: &str) -> Vec<&ChecksumEntry> {
+        let base = base_name(file_name);
+        self.entries.iter()
+            .filter(|e| e.file_name == file_name || base_name(&e.file_name) == base)
+            .collect()
+    }
+
+    /// Expected digests for `file_name`, strongest algorithm first.
+    pub fn expected_for(&self, file_name: &str) -> Vec<ExpectedHash> {
+        let mut expected: Vec<ExpectedHash> = self.find(file_name).into_iter().map(|e| e.expected.clone()).collect();
+        expected.sort_by_key(|e| e.algorithm);
+        expected.dedup();
+        expected
+    }
+}
+
+fn base_name(name: &str) -> &str {
+    name.rsplit(['/', '\\']).next().unwrap_or(name)
+}
+
+/// `SHA256 (name) = <hex>`
+fn parse_bsd_line(line: &str) -> Option<Result<ChecksumEntry>> {
+    let (tag, rest) = line.split_once(" (")?;
+    let (file_name, digest) = rest.rsplit_once(") = ")?;
+    let algorithm = tag.parse::<HashAlgorithm>().ok()?;
+
+    Some(ExpectedHash::new(algorithm, digest).map(|expected| ChecksumEntry {
+        expected,
+        file_name: file_name.to_string(),
+        binary: false,
+    }))
+}
+
+/// `<hex>  name`, `<hex> *name`, or `\<hex>  escaped\nname`
+fn parse_gnu_line(line: &str, default_algorithm: Option<HashAlgorithm>) -> Option<Result<ChecksumEntry>> {
+    let (escaped, line) = match line.strip_prefix('\\') {
+        Some(rest) => (true, rest),
+        None => (false, line),
+    };
+
+    let (digest, rest) = line.split_once(' ')?;
+    if digest.is_empty() || !digest.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+
+    let (binary, name) = match rest.chars().next()? {
+        '*' => (true, &rest[1..]),
+        ' ' => (false, &rest[1..]),
+        _ => return None,
+    };
+    if name.is_empty() {
+        return None;
+    }
+
+    let file_name = if escaped { name.replace("\\n", "\n").replace("\\\\", "\\") } else { name.to_string() };
+    let algorithm = default_algorithm.or_else(|| HashAlgorithm::from_hex_len(digest.len()))?;
+
+    Some(ExpectedHash::new(algorithm, digest).map(|expected| ChecksumEntry { expected, file_name, binary }))
+}
+
+/// Sidecar manifests that may describe `path`, nearest first: `<name>.sha256`
+/// style files next to it, then `SHA256SUMS`-style files in its directory.
+pub fn find_sidecars(path: &Path) -> Vec<PathBuf> {
+    let Some(name) = path.file_name().and_then(|n| n.to_str()) else {
+        return Vec::new();
+    };
+    let dir = path.parent().filter(|d| !d.as_os_str().is_empty()).unwrap_or(Path::new("."));
+
+    let per_file = ["sha256", "sha512", "b3", "blake3", "md5"]
+        .into_iter()
+        .map(|ext| dir.join(format!("{}.{}", name, ext)));
+    let shared = ["SHA256SUMS", "SHA512SUMS", "B3SUMS", "MD5SUMS", "CHECKSUM", "sha256sum.txt"]
+        .into_iter()
+        .map(|file| dir.join(file));
+
+    per_file.chain(shared).filter(|p| p.is_file()).collect()
+}
+
+/// Expected digests for `path` from the first sidecar that mentions it.
+pub fn expected_from_sidecars(path: &Path) -> Result<Option<(PathBuf, Vec<ExpectedHash>)>> {
+    let Some(name) = path.file_name().and_then(|n| n.to_str()) else {
+        return Ok(None);
+    };
+
+    for sidecar in find_sidecars(path) {
+        let expected = ChecksumManifest::load(&sidecar)?.expected_for(name);
+        if !expected.is_empty() {
+            return Ok(Some((sidecar, expected)));
+        }
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    const HELLO_SHA256: &str = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+    const HELLO_MD5: &str = "5d41402abc4b2a76b9719d911017c592";
+
+    #[test]
+    fn test_known_digests() {
+        let digests = HashService::with_chunk_size(2)
+            .hash_reader(Cursor::new(b"hello"), Some(5), &HashAlgorithm::ALL)
+            .unwrap();
+
+        assert_eq!(digests.bytes, 5);
+        assert_eq!(digests.get(HashAlgorithm::Sha256), Some(HELLO_SHA256));
+        assert_eq!(digests.get(HashAlgorithm::Md5), Some(HELLO_MD5));
+        assert_eq!(
+            digests.get(HashAlgorithm::Blake3),
+            Some("ea8f163db38682925e4491c5e58d4bb3506ef8c14eb78a86e908c5624a67200f")
+        );
+        assert_eq!(digests.get(HashAlgorithm::Sha512).unwrap(), hash_bytes(b"hello", HashAlgorithm::Sha512));
+        assert!(digests.get(HashAlgorithm::Sha512).unwrap().starts_with("9b71d224bd62f378"));
+    }
+
+    #[test]
+    fn test_chunking_does_not_change_digest() {
+        let data: Vec<u8> = (0..3 * BLAKE3_PARALLEL_MIN + 17).map(|i| (i % 253) as u8).collect();
+        let whole = HashService::with_chunk_size(data.len()).hash_reader(Cursor::new(&data), None, &HashAlgorithm::ALL).unwrap();
+        let chunked = HashService::with_chunk_size(BLAKE3_PARALLEL_MIN).hash_reader(Cursor::new(&data), None, &HashAlgorithm::ALL).unwrap();
+
+        assert_eq!(whole, chunked);
+        assert_eq!(whole.get(HashAlgorithm::Blake3).unwrap(), blake3::hash(&data).to_hex().as_str());
+    }
+
+    #[test]
+    fn test_progress_and_cancel() {
+        let service = HashService::with_chunk_size(4);
+        let progress = service.subscribe();
+        service.hash_reader(Cursor::new(vec![0u8; 10]), Some(10), &[HashAlgorithm::Sha256]).unwrap();
+        assert_eq!(progress.borrow().hashed_bytes, 10);
+        assert_eq!(progress.borrow().percentage, 100.0);
+
+        service.cancel_token().cancel();
+        let err = service.hash_reader(Cursor::new(vec![0u8; 10]), Some(10), &[HashAlgorithm::Sha256]).unwrap_err();
+        assert!(err.to_string().contains("cancelled"));
+    }
+
+    #[test]
+    fn test_expected_hash_parsing() {
+        assert_eq!(ExpectedHash::parse(HELLO_SHA256).unwrap().algorithm, HashAlgorithm::Sha256);
+        assert_eq!(ExpectedHash::parse(&format!("MD5:{}", HELLO_MD5.to_uppercase())).unwrap().digest, HELLO_MD5);
+        assert_eq!(ExpectedHash::parse(&format!("blake3:{}", HELLO_SHA256)).unwrap().algorithm, HashAlgorithm::Blake3);
+        assert!(ExpectedHash::parse("sha256:abcd").is_err());
+        assert!(ExpectedHash::parse("not-a-hash").is_err());
+    }
+
+    #[test]
+    fn test_parse_manifests() {
+        let gnu = format!(
+            "# release checksums\n{}  hello.img\n{} *dir/other.bin\n\\{}  new\\nline\n",
+            HELLO_SHA256, HELLO_SHA256, HELLO_SHA256
+        );
+        let manifest = ChecksumManifest::parse(&gnu, None).unwrap();
+        assert_eq!(manifest.entries.len(), 3);
+        assert!(manifest.entries[1].binary);
+        assert_eq!(manifest.entries[2].file_name, "new\nline");
+        assert_eq!(manifest.find("other.bin").len(), 1);
+
+        let clearsigned = format!(
+            "-----BEGIN PGP SIGNED MESSAGE-----\nHash: SHA256\n\n# Fedora\nSHA256 (hello.iso) = {}\nMD5 (hello.iso) = {}\n-----BEGIN PGP SIGNATURE-----\nabc\n-----END PGP SIGNATURE-----\n",
+            HELLO_SHA256, HELLO_MD5
+        );
+        let manifest = ChecksumManifest::parse(&clearsigned, None).unwrap();
+        let expected = manifest.expected_for("hello.iso");
+        assert_eq!(expected.iter().map(|e| e.algorithm).collect::<Vec<_>>(), [HashAlgorithm::Sha256, HashAlgorithm::Md5]);
+
+        // A 64 character digest in B3SUMS is BLAKE3, not SHA-256
+        assert_eq!(HashAlgorithm::from_sums_file_name("B3SUMS"), Some(HashAlgorithm::Blake3));
+        let b3 = ChecksumManifest::parse(&format!("{}  a.img", HELLO_SHA256), Some(HashAlgorithm::Blake3)).unwrap();
+        assert_eq!(b3.entries[0].expected.algorithm, HashAlgorithm::Blake3);
+
+        let err = ChecksumManifest::parse("garbage line here\n", None).unwrap_err();
+        assert!(err.to_string().contains("line 1"));
+    }
+
+    #[tokio::test]
+    async fn test_verify_with_sidecar() {
+        let dir = tempfile::tempdir().unwrap();
+        let image = dir.path().join("hello.img");
+        fs::write(&image, b"hello").unwrap();
+        fs::write(dir.path().join("SHA256SUMS"), format!("{}  hello.img\n", HELLO_SHA256)).unwrap();
+
+        let (sidecar, expected) = expected_from_sidecars(&image).unwrap().unwrap();
+        assert!(sidecar.ends_with("SHA256SUMS"));
+        assert!(HashService::new().verify_file(&image, &expected).await.unwrap());
+
+        fs::write(&image, b"hellO").unwrap();
+        assert!(!HashService::new().verify_file(&image, &expected).await.unwrap());
+    }
+}
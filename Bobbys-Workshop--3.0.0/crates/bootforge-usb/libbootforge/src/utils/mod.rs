@@ -1,5 +1,17 @@
 pub mod thermal;
 pub mod checksum;
+pub mod hash;
 
 pub use thermal::ThermalMonitor;
 pub use checksum::ChecksumVerifier;
+pub use hash::{
+    HashService,
+    HashAlgorithm,
+    HashProgress,
+    ExpectedHash,
+    FileDigests,
+    StreamHasher,
+    ChecksumManifest,
+    ChecksumEntry,
+    hash_bytes,
+};
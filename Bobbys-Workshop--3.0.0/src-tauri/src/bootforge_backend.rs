@@ -1,15 +1,23 @@
+use device_analysis::device_state::UnifiedDeviceState;
+use libbootforge::drivers::android::adb::DEFAULT_SERVER_ADDR;
+use libbootforge::drivers::{AdbClient, FastbootClient};
+use libbootforge::imaging::{ReadbackVerifier, SigningKey, VerificationRecord, VerificationStatus};
+use libbootforge::imaging::sparse::SPARSE_HEADER_MAGIC;
+use libbootforge::usb::{CancelHandle, ProtocolType, UsbDeviceInfo};
 use serde::{Deserialize, Serialize};
 use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::Read;
 use std::path::Path;
-use std::process::{Child, Command, Stdio};
+use std::process::Command;
 use std::sync::{Arc, Mutex};
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use tauri::{AppHandle, Manager, State};
 
 #[derive(Clone)]
 pub struct BootForgeState {
     flash_jobs: Arc<Mutex<HashMap<String, FlashOperation>>>,
-    flash_job_children: Arc<Mutex<HashMap<String, Arc<Mutex<Option<Child>>>>>>,
+    flash_job_cancels: Arc<Mutex<HashMap<String, Arc<Mutex<Option<CancelHandle>>>>>>,
     flash_history: Arc<Mutex<Vec<FlashOperation>>>,
     device_monitor_stop: Arc<Mutex<Option<std::sync::mpsc::Sender<()>>>>,
 }
@@ -18,7 +26,7 @@ impl BootForgeState {
     pub fn new() -> Self {
         Self {
             flash_jobs: Arc::new(Mutex::new(HashMap::new())),
-            flash_job_children: Arc::new(Mutex::new(HashMap::new())),
+            flash_job_cancels: Arc::new(Mutex::new(HashMap::new())),
             flash_history: Arc::new(Mutex::new(Vec::new())),
             device_monitor_stop: Arc::new(Mutex::new(None)),
         }
@@ -48,6 +56,8 @@ pub struct FlashJobConfig {
     pub verify_after_flash: bool,
     pub auto_reboot: bool,
     pub wipe_user_data: bool,
+    #[serde(default)]
+    pub case_id: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -90,6 +100,8 @@ pub struct FlashOperation {
     pub can_pause: bool,
     pub can_resume: bool,
     pub can_cancel: bool,
+    #[serde(default)]
+    pub verification: Vec<VerificationRecord>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -136,6 +148,9 @@ pub struct BootForgeRawDevice {
     pub capabilities: Vec<String>,
     pub brand: Option<String>,
     pub model: Option<String>,
+    /// Identity, lock and security state read from the device: `getprop`
+    /// over ADB, `getvar:all` in fastboot. `None` if it couldn't be read.
+    pub state: Option<UnifiedDeviceState>,
 }
 
 fn emit_flash(app: &AppHandle, update: RealTimeFlashUpdate) {
@@ -147,51 +162,6 @@ fn emit_device_event(app: &AppHandle, payload: serde_json::Value) {
     let _ = app.emit_all("device-events", payload);
 }
 
-fn run_tool_lines(
-    mut child: Child,
-    on_line: impl Fn(String) + Send + Sync + 'static,
-) -> Result<i32, String> {
-    let stdout = child
-        .stdout
-        .take()
-        .ok_or_else(|| "Failed to capture stdout".to_string())?;
-    let stderr = child
-        .stderr
-        .take()
-        .ok_or_else(|| "Failed to capture stderr".to_string())?;
-
-    let on_line = Arc::new(on_line);
-
-    let out_handle = {
-        let on_line = Arc::clone(&on_line);
-        std::thread::spawn(move || {
-            let reader = std::io::BufReader::new(stdout);
-            for line in std::io::BufRead::lines(reader).flatten() {
-                on_line(line);
-            }
-        })
-    };
-
-    let err_handle = {
-        let on_line = Arc::clone(&on_line);
-        std::thread::spawn(move || {
-            let reader = std::io::BufReader::new(stderr);
-            for line in std::io::BufRead::lines(reader).flatten() {
-                on_line(line);
-            }
-        })
-    };
-
-    let status = child
-        .wait()
-        .map_err(|e| format!("Process wait failed: {e}"))?;
-
-    let _ = out_handle.join();
-    let _ = err_handle.join();
-
-    Ok(status.code().unwrap_or(1))
-}
-
 fn run_adb_devices() -> Result<Vec<String>, String> {
     let output = Command::new("adb")
         .args(["devices", "-l"])
@@ -266,28 +236,56 @@ fn run_fastboot_devices() -> Result<Vec<String>, String> {
 
 #[tauri::command]
 pub fn bootforge_backend_status() -> Result<String, String> {
-    Ok("Backend: tauri-inprocess (adb/fastboot via child processes)".to_string())
+    Ok("Backend: tauri-inprocess (native fastboot flashing and device state; adb/fastboot scans via child processes)".to_string())
 }
 
+/// List ADB and fastboot devices with the state read from each.
+///
+/// USB is enumerated once for all fastboot devices. Devices with a flash job
+/// in progress are listed without reading their state, so the scan never
+/// claims an interface a job is using.
 #[tauri::command]
-pub fn bootforge_scan_devices() -> Result<Vec<BootForgeRawDevice>, String> {
-    let mut errors: Vec<String> = Vec::new();
+pub async fn bootforge_scan_devices(state: State<'_, BootForgeState>) -> Result<Vec<BootForgeRawDevice>, String> {
+    let busy: HashSet<String> = state
+        .flash_jobs
+        .lock()
+        .map_err(|_| "Internal lock poisoned".to_string())?
+        .values()
+        .map(|op| op.job_config.device_serial.clone())
+        .collect();
 
-    let adb_devices = match run_adb_devices() {
-        Ok(s) => s,
-        Err(e) => {
-            errors.push(e);
-            Vec::new()
-        }
-    };
+    let (adb_devices, fastboot_devices, usb_devices, errors) = tauri::async_runtime::spawn_blocking(|| {
+        let mut errors: Vec<String> = Vec::new();
 
-    let fastboot_devices = match run_fastboot_devices() {
-        Ok(s) => s,
-        Err(e) => {
-            errors.push(e);
+        let adb_devices = match run_adb_devices() {
+            Ok(s) => s,
+            Err(e) => {
+                errors.push(e);
+                Vec::new()
+            }
+        };
+
+        let fastboot_devices = match run_fastboot_devices() {
+            Ok(s) => s,
+            Err(e) => {
+                errors.push(e);
+                Vec::new()
+            }
+        };
+
+        let usb_devices = if fastboot_devices.is_empty() {
             Vec::new()
-        }
-    };
+        } else {
+            libbootforge::detect_devices().unwrap_or_else(|e| {
+                log::warn!("[BootForge] USB scan failed: {e}");
+                Vec::new()
+            })
+        };
+
+        (adb_devices, fastboot_devices, usb_devices, errors)
+    })
+    .await
+    .map_err(|e| format!("Device scan task failed: {e}"))?;
 
     if adb_devices.is_empty() && fastboot_devices.is_empty() && !errors.is_empty() {
         return Err(errors.join("\n"));
@@ -296,28 +294,33 @@ pub fn bootforge_scan_devices() -> Result<Vec<BootForgeRawDevice>, String> {
     let mut devices = Vec::new();
 
     for serial in adb_devices {
+        let state = if busy.contains(&serial) { None } else { read_adb_state(&serial).await };
         devices.push(BootForgeRawDevice {
-            serial,
             is_bootloader: false,
             is_recovery: false,
             is_dfu: false,
             is_edl: false,
             capabilities: vec!["adb-sideload".to_string()],
-            brand: None,
-            model: None,
+            brand: identity_field(&state, |i| i.brand.clone().or_else(|| i.manufacturer.clone())),
+            model: identity_field(&state, |i| i.model.clone()),
+            serial,
+            state,
         });
     }
 
     for serial in fastboot_devices {
+        let state = if busy.contains(&serial) { None } else { read_fastboot_state(&usb_devices, &serial).await };
         devices.push(BootForgeRawDevice {
-            serial,
             is_bootloader: true,
             is_recovery: false,
             is_dfu: false,
             is_edl: false,
             capabilities: vec!["fastboot".to_string()],
             brand: None,
-            model: None,
+            // Bootloaders only report the product codename
+            model: identity_field(&state, |i| i.device_codename.clone()),
+            serial,
+            state,
         });
     }
 
@@ -389,6 +392,7 @@ pub fn bootforge_flash_start(
         can_pause: false,
         can_resume: false,
         can_cancel: true,
+        verification: Vec::new(),
     };
 
     {
@@ -399,19 +403,19 @@ pub fn bootforge_flash_start(
         jobs.insert(job_id.clone(), operation);
     }
 
-    let child_holder: Arc<Mutex<Option<Child>>> = Arc::new(Mutex::new(None));
+    let cancel_holder: Arc<Mutex<Option<CancelHandle>>> = Arc::new(Mutex::new(None));
     {
-        let mut children = state
-            .flash_job_children
+        let mut cancels = state
+            .flash_job_cancels
             .lock()
             .map_err(|_| "Internal lock poisoned".to_string())?;
-        children.insert(job_id.clone(), Arc::clone(&child_holder));
+        cancels.insert(job_id.clone(), Arc::clone(&cancel_holder));
     }
 
     let app_clone = app.clone();
     let state_clone = BootForgeState {
         flash_jobs: Arc::clone(&state.flash_jobs),
-        flash_job_children: Arc::clone(&state.flash_job_children),
+        flash_job_cancels: Arc::clone(&state.flash_job_cancels),
         flash_history: Arc::clone(&state.flash_history),
         device_monitor_stop: Arc::clone(&state.device_monitor_stop),
     };
@@ -419,7 +423,7 @@ pub fn bootforge_flash_start(
     let job_id_clone = job_id.clone();
 
     std::thread::spawn(move || {
-        run_fastboot_flash_job(app_clone, state_clone, job_id_clone, config_clone, child_holder);
+        run_fastboot_flash_job(app_clone, state_clone, job_id_clone, config_clone, cancel_holder);
     });
 
     Ok(FlashStartResponse { job_id })
@@ -454,6 +458,11 @@ fn set_progress(state: &BootForgeState, job_id: &str, overall: u32, current_part
     }
 }
 
+fn job_status(state: &BootForgeState, job_id: &str) -> Option<String> {
+    let jobs = state.flash_jobs.lock().ok()?;
+    jobs.get(job_id).map(|op| op.progress.status.clone())
+}
+
 fn fail_job(state: &BootForgeState, job_id: &str, error: String) {
     if let Ok(mut jobs) = state.flash_jobs.lock() {
         if let Some(op) = jobs.get_mut(job_id) {
@@ -474,6 +483,79 @@ fn complete_job(state: &BootForgeState, job_id: &str) {
     }
 }
 
+fn add_verification(state: &BootForgeState, job_id: &str, record: VerificationRecord) {
+    if let Ok(mut jobs) = state.flash_jobs.lock() {
+        if let Some(op) = jobs.get_mut(job_id) {
+            if !record.is_verified() {
+                if let Some(note) = &record.note {
+                    op.progress.warnings.push(format!("{}: {}", record.target, note));
+                }
+            }
+            op.verification.push(record);
+        }
+    }
+}
+
+/// Android sparse images are expanded by the device, so their bytes never
+/// match what is read back from the partition.
+fn is_sparse_image(path: &str) -> bool {
+    let mut magic = [0u8; 4];
+    File::open(path)
+        .and_then(|mut f| f.read_exact(&mut magic))
+        .map(|_| u32::from_le_bytes(magic) == SPARSE_HEADER_MAGIC)
+        .unwrap_or(false)
+}
+
+/// Read `part` back over the open fastboot session and compare it with its
+/// image.
+///
+/// Partitions that cannot be fetched (sparse images, bootloaders without
+/// `fetch`) come back as unverifiable rather than as an error. The record is
+/// signed with the install's verification key.
+fn verify_partition(
+    client: &mut FastbootClient,
+    config: &FlashJobConfig,
+    part: &FlashPartition,
+) -> Result<VerificationRecord, String> {
+    let target = format!("fastboot:{}:{}", config.device_serial, part.name);
+
+    let mut record = if is_sparse_image(&part.image_path) {
+        VerificationRecord::unverifiable(
+            &part.image_path,
+            &target,
+            "sparse image is expanded by the device and cannot be compared with a read-back",
+        )
+    } else {
+        let len = std::fs::metadata(&part.image_path)
+            .map_err(|e| format!("Failed to read {}: {e}", part.image_path))?
+            .len();
+        // Created exclusively and owner-only, and removed when dropped
+        let mut fetched = tempfile::Builder::new()
+            .prefix("bootforge-readback-")
+            .suffix(".img")
+            .tempfile()
+            .map_err(|e| format!("Failed to create read-back file: {e}"))?;
+
+        match tauri::async_runtime::block_on(client.fetch(&part.name, 0, len, fetched.as_file_mut())) {
+            Ok(()) => ReadbackVerifier::new()
+                .verify(Path::new(&part.image_path), fetched.path())
+                .map(|mut record| {
+                    record.target = target.clone();
+                    record
+                })
+                .map_err(|e| e.to_string())?,
+            Err(e) => VerificationRecord::unverifiable(&part.image_path, &target, &e.to_string()),
+        }
+    }
+    .with_case(config.case_id.as_deref());
+
+    let key = SigningKey::default_path()
+        .and_then(|path| SigningKey::load_or_create(&path))
+        .map_err(|e| e.to_string())?;
+    record.sign(&key).map_err(|e| e.to_string())?;
+    Ok(record)
+}
+
 fn archive_job(state: &BootForgeState, job_id: &str) {
     let op = {
         let mut jobs = match state.flash_jobs.lock() {
@@ -492,17 +574,124 @@ fn archive_job(state: &BootForgeState, job_id: &str) {
         }
     }
 
-    if let Ok(mut children) = state.flash_job_children.lock() {
-        children.remove(job_id);
+    if let Ok(mut cancels) = state.flash_job_cancels.lock() {
+        cancels.remove(job_id);
+    }
+}
+
+/// Find the device with `serial` among those in fastboot mode and open it.
+fn open_fastboot(serial: &str) -> Result<FastbootClient, String> {
+    let devices = libbootforge::detect_devices().map_err(|e| format!("USB scan failed: {e}"))?;
+    open_fastboot_in(&devices, serial)
+}
+
+/// `open_fastboot` against an existing enumeration.
+fn open_fastboot_in(devices: &[UsbDeviceInfo], serial: &str) -> Result<FastbootClient, String> {
+    let device = devices
+        .iter()
+        .find(|d| d.protocol == ProtocolType::Fastboot && d.serial.as_deref() == Some(serial))
+        .ok_or_else(|| format!("No device in fastboot mode with serial {serial}"))?;
+    FastbootClient::open(device).map_err(|e| format!("Failed to open fastboot device {serial}: {e}"))
+}
+
+async fn read_adb_state(serial: &str) -> Option<UnifiedDeviceState> {
+    let mut client = AdbClient::server(DEFAULT_SERVER_ADDR, Some(serial.to_string()));
+    match client.device_state().await {
+        Ok(state) => Some(state),
+        Err(e) => {
+            log::warn!("[BootForge] Failed to read state of {serial}: {e}");
+            None
+        }
+    }
+}
+
+async fn read_fastboot_state(devices: &[UsbDeviceInfo], serial: &str) -> Option<UnifiedDeviceState> {
+    let state = match open_fastboot_in(devices, serial) {
+        Ok(mut client) => client.device_state().await.map_err(|e| format!("getvar:all failed: {e}")),
+        Err(e) => Err(e),
+    };
+    match state {
+        Ok(mut state) => {
+            // Keyed like the ADB entry for the same device
+            state.device_id = serial.to_string();
+            Some(state)
+        }
+        Err(e) => {
+            log::warn!("[BootForge] Failed to read state of {serial}: {e}");
+            None
+        }
     }
 }
 
+fn identity_field(
+    state: &Option<UnifiedDeviceState>,
+    field: impl Fn(&device_analysis::device_state::DeviceIdentity) -> Option<String>,
+) -> Option<String> {
+    state.as_ref()?.identity.as_ref().and_then(field)
+}
+
+fn set_transfer(state: &BootForgeState, job_id: &str, transferred: u64, partition_progress: u32, speed: u64) {
+    if let Ok(mut jobs) = state.flash_jobs.lock() {
+        if let Some(op) = jobs.get_mut(job_id) {
+            op.progress.bytes_transferred = transferred;
+            op.progress.partition_progress = partition_progress;
+            op.progress.transfer_speed = speed;
+            op.progress.estimated_time_remaining = match speed {
+                0 => 0,
+                speed => op.progress.total_bytes.saturating_sub(transferred) / speed,
+            };
+        }
+    }
+}
+
+fn fail_and_archive(app: &AppHandle, state: &BootForgeState, job_id: &str, msg: String) {
+    fail_job(state, job_id, msg.clone());
+    emit_flash(
+        app,
+        RealTimeFlashUpdate {
+            kind: "error".to_string(),
+            job_id: job_id.to_string(),
+            timestamp: unix_ms(),
+            data: RealTimeFlashUpdateData {
+                status: Some("failed".to_string()),
+                progress: None,
+                message: Some(msg),
+                bytes_transferred: None,
+                transfer_speed: None,
+            },
+        },
+    );
+    archive_job(state, job_id);
+}
+
+fn cancel_and_archive(app: &AppHandle, state: &BootForgeState, job_id: &str) {
+    set_status(state, job_id, "cancelled", "Cancelled");
+    emit_flash(
+        app,
+        RealTimeFlashUpdate {
+            kind: "status".to_string(),
+            job_id: job_id.to_string(),
+            timestamp: unix_ms(),
+            data: RealTimeFlashUpdateData {
+                status: Some("cancelled".to_string()),
+                progress: None,
+                message: Some("Flash cancelled".to_string()),
+                bytes_transferred: None,
+                transfer_speed: None,
+            },
+        },
+    );
+    archive_job(state, job_id);
+}
+
+/// Flash every partition over a native fastboot session. Large and sparse
+/// images are split to the bootloader's `max-download-size` by the client.
 fn run_fastboot_flash_job(
     app: AppHandle,
     state: BootForgeState,
     job_id: String,
     config: FlashJobConfig,
-    child_holder: Arc<Mutex<Option<Child>>>,
+    cancel_holder: Arc<Mutex<Option<CancelHandle>>>,
 ) {
     set_status(&state, &job_id, "preparing", "Preparing");
     emit_flash(
@@ -521,9 +710,29 @@ fn run_fastboot_flash_job(
         },
     );
 
+    let mut client = match open_fastboot(&config.device_serial) {
+        Ok(client) => client,
+        Err(msg) => return fail_and_archive(&app, &state, &job_id, msg),
+    };
+    let cancel = client.transport().cancel_handle();
+    {
+        let mut holder = cancel_holder.lock().unwrap_or_else(|p| p.into_inner());
+        // Cancelled while the device was being opened
+        if holder.is_none() && matches!(job_status(&state, &job_id).as_deref(), Some("cancelled")) {
+            cancel.cancel();
+        }
+        *holder = Some(cancel.clone());
+    }
+
     let total = config.partitions.len().max(1) as u32;
+    let started = Instant::now();
+    let mut transferred = 0u64;
 
     for (idx, part) in config.partitions.iter().enumerate() {
+        if cancel.is_cancelled() {
+            return cancel_and_archive(&app, &state, &job_id);
+        }
+
         set_status(&state, &job_id, "flashing", &format!("Flashing {}", part.name));
         set_progress(&state, &job_id, (idx as u32 * 100) / total, Some(part.name.clone()));
 
@@ -543,96 +752,86 @@ fn run_fastboot_flash_job(
             },
         );
 
-        let mut cmd = Command::new("fastboot");
-        cmd.args(["-s", &config.device_serial, "flash", &part.name, &part.image_path])
-            .stdout(Stdio::piped())
-            .stderr(Stdio::piped());
+        let mut sent_total = 0u64;
+        let mut last_emit = Instant::now();
+        let on_progress = |sent: u64, piece_total: u64| {
+            sent_total = piece_total;
+            let done = transferred + sent;
+            let speed = (done as f64 / started.elapsed().as_secs_f64().max(0.001)) as u64;
+            let partition_progress = (sent * 100 / piece_total.max(1)) as u32;
+            set_transfer(&state, &job_id, done, partition_progress, speed);
 
-        let child = match cmd.spawn() {
-            Ok(c) => c,
-            Err(e) => {
-                let msg = format!(
-                    "Failed to spawn fastboot. Ensure platform-tools are installed and on PATH. Error: {e}"
-                );
-                fail_job(&state, &job_id, msg.clone());
-                emit_flash(
-                    &app,
-                    RealTimeFlashUpdate {
-                        kind: "error".to_string(),
-                        job_id: job_id.clone(),
-                        timestamp: unix_ms(),
-                        data: RealTimeFlashUpdateData {
-                            status: Some("failed".to_string()),
-                            progress: None,
-                            message: Some(msg),
-                            bytes_transferred: None,
-                            transfer_speed: None,
-                        },
-                    },
-                );
-                archive_job(&state, &job_id);
+            if sent < piece_total && last_emit.elapsed() < Duration::from_millis(250) {
                 return;
             }
-        };
-
-        {
-            if let Ok(mut holder) = child_holder.lock() {
-                *holder = Some(child);
-            }
-        }
-
-        let exit_code = {
-            let holder = Arc::clone(&child_holder);
-            let on_line = move |line: String| {
-                append_log(&state, &job_id, line.clone());
-                emit_flash(
-                    &app,
-                    RealTimeFlashUpdate {
-                        kind: "log".to_string(),
-                        job_id: job_id.clone(),
-                        timestamp: unix_ms(),
-                        data: RealTimeFlashUpdateData {
-                            status: None,
-                            progress: None,
-                            message: Some(line),
-                            bytes_transferred: None,
-                            transfer_speed: None,
-                        },
-                    },
-                );
-            };
-
-            let child_opt = {
-                let mut guard = holder.lock().unwrap_or_else(|p| p.into_inner());
-                guard.take()
-            };
-
-            match child_opt {
-                Some(child) => run_tool_lines(child, on_line).unwrap_or_else(|_| 1),
-                None => 1,
-            }
-        };
-
-        if exit_code != 0 {
-            let msg = format!("fastboot flash {} failed with exit code {}", part.name, exit_code);
-            fail_job(&state, &job_id, msg.clone());
+            last_emit = Instant::now();
             emit_flash(
                 &app,
                 RealTimeFlashUpdate {
-                    kind: "error".to_string(),
+                    kind: "progress".to_string(),
                     job_id: job_id.clone(),
                     timestamp: unix_ms(),
                     data: RealTimeFlashUpdateData {
-                        status: Some("failed".to_string()),
-                        progress: None,
-                        message: Some(msg),
-                        bytes_transferred: None,
-                        transfer_speed: None,
+                        status: Some("flashing".to_string()),
+                        progress: Some((idx as u32 * 100 + partition_progress) / total),
+                        message: None,
+                        bytes_transferred: Some(done),
+                        transfer_speed: Some(speed),
                     },
                 },
             );
-            archive_job(&state, &job_id);
-            return;
+        };
+
+        let flashed = tauri::async_runtime::block_on(client.flash_with_progress(
+            &part.name,
+            Path::new(&part.image_path),
+            on_progress,
+        ));
+        transferred += sent_total;
+
+        if let Err(e) = flashed {
+            if cancel.is_cancelled() {
+                append_log(&state, &job_id, format!("Cancelled while flashing {}", part.name));
+                return cancel_and_archive(&app, &state, &job_id);
+            }
+            let msg = format!("fastboot flash {} failed: {}", part.name, e);
+            append_log(&state, &job_id, msg.clone());
+            return fail_and_archive(&app, &state, &job_id, msg);
+        }
+        append_log(&state, &job_id, format!("Flashed {}", part.name));
+
+        if config.verify_after_flash {
+            set_status(&state, &job_id, "verifying", &format!("Verifying {}", part.name));
+            // Verification was asked for, so a partition that can't be read
+            // back fails the job rather than passing unchecked. Sparse images
+            // are the exception: they are recorded as unverifiable with a
+            // warning, since factory images are mostly sparse.
+            let sparse = is_sparse_image(&part.image_path);
+            let outcome = verify_partition(&mut client, &config, part).and_then(|record| {
+                let status = record.status;
+                let offset = record.first_mismatch_offset;
+                let note = record.note.clone().unwrap_or_default();
+                append_log(&state, &job_id, format!("Read-back of {}: {:?}", part.name, status));
+                add_verification(&state, &job_id, record);
+                match status {
+                    VerificationStatus::Verified => Ok(()),
+                    VerificationStatus::Mismatch => Err(match offset {
+                        Some(offset) => format!("Verification of {} failed at offset {}", part.name, offset),
+                        None => format!("Verification of {} failed", part.name),
+                    }),
+                    VerificationStatus::Unverifiable if sparse => {
+                        append_log(&state, &job_id, format!("Warning: {} not verified: {}", part.name, note));
+                        Ok(())
+                    }
+                    VerificationStatus::Unverifiable => {
+                        Err(format!("{} was flashed but could not be verified: {}", part.name, note))
+                    }
+                }
+            });
+
+            if let Err(msg) = outcome {
+                return fail_and_archive(&app, &state, &job_id, msg);
+            }
         }
 
         let next_progress = (((idx as u32) + 1) * 100) / total;
@@ -647,7 +846,7 @@ fn run_fastboot_flash_job(
                     status: Some("flashing".to_string()),
                     progress: Some(next_progress),
                     message: Some(format!("Flashed {}", part.name)),
-                    bytes_transferred: None,
+                    bytes_transferred: Some(transferred),
                     transfer_speed: None,
                 },
             },
@@ -655,10 +854,10 @@ fn run_fastboot_flash_job(
     }
 
     if config.auto_reboot {
-        let _ = Command::new("fastboot")
-            .args(["-s", &config.device_serial, "reboot"])
-            .output();
-        append_log(&state, &job_id, "Issued fastboot reboot".to_string());
+        match tauri::async_runtime::block_on(client.reboot()) {
+            Ok(()) => append_log(&state, &job_id, "Issued fastboot reboot".to_string()),
+            Err(e) => append_log(&state, &job_id, format!("fastboot reboot failed: {e}")),
+        }
     }
 
     complete_job(&state, &job_id);
@@ -672,7 +871,7 @@ fn run_fastboot_flash_job(
                 status: Some("completed".to_string()),
                 progress: Some(100),
                 message: Some("Flash completed".to_string()),
-                bytes_transferred: None,
+                bytes_transferred: Some(transferred),
                 transfer_speed: None,
             },
         },
@@ -690,23 +889,6 @@ pub fn bootforge_flash_cancel(
         return Err("jobId is required".to_string());
     }
 
-    let child_arc = {
-        let children = state
-            .flash_job_children
-            .lock()
-            .map_err(|_| "Internal lock poisoned".to_string())?;
-        children.get(&job_id).cloned()
-    };
-
-    if let Some(child_holder) = child_arc {
-        if let Ok(mut child_opt) = child_holder.lock() {
-            if let Some(mut child) = child_opt.take() {
-                let _ = child.kill();
-                let _ = child.wait();
-            }
-        }
-    }
-
     // Mark cancelled if present.
     {
         let mut jobs = state
@@ -719,6 +901,24 @@ pub fn bootforge_flash_cancel(
         }
     }
 
+    // Aborts the USB transfer in flight; a job still opening its device
+    // sees the cancelled status instead.
+    let cancel_arc = {
+        let cancels = state
+            .flash_job_cancels
+            .lock()
+            .map_err(|_| "Internal lock poisoned".to_string())?;
+        cancels.get(&job_id).cloned()
+    };
+
+    if let Some(cancel_holder) = cancel_arc {
+        if let Ok(cancel) = cancel_holder.lock() {
+            if let Some(cancel) = cancel.as_ref() {
+                cancel.cancel();
+            }
+        }
+    }
+
     Ok(FlashActionResponse {
         success: true,
         message: Some("Cancel requested".to_string()),
@@ -6,6 +6,12 @@
 use serde::{Deserialize, Serialize};
 use chrono::{DateTime, Utc};
 
+pub mod android;
+pub mod fastboot;
+
+pub use android::{parse_getprop, AndroidStateBuilder};
+pub use fastboot::{FastbootInfo, FastbootPartition, FastbootStateBuilder};
+
 /// Connection status for a device
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 #[serde(rename_all = "snake_case")]
@@ -293,7 +299,8 @@ impl UnifiedDeviceState {
         }
     }
     
-    /// Create from a connected ADB device
+    /// Create from a connected ADB device. `AndroidStateBuilder` fills in
+    /// the rest from the device's properties.
     pub fn from_adb_device(serial: String, model: String, manufacturer: String) -> Self {
         let mut state = Self::new(
             serial.clone(),
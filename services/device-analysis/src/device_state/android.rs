@@ -0,0 +1,493 @@
+//! Android device state from `getprop` and `dumpsys` output.
+//!
+//! Everything here works on captured text, so state can be built from a
+//! live ADB session or from a saved bug report alike.
+
+use super::*;
+use std::collections::BTreeMap;
+
+/// Parse `getprop` output (`[key]: [value]` per line). Values can span
+/// lines; the continuation lines are joined with newlines.
+pub fn parse_getprop(output: &str) -> BTreeMap<String, String> {
+    let mut props = BTreeMap::new();
+    let mut open: Option<(String, String)> = None;
+
+    for line in output.lines() {
+        if let Some((key, value)) = open.as_mut() {
+            value.push('\n');
+            match line.strip_suffix(']') {
+                Some(last) => {
+                    value.push_str(last);
+                    props.insert(std::mem::take(key), std::mem::take(value));
+                    open = None;
+                }
+                None => value.push_str(line),
+            }
+            continue;
+        }
+
+        let Some((key, value)) = line.trim_start().strip_prefix('[').and_then(|rest| rest.split_once("]: [")) else {
+            continue;
+        };
+        match value.strip_suffix(']') {
+            Some(value) => {
+                props.insert(key.to_string(), value.to_string());
+            }
+            None => open = Some((key.to_string(), value.to_string())),
+        }
+    }
+    props
+}
+
+/// Builds a `UnifiedDeviceState` for an Android device from its properties
+/// and whatever `dumpsys` sections are available. Anything the inputs
+/// don't say is left `None`.
+#[derive(Debug, Clone, Default)]
+pub struct AndroidStateBuilder {
+    props: BTreeMap<String, String>,
+    device_id: Option<String>,
+    mode: Option<DeviceMode>,
+    kernel_version: Option<String>,
+    ram_bytes: Option<i64>,
+    storage_bytes: Option<i64>,
+    mdm_enrolled: Option<bool>,
+    root_access: Option<bool>,
+    history: Option<DeviceHistory>,
+}
+
+impl AndroidStateBuilder {
+    /// Start from `getprop` output.
+    pub fn new(getprop: &str) -> Self {
+        Self::from_props(parse_getprop(getprop))
+    }
+
+    pub fn from_props(props: BTreeMap<String, String>) -> Self {
+        Self { props, ..Default::default() }
+    }
+
+    /// Use `device_id` instead of the serial number.
+    pub fn device_id(mut self, device_id: impl Into<String>) -> Self {
+        self.device_id = Some(device_id.into());
+        self
+    }
+
+    /// The mode the device was found in; `Normal` unless set.
+    pub fn mode(mut self, mode: DeviceMode) -> Self {
+        self.mode = Some(mode);
+        self
+    }
+
+    /// `uname -r` or `/proc/version` output.
+    pub fn kernel(mut self, output: &str) -> Self {
+        self.kernel_version = parse_kernel_version(output);
+        self
+    }
+
+    /// `dumpsys meminfo` or `/proc/meminfo` output.
+    pub fn meminfo(mut self, output: &str) -> Self {
+        self.ram_bytes = parse_total_ram(output);
+        self
+    }
+
+    /// `dumpsys diskstats` output.
+    pub fn diskstats(mut self, output: &str) -> Self {
+        self.storage_bytes = parse_data_partition_size(output);
+        self
+    }
+
+    /// `dumpsys device_policy` output.
+    pub fn device_policy(mut self, output: &str) -> Self {
+        self.mdm_enrolled = Some(has_device_owner(output));
+        self
+    }
+
+    /// Whether `su` works, if that was checked.
+    pub fn root_access(mut self, root: bool) -> Self {
+        self.root_access = Some(root);
+        self
+    }
+
+    /// What the host has recorded about earlier connections of the device.
+    pub fn history(mut self, history: DeviceHistory) -> Self {
+        self.history = Some(history);
+        self
+    }
+
+    pub fn props(&self) -> &BTreeMap<String, String> {
+        &self.props
+    }
+
+    pub fn identity(&self) -> DeviceIdentity {
+        DeviceIdentity {
+            vendor_id: None,
+            product_id: None,
+            serial: self.serial(),
+            manufacturer: self.product_prop("manufacturer"),
+            model: self.product_prop("model"),
+            brand: self.product_prop("brand"),
+            device_codename: self.product_prop("device"),
+        }
+    }
+
+    pub fn platform_info(&self) -> PlatformInfo {
+        PlatformInfo {
+            platform_type: Some(PlatformType::Android),
+            version: self.prop("ro.build.version.release"),
+            api_level: self.prop("ro.build.version.sdk").and_then(|sdk| sdk.parse().ok()),
+            build_id: self.prop("ro.build.id"),
+            security_patch: self.prop("ro.build.version.security_patch"),
+            kernel_version: self.kernel_version.clone(),
+        }
+    }
+
+    pub fn hardware_info(&self) -> HardwareInfo {
+        HardwareInfo {
+            cpu_abi: self
+                .prop("ro.product.cpu.abi")
+                .or_else(|| self.abi_list().and_then(|list| list.into_iter().next())),
+            cpu_abi_list: self.abi_list(),
+            soc: self.first_prop(&["ro.soc.model", "ro.board.platform", "ro.hardware"]),
+            ram_bytes: self.ram_bytes,
+            storage_bytes: self.storage_bytes,
+        }
+    }
+
+    pub fn security_info(&self) -> SecurityInfo {
+        let secure_boot = self
+            .first_prop(&["ro.boot.secureboot", "ro.boot.secure_boot"])
+            .map(|value| value == "1")
+            .or_else(|| match (self.verified_boot(), self.locked()) {
+                (Some(VerifiedBootState::Green | VerifiedBootState::Yellow), Some(true)) => Some(true),
+                (Some(VerifiedBootState::Orange | VerifiedBootState::Red), _) | (_, Some(false)) => Some(false),
+                _ => None,
+            });
+
+        SecurityInfo {
+            secure_boot,
+            encryption_state: self.prop("ro.crypto.state").map(|state| match state.as_str() {
+                "encrypted" => EncryptionState::Encrypted,
+                "unencrypted" => EncryptionState::Decrypted,
+                "unsupported" => EncryptionState::Unsupported,
+                _ => EncryptionState::Unknown,
+            }),
+            frp_active: None,
+            mdm_enrolled: self.mdm_enrolled,
+            // Only Samsung devices have Knox; a tripped warranty bit disables it
+            knox_active: self
+                .prop("ro.config.knox")
+                .map(|_| self.prop("ro.boot.warranty_bit").as_deref() != Some("1")),
+        }
+    }
+
+    pub fn capabilities(&self) -> DeviceCapabilities {
+        let usb_config = self.first_prop(&["sys.usb.state", "sys.usb.config", "persist.sys.usb.config"]);
+        let adb_enabled = usb_config
+            .map(|config| config.split(',').any(|function| function == "adb"))
+            .or_else(|| self.prop("init.svc.adbd").map(|state| state == "running"));
+        let root_access = self.root_access.or_else(|| {
+            let adb_root = self.prop("ro.secure").as_deref() == Some("0")
+                || self.prop("service.adb.root").as_deref() == Some("1");
+            adb_root.then_some(true)
+        });
+
+        DeviceCapabilities {
+            adb_enabled,
+            oem_unlock_allowed: self.prop("sys.oem_unlock_allowed").map(|value| value == "1"),
+            // Dynamic partitions imply fastbootd in recovery
+            fastboot_available: self
+                .prop("ro.boot.dynamic_partitions")
+                .filter(|value| value == "true")
+                .map(|_| true),
+            recovery_available: None,
+            root_access,
+            custom_recovery: self.prop("ro.twrp.version").map(|_| true),
+        }
+    }
+
+    pub fn build(self) -> UnifiedDeviceState {
+        let device_id = self
+            .device_id
+            .clone()
+            .or_else(|| self.serial())
+            .unwrap_or_else(|| "unknown".to_string());
+        let mut state = UnifiedDeviceState::new(
+            device_id,
+            ConnectionStatus::Connected,
+            self.mode.clone().unwrap_or(DeviceMode::Normal),
+        );
+
+        state.state.locked = self.locked();
+        state.state.verified_boot = self.verified_boot();
+        state.identity = Some(self.identity());
+        state.platform = Some(self.platform_info());
+        state.hardware = Some(self.hardware_info());
+        state.security = Some(self.security_info());
+        state.capabilities = Some(self.capabilities());
+        state.history = self.history.clone();
+        state.metadata = self
+            .prop("ro.build.fingerprint")
+            .map(|fingerprint| serde_json::json!({ "build_fingerprint": fingerprint }));
+        state
+    }
+
+    fn serial(&self) -> Option<String> {
+        self.first_prop(&["ro.serialno", "ro.boot.serialno"])
+    }
+
+    fn locked(&self) -> Option<bool> {
+        self.prop("ro.boot.flash.locked")
+            .map(|value| value == "1")
+            .or_else(|| self.prop("ro.boot.vbmeta.device_state").map(|state| state == "locked"))
+    }
+
+    fn verified_boot(&self) -> Option<VerifiedBootState> {
+        self.prop("ro.boot.verifiedbootstate").map(|state| match state.as_str() {
+            "green" => VerifiedBootState::Green,
+            "yellow" => VerifiedBootState::Yellow,
+            "orange" => VerifiedBootState::Orange,
+            "red" => VerifiedBootState::Red,
+            _ => VerifiedBootState::Unknown,
+        })
+    }
+
+    fn abi_list(&self) -> Option<Vec<String>> {
+        self.prop("ro.product.cpu.abilist").map(|list| {
+            list.split(',')
+                .map(|abi| abi.trim().to_string())
+                .filter(|abi| !abi.is_empty())
+                .collect()
+        })
+    }
+
+    /// `ro.product.<name>`, or one of the per-partition variants newer
+    /// builds use instead.
+    fn product_prop(&self, name: &str) -> Option<String> {
+        ["", "vendor.", "system.", "odm."]
+            .iter()
+            .find_map(|partition| self.prop(&format!("ro.product.{}{}", partition, name)))
+    }
+
+    fn first_prop(&self, keys: &[&str]) -> Option<String> {
+        keys.iter().find_map(|key| self.prop(key))
+    }
+
+    fn prop(&self, key: &str) -> Option<String> {
+        self.props
+            .get(key)
+            .map(|value| value.trim())
+            .filter(|value| !value.is_empty())
+            .map(str::to_string)
+    }
+}
+
+impl UnifiedDeviceState {
+    /// State of an Android device from its `getprop` output alone.
+    pub fn from_getprop(getprop: &str) -> Self {
+        AndroidStateBuilder::new(getprop).build()
+    }
+}
+
+/// The release from `uname -r` (`4.9.270-g8d1d5b6`) or `/proc/version`
+/// (`Linux version 4.9.270-g8d1d5b6 (...)`).
+pub fn parse_kernel_version(output: &str) -> Option<String> {
+    let line = output.lines().map(str::trim).find(|line| !line.is_empty())?;
+    let version = match line.strip_prefix("Linux version ") {
+        Some(rest) => rest.split_whitespace().next()?,
+        None => line,
+    };
+    Some(version.to_string())
+}
+
+/// Total RAM in bytes from `dumpsys meminfo` (`Total RAM: 3,722,104K`) or
+/// `/proc/meminfo` (`MemTotal: 3722104 kB`).
+pub fn parse_total_ram(output: &str) -> Option<i64> {
+    output.lines().find_map(|line| {
+        let line = line.trim();
+        let value = line
+            .strip_prefix("Total RAM:")
+            .or_else(|| line.strip_prefix("MemTotal:"))?;
+        parse_kilobytes(value.split_whitespace().next()?)
+    })
+}
+
+/// Size of `/data` in bytes from `dumpsys diskstats`
+/// (`Data-Free: 30125440K / 52286080K total = 57% free`).
+pub fn parse_data_partition_size(output: &str) -> Option<i64> {
+    output.lines().find_map(|line| {
+        let (_, total) = line.trim().strip_prefix("Data-Free:")?.split_once('/')?;
+        parse_kilobytes(total.split_whitespace().next()?)
+    })
+}
+
+/// Whether `dumpsys device_policy` lists a device or profile owner, which
+/// means the device is managed.
+pub fn has_device_owner(output: &str) -> bool {
+    output.lines().any(|line| {
+        let line = line.trim();
+        line.starts_with("Device Owner:") || line.starts_with("Profile Owner")
+    })
+}
+
+/// `3,722,104K` or `3722104` (kilobytes) in bytes.
+fn parse_kilobytes(value: &str) -> Option<i64> {
+    let digits: String = value
+        .trim_end_matches(['K', 'k', 'B'])
+        .chars()
+        .filter(|c| *c != ',')
+        .collect();
+    digits.parse::<i64>().ok().and_then(|kb| kb.checked_mul(1024))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PIXEL_GETPROP: &str = include_str!("../../tests/fixtures/android/pixel3a_getprop.txt");
+    const GALAXY_GETPROP: &str = include_str!("../../tests/fixtures/android/galaxy_getprop.txt");
+    const MEMINFO: &str = include_str!("../../tests/fixtures/android/meminfo.txt");
+    const PROC_MEMINFO: &str = include_str!("../../tests/fixtures/android/proc_meminfo.txt");
+    const DISKSTATS: &str = include_str!("../../tests/fixtures/android/diskstats.txt");
+    const DEVICE_POLICY: &str = include_str!("../../tests/fixtures/android/device_policy.txt");
+    const DEVICE_POLICY_NONE: &str = include_str!("../../tests/fixtures/android/device_policy_none.txt");
+
+    #[test]
+    fn test_parse_getprop() {
+        let props = parse_getprop("[a]: [1]\n[motd]: [first line\nsecond line]\n[empty]: []\nnoise\n");
+        assert_eq!(props["a"], "1");
+        assert_eq!(props["motd"], "first line\nsecond line");
+        assert_eq!(props["empty"], "");
+        assert_eq!(props.len(), 3);
+
+        assert_eq!(parse_getprop(PIXEL_GETPROP).len(), 32);
+    }
+
+    #[test]
+    fn test_dumpsys_parsers() {
+        assert_eq!(parse_total_ram(MEMINFO), Some(3_722_104 * 1024));
+        assert_eq!(parse_total_ram(PROC_MEMINFO), Some(7_841_236 * 1024));
+        assert_eq!(parse_data_partition_size(DISKSTATS), Some(52_286_080 * 1024));
+        assert!(has_device_owner(DEVICE_POLICY));
+        assert!(!has_device_owner(DEVICE_POLICY_NONE));
+        assert_eq!(parse_kernel_version("4.9.270-g8d1d5b6\n").as_deref(), Some("4.9.270-g8d1d5b6"));
+        assert_eq!(
+            parse_kernel_version("Linux version 5.10.149-android12 (build-user@build-host) #1 SMP PREEMPT").as_deref(),
+            Some("5.10.149-android12")
+        );
+        assert_eq!(parse_total_ram("nothing here"), None);
+    }
+
+    #[test]
+    fn test_build_pixel_state() {
+        let state = AndroidStateBuilder::new(PIXEL_GETPROP)
+            .kernel("4.9.270-g8d1d5b6")
+            .meminfo(MEMINFO)
+            .diskstats(DISKSTATS)
+            .device_policy(DEVICE_POLICY_NONE)
+            .build();
+
+        assert_eq!(state.device_id, "94QAY0LR7A");
+        assert_eq!(state.state.mode, DeviceMode::Normal);
+        assert_eq!(state.state.locked, Some(true));
+        assert_eq!(state.state.verified_boot, Some(VerifiedBootState::Green));
+
+        let identity = state.identity.unwrap();
+        assert_eq!(identity.model.as_deref(), Some("Pixel 3a"));
+        assert_eq!(identity.manufacturer.as_deref(), Some("Google"));
+        assert_eq!(identity.device_codename.as_deref(), Some("sargo"));
+
+        let platform = state.platform.unwrap();
+        assert_eq!(platform.platform_type, Some(PlatformType::Android));
+        assert_eq!(platform.api_level, Some(32));
+        assert_eq!(platform.build_id.as_deref(), Some("SP2A.220505.008"));
+        assert_eq!(platform.security_patch.as_deref(), Some("2022-05-05"));
+        assert_eq!(platform.kernel_version.as_deref(), Some("4.9.270-g8d1d5b6"));
+
+        let hardware = state.hardware.unwrap();
+        assert_eq!(hardware.cpu_abi.as_deref(), Some("arm64-v8a"));
+        assert_eq!(hardware.cpu_abi_list.unwrap().len(), 3);
+        assert_eq!(hardware.soc.as_deref(), Some("SDM670"));
+        assert_eq!(hardware.ram_bytes, Some(3_722_104 * 1024));
+        assert_eq!(hardware.storage_bytes, Some(52_286_080 * 1024));
+
+        let security = state.security.unwrap();
+        assert_eq!(security.secure_boot, Some(true));
+        assert_eq!(security.encryption_state, Some(EncryptionState::Encrypted));
+        assert_eq!(security.mdm_enrolled, Some(false));
+        assert_eq!(security.knox_active, None);
+
+        let capabilities = state.capabilities.unwrap();
+        assert_eq!(capabilities.adb_enabled, Some(true));
+        assert_eq!(capabilities.oem_unlock_allowed, Some(true));
+        assert_eq!(capabilities.fastboot_available, Some(true));
+        assert_eq!(capabilities.root_access, None);
+        assert_eq!(capabilities.custom_recovery, None);
+
+        assert_eq!(
+            state.metadata.unwrap()["build_fingerprint"],
+            "google/sargo/sargo:12/SP2A.220505.008/8782922:user/release-keys"
+        );
+    }
+
+    #[test]
+    fn test_build_unlocked_samsung_state() {
+        let state = AndroidStateBuilder::new(GALAXY_GETPROP)
+            .device_id("usb-1-4")
+            .mode(DeviceMode::Recovery)
+            .meminfo(PROC_MEMINFO)
+            .device_policy(DEVICE_POLICY)
+            .root_access(true)
+            .build();
+
+        assert_eq!(state.device_id, "usb-1-4");
+        assert_eq!(state.state.mode, DeviceMode::Recovery);
+        assert_eq!(state.state.locked, Some(false));
+        assert_eq!(state.state.verified_boot, Some(VerifiedBootState::Orange));
+
+        // Only the per-partition product properties are set
+        let identity = state.identity.unwrap();
+        assert_eq!(identity.model.as_deref(), Some("SM-G991B"));
+        assert_eq!(identity.brand.as_deref(), Some("samsung"));
+        assert_eq!(identity.serial.as_deref(), Some("R5CR10ABCDE"));
+
+        let hardware = state.hardware.unwrap();
+        assert_eq!(hardware.cpu_abi.as_deref(), Some("arm64-v8a"));
+        assert_eq!(hardware.soc.as_deref(), Some("exynos2100"));
+        assert_eq!(hardware.ram_bytes, Some(7_841_236 * 1024));
+        assert_eq!(hardware.storage_bytes, None);
+
+        let security = state.security.unwrap();
+        assert_eq!(security.secure_boot, Some(false));
+        assert_eq!(security.mdm_enrolled, Some(true));
+        assert_eq!(security.knox_active, Some(false));
+
+        let capabilities = state.capabilities.unwrap();
+        assert_eq!(capabilities.adb_enabled, Some(true));
+        assert_eq!(capabilities.root_access, Some(true));
+        assert_eq!(capabilities.custom_recovery, Some(true));
+        assert_eq!(capabilities.fastboot_available, None);
+    }
+
+    #[test]
+    fn test_from_getprop_round_trips_through_json() {
+        let state = UnifiedDeviceState::from_getprop(PIXEL_GETPROP);
+        let parsed = UnifiedDeviceState::from_json(&state.to_json().unwrap()).unwrap();
+        assert_eq!(parsed.device_id, "94QAY0LR7A");
+        assert_eq!(parsed.platform.unwrap().version.as_deref(), Some("12"));
+
+        let empty = UnifiedDeviceState::from_getprop("");
+        assert_eq!(empty.device_id, "unknown");
+        assert_eq!(empty.state.locked, None);
+        assert!(empty.hardware.unwrap().cpu_abi.is_none());
+        assert!(empty.history.is_none());
+    }
+
+    #[test]
+    fn test_history() {
+        let history = DeviceHistory {
+            connection_count: Some(3),
+            ..Default::default()
+        };
+        let state = AndroidStateBuilder::new(PIXEL_GETPROP).history(history).build();
+        assert_eq!(state.history.unwrap().connection_count, Some(3));
+    }
+}
@@ -0,0 +1,297 @@
+//! Device state of a bootloader-mode device from `getvar:all`.
+
+use super::*;
+use std::collections::BTreeMap;
+
+/// Parse `getvar:all` lines (`key: value` or `key:value`), with or without
+/// the `(bootloader)` prefix the fastboot tool adds. Per-partition variables
+/// keep their partition in the key (`partition-size:boot_a`), while values
+/// may themselves contain `": "`. Later lines win when a key repeats.
+pub fn parse_getvar_all<S: AsRef<str>>(lines: impl IntoIterator<Item = S>) -> BTreeMap<String, String> {
+    lines
+        .into_iter()
+        .filter_map(|line| {
+            let line = line.as_ref().trim().trim_start_matches("(bootloader)").trim();
+            let (key, value) = line.split_once(": ").or_else(|| line.rsplit_once(':'))?;
+            let key = key.trim();
+            // Skips the tool's own "all:" and "Finished. Total time: ..." lines
+            (!key.is_empty() && key != "all" && !key.contains(' '))
+                .then(|| (key.to_string(), value.trim().to_string()))
+        })
+        .collect()
+}
+
+/// Decimal or `0x`-prefixed hex, as bootloaders report sizes either way.
+pub fn parse_number(value: &str) -> Option<u64> {
+    let value = value.trim();
+    match value.strip_prefix("0x").or_else(|| value.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => value.parse().ok(),
+    }
+}
+
+/// A partition as the bootloader describes it.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct FastbootPartition {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+    #[serde(rename = "type", skip_serializing_if = "Option::is_none")]
+    pub fs_type: Option<String>,
+    /// Lives inside `super` (dynamic partitions).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub logical: Option<bool>,
+}
+
+/// What the bootloader reports beyond `UnifiedDeviceState`'s fields. Kept
+/// under `metadata.fastboot`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct FastbootInfo {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub slot_count: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub current_slot: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub battery_voltage_mv: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bootloader_version: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub baseband_version: Option<String>,
+    /// fastbootd (in recovery) rather than the bootloader.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub userspace: Option<bool>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty", default)]
+    pub partitions: BTreeMap<String, FastbootPartition>,
+}
+
+/// Builds a `UnifiedDeviceState` for a device in fastboot from its
+/// `getvar:all` variables.
+#[derive(Debug, Clone, Default)]
+pub struct FastbootStateBuilder {
+    vars: BTreeMap<String, String>,
+    device_id: Option<String>,
+    history: Option<DeviceHistory>,
+}
+
+impl FastbootStateBuilder {
+    pub fn new(vars: BTreeMap<String, String>) -> Self {
+        Self { vars, ..Default::default() }
+    }
+
+    /// Start from `fastboot getvar all` output.
+    pub fn from_output(output: &str) -> Self {
+        Self::new(parse_getvar_all(output.lines()))
+    }
+
+    /// Use `device_id` instead of the serial number.
+    pub fn device_id(mut self, device_id: impl Into<String>) -> Self {
+        self.device_id = Some(device_id.into());
+        self
+    }
+
+    /// What the host has recorded about earlier connections of the device.
+    pub fn history(mut self, history: DeviceHistory) -> Self {
+        self.history = Some(history);
+        self
+    }
+
+    pub fn vars(&self) -> &BTreeMap<String, String> {
+        &self.vars
+    }
+
+    pub fn identity(&self) -> DeviceIdentity {
+        DeviceIdentity {
+            serial: self.var("serialno"),
+            device_codename: self.var("product"),
+            ..Default::default()
+        }
+    }
+
+    pub fn security_info(&self) -> SecurityInfo {
+        SecurityInfo {
+            secure_boot: self.flag("secure"),
+            ..Default::default()
+        }
+    }
+
+    pub fn fastboot_info(&self) -> FastbootInfo {
+        let mut partitions: BTreeMap<String, FastbootPartition> = BTreeMap::new();
+        for (key, value) in &self.vars {
+            let Some((name, partition)) = key.split_once(':') else {
+                continue;
+            };
+            if !matches!(name, "partition-size" | "partition-type" | "is-logical") {
+                continue;
+            }
+            let entry = partitions.entry(partition.to_string()).or_default();
+            match name {
+                "partition-size" => entry.size = parse_number(value),
+                "partition-type" => entry.fs_type = Some(value.clone()).filter(|t| !t.is_empty()),
+                _ => entry.logical = Some(value == "yes"),
+            }
+        }
+
+        FastbootInfo {
+            slot_count: self.var("slot-count").and_then(|count| count.parse().ok()),
+            current_slot: self.var("current-slot").map(|slot| slot.trim_start_matches('_').to_string()),
+            battery_voltage_mv: self.var("battery-voltage").and_then(|mv| mv.parse().ok()),
+            bootloader_version: self.var("version-bootloader"),
+            baseband_version: self.var("version-baseband"),
+            userspace: self.flag("is-userspace"),
+            partitions,
+        }
+    }
+
+    pub fn build(self) -> UnifiedDeviceState {
+        let device_id = self
+            .device_id
+            .clone()
+            .or_else(|| self.var("serialno"))
+            .unwrap_or_else(|| "unknown".to_string());
+        let mut state = UnifiedDeviceState::new(device_id, ConnectionStatus::Connected, DeviceMode::Fastboot);
+
+        state.state.locked = self.flag("unlocked").map(|unlocked| !unlocked);
+        state.identity = Some(self.identity());
+        state.platform = Some(PlatformInfo {
+            platform_type: Some(PlatformType::Android),
+            version: self.var("version-os"),
+            ..Default::default()
+        });
+        state.hardware = self.var("cpu-abi").map(|abi| HardwareInfo {
+            cpu_abi: Some(abi),
+            ..Default::default()
+        });
+        state.security = Some(self.security_info());
+        state.history = self.history.clone();
+        state.capabilities = Some(DeviceCapabilities {
+            fastboot_available: Some(true),
+            ..Default::default()
+        });
+        state.metadata = serde_json::to_value(self.fastboot_info())
+            .ok()
+            .map(|info| serde_json::json!({ "fastboot": info }));
+        state
+    }
+
+    /// `yes`/`no` variables.
+    fn flag(&self, key: &str) -> Option<bool> {
+        self.var(key).and_then(|value| match value.to_ascii_lowercase().as_str() {
+            "yes" | "true" | "1" => Some(true),
+            "no" | "false" | "0" => Some(false),
+            _ => None,
+        })
+    }
+
+    fn var(&self, key: &str) -> Option<String> {
+        self.vars
+            .get(key)
+            .map(|value| value.trim())
+            .filter(|value| !value.is_empty())
+            .map(str::to_string)
+    }
+}
+
+impl UnifiedDeviceState {
+    /// State of a device in fastboot from `fastboot getvar all` output.
+    pub fn from_getvar_all(output: &str) -> Self {
+        FastbootStateBuilder::from_output(output).build()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PIXEL_GETVAR: &str = include_str!("../../tests/fixtures/fastboot/pixel3a_getvar_all.txt");
+    const FASTBOOTD_GETVAR: &str = include_str!("../../tests/fixtures/fastboot/fastbootd_getvar_all.txt");
+
+    #[test]
+    fn test_parse_getvar_all() {
+        let vars = parse_getvar_all(PIXEL_GETVAR.lines());
+        assert_eq!(vars["product"], "sargo");
+        assert_eq!(vars["partition-size:boot_a"], "0x4000000");
+        assert_eq!(vars["partition-type:userdata"], "f2fs");
+        assert_eq!(vars["has-slot:boot"], "yes");
+        assert_eq!(vars["variant"], "SDM UFS");
+        assert!(!vars.contains_key("all"));
+        assert!(!vars.keys().any(|key| key.starts_with("Finished")));
+
+        // The client hands over INFO payloads without the prefix
+        let vars = parse_getvar_all([
+            "version: 0.4",
+            "partition-size:boot_a: 0x1000",
+            "version-baseband: g8150-00041-2106: MPSS.HI.2.0",
+            "partition-type:boot_b:raw",
+        ]);
+        assert_eq!(vars["version"], "0.4");
+        assert_eq!(vars["partition-size:boot_a"], "0x1000");
+        assert_eq!(vars["version-baseband"], "g8150-00041-2106: MPSS.HI.2.0");
+        assert_eq!(vars["partition-type:boot_b"], "raw");
+
+        assert_eq!(parse_number("0x20000000"), Some(0x2000_0000));
+        assert_eq!(parse_number("536870912"), Some(536_870_912));
+        assert_eq!(parse_number("lots"), None);
+    }
+
+    #[test]
+    fn test_locked_bootloader_state() {
+        let state = UnifiedDeviceState::from_getvar_all(PIXEL_GETVAR);
+
+        assert_eq!(state.device_id, "94QAY0LR7A");
+        assert_eq!(state.state.mode, DeviceMode::Fastboot);
+        assert_eq!(state.state.locked, Some(true));
+        assert_eq!(state.security.unwrap().secure_boot, Some(true));
+
+        let identity = state.identity.unwrap();
+        assert_eq!(identity.serial.as_deref(), Some("94QAY0LR7A"));
+        assert_eq!(identity.device_codename.as_deref(), Some("sargo"));
+        assert_eq!(state.capabilities.unwrap().fastboot_available, Some(true));
+        assert!(state.hardware.is_none());
+
+        let info: FastbootInfo = serde_json::from_value(state.metadata.unwrap()["fastboot"].clone()).unwrap();
+        assert_eq!(info.slot_count, Some(2));
+        assert_eq!(info.current_slot.as_deref(), Some("a"));
+        assert_eq!(info.battery_voltage_mv, Some(4012));
+        assert_eq!(info.bootloader_version.as_deref(), Some("b4s4-0.4-7617406"));
+        assert_eq!(info.userspace, Some(false));
+        assert_eq!(info.partitions.len(), 4);
+        assert_eq!(
+            info.partitions["userdata"],
+            FastbootPartition { size: Some(0xC6A3F8000), fs_type: Some("f2fs".to_string()), logical: None }
+        );
+        assert_eq!(info.partitions["boot_a"].size, Some(0x400_0000));
+    }
+
+    #[test]
+    fn test_unlocked_fastbootd_state() {
+        let state = FastbootStateBuilder::from_output(FASTBOOTD_GETVAR).device_id("usb-2-1").build();
+
+        assert_eq!(state.device_id, "usb-2-1");
+        assert_eq!(state.state.locked, Some(false));
+        assert_eq!(state.security.unwrap().secure_boot, Some(false));
+        assert_eq!(state.platform.unwrap().version.as_deref(), Some("13"));
+        assert_eq!(state.hardware.unwrap().cpu_abi.as_deref(), Some("arm64-v8a"));
+
+        let info: FastbootInfo = serde_json::from_value(state.metadata.unwrap()["fastboot"].clone()).unwrap();
+        assert_eq!(info.userspace, Some(true));
+        assert_eq!(info.current_slot.as_deref(), Some("b"));
+        assert_eq!(info.partitions["system_a"].logical, Some(true));
+        assert_eq!(info.partitions["boot_a"].logical, Some(false));
+        assert_eq!(info.partitions["super"].size, Some(0x2_2000_0000));
+
+        let empty = UnifiedDeviceState::from_getvar_all("");
+        assert_eq!(empty.device_id, "unknown");
+        assert_eq!(empty.state.locked, None);
+        assert_eq!(empty.security.unwrap().secure_boot, None);
+        assert!(empty.history.is_none());
+    }
+
+    #[test]
+    fn test_history() {
+        let history = DeviceHistory {
+            connection_count: Some(2),
+            ..Default::default()
+        };
+        let state = FastbootStateBuilder::from_output(PIXEL_GETVAR).history(history).build();
+        assert_eq!(state.history.unwrap().connection_count, Some(2));
+    }
+}
@@ -1,6 +1,8 @@
 // ForgeWorks Core - Device Analysis Service
 // COMPLIANCE-FIRST: Analysis only, no execution
 
+pub mod device_state;
+
 use serde::{Deserialize, Serialize};
 use chrono::Utc;
 